@@ -157,7 +157,36 @@ fn main() {
             .help("use the staging environment")
         )
         .subcommand(SubCommand::with_name("daemon")
-            .about("run SafeDrive daemon")
+            .about("run SafeDrive daemon, syncing folders whenever they change")
+            .arg(Arg::with_name("schedule")
+                .long("schedule")
+                .value_name("SCHEDULE")
+                .help("sync unchanged folders at least this often")
+                .takes_value(true)
+                .possible_values(&["hourly", "daily", "weekly", "monthly"])
+                .default_value("daily")
+            )
+            .arg(Arg::with_name("status")
+                .long("status")
+                .help("show the syncs and restores a running daemon is working on")
+                .conflicts_with("stop")
+            )
+            .arg(Arg::with_name("stop")
+                .long("stop")
+                .help("stop a running daemon once its current sync finishes")
+                .conflicts_with("status")
+            )
+        )
+        .subcommand(SubCommand::with_name("cancel")
+            .about("cancel a sync or restore the daemon is running")
+            .arg(Arg::with_name("session")
+                .short("s")
+                .long("session")
+                .value_name("SESSION")
+                .help("session name of the sync or restore")
+                .takes_value(true)
+                .required(true)
+            )
         )
         .subcommand(SubCommand::with_name("login")
             .about("login to SafeDrive account")
@@ -222,6 +251,42 @@ fn main() {
         .subcommand(SubCommand::with_name("sessions")
             .about("list sync sessions, default will list all if not given a specific folder ID")
         )
+        .subcommand(SubCommand::with_name("ls")
+            .about("list the files in a sync session")
+            .arg(Arg::with_name("folder")
+                .value_name("FOLDER_ID")
+                .help("folder ID")
+                .index(1)
+                .required(true)
+            )
+            .arg(Arg::with_name("session")
+                .value_name("SESSION")
+                .help("session name")
+                .index(2)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("diff")
+            .about("show what changed between two sync sessions of a folder")
+            .arg(Arg::with_name("folder")
+                .value_name("FOLDER_ID")
+                .help("folder ID")
+                .index(1)
+                .required(true)
+            )
+            .arg(Arg::with_name("session-a")
+                .value_name("SESSION_A")
+                .help("older session name")
+                .index(2)
+                .required(true)
+            )
+            .arg(Arg::with_name("session-b")
+                .value_name("SESSION_B")
+                .help("newer session name")
+                .index(3)
+                .required(true)
+            )
+        )
         .subcommand(SubCommand::with_name("clean")
             .about("clean sync sessions")
             .arg(Arg::with_name("id")
@@ -230,26 +295,31 @@ fn main() {
                 .value_name("ID")
                 .help("session ID to remove")
                 .takes_value(true)
-                .conflicts_with_all(&["auto", "all", "before-today", "before-date"])
+                .conflicts_with_all(&["auto", "all", "before-today", "before-date", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"])
                 .required(true)
             )
             .arg(Arg::with_name("auto")
                 .long("auto")
-                .help("session ID to remove")
-                .conflicts_with_all(&["id", "all", "before-today", "before-date"])
+                .help("keep hourly sessions for a day, daily for a week, weekly for a month and monthly after that")
+                .conflicts_with_all(&["id", "all", "before-today", "before-date", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"])
                 .required(true)
             )
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("list the sessions that would be removed without removing them")
+                .conflicts_with("id")
+            )
             .arg(Arg::with_name("all")
                 .long("all")
                 .help("remove all sessions")
-                .conflicts_with_all(&["id", "auto", "before-today", "before-date"])
+                .conflicts_with_all(&["id", "auto", "before-today", "before-date", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"])
                 .required(true)
             )
             .arg(Arg::with_name("before-today")
                 .short("t")
                 .long("before-today")
                 .help("remove all sessions before today at 00:00")
-                .conflicts_with_all(&["id", "auto", "all", "before-date"])
+                .conflicts_with_all(&["id", "auto", "all", "before-date", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"])
                 .required(true)
             )
             .arg(Arg::with_name("before-date")
@@ -257,12 +327,126 @@ fn main() {
                 .long("before-date")
                 .value_name("DATE")
                 .help("delete all sessions older than this date (RFC 3339 format)\n\n")
-                .conflicts_with_all(&["id", "auto", "all", "before-today"])
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"])
+                .required(true)
+            )
+            .arg(Arg::with_name("keep-hourly")
+                .long("keep-hourly")
+                .value_name("COUNT")
+                .help("keep the oldest session for each of the last COUNT hours that have one, for each folder")
+                .takes_value(true)
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "before-date"])
+            )
+            .arg(Arg::with_name("keep-daily")
+                .long("keep-daily")
+                .value_name("COUNT")
+                .help("keep the oldest session for each of the last COUNT days that have one, for each folder")
+                .takes_value(true)
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "before-date"])
+            )
+            .arg(Arg::with_name("keep-weekly")
+                .long("keep-weekly")
+                .value_name("COUNT")
+                .help("keep the oldest session for each of the last COUNT weeks that have one, for each folder")
+                .takes_value(true)
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "before-date"])
+            )
+            .arg(Arg::with_name("keep-monthly")
+                .long("keep-monthly")
+                .value_name("COUNT")
+                .help("keep the oldest session for each of the last COUNT months that have one, for each folder")
+                .takes_value(true)
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "before-date"])
+            )
+            .arg(Arg::with_name("keep-yearly")
+                .long("keep-yearly")
+                .value_name("COUNT")
+                .help("keep the oldest session for each of the last COUNT years that have one, for each folder")
+                .takes_value(true)
+                .conflicts_with_all(&["id", "auto", "all", "before-today", "before-date"])
+            )
+        )
+        .subcommand(SubCommand::with_name("verify")
+            .about("check that sync sessions can be restored, default will check all sessions of all folders")
+            .arg(Arg::with_name("folder")
+                .long("folder")
+                .value_name("ID")
+                .help("only check sessions of this folder")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("session")
+                .long("session")
+                .value_name("NAME")
+                .help("only check this session")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("deep")
+                .long("deep")
+                .help("download, decrypt and hash every block instead of only checking that it exists")
+            )
+        )
+        .subcommand(SubCommand::with_name("ignore")
+            .about("show or change the rules for what a folder's sync sessions leave out")
+            .arg(Arg::with_name("folder")
+                .value_name("FOLDER_ID")
+                .help("folder ID")
+                .index(1)
                 .required(true)
             )
+            .arg(Arg::with_name("add")
+                .long("add")
+                .value_name("PATTERN")
+                .help("ignore paths matching this gitignore style pattern, can be used more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .value_name("PATTERN")
+                .help("remove a pattern added earlier, can be used more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("max-size")
+                .long("max-size")
+                .value_name("BYTES")
+                .help("skip files larger than this")
+                .takes_value(true)
+                .conflicts_with("no-max-size")
+            )
+            .arg(Arg::with_name("no-max-size")
+                .long("no-max-size")
+                .help("sync files of any size")
+            )
+            .arg(Arg::with_name("skip-hidden")
+                .long("skip-hidden")
+                .help("skip files and directories with names starting with a dot")
+                .conflicts_with("keep-hidden")
+            )
+            .arg(Arg::with_name("keep-hidden")
+                .long("keep-hidden")
+                .help("sync files and directories with names starting with a dot")
+            )
+        )
+        .subcommand(SubCommand::with_name("gc")
+            .about("remove blocks in the local cache that are no longer used by any sync session")
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("count the blocks that would be removed without removing them")
+            )
         )
         .subcommand(SubCommand::with_name("syncall")
             .about("sync all registered folders")
+            .arg(Arg::with_name("workers")
+                .short("w")
+                .long("workers")
+                .value_name("COUNT")
+                .help("number of threads used to process blocks, defaults to one per CPU")
+                .takes_value(true)
+                .required(false)
+            )
         )
         .subcommand(SubCommand::with_name("sync")
             .about("sync a folder")
@@ -274,6 +458,14 @@ fn main() {
                 .takes_value(true)
                 .required(true)
             )
+            .arg(Arg::with_name("workers")
+                .short("w")
+                .long("workers")
+                .value_name("COUNT")
+                .help("number of threads used to process blocks, defaults to one per CPU")
+                .takes_value(true)
+                .required(false)
+            )
         )
         .subcommand(SubCommand::with_name("restore")
             .about("restore a folder")
@@ -301,8 +493,62 @@ fn main() {
                 .takes_value(true)
                 .required(false)
             )
+            .arg(Arg::with_name("workers")
+                .short("w")
+                .long("workers")
+                .value_name("COUNT")
+                .help("number of blocks to download at the same time")
+                .takes_value(true)
+                .required(false)
+            )
+            .arg(Arg::with_name("path")
+                .long("path")
+                .value_name("GLOB")
+                .help("only restore files matching this pattern, can be used more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+            )
+            .arg(Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .help("do not restore files matching this pattern, can be used more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+            )
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .help("keep files already in the destination that match the session, and only download the parts of others that differ")
+            )
         );
 
+    #[cfg(feature = "sessionfs")]
+    let app = app.subcommand(SubCommand::with_name("mount")
+        .about("mount every sync session read-only, to browse old versions of files")
+        .arg(Arg::with_name("mountpoint")
+            .value_name("MOUNTPOINT")
+            .help("empty directory to mount the sessions at")
+            .index(1)
+            .required(true)
+        )
+    );
+
+    #[cfg(feature = "webui")]
+    let app = app.subcommand(SubCommand::with_name("webui")
+        .about("browse folders and sessions, download files and start syncs in a web browser")
+        .arg(Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .value_name("PORT")
+            .help("local port to listen on, default 8090")
+            .takes_value(true)
+            .required(false)
+        )
+    );
+
     let matches = app.get_matches();
 
     if let Some(m) = matches.subcommand_matches("version") {
@@ -370,6 +616,33 @@ fn main() {
         },
     }
 
+    #[cfg(feature = "sessionfs")] {
+        if let Some(m) = matches.subcommand_matches("mount") {
+            let mountpoint = m.value_of("mountpoint").unwrap();
+
+            let (token, keyset, _) = sign_in();
+
+            mount(token, keyset, mountpoint);
+
+            return;
+        }
+    }
+
+    #[cfg(feature = "webui")] {
+        if let Some(m) = matches.subcommand_matches("webui") {
+            let port: u16 = match m.value_of("port") {
+                Some(port) => port.trim().parse().expect("Expected a port number"),
+                None => 8090,
+            };
+
+            let (token, keyset, _) = sign_in();
+
+            webui(token, keyset, port);
+
+            return;
+        }
+    }
+
     if let Some(m) = matches.subcommand_matches("bench") {
 
         let version = match m.is_present("versiontwo") {
@@ -434,10 +707,60 @@ fn main() {
             },
         }
 
-    } else if let Some(_) = matches.subcommand_matches("daemon") {
-        let (_, _, _) = sign_in();
+    } else if let Some(m) = matches.subcommand_matches("daemon") {
+        if m.is_present("status") || m.is_present("stop") {
+            let mut client = match daemon_client() {
+                Some(client) => client,
+                None => {
+                    error!("the daemon is not running");
+                    std::process::exit(1);
+                },
+            };
+
+            if m.is_present("stop") {
+                match client.stop() {
+                    Ok(()) => println!("daemon stopping"),
+                    Err(e) => {
+                        error!("failed to stop daemon: {}", e);
+                        std::process::exit(1);
+                    },
+                }
+            } else {
+                daemon_status(client);
+            }
+
+            return;
+        }
+
+        let schedule = match m.value_of("schedule") {
+            Some("hourly") => SyncSchedule::Hourly,
+            Some("weekly") => SyncSchedule::Weekly,
+            Some("monthly") => SyncSchedule::Monthly,
+            _ => SyncSchedule::Daily,
+        };
+
+        let (token, keyset, _) = sign_in();
+
+        daemon(token, keyset, schedule);
+
+    } else if let Some(m) = matches.subcommand_matches("cancel") {
+        let session_name = m.value_of("session").unwrap();
 
-        daemon();
+        let mut client = match daemon_client() {
+            Some(client) => client,
+            None => {
+                error!("the daemon is not running");
+                std::process::exit(1);
+            },
+        };
+
+        match client.cancel(session_name) {
+            Ok(()) => println!("cancelled {}", session_name),
+            Err(e) => {
+                error!("failed to cancel {}: {}", session_name, e);
+                std::process::exit(1);
+            },
+        }
 
     } else if let Some(m) = matches.subcommand_matches("login") {
 
@@ -476,7 +799,15 @@ fn main() {
 
         remove(token, id);
 
-    } else if let Some(_) = matches.subcommand_matches("syncall") {
+    } else if let Some(m) = matches.subcommand_matches("syncall") {
+
+        if let Some(client) = daemon_client() {
+            sync_all_with_daemon(client);
+
+            return;
+        }
+
+        set_workers(m.value_of("workers"));
 
         let (token, keyset, _) = sign_in();
 
@@ -489,6 +820,24 @@ fn main() {
             .parse()
             .expect("Expected a number");
 
+        if let Some(mut client) = daemon_client() {
+            let task = match client.subscribe().and_then(|_| client.sync(id)) {
+                Ok(task) => task,
+                Err(e) => {
+                    error!("failed to start sync in daemon: {}", e);
+                    std::process::exit(1);
+                },
+            };
+
+            println!("Syncing folder {} in the daemon as session {}", id, task.session_name);
+
+            follow_daemon_task(&mut client, &format!("folder {}", id), &task);
+
+            return;
+        }
+
+        set_workers(m.value_of("workers"));
+
         let (token, keyset, _) = sign_in();
 
         sync_one(token, keyset, id);
@@ -504,12 +853,76 @@ fn main() {
 
         let session_name = m.value_of("session");
 
+        if let Some(w) = m.value_of("workers") {
+            let count: usize = w.trim()
+                .parse()
+                .expect("Expected a number");
+
+            set_restore_workers(count);
+        }
+
+        let include: Vec<&str> = match m.values_of("path") {
+            Some(values) => values.collect(),
+            None => Vec::new(),
+        };
+
+        let exclude: Vec<&str> = match m.values_of("exclude") {
+            Some(values) => values.collect(),
+            None => Vec::new(),
+        };
+
+        if let Some(mut client) = daemon_client() {
+            // the daemon has its own working directory, so it needs an absolute path
+            let destination = match env::current_dir() {
+                Ok(dir) => dir.join(destination),
+                Err(e) => {
+                    error!("failed to read current directory: {}", e);
+                    std::process::exit(1);
+                },
+            };
+
+            let request = RestoreRequest {
+                folder_id: id,
+                destination: destination.to_string_lossy().into_owned(),
+                session_name: session_name.map(|name| name.to_string()),
+                include: include.iter().map(|p| p.to_string()).collect(),
+                exclude: exclude.iter().map(|p| p.to_string()).collect(),
+                resume: m.is_present("resume"),
+            };
+
+            let task = match client.subscribe().and_then(|_| client.restore(&request)) {
+                Ok(task) => task,
+                Err(e) => {
+                    error!("failed to start restore in daemon: {}", e);
+                    std::process::exit(1);
+                },
+            };
+
+            println!("Restoring session {} of folder {} to {} in the daemon", task.session_name, id, destination.display());
+
+            follow_daemon_task(&mut client, &format!("folder {}", id), &task);
+
+            return;
+        }
+
         let (token, keyset, _) = sign_in();
 
-        restore_one(token, keyset, id, destination, session_name);
+        restore_one(token, keyset, id, destination, session_name, &include, &exclude, m.is_present("resume"));
 
     } else if let Some(_) = matches.subcommand_matches("list") {
 
+        if let Some(mut client) = daemon_client() {
+            match client.list_folders() {
+                Ok(folders) => print_folders(folders),
+                Err(e) => {
+                    error!("Read folders error: {}", e);
+                    std::process::exit(1);
+                },
+            }
+
+            return;
+        }
+
         let (token, _, _) = sign_in();
 
         list_folders(token);
@@ -544,6 +957,33 @@ fn main() {
 
         list_sessions(token);
 
+    } else if let Some(m) = matches.subcommand_matches("ls") {
+
+        let id: u64 = m.value_of("folder").unwrap()
+            .trim()
+            .parse()
+            .expect("Expected a number");
+
+        let session_name = m.value_of("session").unwrap();
+
+        let (token, keyset, _) = sign_in();
+
+        list_session(token, keyset, id, session_name);
+
+    } else if let Some(m) = matches.subcommand_matches("diff") {
+
+        let id: u64 = m.value_of("folder").unwrap()
+            .trim()
+            .parse()
+            .expect("Expected a number");
+
+        let session_a = m.value_of("session-a").unwrap();
+        let session_b = m.value_of("session-b").unwrap();
+
+        let (token, keyset, _) = sign_in();
+
+        diff(token, keyset, id, session_a, session_b);
+
     } else if let Some(m) = matches.subcommand_matches("clean") {
 
         // if we got a specific session id to delete, use that
@@ -566,8 +1006,23 @@ fn main() {
 
             let mut schedule = SyncCleaningSchedule::Auto;
 
-            if m.is_present("before-date") {
-                if let Some(date) = m.value_of("DATE") {
+            let keep = |name: &str| -> u32 {
+                match m.value_of(name) {
+                    Some(count) => count.trim().parse().expect("Expected a number"),
+                    None => 0,
+                }
+            };
+
+            if m.is_present("keep-hourly") || m.is_present("keep-daily") || m.is_present("keep-weekly") || m.is_present("keep-monthly") || m.is_present("keep-yearly") {
+                schedule = SyncCleaningSchedule::Custom {
+                    hourly: keep("keep-hourly"),
+                    daily: keep("keep-daily"),
+                    weekly: keep("keep-weekly"),
+                    monthly: keep("keep-monthly"),
+                    yearly: keep("keep-yearly"),
+                }
+            } else if m.is_present("before-date") {
+                if let Some(date) = m.value_of("before-date") {
                     schedule = SyncCleaningSchedule::ExactDateRFC3339 { date: date.to_string() }
                 } else {
                     error!("no date given");
@@ -583,8 +1038,53 @@ fn main() {
 
             let (token, _, _) = sign_in();
 
-            clean_sessions(token, schedule);
+            clean_sessions(token, schedule, m.is_present("dry-run"));
         }
+    } else if let Some(m) = matches.subcommand_matches("verify") {
+        let folder_id: Option<u64> = m.value_of("folder").map(|id| id.trim().parse().expect("Expected a number"));
+
+        let (token, keyset, _) = sign_in();
+
+        verify(token, keyset, folder_id, m.value_of("session"), m.is_present("deep"));
+    } else if let Some(m) = matches.subcommand_matches("ignore") {
+        let id: u64 = m.value_of("folder").unwrap()
+            .trim()
+            .parse()
+            .expect("Expected a number");
+
+        let add: Vec<&str> = match m.values_of("add") {
+            Some(values) => values.collect(),
+            None => Vec::new(),
+        };
+
+        let remove: Vec<&str> = match m.values_of("remove") {
+            Some(values) => values.collect(),
+            None => Vec::new(),
+        };
+
+        let max_size: Option<Option<u64>> = if m.is_present("no-max-size") {
+            Some(None)
+        } else {
+            m.value_of("max-size").map(|size| {
+                Some(size.trim()
+                    .parse()
+                    .expect("Expected a number"))
+            })
+        };
+
+        let skip_hidden = if m.is_present("skip-hidden") {
+            Some(true)
+        } else if m.is_present("keep-hidden") {
+            Some(false)
+        } else {
+            None
+        };
+
+        ignore(id, &add, &remove, max_size, skip_hidden);
+    } else if let Some(m) = matches.subcommand_matches("gc") {
+        let (token, keyset, _) = sign_in();
+
+        gc(token, keyset, m.is_present("dry-run"));
     }
 }
 
@@ -709,10 +1209,20 @@ pub fn sign_in() -> (Token, Keyset, AccountStatus) {
     (token, keyset, status)
 }
 
-pub fn daemon() {
-    match start_daemon() {
+pub fn daemon(token: Token, keyset: Keyset, schedule: SyncSchedule) {
+    println!("Watching folders for changes, syncing at least {}", schedule);
+
+    match start_daemon(&token,
+                       &keyset.main,
+                       &keyset.hmac,
+                       &keyset.tweak,
+                       schedule,
+                       512_000_000,
+                       &mut |message| {
+                           warn!("{}", message);
+                       }) {
         Ok(()) => {
-            println!("started daemon");
+            println!("daemon stopped");
         },
         Err(e) => {
             error!("starting daemon failed: {}", e);
@@ -721,48 +1231,248 @@ pub fn daemon() {
     }
 }
 
-pub fn list_clients(username: &str, password: &str, single: Option<&str>) {
+/// a connection to the running daemon, if there is one
+///
+/// Commands that can be handed to the daemon use this to act as thin clients of it, rather than
+/// signing in and syncing or restoring on their own.
+pub fn daemon_client() -> Option<DaemonClient> {
+    match DaemonClient::connect() {
+        Ok(client) => client,
+        Err(e) => {
+            debug!("not able to connect to daemon: {}", e);
 
-    let mut table = Table::new();
+            None
+        },
+    }
+}
 
-    // Add a row
-    table.add_row(row!["ID", "Name", "OS", "Language"]);
+pub fn daemon_status(mut client: DaemonClient) {
+    let tasks = match client.status() {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            error!("Read daemon status error: {}", e);
+            std::process::exit(1);
+        },
+    };
 
-    let client_list = match get_software_clients(username, password) {
-        Ok(cl) => cl,
+    let stats = match client.cache_stats() {
+        Ok(stats) => stats,
         Err(e) => {
-            error!("Read clients error: {}", e);
+            error!("Read cache stats error: {}", e);
             std::process::exit(1);
         },
     };
 
-    let mut current_index = 1;
+    println!("Cache: {} blocks, {} of {}", stats.blocks, pretty_bytes(stats.size as f64), pretty_bytes(stats.limit as f64));
 
-    for client in client_list {
-        let mut ucid_raw = client.uniqueId.clone();
-        let ucid = match ucid_raw.len() {
-            64 => {
-                let ucid: String = ucid_raw.drain(..64).collect();
+    if tasks.is_empty() {
+        println!("The daemon is idle");
 
-                ucid
-            },
-            _ => {
-                error!("Your account has an invalid client present, contact support: {}", ucid_raw);
-                std::process::exit(1);
-            },
-        };
+        return;
+    }
 
-        if let Some(single_client) = single {
-            if single_client != &ucid {
-                continue;
-            }
-        }
+    let mut table = Table::new();
+
+    table.add_row(row!["Task", "Folder ID", "Session"]);
 
+    for task in tasks {
         table.add_row(Row::new(vec![
-            Cell::new(&format!("{}", current_index)),
-            Cell::new(&client.uniqueName),
-            Cell::new(&client.operatingSystem),
-            Cell::new(&client.language)])
+            Cell::new(if task.restore { "restore" } else { "sync" }),
+            Cell::new(&format!("{}", task.folder_id)),
+            Cell::new(&task.session_name)])
+        );
+    }
+
+    table.printstd();
+}
+
+/// show the progress of a sync or restore the daemon is running until it ends
+///
+/// The client must have subscribed to events before the task was started.
+pub fn follow_daemon_task(client: &mut DaemonClient, label: &str, task: &DaemonTask) {
+    let mut pb = ProgressBar::new(0);
+    pb.show_speed = false;
+
+    pb.format("╢▌▌░╟");
+    pb.set_units(Units::Bytes);
+    let message = format!("{}<waiting for daemon>: ", label);
+    pb.message(&message);
+    pb.tick();
+
+    let (working, transferring, verb) = match task.restore {
+        true => ("processing", "downloading", "restore"),
+        false => ("checking data", "uploading", "sync"),
+    };
+
+    loop {
+        let event = match client.next_event() {
+            Ok(event) => event,
+            Err(e) => {
+                let message = format!("{}: lost connection to daemon: {}", label, e);
+                pb.finish_print(&message);
+                std::process::exit(1);
+            },
+        };
+
+        if event.session_name() != task.session_name {
+            continue;
+        }
+
+        match event {
+            DaemonEvent::Started { .. } => {
+                let message = format!("{}<{}>: ", label, working);
+                pb.message(&message);
+                pb.tick();
+            },
+            DaemonEvent::Progress { total, new, .. } => {
+                pb.total = total;
+                pb.add(new);
+            },
+            DaemonEvent::Bandwidth { speed, .. } => {
+                if speed > 0 {
+                    let speed_bytes = pretty_bytes(speed as f64);
+
+                    let message = format!("{}<{}@{}/s>: ", label, transferring, speed_bytes);
+                    pb.message(&message);
+                } else {
+                    let message = format!("{}<{}>: ", label, working);
+                    pb.message(&message);
+                }
+                pb.tick();
+            },
+            DaemonEvent::Issue { message, .. } => {
+                let message = format!("{}: {}", label, message);
+                pb.log(&message);
+            },
+            DaemonEvent::Finished { .. } => {
+                let message = format!("{}: finished", label);
+                pb.finish_print(&message);
+
+                break;
+            },
+            DaemonEvent::Failed { message, .. } => {
+                let message = format!("{}: {} failed: {}", label, verb, message);
+                pb.finish_print(&message);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    println!();
+}
+
+pub fn sync_all_with_daemon(mut client: DaemonClient) {
+    let folder_list = match client.list_folders() {
+        Ok(fl) => fl,
+        Err(e) => {
+            error!("Read folders error: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    if let Err(e) = client.subscribe() {
+        error!("failed to subscribe to daemon events: {}", e);
+        std::process::exit(1);
+    }
+
+    println!("Syncing all folders in the daemon");
+
+    // session name -> folder name
+    let mut pending: HashMap<String, String> = HashMap::new();
+
+    for folder in folder_list.into_iter().filter(|f| f.encrypted) {
+        match client.sync(folder.id) {
+            Ok(task) => {
+                println!("{}: queued as session {}", folder.folderName, task.session_name);
+
+                pending.insert(task.session_name, folder.folderName);
+            },
+            Err(e) => {
+                error!("{}: failed to start sync: {}", folder.folderName, e);
+            },
+        }
+    }
+
+    let mut failed = false;
+
+    while !pending.is_empty() {
+        let event = match client.next_event() {
+            Ok(event) => event,
+            Err(e) => {
+                error!("lost connection to daemon: {}", e);
+                std::process::exit(1);
+            },
+        };
+
+        match event {
+            DaemonEvent::Issue { session_name, message } => {
+                if let Some(name) = pending.get(&session_name) {
+                    println!("{}: {}", name, message);
+                }
+            },
+            DaemonEvent::Finished { session_name } => {
+                if let Some(name) = pending.remove(&session_name) {
+                    println!("{}: finished", name);
+                }
+            },
+            DaemonEvent::Failed { session_name, message } => {
+                if let Some(name) = pending.remove(&session_name) {
+                    println!("{}: sync failed: {}", name, message);
+
+                    failed = true;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+pub fn list_clients(username: &str, password: &str, single: Option<&str>) {
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["ID", "Name", "OS", "Language"]);
+
+    let client_list = match get_software_clients(username, password) {
+        Ok(cl) => cl,
+        Err(e) => {
+            error!("Read clients error: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    let mut current_index = 1;
+
+    for client in client_list {
+        let mut ucid_raw = client.uniqueId.clone();
+        let ucid = match ucid_raw.len() {
+            64 => {
+                let ucid: String = ucid_raw.drain(..64).collect();
+
+                ucid
+            },
+            _ => {
+                error!("Your account has an invalid client present, contact support: {}", ucid_raw);
+                std::process::exit(1);
+            },
+        };
+
+        if let Some(single_client) = single {
+            if single_client != &ucid {
+                continue;
+            }
+        }
+
+        table.add_row(Row::new(vec![
+            Cell::new(&format!("{}", current_index)),
+            Cell::new(&client.uniqueName),
+            Cell::new(&client.operatingSystem),
+            Cell::new(&client.language)])
         );
 
         current_index = current_index + 1;
@@ -846,6 +1556,16 @@ pub fn remove(token: Token, id: u64) {
         }
 }
 
+pub fn set_workers(workers: Option<&str>) {
+    if let Some(w) = workers {
+        let count: usize = w.trim()
+            .parse()
+            .expect("Expected a number");
+
+        set_sync_workers(count);
+    }
+}
+
 pub fn sync_all(token: Token, keyset: Keyset) {
 
     let folder_list = match get_sync_folders(&token) {
@@ -874,7 +1594,11 @@ pub fn sync_all(token: Token, keyset: Keyset) {
         pb.set_units(Units::Bytes);
 
 
-        let sync_uuid = Uuid::new_v4().hyphenated().to_string();
+        // finish an interrupted session rather than starting over
+        let sync_uuid = match interrupted_sync_session(folder.id) {
+            Some(name) => name,
+            None => Uuid::new_v4().hyphenated().to_string(),
+        };
         let local_token = token.clone();
         let local_main = keyset.main.clone();
         let local_hmac = keyset.hmac.clone();
@@ -884,6 +1608,8 @@ pub fn sync_all(token: Token, keyset: Keyset) {
         let pbt = ::parking_lot::Mutex::new(pb);
 
         let _ = thread::spawn(move || {
+            let summary = ::parking_lot::Mutex::new(None);
+
             match sync(&local_token,
                        &sync_uuid,
                        &local_main,
@@ -915,12 +1641,20 @@ pub fn sync_all(token: Token, keyset: Keyset) {
 
                            let message = format!("{}: {}", local_folder_name, message);
                            pb.log(&message);
+                       },
+                       &mut |event| {
+                           if let SyncEvent::Summary(ref s) = *event {
+                               *summary.lock() = Some(s.clone());
+                           }
                        }
             ) {
                 Ok(_) => {
                     let mut pb = pbt.lock();
 
-                    let message = format!("{}: finished", local_folder_name);
+                    let message = match summary.lock().take() {
+                        Some(s) => format!("{}: finished, {}", local_folder_name, summary_message(&s)),
+                        None => format!("{}: finished", local_folder_name),
+                    };
                     pb.finish_print(&message);
                 },
                 Err(e) => {
@@ -960,8 +1694,17 @@ pub fn sync_one(token: Token, keyset: Keyset, id: u64) {
     pb.message(&message);
     pb.tick();
 
-    let sync_uuid = Uuid::new_v4().hyphenated().to_string();
+    // finish an interrupted session rather than starting over
+    let sync_uuid = match interrupted_sync_session(folder.id) {
+        Some(name) => {
+            println!("Resuming interrupted session {}", name);
+
+            name
+        },
+        None => Uuid::new_v4().hyphenated().to_string(),
+    };
     let pbt = ::parking_lot::Mutex::new(pb);
+    let summary = ::parking_lot::Mutex::new(None);
 
     match sync(&token,
                &sync_uuid,
@@ -997,12 +1740,20 @@ pub fn sync_one(token: Token, keyset: Keyset, id: u64) {
 
                    pb.log(&message);
                    //pb.log(::log::LogLevel::Warn, &message);
+               },
+               &mut |event| {
+                   if let SyncEvent::Summary(ref s) = *event {
+                       *summary.lock() = Some(s.clone());
+                   }
                }
     ) {
         Ok(_) => {
             let mut pb = pbt.lock();
 
-            let message = format!("{}: finished", &folder.folderName);
+            let message = match summary.lock().take() {
+                Some(s) => format!("{}: finished, {}", &folder.folderName, summary_message(&s)),
+                None => format!("{}: finished", &folder.folderName),
+            };
             pb.finish_print(&message);
         },
         Err(e) => {
@@ -1017,7 +1768,7 @@ pub fn sync_one(token: Token, keyset: Keyset, id: u64) {
     println!();
 }
 
-pub fn restore_one(token: Token, keyset: Keyset, id: u64, destination: &str, session_name: Option<&str>) {
+pub fn restore_one(token: Token, keyset: Keyset, id: u64, destination: &str, session_name: Option<&str>, include: &[&str], exclude: &[&str], resume: bool) {
 
     let path = PathBuf::from(destination);
 
@@ -1080,45 +1831,83 @@ pub fn restore_one(token: Token, keyset: Keyset, id: u64, destination: &str, ses
 
     let pbt = ::parking_lot::Mutex::new(pb);
 
-    match restore(&token,
-                  &session.name,
-                  &keyset.main,
-                  folder.id,
-                  path,
-                  session.size.unwrap(),
-                  &mut |total, _, new| {
-                      let mut pb = pbt.lock();
-
-                      pb.total = total as u64;
-                      pb.add(new as u64);
-                  },
-                  &mut |speed| {
-                      let mut pb = pbt.lock();
-
-                      if speed > 0 {
-                          let speed_bytes = pretty_bytes(speed as f64);
-
-                          let message = format!("{}<downloading@{}/s>: ", &folder.folderName, speed_bytes);
-                          pb.message(&message);
-                      } else {
-                          let message = format!("{}<processing>: ", &folder.folderName);
-                          pb.message(&message);
-                      }
-                      pb.tick();
-                  },
-                  &mut |message| {
-                      let mut pb = pbt.lock();
-
-                      let message = format!("{}: {}", &folder.folderName, message);
-
-                      pb.log(&message);
-                      //pb.log(::log::LogLevel::Warn, &message);
-                  }
-    ) {
+    let mut progress = |total: u64, _: u64, new: u64| {
+        let mut pb = pbt.lock();
+
+        pb.total = total as u64;
+        pb.add(new as u64);
+    };
+
+    let mut bandwidth = |speed: u64| {
+        let mut pb = pbt.lock();
+
+        if speed > 0 {
+            let speed_bytes = pretty_bytes(speed as f64);
+
+            let message = format!("{}<downloading@{}/s>: ", &folder.folderName, speed_bytes);
+            pb.message(&message);
+        } else {
+            let message = format!("{}<processing>: ", &folder.folderName);
+            pb.message(&message);
+        }
+        pb.tick();
+    };
+
+    let mut issue = |message: &str| {
+        let mut pb = pbt.lock();
+
+        let message = format!("{}: {}", &folder.folderName, message);
+
+        pb.log(&message);
+        //pb.log(::log::LogLevel::Warn, &message);
+    };
+
+    let summary = ::parking_lot::Mutex::new(None);
+
+    let mut event = |event: &SyncEvent| {
+        if let SyncEvent::Summary(ref s) = *event {
+            *summary.lock() = Some(s.clone());
+        }
+    };
+
+    let result = if resume {
+        restore_resume(&token,
+                       &session.name,
+                       &keyset.main,
+                       &keyset.hmac,
+                       &keyset.tweak,
+                       folder.id,
+                       path,
+                       session.size.unwrap(),
+                       include,
+                       exclude,
+                       &mut progress,
+                       &mut bandwidth,
+                       &mut issue,
+                       &mut event)
+    } else {
+        restore_paths(&token,
+                      &session.name,
+                      &keyset.main,
+                      folder.id,
+                      path,
+                      session.size.unwrap(),
+                      include,
+                      exclude,
+                      &mut progress,
+                      &mut bandwidth,
+                      &mut issue,
+                      &mut event)
+    };
+
+    match result {
         Ok(_) => {
             let mut pb = pbt.lock();
 
-            let message = format!("{}: finished", &folder.folderName);
+            let message = match summary.lock().take() {
+                Some(s) => format!("{}: finished, {}", &folder.folderName, summary_message(&s)),
+                None => format!("{}: finished", &folder.folderName),
+            };
             pb.finish_print(&message);
         },
         Err(e) => {
@@ -1133,12 +1922,22 @@ pub fn restore_one(token: Token, keyset: Keyset, id: u64, destination: &str, ses
     println!();
 }
 
-pub fn list_folders(token: Token) {
+/// describe what a finished sync or restore did
+fn summary_message(summary: &SyncSummary) -> String {
+    let mut message = format!("{} files ({})", summary.files, pretty_bytes(summary.size as f64));
 
-    let mut table = Table::new();
+    if summary.blocks_uploaded > 0 || summary.blocks_deduplicated > 0 {
+        message.push_str(&format!(", {} blocks uploaded ({}), {} already stored", summary.blocks_uploaded, pretty_bytes(summary.uploaded_size as f64), summary.blocks_deduplicated));
+    }
 
-    // Add a row
-    table.add_row(row!["Name", "Path", "Encrypted", "ID"]);
+    if summary.skipped > 0 {
+        message.push_str(&format!(", {} skipped", summary.skipped));
+    }
+
+    message
+}
+
+pub fn list_folders(token: Token) {
 
     let folder_list = match get_sync_folders(&token) {
         Ok(fl) => fl,
@@ -1147,6 +1946,17 @@ pub fn list_folders(token: Token) {
             std::process::exit(1);
         },
     };
+
+    print_folders(folder_list);
+}
+
+pub fn print_folders(folder_list: Vec<RegisteredFolder>) {
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["Name", "Path", "Encrypted", "ID"]);
+
     for folder in folder_list {
         table.add_row(Row::new(vec![
             Cell::new(&folder.folderName),
@@ -1205,17 +2015,333 @@ pub fn list_sessions(token: Token) {
     table.printstd();
 }
 
-pub fn clean_sessions(token: Token, schedule: SyncCleaningSchedule) {
+pub fn list_session(token: Token, keyset: Keyset, id: u64, session_name: &str) {
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["Type", "Mode", "Size", "Modified", "Path"]);
+
+    let entries = match list_session_entries(&token, id, session_name, &keyset.main) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Read session error: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    for entry in entries {
+        let entry_type = match entry.entry_type {
+            SessionEntryType::File => "file",
+            SessionEntryType::Directory => "dir",
+            SessionEntryType::Symlink => "link",
+            SessionEntryType::HardLink => "hardlink",
+            SessionEntryType::Other => "other",
+        };
+
+        let entry_size = match entry.size {
+            Some(size) => pretty_bytes(size as f64),
+            None => "".to_string(),
+        };
+
+        let entry_time = format!("{}", Utc.timestamp(entry.mtime as i64, 0).with_timezone(&Local));
+
+        let entry_path = match entry.link_target {
+            Some(ref target) => format!("{} -> {}", entry.path, target),
+            None => entry.path.clone(),
+        };
+
+        table.add_row(Row::new(vec![
+            Cell::new(entry_type),
+            Cell::new(&format!("{:o}", entry.mode)),
+            Cell::new(&entry_size),
+            Cell::new(&entry_time),
+            Cell::new(&entry_path),
+        ]));
+    }
+    table.printstd();
+
+    match get_session_ignore_rules(&token, id, session_name, &keyset.main) {
+        Ok(Some(rules)) => {
+            println!();
+            println!("The session was created with these ignore rules:");
+
+            print_ignore_rules(&rules);
+        },
+        Ok(None) => {},
+        Err(e) => {
+            error!("Read session ignore rules error: {}", e);
+        },
+    }
+}
+
+pub fn ignore(id: u64, add: &[&str], remove: &[&str], max_size: Option<Option<u64>>, skip_hidden: Option<bool>) {
+
+    let mut rules = match get_ignore_rules(id) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Read ignore rules error: {}", e);
+            std::process::exit(1);
+        },
+    };
 
-    println!("Cleaning sync sessions with schedule: {}", schedule);
+    let changed = !add.is_empty() || !remove.is_empty() || max_size.is_some() || skip_hidden.is_some();
 
-    match clean_sync_sessions(&token, schedule) {
+    rules.patterns.retain(|pattern| !remove.contains(&pattern.as_str()));
+
+    for pattern in add {
+        if !rules.patterns.iter().any(|p| p == pattern) {
+            rules.patterns.push(pattern.to_string());
+        }
+    }
+
+    if let Some(size) = max_size {
+        rules.max_file_size = size;
+    }
+
+    if let Some(skip) = skip_hidden {
+        rules.skip_hidden = skip;
+    }
+
+    if changed {
+        if let Err(e) = set_ignore_rules(id, &rules) {
+            error!("Save ignore rules error: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    print_ignore_rules(&rules);
+
+    println!("Patterns in a {} file in the root of the folder are applied as well", IGNORE_FILE);
+}
+
+fn print_ignore_rules(rules: &IgnoreRules) {
+    if rules.patterns.is_empty() {
+        println!("Patterns: none");
+    } else {
+        println!("Patterns:");
+
+        for pattern in &rules.patterns {
+            println!("    {}", pattern);
+        }
+    }
+
+    match rules.max_file_size {
+        Some(size) => println!("Largest file synced: {}", pretty_bytes(size as f64)),
+        None => println!("Largest file synced: no limit"),
+    }
+
+    println!("Hidden files: {}", if rules.skip_hidden { "skipped" } else { "synced" });
+}
+
+#[cfg(feature = "sessionfs")]
+pub fn mount(token: Token, keyset: Keyset, mountpoint: &str) {
+    println!("Mounting sync sessions at {}, unmount it to stop", mountpoint);
+
+    match mount_sessions(&token, &keyset.main, Path::new(mountpoint)) {
         Ok(()) => {},
+        Err(e) => {
+            error!("Mount error: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+#[cfg(feature = "webui")]
+pub fn webui(token: Token, keyset: Keyset, port: u16) {
+    let result = start_webui(&token, &keyset, port, &mut |address| {
+        println!("Web UI running, open {} to log in", address);
+    });
+
+    match result {
+        Ok(()) => {},
+        Err(e) => {
+            error!("Web UI error: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+pub fn diff(token: Token, keyset: Keyset, id: u64, session_a: &str, session_b: &str) {
+
+    let diff = match diff_sessions(&token, id, session_a, session_b, &keyset.main) {
+        Ok(diff) => diff,
+        Err(e) => {
+            error!("Compare sessions error: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["Change", "Size", "Path"]);
+
+    let groups = vec![("added", &diff.added),
+                      ("removed", &diff.removed),
+                      ("modified", &diff.modified),
+                      ("metadata", &diff.metadata_changed)];
+
+    for (change, entries) in groups {
+        for entry in entries {
+            let entry_size = match entry.size {
+                Some(size) => pretty_bytes(size as f64),
+                None => "".to_string(),
+            };
+
+            table.add_row(Row::new(vec![
+                Cell::new(change),
+                Cell::new(&entry_size),
+                Cell::new(&entry.path),
+            ]));
+        }
+    }
+    table.printstd();
+
+    println!("{} added, {} removed, {} modified, {} metadata only", diff.added.len(), diff.removed.len(), diff.modified.len(), diff.metadata_changed.len());
+    println!("{} blocks only in {}, {} blocks only in {}", diff.a_unique_blocks, session_a, diff.b_unique_blocks, session_b);
+}
+
+pub fn clean_sessions(token: Token, schedule: SyncCleaningSchedule, dry_run: bool) {
+
+    if dry_run {
+        println!("Sessions that would be removed with schedule: {}", schedule);
+    } else {
+        println!("Cleaning sync sessions with schedule: {}", schedule);
+    }
+
+    let now = Local::now();
+
+    let removed = match clean_sync_sessions_at(&token, schedule, &now, dry_run) {
+        Ok(removed) => removed,
         Err(e) => {
             error!("failed to clean sync sessions: {}", e);
             std::process::exit(1);
         },
     };
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["Session ID", "Time", "Name", "Folder ID"]);
+
+    for session in &removed {
+        let session_time = match session.time {
+            Some(t) => format!("{}", Utc.timestamp(t as i64 / 1000, (t % 1000) as u32 * 1_000_000).with_timezone(&Local)),
+            None => "".to_string(),
+        };
+
+        table.add_row(Row::new(vec![
+            Cell::new(&session.id.map(|id| format!("{}", id)).unwrap_or_default()),
+            Cell::new(&session_time),
+            Cell::new(&session.name),
+            Cell::new(&session.folder_id.map(|id| format!("{}", id)).unwrap_or_default()),
+        ]));
+    }
+    table.printstd();
+
+    println!("{} sessions {}", removed.len(), if dry_run { "would be removed" } else { "removed" });
+}
+
+pub fn verify(token: Token, keyset: Keyset, folder_id: Option<u64>, session_name: Option<&str>, deep: bool) {
+
+    let session_list = match get_sync_sessions(&token) {
+        Ok(sl) => sl,
+        Err(e) => {
+            error!("Read sessions error: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    let to_check: Vec<SyncSession> = session_list.into_iter().filter(|session| {
+        folder_id.map_or(true, |id| session.folder_id == Some(id)) && session_name.map_or(true, |name| session.name == name)
+    }).collect();
+
+    if to_check.is_empty() {
+        error!("no matching sessions found");
+        std::process::exit(1);
+    }
+
+    let mut table = Table::new();
+
+    // Add a row
+    table.add_row(row!["Folder ID", "Session", "Path", "Missing blocks", "Corrupt blocks"]);
+
+    let mut failed = 0;
+
+    for session in &to_check {
+        let session_folder_id = session.folder_id.unwrap();
+
+        println!("Checking session {}", session.name);
+
+        let report = match verify_session(&token, session_folder_id, &session.name, &keyset.main, &keyset.hmac, deep) {
+            Ok(report) => report,
+            Err(e) => {
+                error!("session {} can't be read: {}", session.name, e);
+
+                table.add_row(Row::new(vec![
+                    Cell::new(&format!("{}", session_folder_id)),
+                    Cell::new(&session.name),
+                    Cell::new("(entire session)"),
+                    Cell::new(""),
+                    Cell::new(""),
+                ]));
+
+                failed += 1;
+                continue;
+            },
+        };
+
+        if !report.is_restorable() {
+            failed += 1;
+        }
+
+        for file in &report.broken {
+            table.add_row(Row::new(vec![
+                Cell::new(&format!("{}", session_folder_id)),
+                Cell::new(&session.name),
+                Cell::new(&file.path),
+                Cell::new(&format!("{}", file.missing_blocks)),
+                Cell::new(&format!("{}", file.corrupt_blocks)),
+            ]));
+        }
+    }
+
+    if failed > 0 {
+        table.printstd();
+        println!("{} of {} sessions can't be restored completely", failed, to_check.len());
+        std::process::exit(1);
+    }
+
+    println!("{} sessions checked, all can be restored", to_check.len());
+}
+
+pub fn gc(token: Token, keyset: Keyset, dry_run: bool) {
+
+    if dry_run {
+        println!("Looking for blocks that are no longer used");
+    } else {
+        println!("Removing blocks that are no longer used");
+    }
+
+    let report = match collect_garbage(&token, &keyset.main, dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("failed to collect garbage: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    println!("{} sessions use {} blocks", report.sessions, report.live_blocks);
+
+    let action = if dry_run { "reclaimable" } else { "removed" };
+
+    match report.storage {
+        Some(ref count) => println!("storage: {} blocks {} ({})", count.blocks, action, pretty_bytes(count.bytes as f64)),
+        None => println!("storage: not collected, garbage collection is not supported for SafeDrive storage"),
+    }
+
+    println!("cache: {} blocks {} ({})", report.cache.blocks, action, pretty_bytes(report.cache.bytes as f64));
 }
 
 
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+/// internal imports
+
+use error::SDAPIError;
+use models::{Token, RegisteredFolder, SyncSessionResponse};
+use session::{SyncSession, SessionFile};
+use block::WrappedBlock;
+use keys::WrappedKeyset;
+
+pub use sdapi::BandwidthCallback;
+
+/// Storage for folders, sync sessions and blocks
+///
+/// Sync, restore and the write cache upload thread only talk to storage through this trait, so
+/// they can be pointed at something other than the SafeDrive API. Implementations are shared
+/// between threads, so they must be `Send` and `Sync`.
+///
+/// All errors use `SDAPIError` so that callers can treat every backend the same way they
+/// have always treated the SafeDrive API, including `Authentication` and `ServiceUnavailable`.
+pub trait StorageBackend: Send + Sync {
+    /// store a new wrapped keyset, or return the one already stored if there is one
+    fn account_key(&self, new_wrapped_keyset: &WrappedKeyset) -> Result<WrappedKeyset, SDAPIError>;
+
+    /// list the folders registered with this backend
+    fn read_folders(&self) -> Result<Vec<RegisteredFolder>, SDAPIError>;
+
+    /// list sync sessions, in the same `sessionDetails` layout the SafeDrive API returns
+    fn read_sessions(&self) -> Result<HashMap<String, HashMap<u64, Vec<SyncSession>>>, SDAPIError>;
+
+    /// reserve a session name for a folder before any blocks are written for it, failing with
+    /// `Conflict` if the name is already registered
+    fn register_sync_session(&self, folder_id: u64, name: &str, encrypted: bool) -> Result<(), SDAPIError>;
+
+    /// store the session file, completing a session started with `register_sync_session`
+    fn finish_sync_session(&self, folder_id: u64, encrypted: bool, session: &SessionFile, size: usize, progress: BandwidthCallback) -> Result<(), SDAPIError>;
+
+    /// read the stored form of a session, as it arrives
+    fn read_session<'a>(&self, folder_id: u64, name: &'a str, encrypted: bool) -> Result<SyncSessionResponse<'a>, SDAPIError>;
+
+    /// check whether a block already exists in storage
+    fn check_block(&self, name: &str) -> Result<bool, SDAPIError>;
+
+    /// write a group of blocks, returning the names of any blocks that storage does not have
+    /// and that must be sent again with their data included
+    fn write_blocks(&self, session: &str, blocks: &[WrappedBlock], progress: BandwidthCallback) -> Result<Vec<String>, SDAPIError>;
+
+    /// read the raw binary form of a wrapped block
+    fn read_block(&self, name: &str) -> Result<Vec<u8>, SDAPIError>;
+
+    /// list every block in storage, or `None` if the storage has no way to list its blocks
+    fn list_blocks(&self) -> Result<Option<Vec<StoredBlock>>, SDAPIError>;
+
+    /// remove blocks that are no longer used by any session
+    fn remove_blocks(&self, names: &[String]) -> Result<(), SDAPIError>;
+}
+
+/// A block in storage, as listed by `StorageBackend::list_blocks()`
+#[derive(Debug, Clone)]
+pub struct StoredBlock {
+    pub name: String,
+    pub size: u64,
+    /// when the block was written, in milliseconds, if storage knows
+    pub modified: Option<u64>,
+}
+
+/// The SafeDrive API, authenticated with an account token
+pub struct SafeDriveBackend {
+    token: Token,
+}
+
+impl SafeDriveBackend {
+    pub fn new(token: &Token) -> SafeDriveBackend {
+        SafeDriveBackend {
+            token: token.clone(),
+        }
+    }
+}
+
+impl StorageBackend for SafeDriveBackend {
+    fn account_key(&self, new_wrapped_keyset: &WrappedKeyset) -> Result<WrappedKeyset, SDAPIError> {
+        ::sdapi::account_key(&self.token, new_wrapped_keyset)
+    }
+
+    fn read_folders(&self) -> Result<Vec<RegisteredFolder>, SDAPIError> {
+        ::sdapi::read_folders(&self.token)
+    }
+
+    fn read_sessions(&self) -> Result<HashMap<String, HashMap<u64, Vec<SyncSession>>>, SDAPIError> {
+        ::sdapi::read_sessions(&self.token)
+    }
+
+    fn register_sync_session(&self, folder_id: u64, name: &str, encrypted: bool) -> Result<(), SDAPIError> {
+        ::sdapi::register_sync_session(&self.token, folder_id, name, encrypted)
+    }
+
+    fn finish_sync_session(&self, folder_id: u64, encrypted: bool, session: &SessionFile, size: usize, progress: BandwidthCallback) -> Result<(), SDAPIError> {
+        ::sdapi::finish_sync_session(&self.token, folder_id, encrypted, session, size, progress)
+    }
+
+    fn read_session<'a>(&self, folder_id: u64, name: &'a str, encrypted: bool) -> Result<SyncSessionResponse<'a>, SDAPIError> {
+        ::sdapi::read_session(&self.token, folder_id, name, encrypted)
+    }
+
+    fn check_block(&self, name: &str) -> Result<bool, SDAPIError> {
+        ::sdapi::check_block(&self.token, name)
+    }
+
+    fn write_blocks(&self, session: &str, blocks: &[WrappedBlock], progress: BandwidthCallback) -> Result<Vec<String>, SDAPIError> {
+        ::sdapi::write_blocks(&self.token, session, blocks, progress)
+    }
+
+    fn read_block(&self, name: &str) -> Result<Vec<u8>, SDAPIError> {
+        ::sdapi::read_block(&self.token, name)
+    }
+
+    // the API has no way to list or remove blocks, so storage garbage collection isn't supported
+    fn list_blocks(&self) -> Result<Option<Vec<StoredBlock>>, SDAPIError> {
+        Ok(None)
+    }
+
+    fn remove_blocks(&self, _: &[String]) -> Result<(), SDAPIError> {
+        Err(SDAPIError::Internal("the SafeDrive API does not support removing blocks".to_string()))
+    }
+}
+
+/// find a single registered folder using a backend
+pub fn find_folder(backend: &StorageBackend, folder_id: u64) -> Result<RegisteredFolder, SDAPIError> {
+    let folders = backend.read_folders()?;
+
+    for folder in folders {
+        if folder.id == folder_id {
+            return Ok(folder);
+        }
+    }
+
+    Err(SDAPIError::Internal(format!("unexpected failure to find folder_id {}", folder_id)))
+}
@@ -18,6 +18,7 @@ pub struct BinaryFormat<'a> {
     pub file_type: &'a str,
     pub version: &'a str,
     pub compressed: bool,
+    pub chunked: bool,
     pub channel: Channel,
     pub production: bool,
     pub wrapped_key: &'a [u8],
@@ -36,7 +37,7 @@ named!(pub binary_parse<&[u8], (BinaryFormat)>,
     magic: map_res!(tag!("sd"), std::str::from_utf8)                             >>
     file_type: map_res!(alt!(tag!("b") | tag!("s")), std::str::from_utf8)        >>
     version: map_res!(take!(2), std::str::from_utf8)                             >>
-    flags: bits!(tuple!(take_bits!(u8, 3), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1) ))>>
+    flags: bits!(tuple!(take_bits!(u8, 2), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1), take_bits!(u8, 1) ))>>
     take!(2)                                                                     >>
     wrapped_key: take!(SECRETBOX_KEY_SIZE + SECRETBOX_MAC_SIZE)                  >>
     nonce: take!(SECRETBOX_NONCE_SIZE)                                           >>
@@ -46,19 +47,20 @@ named!(pub binary_parse<&[u8], (BinaryFormat)>,
         magic: magic,
         file_type: file_type,
         version: version,
-        compressed: flags.1 == 1,
+        compressed: flags.2 == 1,
+        chunked: flags.1 == 1,
         channel: {
-            if flags.2 == 1 {
+            if flags.3 == 1 {
                 Channel::Nightly
-            } else if flags.3 == 1 {
-                Channel::Beta
             } else if flags.4 == 1 {
+                Channel::Beta
+            } else if flags.5 == 1 {
                 Channel::Stable
             } else {
                 Channel::Nightly
             }
         },
-        production: flags.5 == 1,
+        production: flags.6 == 1,
         wrapped_key: wrapped_key,
         nonce: nonce,
         wrapped_data: wrapped_data,
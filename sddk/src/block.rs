@@ -33,31 +33,7 @@ impl Block {
 
         // calculate hmac of the block
 
-        let block_hmac = match version {
-            SyncVersion::Version1 => {
-                let raw_chunk = data.as_slice();
-
-                // use HMACSHA256
-                let hmac_key = hmac.as_sodium_auth_key();
-
-                let tag = ::sodiumoxide::crypto::auth::authenticate(raw_chunk, &hmac_key);
-
-                tag.as_ref().to_vec()
-            },
-            SyncVersion::Version2 => {
-                let raw_chunk = data.as_slice();
-
-                // use blake2b
-                let hmac_key = hmac.as_blake2_256();
-
-                let hash = blake2b(HMAC_SIZE, hmac_key, raw_chunk);
-
-                hash.as_ref().to_vec()
-            },
-            _ => {
-                panic!("Attempted to create invalid block version");
-            },
-        };
+        let block_hmac = block_hmac(version, hmac, data.as_slice());
 
         let (compressed, maybe_compressed_data, maybe_compressed_size) = match version {
             SyncVersion::Version1 => {
@@ -131,6 +107,16 @@ impl Block {
         self.hmac.clone()
     }
 
+    /// recalculate the hmac of the block data and check that it matches the block name
+    ///
+    /// Only meaningful for a block returned by `WrappedBlock::to_block()`, a block created with
+    /// `Block::new()` may be holding compressed data.
+    pub fn verify(&self, hmac: &Key) -> bool {
+        let calculated = block_hmac(self.version, hmac, self.data.as_slice());
+
+        calculated == self.hmac
+    }
+
     pub fn compressed(&self) -> bool {
         self.compressed
     }
@@ -214,6 +200,31 @@ impl Block {
     }
 }
 
+/// calculate the hmac of raw block data, which is also the name of the block
+pub fn block_hmac(version: SyncVersion, hmac: &Key, raw_chunk: &[u8]) -> Vec<u8> {
+    match version {
+        SyncVersion::Version1 => {
+            // use HMACSHA256
+            let hmac_key = hmac.as_sodium_auth_key();
+
+            let tag = ::sodiumoxide::crypto::auth::authenticate(raw_chunk, &hmac_key);
+
+            tag.as_ref().to_vec()
+        },
+        SyncVersion::Version2 => {
+            // use blake2b
+            let hmac_key = hmac.as_blake2_256();
+
+            let hash = blake2b(HMAC_SIZE, hmac_key, raw_chunk);
+
+            hash.as_ref().to_vec()
+        },
+        _ => {
+            panic!("Attempted to create invalid block version");
+        },
+    }
+}
+
 impl AsRef<[u8]> for Block {
     fn as_ref(&self) -> &[u8] {
         self.data.as_ref()
@@ -396,7 +407,11 @@ impl WrappedBlock {
         let block_ver = match raw_block.version {
             "01" => SyncVersion::Version1,
             "02" => SyncVersion::Version2,
-            _ => panic!("unknown binary version"),
+            _ => {
+                debug!("block has an unknown version: {}", raw_block.version);
+
+                return Err(SDError::BlockUnreadable);
+            },
         };
         let wrapped_block_key_raw = raw_block.wrapped_key.to_vec();
         let nonce_raw = raw_block.nonce;
@@ -674,3 +689,24 @@ fn wrapped_block_from_vec_v2_test() {
         },
     };
 }
+
+#[test]
+fn verify_block_test() {
+    let main = Key::new(KeyType::Main);
+    let hmac = Key::new(KeyType::HMAC);
+    let other_hmac = Key::new(KeyType::HMAC);
+
+    for version in vec![SyncVersion::Version1, SyncVersion::Version2] {
+        let test_data = Vec::from(TEST_BLOCK_DATA_UNENCRYPTED.as_ref());
+
+        let block = Block::new(version, &hmac, test_data);
+        let hmac_value = block.get_hmac();
+
+        let raw_wrapped_data = block.to_wrapped(&main).expect("failed to wrap block").as_binary();
+
+        let unwrapped = WrappedBlock::from(raw_wrapped_data, hmac_value).expect("failed to parse block").to_block(&main).expect("failed to unwrap block");
+
+        assert!(unwrapped.verify(&hmac));
+        assert!(!unwrapped.verify(&other_hmac));
+    }
+}
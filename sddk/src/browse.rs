@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+// external crate imports
+
+use tar::{Archive, EntryType};
+
+// internal imports
+
+use backend::StorageBackend;
+use constants::HMAC_SIZE;
+use error::SDError;
+use keys::Key;
+use models::{IgnoreRules, SessionDiff, SessionEntry, SessionEntryType};
+use session::{SyncSession, read_session_file};
+
+/// download a sync session and unwrap it with the main key, along with the archive of a version 3
+/// session
+pub fn read_session(backend: &StorageBackend, folder_id: u64, session_name: &str, main_key: &Key) -> Result<SyncSession, SDError> {
+    let session_body = backend.read_session(folder_id, session_name, true)?;
+
+    let session = read_session_file(session_body, main_key)?;
+
+    if !session.version().chunked_manifest() {
+        return Ok(session);
+    }
+
+    // version 3 sessions only hold the list of blocks their archive is stored in
+    session.read_manifest(|hmac| {
+        let block = ::fetch::fetch_block(backend, main_key, session_name, hmac)?;
+
+        Ok(block.as_ref().to_vec())
+    })
+}
+
+/// list every entry recorded in an unwrapped sync session, in the order they were synced
+pub fn session_entries(session: &SyncSession) -> Result<Vec<SessionEntry>, SDError> {
+    let contents = session_contents(session)?;
+
+    Ok(contents.into_iter().map(|(entry, _)| entry).collect())
+}
+
+/// list every entry recorded in an unwrapped sync session along with its HMAC list, which is
+/// empty for anything other than a regular file
+pub fn session_contents(session: &SyncSession) -> Result<Vec<(SessionEntry, Vec<u8>)>, SDError> {
+    let contents = session_contents_with_block_sizes(session)?;
+
+    Ok(contents.into_iter().map(|(entry, hmac_bag, _)| (entry, hmac_bag)).collect())
+}
+
+/// list every entry recorded in an unwrapped sync session along with its HMAC list and the real
+/// size of each block in it
+///
+/// The block sizes are empty for anything other than a regular file, and for files synced before
+/// sessions recorded them.
+pub fn session_contents_with_block_sizes(session: &SyncSession) -> Result<Vec<(SessionEntry, Vec<u8>, Vec<u64>)>, SDError> {
+    let mut contents = Vec::new();
+
+    let mut ar = Archive::new(session.reader()?);
+
+    let archive_entries = match ar.entries() {
+        Ok(e) => e,
+        Err(e) => {
+            debug!("failed to read session entries: {}", e);
+
+            return Err(SDError::SessionUnreadable);
+        },
+    };
+
+    for item in archive_entries {
+        let mut file_entry = match item {
+            Ok(e) => e,
+            Err(e) => {
+                debug!("failed to read session entry: {}", e);
+
+                return Err(SDError::SessionUnreadable);
+            },
+        };
+
+        if file_entry.header().entry_type() == EntryType::XGlobalHeader {
+            continue;
+        }
+
+        let path = match file_entry.path() {
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(e) => {
+                debug!("failed to read session entry path: {}", e);
+
+                return Err(SDError::SessionUnreadable);
+            },
+        };
+
+        let link_target = match file_entry.link_name() {
+            Ok(Some(target)) => Some(target.to_string_lossy().into_owned()),
+            _ => None,
+        };
+
+        let entry = {
+            let header = file_entry.header();
+
+            let entry_type = match header.entry_type() {
+                EntryType::Regular => SessionEntryType::File,
+                EntryType::Directory => SessionEntryType::Directory,
+                EntryType::Symlink => SessionEntryType::Symlink,
+                EntryType::Link => SessionEntryType::HardLink,
+                _ => SessionEntryType::Other,
+            };
+
+            let hmac_list_size = header.size().unwrap_or(0);
+
+            let size = match entry_type {
+                SessionEntryType::File => {
+                    // empty files never have a size recorded, since they have no blocks
+                    match ::metadata::real_size(header) {
+                        Some(size) => Some(size),
+                        None if hmac_list_size == 0 => Some(0),
+                        None => None,
+                    }
+                },
+                _ => None,
+            };
+
+            SessionEntry {
+                path: path,
+                entry_type: entry_type,
+                size: size,
+                mode: header.mode().map(|m| m as u32 & 0o7777).unwrap_or(0),
+                mtime: header.mtime().map(|m| m as u64).unwrap_or(0),
+                link_target: link_target,
+                block_count: hmac_list_size / HMAC_SIZE as u64,
+            }
+        };
+
+        let mut hmac_bag = Vec::new();
+        let mut block_sizes = Vec::new();
+
+        if entry.entry_type == SessionEntryType::File {
+            if let Err(e) = file_entry.read_to_end(&mut hmac_bag) {
+                debug!("failed to read hmac list for {}: {}", entry.path, e);
+
+                return Err(SDError::SessionUnreadable);
+            }
+
+            // sizes that don't account for every block can't be used
+            match ::metadata::block_sizes(&mut file_entry) {
+                Some(sizes) if sizes.len() * HMAC_SIZE == hmac_bag.len() => block_sizes = sizes,
+                _ => {},
+            }
+        }
+
+        contents.push((entry, hmac_bag, block_sizes));
+    }
+
+    Ok(contents)
+}
+
+/// the ignore rules an unwrapped sync session was created with
+///
+/// Sessions created before ignore rules existed don't record any, and `None` is returned.
+pub fn session_ignore_rules(session: &SyncSession) -> Result<Option<IgnoreRules>, SDError> {
+    let mut ar = Archive::new(session.reader()?);
+
+    let mut archive_entries = match ar.entries() {
+        Ok(e) => e,
+        Err(e) => {
+            debug!("failed to read session entries: {}", e);
+
+            return Err(SDError::SessionUnreadable);
+        },
+    };
+
+    // the records are always at the start of the session
+    let mut file_entry = match archive_entries.next() {
+        Some(Ok(e)) => e,
+        Some(Err(e)) => {
+            debug!("failed to read session entry: {}", e);
+
+            return Err(SDError::SessionUnreadable);
+        },
+        None => return Ok(None),
+    };
+
+    if file_entry.header().entry_type() != EntryType::XGlobalHeader {
+        return Ok(None);
+    }
+
+    let mut records = Vec::new();
+
+    if let Err(e) = file_entry.read_to_end(&mut records) {
+        debug!("failed to read session records: {}", e);
+
+        return Err(SDError::SessionUnreadable);
+    }
+
+    match ::metadata::session_record(&records, "ignore") {
+        Some(rules) => Ok(Some(::serde_json::from_slice(&rules)?)),
+        None => Ok(None),
+    }
+}
+
+/// compare the entries of an older session `a` with a newer session `b`
+pub fn diff_sessions(a: &SyncSession, b: &SyncSession) -> Result<SessionDiff, SDError> {
+    let a_contents = session_contents(a)?;
+    let b_contents = session_contents(b)?;
+
+    let a_blocks = block_set(&a_contents);
+    let b_blocks = block_set(&b_contents);
+
+    let mut diff = SessionDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        modified: Vec::new(),
+        metadata_changed: Vec::new(),
+        a_unique_blocks: a_blocks.difference(&b_blocks).count() as u64,
+        b_unique_blocks: b_blocks.difference(&a_blocks).count() as u64,
+    };
+
+    let mut a_by_path: HashMap<String, (SessionEntry, Vec<u8>)> = HashMap::new();
+
+    for (entry, hmac_bag) in a_contents {
+        a_by_path.insert(entry_key(&entry.path), (entry, hmac_bag));
+    }
+
+    for (entry, hmac_bag) in b_contents {
+        match a_by_path.remove(&entry_key(&entry.path)) {
+            Some((previous, previous_hmac_bag)) => {
+                if previous.entry_type != entry.entry_type || previous.link_target != entry.link_target || previous_hmac_bag != hmac_bag {
+                    diff.modified.push(entry);
+                } else if previous.mode != entry.mode || previous.mtime != entry.mtime {
+                    diff.metadata_changed.push(entry);
+                }
+            },
+            None => diff.added.push(entry),
+        }
+    }
+
+    let mut removed: Vec<SessionEntry> = a_by_path.into_iter().map(|(_, (entry, _))| entry).collect();
+    removed.sort_by(|x, y| x.path.cmp(&y.path));
+
+    diff.removed = removed;
+
+    Ok(diff)
+}
+
+// directories may or may not have been stored with a trailing separator
+fn entry_key(path: &str) -> String {
+    path.trim_right_matches('/').to_string()
+}
+
+pub fn block_set(contents: &[(SessionEntry, Vec<u8>)]) -> HashSet<Vec<u8>> {
+    let mut blocks = HashSet::new();
+
+    for &(_, ref hmac_bag) in contents {
+        for hmac in hmac_bag.chunks(HMAC_SIZE) {
+            blocks.insert(hmac.to_vec());
+        }
+    }
+
+    blocks
+}
+
+#[test]
+fn list_session_entries_test() {
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    folder.write("Documents/report.pdf", b"report");
+
+    let (_server, token, folder_id) = folder.serve();
+
+    let session_name = ::util::generate_uuid();
+    ::core::sync(&token, &session_name, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    let entries = ::core::list_session_entries(&token, folder_id, &session_name, &folder.main).expect("failed to list session");
+
+    let report = entries.iter().find(|e| e.path == "Documents/report.pdf").expect("file missing from session");
+    assert!(report.entry_type == SessionEntryType::File);
+    assert!(report.size == Some(6));
+    assert!(report.block_count == 1);
+
+    let documents = entries.iter().find(|e| e.path.trim_right_matches('/') == "Documents").expect("directory missing from session");
+    assert!(documents.entry_type == SessionEntryType::Directory);
+    assert!(documents.block_count == 0);
+}
+
+#[test]
+fn diff_sessions_test() {
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    for &(name, contents) in &[("kept", "kept"), ("changed", "before"), ("touched", "touched"), ("deleted", "deleted")] {
+        folder.write(name, contents.as_bytes());
+    }
+
+    let (_server, token, folder_id) = folder.serve();
+
+    let session_a = ::util::generate_uuid();
+    ::core::sync(&token, &session_a, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    folder.write("changed", b"after, and longer");
+    folder.write("added", b"added");
+
+    ::std::fs::remove_file(folder.source.join("deleted")).unwrap();
+
+    let earlier = ::filetime::FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    ::filetime::set_file_times(folder.source.join("touched"), earlier, earlier).unwrap();
+
+    let session_b = ::util::generate_uuid();
+    ::core::sync(&token, &session_b, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    let diff = ::core::diff_sessions(&token, folder_id, &session_a, &session_b, &folder.main).expect("failed to compare sessions");
+
+    let paths = |entries: &Vec<SessionEntry>| -> Vec<String> { entries.iter().map(|e| e.path.clone()).collect() };
+
+    assert!(paths(&diff.added) == vec!["added".to_string()]);
+    assert!(paths(&diff.removed) == vec!["deleted".to_string()]);
+    assert!(paths(&diff.modified) == vec!["changed".to_string()]);
+    assert!(paths(&diff.metadata_changed) == vec!["touched".to_string()]);
+
+    // the old and new contents of "changed" and the removed and added files
+    assert!(diff.a_unique_blocks == 2);
+    assert!(diff.b_unique_blocks == 2);
+}
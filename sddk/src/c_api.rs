@@ -23,17 +23,27 @@ use core::has_conflicting_folder;
 use core::log;
 
 use core::get_sync_sessions;
+use core::list_session_entries;
+use core::diff_sessions;
 use core::remove_sync_session;
 use core::clean_sync_sessions;
+use core::auto_clean_sync_sessions;
+use core::clean_sync_sessions_at;
+use core::collect_garbage;
 use core::sync;
+use core::interrupted_sync_session;
+use core::get_ignore_rules;
+use core::set_ignore_rules;
 use core::restore;
+use core::restore_paths;
+use core::restore_resume;
 use core::load_keys;
 use core::login;
 use sync_state::cancel_sync_task;
 
 use constants::Configuration;
 
-use models::{RegisteredFolder, AccountStatus, AccountState, AccountDetails, SFTPFingerprint, Notification, SyncCleaningSchedule, SoftwareClient};
+use models::{RegisteredFolder, AccountStatus, AccountState, AccountDetails, SFTPFingerprint, Notification, SyncCleaningSchedule, SoftwareClient, SessionEntry, SessionEntryType, SessionDiff, GarbageReport, IgnoreRules, SyncEvent, SyncPhase, SkipReason, SyncSummary};
 
 use keychain::KeychainService;
 use core::get_keychain_item;
@@ -288,6 +298,271 @@ impl From<SyncSession> for SDDKSyncSession {
     }
 }
 
+#[derive(Debug)]
+#[repr(C)]
+pub enum SDDKSessionEntryType {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    Other,
+}
+
+impl From<SessionEntryType> for SDDKSessionEntryType {
+    fn from(e: SessionEntryType) -> SDDKSessionEntryType {
+        match e {
+            SessionEntryType::File => SDDKSessionEntryType::File,
+            SessionEntryType::Directory => SDDKSessionEntryType::Directory,
+            SessionEntryType::Symlink => SDDKSessionEntryType::Symlink,
+            SessionEntryType::HardLink => SDDKSessionEntryType::HardLink,
+            SessionEntryType::Other => SDDKSessionEntryType::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct SDDKSessionEntry {
+    pub path: *const std::os::raw::c_char,
+    pub entry_type: SDDKSessionEntryType,
+    /// size of the file contents, 0 for anything other than a file or when the size is unknown
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    /// NULL for anything other than a link
+    pub link_target: *const std::os::raw::c_char,
+    pub block_count: u64,
+}
+
+impl From<SessionEntry> for SDDKSessionEntry {
+    fn from(entry: SessionEntry) -> SDDKSessionEntry {
+        SDDKSessionEntry {
+            path: CString::new(entry.path.as_str()).unwrap().into_raw(),
+            entry_type: SDDKSessionEntryType::from(entry.entry_type),
+            size: entry.size.unwrap_or(0),
+            mode: entry.mode,
+            mtime: entry.mtime,
+            link_target: match entry.link_target {
+                Some(target) => CString::new(target.as_str()).unwrap().into_raw(),
+                None => std::ptr::null(),
+            },
+            block_count: entry.block_count,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub enum SDDKSessionChangeType {
+    Added,
+    Removed,
+    Modified,
+    MetadataChanged,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct SDDKSessionChange {
+    pub change: SDDKSessionChangeType,
+    pub entry: SDDKSessionEntry,
+}
+
+impl SDDKSessionChange {
+    fn list_from(diff: SessionDiff) -> Vec<SDDKSessionChange> {
+        let mut changes = Vec::new();
+
+        let groups = vec![(SDDKSessionChangeType::Added, diff.added),
+                          (SDDKSessionChangeType::Removed, diff.removed),
+                          (SDDKSessionChangeType::Modified, diff.modified),
+                          (SDDKSessionChangeType::MetadataChanged, diff.metadata_changed)];
+
+        for (change, entries) in groups {
+            for entry in entries {
+                changes.push(SDDKSessionChange {
+                    change: change,
+                    entry: SDDKSessionEntry::from(entry),
+                });
+            }
+        }
+
+        changes
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub enum SDDKSyncEventType {
+    PhaseChanged,
+    FileStarted,
+    FileFinished,
+    FileSkipped,
+    BlockUploaded,
+    BlockDeduplicated,
+    Summary,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub enum SDDKSyncPhase {
+    Scan,
+    Upload,
+    Download,
+    Finalize,
+}
+
+impl From<SyncPhase> for SDDKSyncPhase {
+    fn from(phase: SyncPhase) -> SDDKSyncPhase {
+        match phase {
+            SyncPhase::Scan => SDDKSyncPhase::Scan,
+            SyncPhase::Upload => SDDKSyncPhase::Upload,
+            SyncPhase::Download => SDDKSyncPhase::Download,
+            SyncPhase::Finalize => SDDKSyncPhase::Finalize,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub enum SDDKSkipReason {
+    NotFound,
+    PermissionDenied,
+    ReadFailed,
+    WriteFailed,
+    Unsupported,
+    Other,
+}
+
+impl From<SkipReason> for SDDKSkipReason {
+    fn from(reason: SkipReason) -> SDDKSkipReason {
+        match reason {
+            SkipReason::NotFound => SDDKSkipReason::NotFound,
+            SkipReason::PermissionDenied => SDDKSkipReason::PermissionDenied,
+            SkipReason::ReadFailed => SDDKSkipReason::ReadFailed,
+            SkipReason::WriteFailed => SDDKSkipReason::WriteFailed,
+            SkipReason::Unsupported => SDDKSkipReason::Unsupported,
+            SkipReason::Other => SDDKSkipReason::Other,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct SDDKSyncSummary {
+    pub files: u64,
+    pub size: u64,
+    pub skipped: u64,
+    pub blocks_uploaded: u64,
+    pub uploaded_size: u64,
+    pub blocks_deduplicated: u64,
+}
+
+impl From<SyncSummary> for SDDKSyncSummary {
+    fn from(summary: SyncSummary) -> SDDKSyncSummary {
+        SDDKSyncSummary {
+            files: summary.files,
+            size: summary.size,
+            skipped: summary.skipped,
+            blocks_uploaded: summary.blocks_uploaded,
+            uploaded_size: summary.uploaded_size,
+            blocks_deduplicated: summary.blocks_deduplicated,
+        }
+    }
+}
+
+/// An event passed to the `event` callback of `sddk_sync()` and the `sddk_restore*()` functions
+///
+/// Only the fields that apply to `event_type` are meaningful. The strings belong to the library
+/// and are only valid until the callback returns, copy them if they are needed later.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SDDKSyncEvent {
+    pub event_type: SDDKSyncEventType,
+    /// the phase that started, for `PhaseChanged`
+    pub phase: SDDKSyncPhase,
+    /// the path of the file relative to the folder for file events, the block name for block
+    /// events, NULL otherwise
+    pub name: *const std::os::raw::c_char,
+    /// size of the file, or of the block as stored
+    pub size: u64,
+    /// for `FileSkipped`
+    pub skip_reason: SDDKSkipReason,
+    /// why the file was skipped for `FileSkipped`, NULL otherwise
+    pub message: *const std::os::raw::c_char,
+    /// totals for the whole sync or restore, for `Summary`
+    pub summary: SDDKSyncSummary,
+}
+
+/// pass a sync event to a C callback, if the caller gave one
+fn send_sync_event(callback: Option<extern fn(*mut std::os::raw::c_void, *const SDDKSyncEvent)>,
+                   context: *mut std::os::raw::c_void,
+                   event: &SyncEvent) {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return,
+    };
+
+    let mut c_event = SDDKSyncEvent {
+        event_type: SDDKSyncEventType::Summary,
+        phase: SDDKSyncPhase::Scan,
+        name: std::ptr::null(),
+        size: 0,
+        skip_reason: SDDKSkipReason::Other,
+        message: std::ptr::null(),
+        summary: SDDKSyncSummary::from(SyncSummary::default()),
+    };
+
+    // kept alive until the callback returns
+    let mut c_name: Option<CString> = None;
+    let mut c_message: Option<CString> = None;
+
+    match *event {
+        SyncEvent::PhaseChanged { phase } => {
+            c_event.event_type = SDDKSyncEventType::PhaseChanged;
+            c_event.phase = SDDKSyncPhase::from(phase);
+        },
+        SyncEvent::FileStarted { ref path, size } => {
+            c_event.event_type = SDDKSyncEventType::FileStarted;
+            c_name = Some(CString::new(path.as_str()).expect("failed to get event path"));
+            c_event.size = size;
+        },
+        SyncEvent::FileFinished { ref path, size } => {
+            c_event.event_type = SDDKSyncEventType::FileFinished;
+            c_name = Some(CString::new(path.as_str()).expect("failed to get event path"));
+            c_event.size = size;
+        },
+        SyncEvent::FileSkipped { ref path, reason, ref message } => {
+            c_event.event_type = SDDKSyncEventType::FileSkipped;
+            c_name = Some(CString::new(path.as_str()).expect("failed to get event path"));
+            c_event.skip_reason = SDDKSkipReason::from(reason);
+            c_message = Some(CString::new(message.as_str()).expect("failed to get event message"));
+        },
+        SyncEvent::BlockUploaded { ref name, size } => {
+            c_event.event_type = SDDKSyncEventType::BlockUploaded;
+            c_name = Some(CString::new(name.as_str()).expect("failed to get block name"));
+            c_event.size = size;
+        },
+        SyncEvent::BlockDeduplicated { ref name, size } => {
+            c_event.event_type = SDDKSyncEventType::BlockDeduplicated;
+            c_name = Some(CString::new(name.as_str()).expect("failed to get block name"));
+            c_event.size = size;
+        },
+        SyncEvent::Summary(ref summary) => {
+            c_event.event_type = SDDKSyncEventType::Summary;
+            c_event.summary = SDDKSyncSummary::from(summary.clone());
+        },
+    }
+
+    if let Some(ref c_name) = c_name {
+        c_event.name = c_name.as_ptr();
+    }
+
+    if let Some(ref c_message) = c_message {
+        c_event.message = c_message.as_ptr();
+    }
+
+    callback(context, &c_event);
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct SDDKSoftwareClient {
@@ -334,6 +609,7 @@ pub enum SDDKErrorType {
     Cancelled = 0x0015,
     FolderMissing = 0x0016,
     KeyCorrupted = 0x0017,
+    OpLogUnreadable = 0x0018,
 }
 
 #[derive(Debug)]
@@ -369,6 +645,7 @@ impl From<SDError> for SDDKError {
             SDError::ServiceUnavailable => SDDKErrorType::ServiceUnavailable,
             SDError::Cancelled => SDDKErrorType::Cancelled,
             SDError::FolderMissing => SDDKErrorType::FolderMissing,
+            SDError::OpLogUnreadable => SDDKErrorType::OpLogUnreadable,
         };
         SDDKError {
             error_type: error_type,
@@ -467,10 +744,62 @@ pub enum SDDKSyncCleaningSchedule {
     OneWeek,         // 7 days
     OneMonth,        // 30 days
     OneYear,         // 365 days
+
+    /// Keep the oldest session in each of the most recent periods given by an `SDDKRetentionPolicy`,
+    /// each folder is cleaned on its own
+    Custom,
+}
+
+/// The number of hours, days, weeks, months and years to keep a session for, used with
+/// `SDDKSyncCleaningSchedule.Custom`
+#[derive(Debug)]
+#[repr(C)]
+pub struct SDDKRetentionPolicy {
+    pub hourly: u32,
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+    pub yearly: u32,
+}
+
+/// The result of garbage collection, see `sddk_gc_report()`
+///
+/// `storage_supported` is 0 when garbage collection isn't supported for the storage the blocks
+/// are in, in which case the `storage_*` counts are always 0 and only the local cache was
+/// collected. It is always 0 for SafeDrive storage.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SDDKGarbageReport {
+    pub sessions: u64,
+    pub live_blocks: u64,
+    pub storage_supported: std::os::raw::c_int,
+    pub storage_blocks: u64,
+    pub storage_bytes: u64,
+    pub cache_blocks: u64,
+    pub cache_bytes: u64,
+}
+
+impl From<GarbageReport> for SDDKGarbageReport {
+    fn from(report: GarbageReport) -> SDDKGarbageReport {
+        let (storage_supported, storage_blocks, storage_bytes) = match report.storage {
+            Some(count) => (1, count.blocks, count.bytes),
+            None => (0, 0, 0),
+        };
+
+        SDDKGarbageReport {
+            sessions: report.sessions,
+            live_blocks: report.live_blocks,
+            storage_supported: storage_supported,
+            storage_blocks: storage_blocks,
+            storage_bytes: storage_bytes,
+            cache_blocks: report.cache.blocks,
+            cache_bytes: report.cache.bytes,
+        }
+    }
 }
 
 impl SDDKSyncCleaningSchedule {
-    fn to_schedule(&self, date: Option<String>) -> SyncCleaningSchedule {
+    fn to_schedule(&self, date: Option<String>, policy: Option<&SDDKRetentionPolicy>) -> SyncCleaningSchedule {
         match *self {
             SDDKSyncCleaningSchedule::Auto => SyncCleaningSchedule::Auto,
             SDDKSyncCleaningSchedule::ExactDateRFC3339 => {
@@ -492,6 +821,19 @@ impl SDDKSyncCleaningSchedule {
             SDDKSyncCleaningSchedule::OneWeek => SyncCleaningSchedule::OneWeek,
             SDDKSyncCleaningSchedule::OneMonth => SyncCleaningSchedule::OneMonth,
             SDDKSyncCleaningSchedule::OneYear => SyncCleaningSchedule::OneYear,
+            SDDKSyncCleaningSchedule::Custom => {
+                // without a policy nothing would be kept, which cleaning refuses to do
+                match policy {
+                    Some(p) => SyncCleaningSchedule::Custom {
+                        hourly: p.hourly,
+                        daily: p.daily,
+                        weekly: p.weekly,
+                        monthly: p.monthly,
+                        yearly: p.yearly,
+                    },
+                    None => SyncCleaningSchedule::Custom { hourly: 0, daily: 0, weekly: 0, monthly: 0, yearly: 0 },
+                }
+            },
         }
     }
 }
@@ -747,6 +1089,46 @@ pub extern "C" fn sddk_get_version(mut version: *mut *mut std::os::raw::c_char)
     }
 }
 
+/// Set the number of threads used to process blocks during sync
+///
+/// Parameters:
+///
+///     `count`: number of worker threads, or 0 to use one thread per CPU (the default)
+///
+/// Only affects sync tasks started after this is called
+///
+/// # Examples
+///
+/// ```c
+/// sddk_set_sync_workers(4);
+/// ```
+///
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_set_sync_workers(count: std::os::raw::c_uint) {
+    ::core::set_sync_workers(count as usize);
+}
+
+/// Set the number of blocks downloaded at the same time during restore
+///
+/// Parameters:
+///
+///     `count`: number of download threads, the default is 8
+///
+/// Only affects restore tasks started after this is called
+///
+/// # Examples
+///
+/// ```c
+/// sddk_set_restore_workers(16);
+/// ```
+///
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_set_restore_workers(count: std::os::raw::c_uint) {
+    ::core::set_restore_workers(count as usize);
+}
+
 /// Get local app storage directory
 ///
 /// Returned string must be passed back to the SDK to free it
@@ -2631,17 +3013,34 @@ pub extern "C" fn sddk_get_sync_sessions(state: *mut SDDKState,
     len as i64
 }
 
-
-/// Remove a sync session
+/// Get a page of the entries recorded in a sync session, without restoring it
 ///
-/// Will return a failure code and set the error parameter if for any reason the session cannot be removed
+/// The session is downloaded when `offset` is 0, later pages are served from a copy kept in
+/// the state until a different session is listed.
+///
+/// The caller does not own the memory pointed to by `entries` after this function returns, it must
+/// be returned and freed by the library.
+///
+/// As a result, any data that the caller wishes to retain must be copied out of the buffer before
+/// it is freed.
 ///
 ///
 /// Parameters:
 ///
 ///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
 ///
-///     `session_id`: an unsigned 64-bit integer representing the session ID
+///     `folder_id`: the ID of the folder the session belongs to
+///
+///     `name`: a NULL terminated string representing the name of the session
+///
+///     `offset`: index of the first entry to return
+///
+///     `limit`: maximum number of entries to return
+///
+///     `entries`: an uninitialized pointer that will be allocated and initialized when the function
+///                returns if the return value was 0+
+///
+///                must be freed by the caller using `sddk_free_session_entries()`
 ///
 ///     `error`: an uninitialized pointer that will be allocated and initialized when the function
 ///              returns if the return value was -1
@@ -2650,67 +3049,127 @@ pub extern "C" fn sddk_get_sync_sessions(state: *mut SDDKState,
 ///
 /// Return:
 ///
-///     -1: failure, `error` will be set with more information
-///
-///      0: success
+///      -1: failure, `error` will be set with more information
 ///
+///      0+: number of `SDDKSessionEntry` structs allocated in entries, fewer than `limit` means
+///          there are no more entries after this page
 ///
 /// # Examples
 ///
 /// ```c
 /// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
+/// SDDKSessionEntry * entries = NULL;
 ///
-/// if (0 != sddk_remove_sync_session(&state, 7, &error)) {
-///     printf("Failed to remove session");
+/// int length = sddk_get_session_entries(&state, 7, "8d2c...", 0, 100, &entries, &error);
+///
+/// if (-1 == length) {
+///     printf("Failed to get session entries");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
 /// else {
-///     printf("Removed session");
+///     printf("Got session entries");
+///     // do something with entries here, then free it
+///     sddk_free_session_entries(&entries, length);
 /// }
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_remove_sync_session(state: *mut SDDKState,
-                                           session_id: std::os::raw::c_ulonglong,
-                                           mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+pub extern "C" fn sddk_get_session_entries(state: *mut SDDKState,
+                                           folder_id: std::os::raw::c_ulonglong,
+                                           name: *const std::os::raw::c_char,
+                                           offset: std::os::raw::c_ulonglong,
+                                           limit: std::os::raw::c_ulonglong,
+                                           mut entries: *mut *mut SDDKSessionEntry,
+                                           mut error: *mut *mut SDDKError) -> i64 {
     let c = unsafe{ assert!(!state.is_null()); &mut * state };
-
-    let id = session_id as u64;
-
-
-    match remove_sync_session(c.0.get_api_token(), id) {
-        Ok(_) => 0,
+    let c_name: &CStr = unsafe { CStr::from_ptr(name) };
+    let n: String = match c_name.to_str() {
+        Ok(s) => s.to_owned(),
         Err(err) => {
             let c_err = SDDKError::from(err);
 
-            let boxed_error = Box::new(c_err);
-            let ptr = Box::into_raw(boxed_error);
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
 
             unsafe {
                 *error = ptr;
             }
-            -1
+            return -1;
         },
+    };
+
+    let id = folder_id as u64;
+
+    if offset == 0 || c.0.get_session_entries(id, &n).is_none() {
+        let result = match list_session_entries(c.0.get_api_token(), id, &n, c.0.get_main_key()) {
+            Ok(entries) => entries,
+            Err(err) => {
+                let c_err = SDDKError::from(err);
+
+                let boxed_error = Box::new(c_err);
+                let ptr = Box::into_raw(boxed_error);
+
+                unsafe {
+                    *error = ptr;
+                }
+                return -1;
+            },
+        };
+
+        c.0.set_session_entries(id, &n, result);
+    }
+
+    let page: Vec<SessionEntry> = match c.0.get_session_entries(id, &n) {
+        Some(all) => all.iter().skip(offset as usize).take(limit as usize).cloned().collect(),
+        None => Vec::new(),
+    };
+
+    let s = page.into_iter().map(|entry| {
+        SDDKSessionEntry::from(entry)
+    }).collect::<Vec<SDDKSessionEntry>>();
+
+    let mut b = s.into_boxed_slice();
+    let ptr = b.as_mut_ptr();
+    let len = b.len();
+    std::mem::forget(b);
+
+    unsafe {
+        *entries = ptr;
     }
+
+    len as i64
 }
 
-/// Clean sync sessions according to the provided schedule type
+/// Compare two sessions of the same folder
 ///
-/// Will return a failure code and set the error parameter if for any reason the sessions cannot be
-/// cleaned
+/// Changes are reported from the older session `session_a` to the newer session `session_b`,
+/// grouped as added, removed, modified and metadata changed entries, in that order. Entries that
+/// exist in both sessions are reported as they are in `session_b`.
+///
+/// The caller does not own the memory pointed to by `changes` after this function returns, it must
+/// be returned and freed by the library.
 ///
 ///
 /// Parameters:
 ///
 ///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
 ///
-///  `schedule`: an `SDDKSyncCleaningSchedule` variant
+///     `folder_id`: the ID of the folder both sessions belong to
 ///
-///      `date`: the date string used for `SDDKSyncCleaningSchedule.ExactDateRFC*` variants. Should be
-///              null for other enum variants.
+///     `session_a`: a NULL terminated string representing the name of the older session
 ///
+///     `session_b`: a NULL terminated string representing the name of the newer session
+///
+///     `changes`: an uninitialized pointer that will be allocated and initialized when the function
+///                returns if the return value was 0+
+///
+///                must be freed by the caller using `sddk_free_session_changes()`
+///
+///     `a_unique_blocks`: set to the number of distinct blocks used by `session_a` but not by `session_b`
+///
+///     `b_unique_blocks`: set to the number of distinct blocks used by `session_b` but not by `session_a`
 ///
 ///     `error`: an uninitialized pointer that will be allocated and initialized when the function
 ///              returns if the return value was -1
@@ -2719,62 +3178,152 @@ pub extern "C" fn sddk_remove_sync_session(state: *mut SDDKState,
 ///
 /// Return:
 ///
-///     -1: failure, `error` will be set with more information
-///
-///      0: success
+///      -1: failure, `error` will be set with more information
 ///
+///      0+: number of `SDDKSessionChange` structs allocated in changes
 ///
 /// # Examples
 ///
 /// ```c
 /// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
+/// SDDKSessionChange * changes = NULL;
+/// uint64_t a_unique_blocks = 0;
+/// uint64_t b_unique_blocks = 0;
 ///
-/// if (0 != sddk_clean_sync_sessions(&state, SDDKSyncCleaningSchedule.All, &error)) {
-///     printf("Failed to remove sessions");
+/// int length = sddk_diff_sessions(&state, 7, "8d2c...", "a41f...", &changes, &a_unique_blocks, &b_unique_blocks, &error);
+///
+/// if (-1 == length) {
+///     printf("Failed to compare sessions");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
 /// else {
-///     printf("Removed sessions");
+///     printf("Compared sessions");
+///     // do something with changes here, then free it
+///     sddk_free_session_changes(&changes, length);
 /// }
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_clean_sync_sessions(state: *mut SDDKState,
-                                           schedule: SDDKSyncCleaningSchedule,
-                                           date: *const std::os::raw::c_char,
-                                           mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+pub extern "C" fn sddk_diff_sessions(state: *mut SDDKState,
+                                     folder_id: std::os::raw::c_ulonglong,
+                                     session_a: *const std::os::raw::c_char,
+                                     session_b: *const std::os::raw::c_char,
+                                     mut changes: *mut *mut SDDKSessionChange,
+                                     a_unique_blocks: *mut std::os::raw::c_ulonglong,
+                                     b_unique_blocks: *mut std::os::raw::c_ulonglong,
+                                     mut error: *mut *mut SDDKError) -> i64 {
     let c = unsafe{ assert!(!state.is_null()); &mut * state };
 
+    let mut names: Vec<String> = Vec::new();
 
-    let d: Option<String> = {
-        if date.is_null() {
-            None
-        } else {
-            let c_date: &CStr = unsafe { CStr::from_ptr(date) };
-            let d: String = match c_date.to_str() {
-                Ok(s) => s.to_owned(),
-                Err(err) => {
-                    let c_err = SDDKError::from(err);
-
-                    let b = Box::new(c_err);
-                    let ptr = Box::into_raw(b);
+    for name in &[session_a, session_b] {
+        let c_name: &CStr = unsafe { CStr::from_ptr(*name) };
+        match c_name.to_str() {
+            Ok(s) => names.push(s.to_owned()),
+            Err(err) => {
+                let c_err = SDDKError::from(err);
 
-                    unsafe {
-                        *error = ptr;
-                    }
-                    return -1;
-                },
-            };
+                let b = Box::new(c_err);
+                let ptr = Box::into_raw(b);
 
-            Some(d)
-        }
-    };
+                unsafe {
+                    *error = ptr;
+                }
+                return -1;
+            },
+        };
+    }
 
-    let sch = schedule.to_schedule(d);
+    let diff = match diff_sessions(c.0.get_api_token(), folder_id as u64, &names[0], &names[1], c.0.get_main_key()) {
+        Ok(diff) => diff,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
 
-    match clean_sync_sessions(c.0.get_api_token(), sch) {
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    unsafe {
+        if !a_unique_blocks.is_null() {
+            *a_unique_blocks = diff.a_unique_blocks;
+        }
+        if !b_unique_blocks.is_null() {
+            *b_unique_blocks = diff.b_unique_blocks;
+        }
+    }
+
+    let s = SDDKSessionChange::list_from(diff);
+
+    let mut b = s.into_boxed_slice();
+    let ptr = b.as_mut_ptr();
+    let len = b.len();
+    std::mem::forget(b);
+
+    unsafe {
+        *changes = ptr;
+    }
+
+    len as i64
+}
+
+
+/// Remove a sync session
+///
+/// Will return a failure code and set the error parameter if for any reason the session cannot be removed
+///
+///
+/// Parameters:
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `session_id`: an unsigned 64-bit integer representing the session ID
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+///
+/// # Examples
+///
+/// ```c
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_remove_sync_session(&state, 7, &error)) {
+///     printf("Failed to remove session");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("Removed session");
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_remove_sync_session(state: *mut SDDKState,
+                                           session_id: std::os::raw::c_ulonglong,
+                                           mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+
+    let id = session_id as u64;
+
+
+    match remove_sync_session(c.0.get_api_token(), id) {
         Ok(_) => 0,
         Err(err) => {
             let c_err = SDDKError::from(err);
@@ -2790,14 +3339,22 @@ pub extern "C" fn sddk_clean_sync_sessions(state: *mut SDDKState,
     }
 }
 
-
-
-/// Perform garbage collection of the local block cache and registry
+/// Clean sync sessions according to the provided schedule type
+///
+/// Will return a failure code and set the error parameter if for any reason the sessions cannot be
+/// cleaned
+///
 ///
 /// Parameters:
 ///
 ///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
 ///
+///  `schedule`: an `SDDKSyncCleaningSchedule` variant
+///
+///      `date`: the date string used for `SDDKSyncCleaningSchedule.ExactDateRFC*` variants. Should be
+///              null for other enum variants.
+///
+///
 ///     `error`: an uninitialized pointer that will be allocated and initialized when the function
 ///              returns if the return value was -1
 ///
@@ -2816,29 +3373,96 @@ pub extern "C" fn sddk_clean_sync_sessions(state: *mut SDDKState,
 /// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
 ///
-/// if (0 != sddk_gc(&state)) {
-///     printf("Failed to run GC");
+/// if (0 != sddk_clean_sync_sessions(&state, SDDKSyncCleaningSchedule.All, &error)) {
+///     printf("Failed to remove sessions");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
 /// else {
-///     printf("GC successful");
+///     printf("Removed sessions");
 /// }
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_gc(state: *mut SDDKState,
-                          mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
-    let _ = unsafe{ assert!(!state.is_null()); &mut * state };
-    0
+pub extern "C" fn sddk_clean_sync_sessions(state: *mut SDDKState,
+                                           schedule: SDDKSyncCleaningSchedule,
+                                           date: *const std::os::raw::c_char,
+                                           mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+
+
+    let d: Option<String> = {
+        if date.is_null() {
+            None
+        } else {
+            let c_date: &CStr = unsafe { CStr::from_ptr(date) };
+            let d: String = match c_date.to_str() {
+                Ok(s) => s.to_owned(),
+                Err(err) => {
+                    let c_err = SDDKError::from(err);
+
+                    let b = Box::new(c_err);
+                    let ptr = Box::into_raw(b);
+
+                    unsafe {
+                        *error = ptr;
+                    }
+                    return -1;
+                },
+            };
+
+            Some(d)
+        }
+    };
+
+    let sch = schedule.to_schedule(d, None);
+
+    match clean_sync_sessions(c.0.get_api_token(), sch) {
+        Ok(_) => 0,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            -1
+        },
+    }
 }
 
-/// Cancel a sync or restore by name
+
+
+/// Clean sync sessions according to the provided schedule type, returning the sessions that were removed
+///
+/// Unlike `sddk_clean_sync_sessions()`, this accepts a retention policy for the
+/// `SDDKSyncCleaningSchedule.Custom` schedule, and can report the sessions a schedule would remove
+/// without removing them.
+///
+/// The caller does not own the memory pointed to by `sessions` after this function returns, it must
+/// be returned and freed by the library.
 ///
 ///
 /// Parameters:
 ///
-///     `name`: a NULL-terminated `UUIDv4` string representing the name of the sync session
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///  `schedule`: an `SDDKSyncCleaningSchedule` variant
+///
+///      `date`: the date string used for `SDDKSyncCleaningSchedule.ExactDateRFC*` variants. Should be
+///              null for other enum variants.
+///
+///    `policy`: the retention policy used for `SDDKSyncCleaningSchedule.Custom`. Should be null for
+///              other enum variants.
+///
+///   `dry_run`: 1 to only report the sessions that would be removed, 0 to remove them
+///
+///  `sessions`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was 0+
+///
+///              must be freed by the caller using `sddk_free_sync_sessions()`
 ///
 ///     `error`: an uninitialized pointer that will be allocated and initialized when the function
 ///              returns if the return value was -1
@@ -2849,70 +3473,917 @@ pub extern "C" fn sddk_gc(state: *mut SDDKState,
 ///
 ///     -1: failure, `error` will be set with more information
 ///
-///      0: success
+///     0+: number of sessions removed (or that would be), and number of `SDDKSyncSession` structs
+///         allocated in sessions
+///
 ///
 /// # Examples
 ///
 /// ```c
+/// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
+/// SDDKSyncSession * sessions = NULL;
+/// SDDKRetentionPolicy policy = { 24, 7, 4, 12, 0 };
 ///
-/// if (0 != sddk_cancel_sync_task("02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", &error)) {
-///     printf("Failed to cancel sync task");
+/// int length = sddk_clean_sync_sessions_with_policy(&state, SDDKSyncCleaningSchedule.Custom, NULL, &policy, 0, &sessions, &error);
+///
+/// if (-1 == length) {
+///     printf("Failed to remove sessions");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
 /// else {
-///     printf("Cancelled sync task");
+///     printf("Removed %d sessions", length);
+///     sddk_free_sync_sessions(&sessions, length);
 /// }
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_cancel_sync_task(name: *const std::os::raw::c_char,
-                                        mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+pub extern "C" fn sddk_clean_sync_sessions_with_policy(state: *mut SDDKState,
+                                                       schedule: SDDKSyncCleaningSchedule,
+                                                       date: *const std::os::raw::c_char,
+                                                       policy: *const SDDKRetentionPolicy,
+                                                       dry_run: std::os::raw::c_int,
+                                                       mut sessions: *mut *mut SDDKSyncSession,
+                                                       mut error: *mut *mut SDDKError) -> i64 {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+
+    let d: Option<String> = {
+        if date.is_null() {
+            None
+        } else {
+            let c_date: &CStr = unsafe { CStr::from_ptr(date) };
+            let d: String = match c_date.to_str() {
+                Ok(s) => s.to_owned(),
+                Err(err) => {
+                    let c_err = SDDKError::from(err);
+
+                    let b = Box::new(c_err);
+                    let ptr = Box::into_raw(b);
+
+                    unsafe {
+                        *error = ptr;
+                    }
+                    return -1;
+                },
+            };
+
+            Some(d)
+        }
+    };
+
+    let p: Option<&SDDKRetentionPolicy> = unsafe { policy.as_ref() };
+
+    let sch = schedule.to_schedule(d, p);
+
+    let now = ::chrono::Local::now();
+
+    let result = match clean_sync_sessions_at(c.0.get_api_token(), sch, &now, dry_run != 0) {
+        Ok(ses) => ses,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let s = result.into_iter().map(|session| {
+        SDDKSyncSession::from(session)
+    }).collect::<Vec<SDDKSyncSession>>();
+
+    let mut b = s.into_boxed_slice();
+    let ptr = b.as_mut_ptr();
+    let len = b.len();
+    std::mem::forget(b);
+
+    unsafe {
+        *sessions = ptr;
+    }
+
+    len as i64
+}
+
+/// Clean sync sessions with the `Auto` schedule, returning the sessions that were removed
+///
+/// When `dry_run` is non-zero nothing is removed, and the sessions that would have been removed
+/// are returned instead, so they can be shown to the user before cleaning.
+///
+/// The caller does not own the memory pointed to by `sessions` after this function returns, it must
+/// be returned and freed by the library.
+///
+///
+/// Parameters:
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `dry_run`: 1 to only report the sessions that would be removed, 0 to remove them
+///
+///     `sessions`: an uninitialized pointer that will be allocated and initialized when the function
+///                 returns if the return value was 0+
+///
+///                 must be freed by the caller using `sddk_free_sync_sessions()`
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///     0+: number of sessions removed (or that would be), and number of `SDDKSyncSession` structs
+///         allocated in sessions
+///
+///
+/// # Examples
+///
+/// ```c
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKError *error = NULL;
+/// SDDKSyncSession * sessions = NULL;
+///
+/// int length = sddk_auto_clean_sync_sessions(&state, 1, &sessions, &error);
+///
+/// if (-1 == length) {
+///     printf("Failed to plan session cleaning");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("Would remove %d sessions", length);
+///     sddk_free_sync_sessions(&sessions, length);
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_auto_clean_sync_sessions(state: *mut SDDKState,
+                                                dry_run: std::os::raw::c_int,
+                                                mut sessions: *mut *mut SDDKSyncSession,
+                                                mut error: *mut *mut SDDKError) -> i64 {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+
+    let now = ::chrono::Local::now();
+
+    let result = match auto_clean_sync_sessions(c.0.get_api_token(), &now, dry_run != 0) {
+        Ok(ses) => ses,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let s = result.into_iter().map(|session| {
+        SDDKSyncSession::from(session)
+    }).collect::<Vec<SDDKSyncSession>>();
+
+    let mut b = s.into_boxed_slice();
+    let ptr = b.as_mut_ptr();
+    let len = b.len();
+    std::mem::forget(b);
+
+    unsafe {
+        *sessions = ptr;
+    }
+
+    len as i64
+}
+
+
+/// Remove blocks that are no longer used by any sync session
+///
+/// Every remaining session for the account is read to find the blocks still in use, and unused
+/// blocks are removed from the local block cache. Blocks written in the last 24 hours are never
+/// removed, since a sync that is still running may need them.
+///
+/// Garbage collection isn't supported for SafeDrive storage, the API has no way to list or
+/// remove blocks, so nothing is removed from storage.
+///
+/// If any session can't be read, nothing is removed and the function fails.
+///
+/// Use `sddk_gc_report()` to find out what was removed, or to do a dry run.
+///
+/// Parameters:
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+///
+/// # Examples
+///
+/// ```c
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_gc(&state, &error)) {
+///     printf("Failed to run GC");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("GC successful");
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_gc(state: *mut SDDKState,
+                          error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let mut report = SDDKGarbageReport {
+        sessions: 0,
+        live_blocks: 0,
+        storage_supported: 0,
+        storage_blocks: 0,
+        storage_bytes: 0,
+        cache_blocks: 0,
+        cache_bytes: 0,
+    };
+
+    sddk_gc_report(state, 0, &mut report, error)
+}
+
+/// Remove blocks that are no longer used by any sync session, and report what was removed
+///
+/// Works exactly like `sddk_gc()`, but fills in `report` with the number of sessions read, the
+/// number of blocks they use, and the number and size of the unused blocks found in the local
+/// block cache. `storage_supported` is always 0, see `SDDKGarbageReport`.
+///
+/// Parameters:
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `dry_run`: if non-zero, nothing is removed and `report` describes what would be
+///
+///     `report`: a pointer to an `SDDKGarbageReport` owned by the caller, filled in when the
+///               function returns 0
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+///
+/// # Examples
+///
+/// ```c
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKGarbageReport report;
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_gc_report(&state, 1, &report, &error)) {
+///     printf("Failed to run GC");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("%llu unused blocks in the cache", report.cache_blocks);
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_gc_report(state: *mut SDDKState,
+                                 dry_run: std::os::raw::c_int,
+                                 report: *mut SDDKGarbageReport,
+                                 mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+    assert!(!report.is_null());
+
+    let result = match collect_garbage(c.0.get_api_token(), c.0.get_main_key(), dry_run != 0) {
+        Ok(result) => result,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    unsafe {
+        *report = SDDKGarbageReport::from(result);
+    }
+
+    0
+}
+
+/// Cancel a sync or restore by name
+///
+///
+/// Parameters:
+///
+///     `name`: a NULL-terminated `UUIDv4` string representing the name of the sync session
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+/// # Examples
+///
+/// ```c
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_cancel_sync_task("02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", &error)) {
+///     printf("Failed to cancel sync task");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("Cancelled sync task");
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_cancel_sync_task(name: *const std::os::raw::c_char,
+                                        mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+
+    let c_name: &CStr = unsafe { CStr::from_ptr(name) };
+    let n: String = match c_name.to_str() {
+        Ok(s) => s.to_owned(),
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    cancel_sync_task(&n);
+
+    0
+}
+
+
+/// Find a sync session for the folder ID that was cancelled or interrupted before it finished
+///
+/// Passing the name to `sddk_sync()` resumes the session, without uploading the blocks or reading
+/// the files it had already finished again. Passing a new name starts over and discards it.
+///
+/// The caller does not own the memory pointed to by `name` after this function returns, it must
+/// be returned and freed by the library.
+///
+///
+/// Parameters:
+///
+///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///
+///     `name`: an uninitialized pointer that will be allocated and initialized when the function
+///             returns if the return value was 1
+///
+///             must be freed by the caller using `sddk_free_string()`
+///
+/// Return:
+///
+///      0: the folder has no interrupted session
+///
+///      1: success, `name` holds the session name
+///
+/// # Examples
+///
+/// ```c
+/// char * name = NULL;
+///
+/// if (1 == sddk_get_interrupted_sync_session(7, &name)) {
+///     // pass name to sddk_sync() to resume the session, then free it
+///     sddk_free_string(&name);
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_get_interrupted_sync_session(folder_id: std::os::raw::c_ulonglong,
+                                                    mut name: *mut *mut std::os::raw::c_char) -> std::os::raw::c_int {
+    match interrupted_sync_session(folder_id as u64) {
+        Some(session_name) => {
+            unsafe {
+                *name = CString::new(session_name).expect("Failed to get session name").into_raw();
+            }
+
+            1
+        },
+        None => 0,
+    }
+}
+
+/// Get the ignore rules stored for a folder
+///
+/// The patterns are returned the way they would be written in a `.sdignore` file, one per line.
+/// Patterns from the `.sdignore` file in the folder itself are not included.
+///
+///
+/// Parameters:
+///
+///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///
+///     `patterns`: an uninitialized pointer that will be allocated and initialized when the function
+///                 returns if the return value was 0
+///
+///                 must be freed by the caller using `sddk_free_string()`
+///
+///     `max_file_size`: set to the largest size in bytes a file can have and still be synced, or 0
+///                      if there is no limit
+///
+///     `skip_hidden`: set to 1 if files and directories with names starting with a dot are skipped,
+///                    otherwise 0
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+/// # Examples
+///
+/// ```c
+/// char * patterns = NULL;
+/// unsigned long long max_file_size = 0;
+/// int skip_hidden = 0;
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_get_ignore_rules(7, &patterns, &max_file_size, &skip_hidden, &error)) {
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     // do something with the rules here, then free the patterns
+///     sddk_free_string(&patterns);
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_get_ignore_rules(folder_id: std::os::raw::c_ulonglong,
+                                        mut patterns: *mut *mut std::os::raw::c_char,
+                                        max_file_size: *mut std::os::raw::c_ulonglong,
+                                        skip_hidden: *mut std::os::raw::c_int,
+                                        mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let rules = match get_ignore_rules(folder_id as u64) {
+        Ok(rules) => rules,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let joined = rules.patterns.join("\n");
+
+    unsafe {
+        *patterns = CString::new(joined).expect("Failed to get ignore patterns").into_raw();
+        *max_file_size = rules.max_file_size.unwrap_or(0);
+        *skip_hidden = if rules.skip_hidden { 1 } else { 0 };
+    }
+
+    0
+}
+
+/// Replace the ignore rules stored for a folder, they apply from the next sync on
+///
+/// Patterns use the same gitignore syntax as the `.sdignore` file in the root of the folder,
+/// whose patterns are applied after these ones.
+///
+///
+/// Parameters:
+///
+///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///
+///     `patterns`: a NULL-terminated UTF-8 string holding one pattern per line, can be NULL if
+///                 there are no patterns
+///
+///     `max_file_size`: files larger than this many bytes are skipped, 0 for no limit
+///
+///     `skip_hidden`: 1 to skip files and directories with names starting with a dot, otherwise 0
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1, for example when a pattern is invalid
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+/// # Examples
+///
+/// ```c
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_set_ignore_rules(7, "node_modules/\n*.log", 0, 1, &error)) {
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_set_ignore_rules(folder_id: std::os::raw::c_ulonglong,
+                                        patterns: *const std::os::raw::c_char,
+                                        max_file_size: std::os::raw::c_ulonglong,
+                                        skip_hidden: std::os::raw::c_int,
+                                        mut error: *mut *mut SDDKError) -> std::os::raw::c_int {
+    let p: Vec<String> = if patterns.is_null() {
+        Vec::new()
+    } else {
+        let c_patterns: &CStr = unsafe { CStr::from_ptr(patterns) };
+
+        match c_patterns.to_str() {
+            Ok(s) => s.lines().map(|line| line.to_string()).collect(),
+            Err(err) => {
+                let c_err = SDDKError::from(err);
+
+                let b = Box::new(c_err);
+                let ptr = Box::into_raw(b);
+
+                unsafe {
+                    *error = ptr;
+                }
+                return -1;
+            },
+        }
+    };
+
+    let rules = IgnoreRules {
+        patterns: p,
+        max_file_size: if max_file_size > 0 { Some(max_file_size as u64) } else { None },
+        skip_hidden: skip_hidden != 0,
+    };
+
+    match set_ignore_rules(folder_id as u64, &rules) {
+        Ok(()) => 0,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            -1
+        },
+    }
+}
+
+/// Start a sync for the folder ID
+///
+///
+/// Parameters:
+///
+///     `context`: an opaque pointer the caller can pass in and get access to in the progress callback
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `name`: a NULL-terminated `UUIDv4` string representing the name of the sync session
+///
+///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///
+///     `progress`: a C function pointer that will be called periodically to report progress
+///
+///     `issue`: a C function pointer that will be called periodically to report sync issues
+///
+///     `event`: a C function pointer that will be called with each `SDDKSyncEvent`, may be NULL
+///
+///              events say which file is being synced, which ones were skipped and why, and end
+///              with a `Summary` event once the sync finished. `context4` is passed to it
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+/// # Examples
+///
+/// ```c
+/// void *context; // arbitrary, can be anything you want access to in the callback
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_sync(&context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, &fp)) {
+///     printf("Failed to sync");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("Sync successful");
+/// }
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
+                            context2: *mut std::os::raw::c_void,
+                            context3: *mut std::os::raw::c_void,
+                            context4: *mut std::os::raw::c_void,
+                            state: *mut SDDKState,
+                            mut error: *mut *mut SDDKError,
+                            name: *const std::os::raw::c_char,
+                            folder_id: std::os::raw::c_ulonglong,
+                            progress: extern fn(progress_context: *mut std::os::raw::c_void,
+                                                total: std::os::raw::c_ulonglong,
+                                                current: std::os::raw::c_ulonglong,
+                                                new_bytes: std::os::raw::c_ulonglong),
+                            bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
+                                                 speed: std::os::raw::c_ulonglong),
+                            issue: extern fn(issue_context: *mut std::os::raw::c_void,
+                                             message: *const std::os::raw::c_char),
+                            event: Option<extern fn(event_context: *mut std::os::raw::c_void,
+                                             event: *const SDDKSyncEvent)>) -> std::os::raw::c_int {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+    let c_name: &CStr = unsafe { CStr::from_ptr(name) };
+    let n: String = match c_name.to_str() {
+        Ok(s) => s.to_owned(),
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+
+    let main_key = (*c).0.get_main_key();
+    let hmac_key = (*c).0.get_hmac_key();
+    let tweak_key = (*c).0.get_tweak_key();
+
+    let id = folder_id as u64;
+
+    match sync(c.0.get_api_token(),
+               &n,
+               main_key,
+               hmac_key,
+               tweak_key,
+               id,
+               &mut |total, current, new_bytes| {
+                   let c_total: std::os::raw::c_ulonglong = total;
+                   let c_current: std::os::raw::c_ulonglong = current;
+                   let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
+                   progress(context, c_total, c_current, c_new_bytes);
+               },&mut |speed| {
+                   let c_speed: std::os::raw::c_ulonglong = speed;
+                   bandwidth(context2, c_speed);
+               },
+               &mut |message| {
+                   let c_message = CString::new(message).expect("failed to get sync message");
+                   issue(context3, c_message.as_ptr());
+               },
+               &mut |e| {
+                   send_sync_event(event, context4, e);
+               }) {
+        Ok(_) => 0,
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
+
+            unsafe {
+                *error = ptr;
+            }
+            -1
+        },
+    }
+}
+
+/// Start a restore for the folder ID
+///
+/// Parameters:
+///
+///     `context`: an opaque pointer the caller can pass in and get access to in the progress callback
+///
+///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+///
+///     `name`: a NULL-terminated UTF-8 string representing the `UUIDv4` name of the sync session
+///
+///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///
+///     `destination`: a NULL-terminated UTF-8 string representing the path the sync session will be restored to
+///
+///     `progress`: a C function pointer that will be called periodically to report progress
+///
+///     `issue`: a C function pointer that will be called periodically to report restore issues
+///
+///     `event`: a C function pointer that will be called with each `SDDKSyncEvent`, may be NULL
+///
+///              events say which file is being restored, which ones were skipped and why, and end
+///              with a `Summary` event once the restore finished. `context4` is passed to it
+///
+///     `error`: an uninitialized pointer that will be allocated and initialized when the function
+///              returns if the return value was -1
+///
+///              must be freed by the caller using `sddk_free_error()`
+///
+/// Return:
+///
+///     -1: failure, `error` will be set with more information
+///
+///      0: success
+///
+/// # Examples
+///
+/// ```c
+/// void *context; // arbitrary, can be anything you want access to in the callback
+/// SDDKState *state; // retrieve from sddk_initialize()
+/// SDDKError *error = NULL;
+///
+/// if (0 != sddk_restore(&context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, "/path/to/destination", &fp)) {
+///     printf("Failed to restore");
+///     // do something with error here, then free it
+///     sddk_free_error(&error);
+/// }
+/// else {
+///     printf("Restore successful");
+/// }
+///
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_restore(context: *mut std::os::raw::c_void,
+                               context2: *mut std::os::raw::c_void,
+                               context3: *mut std::os::raw::c_void,
+                               context4: *mut std::os::raw::c_void,
+                               state: *mut SDDKState,
+                               mut error: *mut *mut SDDKError,
+                               name: *const std::os::raw::c_char,
+                               folder_id: std::os::raw::c_ulonglong,
+                               destination: *const std::os::raw::c_char,
+                               session_size: std::os::raw::c_ulonglong,
+                               progress: extern fn(progress_context: *mut std::os::raw::c_void,
+                                                   total: std::os::raw::c_ulonglong,
+                                                   current: std::os::raw::c_ulonglong,
+                                                   new_bytes: std::os::raw::c_ulonglong),
+                               bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
+                                                    speed: std::os::raw::c_ulonglong),
+                               issue: extern fn(issue_context: *mut std::os::raw::c_void,
+                                                message: *const std::os::raw::c_char),
+                               event: Option<extern fn(event_context: *mut std::os::raw::c_void,
+                                                event: *const SDDKSyncEvent)>) -> std::os::raw::c_int {
+    let c = unsafe{ assert!(!state.is_null()); &mut * state };
+    let c_name: &CStr = unsafe { CStr::from_ptr(name) };
+    let n: String = match c_name.to_str() {
+        Ok(s) => s.to_owned(),
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+
+    let c_destination: &CStr = unsafe { CStr::from_ptr(destination) };
+    let d: String =  match c_destination.to_str() {
+        Ok(p) => p.to_owned(),
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+    let p = PathBuf::from(d);
+
+    let main_key = (*c).0.get_main_key();
+    let hmac_key = (*c).0.get_hmac_key();
+    let tweak_key = (*c).0.get_tweak_key();
+
+    let id = folder_id as u64;
+    let ses_size = session_size as u64;
+
 
-    let c_name: &CStr = unsafe { CStr::from_ptr(name) };
-    let n: String = match c_name.to_str() {
-        Ok(s) => s.to_owned(),
+    match restore(c.0.get_api_token(),
+                  &n,
+                  main_key,
+                  id,
+                  p,
+                  ses_size,
+                  &mut |total, current, new_bytes| {
+                      let c_total: std::os::raw::c_ulonglong = total;
+                      let c_current: std::os::raw::c_ulonglong = current;
+                      let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
+                      progress(context, c_total, c_current, c_new_bytes);
+                  },
+                  &mut |speed| {
+                      let c_speed: std::os::raw::c_ulonglong = speed;
+                      bandwidth(context2, c_speed);
+                  },
+                  &mut |message| {
+                      let c_message = CString::new(message).expect("failed to get sync message");
+                      issue(context3, c_message.as_ptr());
+                  },
+                  &mut |e| {
+                      send_sync_event(event, context4, e);
+                  }) {
+        Ok(_) => 0,
         Err(err) => {
             let c_err = SDDKError::from(err);
 
-            let b = Box::new(c_err);
-            let ptr = Box::into_raw(b);
+            let boxed_error = Box::new(c_err);
+            let ptr = Box::into_raw(boxed_error);
 
             unsafe {
                 *error = ptr;
             }
-            return -1;
+            -1
         },
-    };
-
-    cancel_sync_task(&n);
-
-    0
+    }
 }
 
 
-/// Start a sync for the folder ID
-///
-///
-/// Parameters:
+
+/// Start a restore of part of a session for the folder ID
 ///
-///     `context`: an opaque pointer the caller can pass in and get access to in the progress callback
+/// Works the same way as `sddk_restore()`, but only restores the session entries matching the
+/// include patterns and none of the exclude patterns. Only the blocks needed for those entries
+/// are downloaded.
 ///
-///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+/// Patterns are globs matched against paths relative to the root of the folder, for example
+/// `Documents/*.pdf` or `Photos/**/*.jpg`. A pattern matching a directory also matches everything
+/// inside it.
 ///
-///     `name`: a NULL-terminated `UUIDv4` string representing the name of the sync session
+/// Parameters:
 ///
-///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+///     (same as `sddk_restore()`, with the following added)
 ///
-///     `progress`: a C function pointer that will be called periodically to report progress
+///     `include`: an array of NULL-terminated UTF-8 strings, entries matching any of them are restored
 ///
-///     `issue`: a C function pointer that will be called periodically to report sync issues
+///     `include_count`: number of strings in `include`, if 0 every entry is included
 ///
-///     `error`: an uninitialized pointer that will be allocated and initialized when the function
-///              returns if the return value was -1
+///     `exclude`: an array of NULL-terminated UTF-8 strings, entries matching any of them are skipped
 ///
-///              must be freed by the caller using `sddk_free_error()`
+///     `exclude_count`: number of strings in `exclude`, may be 0
 ///
 /// Return:
 ///
@@ -2926,33 +4397,44 @@ pub extern "C" fn sddk_cancel_sync_task(name: *const std::os::raw::c_char,
 /// void *context; // arbitrary, can be anything you want access to in the callback
 /// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
+/// const char *include[] = { "Documents/report.pdf" };
 ///
-/// if (0 != sddk_sync(&context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, &fp)) {
-///     printf("Failed to sync");
+/// if (0 != sddk_restore_paths(&context, &context, &context, &context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, "/path/to/destination", 0, include, 1, NULL, 0, &fp, &fb, &fi, &fe)) {
+///     printf("Failed to restore");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
 /// else {
-///     printf("Sync successful");
+///     printf("Restore successful");
 /// }
+///
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
-                            context2: *mut std::os::raw::c_void,
-                            context3: *mut std::os::raw::c_void,
-                            state: *mut SDDKState,
-                            mut error: *mut *mut SDDKError,
-                            name: *const std::os::raw::c_char,
-                            folder_id: std::os::raw::c_ulonglong,
-                            progress: extern fn(progress_context: *mut std::os::raw::c_void,
-                                                total: std::os::raw::c_ulonglong,
-                                                current: std::os::raw::c_ulonglong,
-                                                new_bytes: std::os::raw::c_ulonglong),
-                            bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
-                                                 speed: std::os::raw::c_ulonglong),
-                            issue: extern fn(issue_context: *mut std::os::raw::c_void,
-                                             message: *const std::os::raw::c_char)) -> std::os::raw::c_int {
+pub extern "C" fn sddk_restore_paths(context: *mut std::os::raw::c_void,
+                                     context2: *mut std::os::raw::c_void,
+                                     context3: *mut std::os::raw::c_void,
+                                     context4: *mut std::os::raw::c_void,
+                                     state: *mut SDDKState,
+                                     mut error: *mut *mut SDDKError,
+                                     name: *const std::os::raw::c_char,
+                                     folder_id: std::os::raw::c_ulonglong,
+                                     destination: *const std::os::raw::c_char,
+                                     session_size: std::os::raw::c_ulonglong,
+                                     include: *const *const std::os::raw::c_char,
+                                     include_count: std::os::raw::c_uint,
+                                     exclude: *const *const std::os::raw::c_char,
+                                     exclude_count: std::os::raw::c_uint,
+                                     progress: extern fn(progress_context: *mut std::os::raw::c_void,
+                                                         total: std::os::raw::c_ulonglong,
+                                                         current: std::os::raw::c_ulonglong,
+                                                         new_bytes: std::os::raw::c_ulonglong),
+                                     bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
+                                                          speed: std::os::raw::c_ulonglong),
+                                     issue: extern fn(issue_context: *mut std::os::raw::c_void,
+                                                      message: *const std::os::raw::c_char),
+                                     event: Option<extern fn(event_context: *mut std::os::raw::c_void,
+                                                      event: *const SDDKSyncEvent)>) -> std::os::raw::c_int {
     let c = unsafe{ assert!(!state.is_null()); &mut * state };
     let c_name: &CStr = unsafe { CStr::from_ptr(name) };
     let n: String = match c_name.to_str() {
@@ -2971,31 +4453,83 @@ pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
     };
 
 
+    let c_destination: &CStr = unsafe { CStr::from_ptr(destination) };
+    let d: String =  match c_destination.to_str() {
+        Ok(p) => p.to_owned(),
+        Err(err) => {
+            let c_err = SDDKError::from(err);
+
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+    let p = PathBuf::from(d);
+
+    let include_list = match c_string_list(include, include_count) {
+        Ok(l) => l,
+        Err(c_err) => {
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let exclude_list = match c_string_list(exclude, exclude_count) {
+        Ok(l) => l,
+        Err(c_err) => {
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let i: Vec<&str> = include_list.iter().map(|s| s.as_str()).collect();
+    let e: Vec<&str> = exclude_list.iter().map(|s| s.as_str()).collect();
+
     let main_key = (*c).0.get_main_key();
-    let hmac_key = (*c).0.get_hmac_key();
-    let tweak_key = (*c).0.get_tweak_key();
 
     let id = folder_id as u64;
+    let ses_size = session_size as u64;
 
-    match sync(c.0.get_api_token(),
-               &n,
-               main_key,
-               hmac_key,
-               tweak_key,
-               id,
-               &mut |total, current, new_bytes| {
-                   let c_total: std::os::raw::c_ulonglong = total;
-                   let c_current: std::os::raw::c_ulonglong = current;
-                   let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
-                   progress(context, c_total, c_current, c_new_bytes);
-               },&mut |speed| {
-                   let c_speed: std::os::raw::c_ulonglong = speed;
-                   bandwidth(context2, c_speed);
-               },
-               &mut |message| {
-                   let c_message = CString::new(message).expect("failed to get sync message");
-                   issue(context3, c_message.as_ptr());
-               }) {
+
+    match restore_paths(c.0.get_api_token(),
+                        &n,
+                        main_key,
+                        id,
+                        p,
+                        ses_size,
+                        &i,
+                        &e,
+                        &mut |total, current, new_bytes| {
+                            let c_total: std::os::raw::c_ulonglong = total;
+                            let c_current: std::os::raw::c_ulonglong = current;
+                            let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
+                            progress(context, c_total, c_current, c_new_bytes);
+                        },
+                        &mut |speed| {
+                            let c_speed: std::os::raw::c_ulonglong = speed;
+                            bandwidth(context2, c_speed);
+                        },
+                        &mut |message| {
+                            let c_message = CString::new(message).expect("failed to get sync message");
+                            issue(context3, c_message.as_ptr());
+                        },
+                        &mut |e| {
+                            send_sync_event(event, context4, e);
+                        }) {
         Ok(_) => 0,
         Err(err) => {
             let c_err = SDDKError::from(err);
@@ -3011,28 +4545,27 @@ pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
     }
 }
 
-/// Start a restore for the folder ID
-///
-/// Parameters:
+/// Resume an interrupted restore of a session for the folder ID
 ///
-///     `context`: an opaque pointer the caller can pass in and get access to in the progress callback
-///
-///     `state`: an opaque pointer obtained from calling `sddk_initialize()`
+/// Works the same way as `sddk_restore_paths()`, but files already in the destination are
+/// compared with the session first. Files that match are left alone, and in the others only the
+/// parts that differ are downloaded and written, so restoring into a destination that a previous
+/// restore got most of the way through only downloads what is still missing.
 ///
-///     `name`: a NULL-terminated UTF-8 string representing the `UUIDv4` name of the sync session
+/// Comparing a file means reading all of it, so for a destination that doesn't have any of the
+/// session in it yet `sddk_restore_paths()` is faster.
 ///
-///     `folder_id`: an unsigned 64-bit integer representing a registered folder ID
+/// Parameters:
 ///
-///     `destination`: a NULL-terminated UTF-8 string representing the path the sync session will be restored to
+///     (same as `sddk_restore()`, with the following added)
 ///
-///     `progress`: a C function pointer that will be called periodically to report progress
+///     `include`: an array of NULL-terminated UTF-8 strings, entries matching any of them are restored
 ///
-///     `issue`: a C function pointer that will be called periodically to report restore issues
+///     `include_count`: number of strings in `include`, if 0 every entry is included
 ///
-///     `error`: an uninitialized pointer that will be allocated and initialized when the function
-///              returns if the return value was -1
+///     `exclude`: an array of NULL-terminated UTF-8 strings, entries matching any of them are skipped
 ///
-///              must be freed by the caller using `sddk_free_error()`
+///     `exclude_count`: number of strings in `exclude`, may be 0
 ///
 /// Return:
 ///
@@ -3046,9 +4579,10 @@ pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
 /// void *context; // arbitrary, can be anything you want access to in the callback
 /// SDDKState *state; // retrieve from sddk_initialize()
 /// SDDKError *error = NULL;
+/// const char *include[] = { "Documents/report.pdf" };
 ///
-/// if (0 != sddk_restore(&context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, "/path/to/destination", &fp)) {
-///     printf("Failed to restore");
+/// if (0 != sddk_restore_resume(&context, &context, &context, &context, &state, &error, "02c0dc9c-6217-407b-a3ef-0d7ac5f288b1", 7, "/path/to/destination", 0, include, 1, NULL, 0, &fp, &fb, &fi, &fe)) {
+///     printf("Failed to resume restore");
 ///     // do something with error here, then free it
 ///     sddk_free_error(&error);
 /// }
@@ -3059,23 +4593,30 @@ pub extern "C" fn sddk_sync(context: *mut std::os::raw::c_void,
 /// ```
 #[no_mangle]
 #[allow(dead_code)]
-pub extern "C" fn sddk_restore(context: *mut std::os::raw::c_void,
-                               context2: *mut std::os::raw::c_void,
-                               context3: *mut std::os::raw::c_void,
-                               state: *mut SDDKState,
-                               mut error: *mut *mut SDDKError,
-                               name: *const std::os::raw::c_char,
-                               folder_id: std::os::raw::c_ulonglong,
-                               destination: *const std::os::raw::c_char,
-                               session_size: std::os::raw::c_ulonglong,
-                               progress: extern fn(progress_context: *mut std::os::raw::c_void,
-                                                   total: std::os::raw::c_ulonglong,
-                                                   current: std::os::raw::c_ulonglong,
-                                                   new_bytes: std::os::raw::c_ulonglong),
-                               bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
-                                                    speed: std::os::raw::c_ulonglong),
-                               issue: extern fn(issue_context: *mut std::os::raw::c_void,
-                                                message: *const std::os::raw::c_char)) -> std::os::raw::c_int {
+pub extern "C" fn sddk_restore_resume(context: *mut std::os::raw::c_void,
+                                      context2: *mut std::os::raw::c_void,
+                                      context3: *mut std::os::raw::c_void,
+                                      context4: *mut std::os::raw::c_void,
+                                      state: *mut SDDKState,
+                                      mut error: *mut *mut SDDKError,
+                                      name: *const std::os::raw::c_char,
+                                      folder_id: std::os::raw::c_ulonglong,
+                                      destination: *const std::os::raw::c_char,
+                                      session_size: std::os::raw::c_ulonglong,
+                                      include: *const *const std::os::raw::c_char,
+                                      include_count: std::os::raw::c_uint,
+                                      exclude: *const *const std::os::raw::c_char,
+                                      exclude_count: std::os::raw::c_uint,
+                                      progress: extern fn(progress_context: *mut std::os::raw::c_void,
+                                                          total: std::os::raw::c_ulonglong,
+                                                          current: std::os::raw::c_ulonglong,
+                                                          new_bytes: std::os::raw::c_ulonglong),
+                                      bandwidth: extern fn(bandwidth_context: *mut std::os::raw::c_void,
+                                                           speed: std::os::raw::c_ulonglong),
+                                      issue: extern fn(issue_context: *mut std::os::raw::c_void,
+                                                       message: *const std::os::raw::c_char),
+                                      event: Option<extern fn(event_context: *mut std::os::raw::c_void,
+                                                       event: *const SDDKSyncEvent)>) -> std::os::raw::c_int {
     let c = unsafe{ assert!(!state.is_null()); &mut * state };
     let c_name: &CStr = unsafe { CStr::from_ptr(name) };
     let n: String = match c_name.to_str() {
@@ -3111,6 +4652,35 @@ pub extern "C" fn sddk_restore(context: *mut std::os::raw::c_void,
     };
     let p = PathBuf::from(d);
 
+    let include_list = match c_string_list(include, include_count) {
+        Ok(l) => l,
+        Err(c_err) => {
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let exclude_list = match c_string_list(exclude, exclude_count) {
+        Ok(l) => l,
+        Err(c_err) => {
+            let b = Box::new(c_err);
+            let ptr = Box::into_raw(b);
+
+            unsafe {
+                *error = ptr;
+            }
+            return -1;
+        },
+    };
+
+    let i: Vec<&str> = include_list.iter().map(|s| s.as_str()).collect();
+    let e: Vec<&str> = exclude_list.iter().map(|s| s.as_str()).collect();
+
     let main_key = (*c).0.get_main_key();
     let hmac_key = (*c).0.get_hmac_key();
     let tweak_key = (*c).0.get_tweak_key();
@@ -3119,26 +4689,33 @@ pub extern "C" fn sddk_restore(context: *mut std::os::raw::c_void,
     let ses_size = session_size as u64;
 
 
-    match restore(c.0.get_api_token(),
-                  &n,
-                  main_key,
-                  id,
-                  p,
-                  ses_size,
-                  &mut |total, current, new_bytes| {
-                      let c_total: std::os::raw::c_ulonglong = total;
-                      let c_current: std::os::raw::c_ulonglong = current;
-                      let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
-                      progress(context, c_total, c_current, c_new_bytes);
-                  },
-                  &mut |speed| {
-                      let c_speed: std::os::raw::c_ulonglong = speed;
-                      bandwidth(context2, c_speed);
-                  },
-                  &mut |message| {
-                      let c_message = CString::new(message).expect("failed to get sync message");
-                      issue(context3, c_message.as_ptr());
-                  }) {
+    match restore_resume(c.0.get_api_token(),
+                         &n,
+                         main_key,
+                         hmac_key,
+                         tweak_key,
+                         id,
+                         p,
+                         ses_size,
+                         &i,
+                         &e,
+                         &mut |total, current, new_bytes| {
+                             let c_total: std::os::raw::c_ulonglong = total;
+                             let c_current: std::os::raw::c_ulonglong = current;
+                             let c_new_bytes: std::os::raw::c_ulonglong = new_bytes;
+                             progress(context, c_total, c_current, c_new_bytes);
+                         },
+                         &mut |speed| {
+                             let c_speed: std::os::raw::c_ulonglong = speed;
+                             bandwidth(context2, c_speed);
+                         },
+                         &mut |message| {
+                             let c_message = CString::new(message).expect("failed to get sync message");
+                             issue(context3, c_message.as_ptr());
+                         },
+                         &mut |e| {
+                             send_sync_event(event, context4, e);
+                         }) {
         Ok(_) => 0,
         Err(err) => {
             let c_err = SDDKError::from(err);
@@ -3154,6 +4731,30 @@ pub extern "C" fn sddk_restore(context: *mut std::os::raw::c_void,
     }
 }
 
+// read an array of C strings passed in by the caller
+fn c_string_list(list: *const *const std::os::raw::c_char, count: std::os::raw::c_uint) -> Result<Vec<String>, SDDKError> {
+    let mut strings = Vec::new();
+
+    if list.is_null() || count == 0 {
+        return Ok(strings);
+    }
+
+    let pointers = unsafe { std::slice::from_raw_parts(list, count as usize) };
+
+    for pointer in pointers {
+        assert!(!pointer.is_null());
+
+        let c_string: &CStr = unsafe { CStr::from_ptr(*pointer) };
+
+        match c_string.to_str() {
+            Ok(s) => strings.push(s.to_owned()),
+            Err(err) => return Err(SDDKError::from(err)),
+        }
+    }
+
+    Ok(strings)
+}
+
 
 
 /// Report an error to the server
@@ -3567,6 +5168,68 @@ pub extern "C" fn sddk_free_sync_sessions(sessions: *mut *mut SDDKSyncSession,
     }
 }
 
+/// Free a pointer to a list of session entries
+///
+/// Note: This is *not* the same as calling `free()` in C, they are not interchangeable
+///
+/// Parameters:
+///
+///     `entries`: a pointer obtained from calling `sddk_get_session_entries()`
+///
+///     `length`: number of `SDDKSessionEntry` structs that were allocated in the pointer
+///
+///
+/// # Examples
+///
+/// ```c
+///sddk_free_session_entries(&entries, length);
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_free_session_entries(entries: *mut *mut SDDKSessionEntry,
+                                            length: u64) {
+    assert!(!entries.is_null());
+    let l = length as usize;
+    let entries: Vec<SDDKSessionEntry> = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(*entries, l)).into_vec() };
+    for entry in entries {
+        let _ = unsafe { CString::from_raw(entry.path as *mut std::os::raw::c_char) };
+        if !entry.link_target.is_null() {
+            let _ = unsafe { CString::from_raw(entry.link_target as *mut std::os::raw::c_char) };
+        }
+    }
+}
+
+/// Free a pointer to a list of session changes
+///
+/// Note: This is *not* the same as calling `free()` in C, they are not interchangeable
+///
+/// Parameters:
+///
+///     `changes`: a pointer obtained from calling `sddk_diff_sessions()`
+///
+///     `length`: number of `SDDKSessionChange` structs that were allocated in the pointer
+///
+///
+/// # Examples
+///
+/// ```c
+///sddk_free_session_changes(&changes, length);
+/// ```
+#[no_mangle]
+#[allow(dead_code)]
+pub extern "C" fn sddk_free_session_changes(changes: *mut *mut SDDKSessionChange,
+                                            length: u64) {
+    assert!(!changes.is_null());
+    let l = length as usize;
+    let changes: Vec<SDDKSessionChange> = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(*changes, l)).into_vec() };
+    for change in changes {
+        let _ = unsafe { CString::from_raw(change.entry.path as *mut std::os::raw::c_char) };
+        if !change.entry.link_target.is_null() {
+            let _ = unsafe { CString::from_raw(change.entry.link_target as *mut std::os::raw::c_char) };
+        }
+    }
+}
+
 /// Free an opaque pointer to an `SDDKState`
 ///
 /// Note: This is *not* the same as calling `free()` in C, they are not interchangeable
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::io::{Read, Write};
 use rustc_serialize::hex::FromHex;
 use walkdir::WalkDir;
@@ -10,12 +11,14 @@ use CACHE_DIR;
 use block::WrappedBlock;
 use error::SDError;
 
-use models::Token;
-
 use error::SDAPIError;
 
 use binformat::BinaryWriter;
 
+use backend::{StorageBackend, StoredBlock};
+
+use journal::SyncJournal;
+
 use sync_state::is_sync_task_cancelled;
 
 pub struct WriteCacheMessage {
@@ -142,13 +145,11 @@ impl WriteCache {
         self.data_waiting >= self.data_limit || self.items_waiting() >= self.item_limit
     }
 
-    pub fn upload_thread(self, token: &Token, session_name: &str, sync_status_send: ::parking_lot_mpsc::SyncSender<::models::SyncStatus>) -> (::parking_lot_mpsc::SyncSender<::cache::WriteCacheMessage>, ::parking_lot_mpsc::Receiver<Result<bool, SDError>>) {
+    pub fn upload_thread(self, backend: Arc<StorageBackend>, session_name: &str, journal: Arc<SyncJournal>, sync_status_send: ::parking_lot_mpsc::SyncSender<::models::SyncStatus>) -> (::parking_lot_mpsc::SyncSender<::cache::WriteCacheMessage>, ::parking_lot_mpsc::Receiver<Result<bool, SDError>>) {
 
         let (block_send, block_receive) = ::parking_lot_mpsc::sync_channel::<::cache::WriteCacheMessage>(0);
         let (status_send, status_receive) = ::parking_lot_mpsc::channel::<Result<bool, SDError>>();
 
-        let local_token = token.clone();
-
         let local_session_name = session_name.to_owned();
 
         let mut local_self = self;
@@ -216,7 +217,7 @@ impl WriteCache {
                             }
                             let l_sync_status_send = sync_status_send.clone();
 
-                            match ::sdapi::write_blocks(&local_token, &local_session_name, &block_batch, Box::new(move |speed| {
+                            match backend.write_blocks(&local_session_name, &block_batch, Box::new(move |speed| {
                                 debug!("block upload speed: {}", speed);
 
                                 let status_message = ::models::SyncStatus::Bandwidth(speed);
@@ -233,6 +234,8 @@ impl WriteCache {
                                 Ok(missing) => {
                                     debug!("sending group took {} seconds", block_write_start_time.elapsed().as_secs());
 
+                                    let mut stored: Vec<String> = Vec::new();
+
                                     for block in &block_batch {
                                         let bn = &block.name();
                                         if missing.contains(bn) {
@@ -240,9 +243,34 @@ impl WriteCache {
                                             let mut c = block.clone();
                                             c.needs_upload();
                                             local_self.add(c);
+                                        } else {
+                                            // blocks sent without their data were already in
+                                            // storage, so the server only had to be told about them
+                                            let event = if block.should_include_data() {
+                                                ::models::SyncEvent::BlockUploaded { name: bn.to_owned(), size: block.len() as u64 }
+                                            } else {
+                                                ::models::SyncEvent::BlockDeduplicated { name: bn.to_owned(), size: block.len() as u64 }
+                                            };
+
+                                            match sync_status_send.send(::models::SyncStatus::Event(event)) {
+                                                Ok(()) => {
+
+                                                },
+                                                Err(_) => {
+
+                                                },
+                                            }
+
+                                            stored.push(bn.to_owned());
                                         }
                                     }
 
+                                    // failing to record them only means they'll be sent again if
+                                    // the session has to be resumed
+                                    if let Err(e) = journal.acknowledge(&stored) {
+                                        debug!("not able to record stored blocks in sync journal: {}", e);
+                                    }
+
                                     match status_send.send(Ok(false)) {
                                         Ok(()) => {},
                                         Err(e) => {
@@ -465,6 +493,55 @@ pub fn clear_cache() -> Result<u64, SDError> {
     Ok(deleted)
 }
 
+/// list every block in the cache
+pub fn cached_blocks() -> Result<Vec<StoredBlock>, SDError> {
+    let cd = CACHE_DIR.read();
+    let bp = PathBuf::from(&*cd);
+
+    let mut blocks = Vec::new();
+
+    for item in WalkDir::new(&bp).min_depth(1).max_depth(1).into_iter().filter_map(|e| e.ok()) {
+        if !item.file_type().is_file() {
+            continue;
+        }
+
+        let name = match item.file_name().to_str() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let md = match ::std::fs::symlink_metadata(item.path()) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        let modified = md.modified().ok()
+            .and_then(|m| m.duration_since(::std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000);
+
+        blocks.push(StoredBlock {
+            name: name,
+            size: md.len(),
+            modified: modified,
+        });
+    }
+
+    Ok(blocks)
+}
+
+pub fn remove_block<'a>(name: &'a str) -> Result<(), SDError> {
+    let cd = CACHE_DIR.read();
+    let mut bp = PathBuf::from(&*cd);
+
+    bp.push(name);
+
+    match ::std::fs::remove_file(&bp) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(SDError::from(e)),
+    }
+}
+
 pub fn read_block<'a>(name: &'a str) -> Result<WrappedBlock, SDError> {
     let cd = CACHE_DIR.read();
     let mut bp = PathBuf::from(&*cd);
@@ -97,10 +97,12 @@ impl<'a> BlockGenerator<'a> {
 }
 
 
-impl<'a> Iterator for BlockGenerator<'a> {
-    type Item = Result<::block::Block, SDError>;
-
-    fn next(&mut self) -> Option<Result<::block::Block, SDError>> {
+impl<'a> BlockGenerator<'a> {
+    /// read the data for the next chunk in the file without building a block from it
+    ///
+    /// This is what `next()` uses internally, and lets callers like the block pipeline hash,
+    /// compress and encrypt chunks somewhere else.
+    pub fn next_chunk(&mut self) -> Option<Result<Vec<u8>, SDError>> {
         match self.iter.next() {
             Some(chunk) => {
                 self.discovered_chunk_count += 1;
@@ -122,6 +124,24 @@ impl<'a> Iterator for BlockGenerator<'a> {
                     return Some(Err(SDError::from(e)));
                 }
 
+                Some(Ok(data))
+            },
+            None => None,
+        }
+    }
+
+    /// account for the compressed size of a block built from a chunk returned by `next_chunk()`
+    pub fn add_compressed_size(&mut self, size: u64) {
+        self.processed_size_compressed += size;
+    }
+}
+
+impl<'a> Iterator for BlockGenerator<'a> {
+    type Item = Result<::block::Block, SDError>;
+
+    fn next(&mut self) -> Option<Result<::block::Block, SDError>> {
+        match self.next_chunk() {
+            Some(Ok(data)) => {
                 let block = Block::new(self.version, self.hmac_key, data);
 
                 match block.compressed_size() {
@@ -140,6 +160,7 @@ impl<'a> Iterator for BlockGenerator<'a> {
 
                 Some(Ok(block))
             },
+            Some(Err(e)) => Some(Err(e)),
             None => None,
         }
     }
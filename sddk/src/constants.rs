@@ -4,6 +4,9 @@ use CONFIGURATION;
 pub enum Configuration {
     Staging,
     Production,
+    /// A SafeDrive compatible API at a specific host and port, such as the mock API server used
+    /// by the tests. Everything other than the API behaves as it would for staging.
+    Custom { api_domain: String, secure: bool },
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +56,11 @@ pub static MULTIPART_BOUNDARY: &'static str = "SAFEDRIVEBINARY";
 
 pub static KEY_ECC_LEN: usize = 48;
 
+/// session constants
+
+/// how much of the compressed session archive is sealed in each chunk of a chunked session
+pub static SESSION_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// keychain constants
 
 static SD_ACCOUNT_CREDENTIAL_DOMAIN_PRODUCTION: &'static str = "safedrive.io";
@@ -79,6 +87,7 @@ pub fn is_production() -> bool {
     match *c {
         Configuration::Staging => false,
         Configuration::Production => true,
+        Configuration::Custom { .. } => false,
     }
 }
 
@@ -90,11 +99,20 @@ pub fn web_domain() -> &'static str {
     }
 }
 
-pub fn api_domain() -> &'static str {
-    if is_production() {
-        SD_API_DOMAIN_PRODUCTION
-    } else {
-        SD_API_DOMAIN_STAGING
+pub fn api_domain() -> String {
+    let c = CONFIGURATION.read();
+    match *c {
+        Configuration::Staging => SD_API_DOMAIN_STAGING.to_string(),
+        Configuration::Production => SD_API_DOMAIN_PRODUCTION.to_string(),
+        Configuration::Custom { ref api_domain, .. } => api_domain.clone(),
+    }
+}
+
+pub fn api_protocol() -> &'static str {
+    let c = CONFIGURATION.read();
+    match *c {
+        Configuration::Custom { secure: false, .. } => "http://",
+        _ => "https://",
     }
 }
 
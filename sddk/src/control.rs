@@ -0,0 +1,927 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+// external crate imports
+
+use parking_lot::Mutex;
+use serde_json::Value;
+
+// internal imports
+
+use backend::StorageBackend;
+use error::SDError;
+use keys::Key;
+use models::{CacheStats, DaemonEvent, DaemonTask, RegisteredFolder};
+
+use STORAGE_DIR;
+
+/// name of the daemon's control socket in the storage directory
+pub static CONTROL_SOCKET: &'static str = "daemon.sock";
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcNotification {
+    jsonrpc: String,
+    method: String,
+    params: DaemonEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FolderParams {
+    folder_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SessionParams {
+    session_name: String,
+}
+
+/// Parameters of a restore run by the daemon, see `restore_paths` and `restore_resume`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestoreRequest {
+    pub folder_id: u64,
+    /// must be an absolute path, the daemon rejects anything else
+    pub destination: String,
+    /// the most recent session of the folder is restored if this is `None`
+    #[serde(default)]
+    pub session_name: Option<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub resume: bool,
+}
+
+/// the path of the daemon's control socket
+pub fn control_socket_path() -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push(CONTROL_SOCKET);
+
+    Ok(p)
+}
+
+struct Shared {
+    #[cfg(unix)]
+    backend: Arc<StorageBackend>,
+    #[cfg(unix)]
+    main_key: Key,
+    #[cfg(unix)]
+    hmac_key: Key,
+    #[cfg(unix)]
+    tweak_key: Key,
+    #[cfg(unix)]
+    cache_limit: u64,
+    #[cfg(unix)]
+    subscribers: Mutex<Vec<Arc<Mutex<UnixStream>>>>,
+    running: Mutex<Vec<DaemonTask>>,
+    queued: Mutex<Vec<DaemonTask>>,
+}
+
+impl Shared {
+    #[cfg(unix)]
+    fn publish(&self, event: &DaemonEvent) {
+        let notification = RpcNotification {
+            jsonrpc: "2.0".to_string(),
+            method: "event".to_string(),
+            params: event.clone(),
+        };
+
+        let line = match ::serde_json::to_string(&notification) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        // clients that went away or stopped reading are dropped
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|writer| write_line(writer, &line).is_ok());
+    }
+
+    #[cfg(not(unix))]
+    fn publish(&self, _: &DaemonEvent) {}
+
+    fn begin_task(&self, task: &DaemonTask) {
+        self.running.lock().push(task.clone());
+
+        self.publish(&DaemonEvent::Started {
+            folder_id: task.folder_id,
+            session_name: task.session_name.clone(),
+            restore: task.restore,
+        });
+    }
+
+    fn end_task(&self, session_name: &str, result: &Result<(), SDError>) {
+        self.running.lock().retain(|task| task.session_name != session_name);
+
+        match *result {
+            Ok(()) => self.publish(&DaemonEvent::Finished { session_name: session_name.to_string() }),
+            Err(ref e) => self.publish(&DaemonEvent::Failed { session_name: session_name.to_string(), message: format!("{}", e) }),
+        }
+    }
+
+    #[cfg(unix)]
+    fn is_busy(&self, session_name: &str) -> bool {
+        self.running.lock().iter().chain(self.queued.lock().iter()).any(|task| task.session_name == session_name)
+    }
+}
+
+/// The daemon's side of the control socket
+///
+/// Clients talk to the daemon with JSON-RPC 2.0, one JSON object per line, over a Unix domain
+/// socket in the storage directory that only the user running the daemon can open. Methods:
+///
+/// * `list_folders`: the registered folders
+/// * `status`: the syncs and restores that are running, then the queued ones
+/// * `sync` with `folder_id`: queue a sync of the folder, returns the task
+/// * `restore` with the fields of `RestoreRequest`: start a restore, returns the task
+/// * `cancel` with `session_name`: cancel a running or queued task
+/// * `subscribe`: send every `DaemonEvent` to this connection from now on, as `event`
+///   notifications
+/// * `cache_stats`: the size of the local block cache
+/// * `stop`: stop the daemon once the running sync finishes
+///
+/// Syncs are queued for the daemon to run one at a time, restores start right away on their own
+/// thread.
+pub struct ControlServer {
+    shared: Arc<Shared>,
+    #[cfg(unix)]
+    path: PathBuf,
+    #[cfg(unix)]
+    stop: Arc<AtomicBool>,
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    /// start listening on the control socket
+    ///
+    /// Fails if another daemon is already listening on it. A socket left behind by a daemon that
+    /// didn't exit cleanly is replaced.
+    pub fn start(backend: Arc<StorageBackend>, main_key: &Key, hmac_key: &Key, tweak_key: &Key, cache_limit: u64) -> Result<ControlServer, SDError> {
+        let path = control_socket_path()?;
+
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(SDError::Internal("the daemon is already running".to_string()));
+            }
+
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+        let shared = Arc::new(Shared {
+            backend: backend,
+            main_key: main_key.clone(),
+            hmac_key: hmac_key.clone(),
+            tweak_key: tweak_key.clone(),
+            cache_limit: cache_limit,
+            subscribers: Mutex::new(Vec::new()),
+            running: Mutex::new(Vec::new()),
+            queued: Mutex::new(Vec::new()),
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let l_shared = shared.clone();
+        let l_stop = stop.clone();
+
+        ::std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if l_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                match stream {
+                    Ok(stream) => {
+                        let c_shared = l_shared.clone();
+
+                        ::std::thread::spawn(move || {
+                            serve(c_shared, stream);
+                        });
+                    },
+                    Err(e) => {
+                        debug!("control socket connection failed: {}", e);
+                    },
+                }
+            }
+        });
+
+        debug!("daemon listening on {}", path.display());
+
+        Ok(ControlServer {
+            shared: shared,
+            path: path,
+            stop: stop,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        // wake the listening thread so it sees the stop flag
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+impl ControlServer {
+    pub fn start(_: Arc<StorageBackend>, _: &Key, _: &Key, _: &Key, _: u64) -> Result<ControlServer, SDError> {
+        debug!("the daemon control socket is not available on this platform");
+
+        Ok(ControlServer {
+            shared: Arc::new(Shared {
+                running: Mutex::new(Vec::new()),
+                queued: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+}
+
+impl ControlServer {
+    /// send an event to every subscribed client
+    pub fn publish(&self, event: &DaemonEvent) {
+        self.shared.publish(event);
+    }
+
+    /// the syncs clients asked for since the last call, oldest first
+    pub fn take_sync_requests(&self) -> Vec<DaemonTask> {
+        let mut queued = self.shared.queued.lock();
+
+        queued.drain(..).collect()
+    }
+
+    /// record a task as running, and tell subscribed clients it started
+    pub fn begin_task(&self, task: &DaemonTask) {
+        self.shared.begin_task(task);
+    }
+
+    /// record a task as no longer running, and tell subscribed clients how it ended
+    pub fn end_task(&self, session_name: &str, result: &Result<(), SDError>) {
+        self.shared.end_task(session_name, result);
+    }
+}
+
+#[cfg(unix)]
+fn write_line(writer: &Mutex<UnixStream>, line: &str) -> Result<(), ::std::io::Error> {
+    let mut stream = writer.lock();
+
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.flush()
+}
+
+#[cfg(unix)]
+fn serve(shared: Arc<Shared>, stream: UnixStream) {
+    let writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("control socket connection failed: {}", e);
+
+            return;
+        },
+    };
+
+    // a client that stops reading must not hold up the daemon
+    let _ = writer.set_write_timeout(Some(Duration::from_secs(5)));
+
+    let writer = Arc::new(Mutex::new(writer));
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (id, result) = match ::serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) => {
+                let result = handle(&shared, &writer, &request);
+
+                match request.id {
+                    Some(id) => (id, result),
+                    // a notification, which gets no response
+                    None => continue,
+                }
+            },
+            Err(e) => (Value::Null, Err(RpcError { code: PARSE_ERROR, message: format!("{}", e) })),
+        };
+
+        let response = match result {
+            Ok(result) => RpcResponse { jsonrpc: "2.0".to_string(), id: id, result: Some(result), error: None },
+            Err(error) => RpcResponse { jsonrpc: "2.0".to_string(), id: id, result: None, error: Some(error) },
+        };
+
+        let line = match ::serde_json::to_string(&response) {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if write_line(&writer, &line).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn handle(shared: &Arc<Shared>, writer: &Arc<Mutex<UnixStream>>, request: &RpcRequest) -> Result<Value, RpcError> {
+    if request.jsonrpc != "2.0" {
+        return Err(RpcError { code: INVALID_REQUEST, message: "only JSON-RPC 2.0 is supported".to_string() });
+    }
+
+    debug!("control socket request: {}", request.method);
+
+    match request.method.as_str() {
+        "list_folders" => {
+            let folders: Vec<RegisteredFolder> = shared.backend.read_folders().map_err(server_error)?;
+
+            to_value(&folders)
+        },
+        "status" => {
+            let mut tasks = shared.running.lock().clone();
+            tasks.extend(shared.queued.lock().iter().cloned());
+
+            to_value(&tasks)
+        },
+        "sync" => {
+            let params: FolderParams = from_params(&request.params)?;
+
+            // a folder is only queued once
+            {
+                let queued = shared.queued.lock();
+
+                if let Some(task) = queued.iter().find(|task| task.folder_id == params.folder_id) {
+                    return to_value(task);
+                }
+            }
+
+            let syncing = shared.running.lock().iter().any(|task| task.folder_id == params.folder_id && !task.restore);
+
+            // finish an interrupted session rather than starting over, unless it's the one
+            // still running
+            let session_name = match ::journal::interrupted_session(params.folder_id) {
+                Some(ref name) if !syncing => name.clone(),
+                _ => ::uuid::Uuid::new_v4().hyphenated().to_string(),
+            };
+
+            let task = DaemonTask {
+                folder_id: params.folder_id,
+                session_name: session_name,
+                restore: false,
+            };
+
+            shared.queued.lock().push(task.clone());
+
+            to_value(&task)
+        },
+        "restore" => {
+            let params: RestoreRequest = from_params(&request.params)?;
+
+            let task = start_restore(shared, params).map_err(server_error)?;
+
+            to_value(&task)
+        },
+        "cancel" => {
+            let params: SessionParams = from_params(&request.params)?;
+
+            let was_queued = {
+                let mut queued = shared.queued.lock();
+                let count = queued.len();
+
+                queued.retain(|task| task.session_name != params.session_name);
+
+                queued.len() != count
+            };
+
+            if was_queued {
+                return Ok(Value::Null);
+            }
+
+            if !shared.running.lock().iter().any(|task| task.session_name == params.session_name) {
+                return Err(RpcError { code: SERVER_ERROR, message: format!("no task for session {}", params.session_name) });
+            }
+
+            ::sync_state::cancel_sync_task(&params.session_name);
+
+            Ok(Value::Null)
+        },
+        "subscribe" => {
+            shared.subscribers.lock().push(writer.clone());
+
+            Ok(Value::Bool(true))
+        },
+        "cache_stats" => {
+            let blocks = ::cache::cached_blocks().map_err(server_error)?;
+
+            let stats = CacheStats {
+                blocks: blocks.len() as u64,
+                size: blocks.iter().map(|block| block.size).sum(),
+                limit: shared.cache_limit,
+            };
+
+            to_value(&stats)
+        },
+        "stop" => {
+            ::daemon::stop_daemon();
+
+            Ok(Value::Null)
+        },
+        _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("unknown method {}", request.method) }),
+    }
+}
+
+#[cfg(unix)]
+fn start_restore(shared: &Arc<Shared>, request: RestoreRequest) -> Result<DaemonTask, SDError> {
+    // a relative path would be resolved against the daemon's working directory, not the client's
+    if !Path::new(&request.destination).is_absolute() {
+        return Err(SDError::Internal(format!("restore destination {} is not an absolute path", request.destination)));
+    }
+
+    let mut sessions = shared.backend.read_sessions()?;
+
+    let mut folder_sessions = match sessions.remove("sessionDetails").and_then(|mut details| details.remove(&request.folder_id)) {
+        Some(folder_sessions) => folder_sessions,
+        None => Vec::new(),
+    };
+
+    folder_sessions.sort_by_key(|session| session.time);
+
+    let session = match request.session_name {
+        Some(ref name) => folder_sessions.into_iter().find(|session| &session.name == name),
+        None => folder_sessions.pop(),
+    };
+
+    let session = match session {
+        Some(session) => session,
+        None => return Err(SDError::SessionMissing),
+    };
+
+    let session_size = match session.size {
+        Some(size) => size,
+        None => return Err(SDError::Internal(format!("session {} has no size", session.name))),
+    };
+
+    if shared.is_busy(&session.name) {
+        return Err(SDError::Internal(format!("session {} is already being restored", session.name)));
+    }
+
+    let task = DaemonTask {
+        folder_id: request.folder_id,
+        session_name: session.name.clone(),
+        restore: true,
+    };
+
+    shared.begin_task(&task);
+
+    let t_shared = shared.clone();
+    let t_task = task.clone();
+
+    ::std::thread::spawn(move || {
+        let shared = t_shared;
+        let session_name = t_task.session_name;
+
+        let include: Vec<&str> = request.include.iter().map(|p| p.as_str()).collect();
+        let exclude: Vec<&str> = request.exclude.iter().map(|p| p.as_str()).collect();
+
+        let mut progress = |total: u64, current: u64, new: u64| {
+            shared.publish(&DaemonEvent::Progress { session_name: session_name.clone(), total: total, current: current, new: new });
+        };
+
+        let mut bandwidth = |speed: u64| {
+            shared.publish(&DaemonEvent::Bandwidth { session_name: session_name.clone(), speed: speed });
+        };
+
+        let mut issue = |message: &str| {
+            shared.publish(&DaemonEvent::Issue { session_name: session_name.clone(), message: message.to_string() });
+        };
+
+        let result = if request.resume {
+            ::core::restore_resume_with_backend(shared.backend.clone(),
+                                                &session_name,
+                                                &shared.main_key,
+                                                &shared.hmac_key,
+                                                &shared.tweak_key,
+                                                request.folder_id,
+                                                PathBuf::from(&request.destination),
+                                                session_size,
+                                                &include,
+                                                &exclude,
+                                                &mut progress,
+                                                &mut bandwidth,
+                                                &mut issue,
+                                                &mut |_| {})
+        } else {
+            ::core::restore_paths_with_backend(shared.backend.clone(),
+                                               &session_name,
+                                               &shared.main_key,
+                                               request.folder_id,
+                                               PathBuf::from(&request.destination),
+                                               session_size,
+                                               &include,
+                                               &exclude,
+                                               &mut progress,
+                                               &mut bandwidth,
+                                               &mut issue,
+                                               &mut |_| {})
+        };
+
+        shared.end_task(&session_name, &result);
+    });
+
+    Ok(task)
+}
+
+#[cfg(unix)]
+fn from_params<T>(params: &Value) -> Result<T, RpcError> where T: ::serde::de::DeserializeOwned {
+    ::serde_json::from_value(params.clone()).map_err(|e| RpcError { code: INVALID_PARAMS, message: format!("{}", e) })
+}
+
+#[cfg(unix)]
+fn to_value<T>(value: &T) -> Result<Value, RpcError> where T: ::serde::Serialize {
+    ::serde_json::to_value(value).map_err(|e| RpcError { code: SERVER_ERROR, message: format!("{}", e) })
+}
+
+#[cfg(unix)]
+fn server_error<E>(e: E) -> RpcError where E: ::std::fmt::Display {
+    RpcError { code: SERVER_ERROR, message: format!("{}", e) }
+}
+
+/// A connection to a running daemon's control socket
+///
+/// Events are only sent after `subscribe`. Ones that arrive while waiting for the response to
+/// another call are kept, and returned by `next_event` in order.
+pub struct DaemonClient {
+    reader: BufReader<Box<Read + Send>>,
+    writer: Box<Write + Send>,
+    next_id: u64,
+    events: VecDeque<DaemonEvent>,
+}
+
+impl DaemonClient {
+    /// connect to the daemon, or `None` if it isn't running
+    #[cfg(unix)]
+    pub fn connect() -> Result<Option<DaemonClient>, SDError> {
+        let path = control_socket_path()?;
+
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound || e.kind() == ::std::io::ErrorKind::ConnectionRefused => return Ok(None),
+            Err(e) => return Err(SDError::from(e)),
+        };
+
+        let writer = stream.try_clone()?;
+
+        Ok(Some(DaemonClient {
+            reader: BufReader::new(Box::new(stream)),
+            writer: Box::new(writer),
+            next_id: 1,
+            events: VecDeque::new(),
+        }))
+    }
+
+    /// connect to the daemon, or `None` if it isn't running
+    #[cfg(not(unix))]
+    pub fn connect() -> Result<Option<DaemonClient>, SDError> {
+        Ok(None)
+    }
+
+    pub fn list_folders(&mut self) -> Result<Vec<RegisteredFolder>, SDError> {
+        let result = self.call("list_folders", Value::Null)?;
+
+        Ok(::serde_json::from_value(result)?)
+    }
+
+    pub fn status(&mut self) -> Result<Vec<DaemonTask>, SDError> {
+        let result = self.call("status", Value::Null)?;
+
+        Ok(::serde_json::from_value(result)?)
+    }
+
+    /// queue a sync of a folder, returning the task the daemon will run
+    pub fn sync(&mut self, folder_id: u64) -> Result<DaemonTask, SDError> {
+        let params = ::serde_json::to_value(&FolderParams { folder_id: folder_id })?;
+
+        let result = self.call("sync", params)?;
+
+        Ok(::serde_json::from_value(result)?)
+    }
+
+    /// start a restore, returning the task the daemon is running
+    pub fn restore(&mut self, request: &RestoreRequest) -> Result<DaemonTask, SDError> {
+        let params = ::serde_json::to_value(request)?;
+
+        let result = self.call("restore", params)?;
+
+        Ok(::serde_json::from_value(result)?)
+    }
+
+    pub fn cancel(&mut self, session_name: &str) -> Result<(), SDError> {
+        let params = ::serde_json::to_value(&SessionParams { session_name: session_name.to_string() })?;
+
+        self.call("cancel", params)?;
+
+        Ok(())
+    }
+
+    /// start receiving events
+    pub fn subscribe(&mut self) -> Result<(), SDError> {
+        self.call("subscribe", Value::Null)?;
+
+        Ok(())
+    }
+
+    pub fn cache_stats(&mut self) -> Result<CacheStats, SDError> {
+        let result = self.call("cache_stats", Value::Null)?;
+
+        Ok(::serde_json::from_value(result)?)
+    }
+
+    /// ask the daemon to stop
+    pub fn stop(&mut self) -> Result<(), SDError> {
+        self.call("stop", Value::Null)?;
+
+        Ok(())
+    }
+
+    /// wait for the next event, after `subscribe`
+    pub fn next_event(&mut self) -> Result<DaemonEvent, SDError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            let message = self.read_message()?;
+
+            if let Some(event) = self.as_event(&message)? {
+                self.events.push_back(event);
+            }
+        }
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, SDError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(Value::from(id)),
+            method: method.to_string(),
+            params: params,
+        };
+
+        let mut line = ::serde_json::to_vec(&request)?;
+        line.push(b'\n');
+
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+
+        loop {
+            let message = self.read_message()?;
+
+            if let Some(event) = self.as_event(&message)? {
+                self.events.push_back(event);
+
+                continue;
+            }
+
+            let response: RpcResponse = ::serde_json::from_value(message)?;
+
+            if response.id != Value::from(id) {
+                debug!("ignoring response to unknown request {}", response.id);
+
+                continue;
+            }
+
+            return match response.error {
+                Some(error) => Err(SDError::Internal(format!("daemon: {}", error.message))),
+                None => Ok(response.result.unwrap_or(Value::Null)),
+            };
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Value, SDError> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(SDError::Internal("the daemon closed the connection".to_string()));
+            }
+
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+
+        Ok(::serde_json::from_str(&line)?)
+    }
+
+    fn as_event(&self, message: &Value) -> Result<Option<DaemonEvent>, SDError> {
+        match message.get("method") {
+            Some(method) if method == "event" => {},
+            _ => return Ok(None),
+        }
+
+        match message.get("params") {
+            Some(params) => Ok(Some(::serde_json::from_value(params.clone())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn control_protocol_test() {
+    let event = DaemonEvent::Progress { session_name: "session".to_string(), total: 10, current: 5, new: 1 };
+
+    let notification = RpcNotification {
+        jsonrpc: "2.0".to_string(),
+        method: "event".to_string(),
+        params: event.clone(),
+    };
+
+    let line = ::serde_json::to_string(&notification).unwrap();
+    let value: Value = ::serde_json::from_str(&line).unwrap();
+
+    assert!(value["params"]["event"] == "progress");
+    assert!(value.get("id").is_none());
+
+    let parsed: DaemonEvent = ::serde_json::from_value(value["params"].clone()).unwrap();
+    assert!(parsed == event);
+    assert!(parsed.session_name() == "session");
+
+    let request: RpcRequest = ::serde_json::from_str(r#"{"jsonrpc": "2.0", "id": 3, "method": "sync", "params": {"folder_id": 7}}"#).unwrap();
+    assert!(request.id == Some(Value::from(3)));
+
+    let params: FolderParams = ::serde_json::from_value(request.params).unwrap();
+    assert!(params.folder_id == 7);
+
+    let request: RpcRequest = ::serde_json::from_str(r#"{"jsonrpc": "2.0", "method": "status"}"#).unwrap();
+    assert!(request.id.is_none());
+    assert!(request.params.is_null());
+
+    let restore: RestoreRequest = ::serde_json::from_str(r#"{"folder_id": 7, "destination": "/tmp/restore"}"#).unwrap();
+    assert!(restore.session_name.is_none());
+    assert!(restore.include.is_empty());
+    assert!(!restore.resume);
+
+    let response = RpcResponse { jsonrpc: "2.0".to_string(), id: Value::from(3), result: None, error: Some(RpcError { code: METHOD_NOT_FOUND, message: "unknown method".to_string() }) };
+    let value = ::serde_json::to_value(&response).unwrap();
+    assert!(value.get("result").is_none());
+    assert!(value["error"]["code"] == METHOD_NOT_FOUND);
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_control_test() {
+    use std::thread;
+    use mockapi::TestFolder;
+    use models::SyncSchedule;
+
+    let folder = TestFolder::new();
+
+    folder.write("notes.txt", b"first version");
+
+    let (_server, token, folder_id) = folder.serve();
+
+    let d_token = token.clone();
+    let d_main = folder.main.clone();
+    let d_hmac = folder.hmac.clone();
+    let d_tweak = folder.tweak.clone();
+
+    let daemon = thread::spawn(move || {
+        ::core::start_daemon(&d_token, &d_main, &d_hmac, &d_tweak, SyncSchedule::Daily, 512_000_000, &mut |_| {})
+    });
+
+    let mut client = None;
+
+    for _ in 0..100 {
+        client = DaemonClient::connect().expect("failed to connect to daemon");
+
+        if client.is_some() {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut client = client.expect("daemon did not start");
+
+    client.subscribe().expect("failed to subscribe");
+
+    let folders = client.list_folders().expect("failed to list folders");
+    assert!(folders.iter().any(|registered| registered.id == folder_id));
+
+    folder.write("notes.txt", b"second version");
+
+    let wait_for = |client: &mut DaemonClient, session_name: &str| {
+        loop {
+            match client.next_event().expect("failed to read event") {
+                DaemonEvent::Finished { session_name: ref name } if name == session_name => break,
+                DaemonEvent::Failed { session_name: ref name, ref message } if name == session_name => panic!("task failed: {}", message),
+                _ => {},
+            }
+        }
+    };
+
+    let task = client.sync(folder_id).expect("failed to queue sync");
+    assert!(task.folder_id == folder_id && !task.restore);
+
+    wait_for(&mut client, &task.session_name);
+
+    assert!(client.cancel(&task.session_name).is_err());
+
+    let destination = folder.destination();
+
+    let request = RestoreRequest {
+        folder_id: folder_id,
+        destination: destination.to_str().unwrap().to_string(),
+        session_name: Some(task.session_name.clone()),
+        include: Vec::new(),
+        exclude: Vec::new(),
+        resume: false,
+    };
+
+    let relative = RestoreRequest { destination: "destination".to_string(), ..request.clone() };
+    assert!(client.restore(&relative).is_err());
+
+    let restore = client.restore(&request).expect("failed to start restore");
+    assert!(restore.restore && restore.session_name == task.session_name);
+
+    wait_for(&mut client, &restore.session_name);
+
+    let mut contents = String::new();
+    fs::File::open(destination.join("notes.txt")).unwrap().read_to_string(&mut contents).unwrap();
+    assert!(contents == "second version");
+
+    let stats = client.cache_stats().expect("failed to read cache stats");
+    assert!(stats.limit == 512_000_000);
+
+    // a second daemon can't take over the socket
+    assert!(::core::start_daemon(&token, &folder.main, &folder.hmac, &folder.tweak, SyncSchedule::Daily, 512_000_000, &mut |_| {}).is_err());
+
+    client.stop().expect("failed to stop daemon");
+    daemon.join().unwrap().expect("daemon failed");
+
+    assert!(DaemonClient::connect().unwrap().is_none());
+}
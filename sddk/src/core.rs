@@ -1,8 +1,10 @@
 use std::str;
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::Arc;
 
 /// external crate imports
+use chrono::{DateTime, TimeZone};
 use simplelog::{Config as LogConfig, CombinedLogger, TermLogger, WriteLogger, SimpleLogger, SharedLogger};
 use log::LogLevelFilter;
 use log::LogLevel;
@@ -32,6 +34,8 @@ use UNIQUE_CLIENT_ID;
 use TOKEN;
 
 use session::{SyncSession};
+use backend::{StorageBackend, SafeDriveBackend};
+use restore::{RestoreFilter, ResumeKeys};
 
 use remotefs::RemoteFS;
 
@@ -51,6 +55,8 @@ pub use util::get_current_os;
 pub use cache::clean_cache;
 pub use cache::clear_cache;
 
+pub use daemon::stop_daemon;
+
 pub fn get_keychain_item(account: &str, service: ::keychain::KeychainService) -> Result<String, SDError> {
     let password = ::keychain::get_keychain_item(account, service)?;
 
@@ -89,6 +95,35 @@ pub fn get_app_directory(config: &Configuration) -> Result<PathBuf, String> {
     ::util::get_app_directory(config)
 }
 
+/// set the number of threads used to hash, compress and encrypt blocks during sync
+///
+/// A count of zero uses one thread per CPU, which is the default.
+pub fn set_sync_workers(count: usize) {
+    let mut w = ::SYNC_WORKERS.write();
+    *w = count;
+}
+
+pub fn get_sync_workers() -> usize {
+    let w = ::SYNC_WORKERS.read();
+
+    match *w {
+        0 => ::num_cpus::get(),
+        count => count,
+    }
+}
+
+/// set the number of blocks downloaded at the same time during restore, the default is 8
+pub fn set_restore_workers(count: usize) {
+    let mut w = ::RESTORE_WORKERS.write();
+    *w = if count == 0 { 1 } else { count };
+}
+
+pub fn get_restore_workers() -> usize {
+    let w = ::RESTORE_WORKERS.read();
+
+    *w
+}
+
 /// internal functions
 
 pub fn initialize<'a>(client_version: &'a str, desktop: bool, operating_system: &'a str, language_code: &'a str, config: Configuration, log_level: LogLevelFilter, local_storage_path: &Path) -> Result<(), SDError> {
@@ -242,10 +277,35 @@ pub fn login(unique_client_id: &str,
     }
 }
 
-pub fn start_daemon() -> Result<(), SDError> {
+/// watch registered folders and sync them when they change, until `stop_daemon` is called
+///
+/// Folders are also synced according to `schedule` when nothing changed, and the block cache is
+/// cleaned down to `cache_limit` bytes periodically. Problems with a single folder, including
+/// failed syncs, are reported through `issue` and don't stop the daemon.
+pub fn start_daemon<I>(token: &Token,
+                       main_key: &Key,
+                       hmac_key: &Key,
+                       tweak_key: &Key,
+                       schedule: SyncSchedule,
+                       cache_limit: u64,
+                       issue: &mut I) -> Result<(), SDError>
+    where I: FnMut(&str) {
+
+    let backend = Arc::new(SafeDriveBackend::new(token));
+
+    start_daemon_with_backend(backend, main_key, hmac_key, tweak_key, schedule, cache_limit, issue)
+}
 
+pub fn start_daemon_with_backend<I>(backend: Arc<StorageBackend>,
+                                    main_key: &Key,
+                                    hmac_key: &Key,
+                                    tweak_key: &Key,
+                                    schedule: SyncSchedule,
+                                    cache_limit: u64,
+                                    issue: &mut I) -> Result<(), SDError>
+    where I: FnMut(&str) {
 
-    Ok(())
+    ::daemon::run_daemon(backend, main_key, hmac_key, tweak_key, schedule, cache_limit, issue)
 }
 
 pub fn remove_software_client(token: &Token) -> Result<(), SDError> {
@@ -285,6 +345,12 @@ pub fn get_sftp_fingerprints() -> Result<Vec<SFTPFingerprint>, SDError> {
 }
 
 pub fn load_keys(token: &Token, recovery_phrase: Option<String>, store_recovery_key: &Fn(&str), issue: &Fn(&str)) -> Result<Keyset, SDError> {
+    let backend = SafeDriveBackend::new(token);
+
+    load_keys_with_backend(&backend, recovery_phrase, store_recovery_key, issue)
+}
+
+pub fn load_keys_with_backend(backend: &StorageBackend, recovery_phrase: Option<String>, store_recovery_key: &Fn(&str), issue: &Fn(&str)) -> Result<Keyset, SDError> {
     // generate new keys in all cases, the account *may* already have some stored, we only
     // find out for sure while trying to store them.
     //
@@ -309,7 +375,7 @@ pub fn load_keys(token: &Token, recovery_phrase: Option<String>, store_recovery_
     };
 
 
-    match account_key(token, &new_wrapped_keyset) {
+    match backend.account_key(&new_wrapped_keyset) {
         Ok(real_wrapped_keyset) => {
             /// now we check to see if the keys returned by the server match the existing phrase or not
 
@@ -425,9 +491,37 @@ pub fn update_sync_folder(token: &Token,
 pub fn remove_sync_folder(token: &Token,
                           folder_id: u64) -> Result<(), SDError> {
     match delete_folder(token, folder_id) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(SDError::from(e)),
+        Ok(()) => {},
+        Err(e) => return Err(SDError::from(e)),
+    }
+
+    // the folder is gone, so there's nothing left for the file index to speed up
+    if let Err(e) = ::fileindex::remove_index(folder_id) {
+        warn!("failed to remove file index for folder {}: {}", folder_id, e);
+    }
+
+    if let Err(e) = ::ignore::remove_rules(folder_id) {
+        warn!("failed to remove ignore rules for folder {}: {}", folder_id, e);
     }
+
+    Ok(())
+}
+
+/// forget what was recorded about the files in a folder, so the next sync reads every file again
+pub fn reset_sync_index(folder_id: u64) -> Result<(), SDError> {
+    ::fileindex::remove_index(folder_id)
+}
+
+/// the ignore rules stored for a folder, not including the patterns in its `.sdignore` file
+pub fn get_ignore_rules(folder_id: u64) -> Result<IgnoreRules, SDError> {
+    ::ignore::load_rules(folder_id)
+}
+
+/// replace the ignore rules for a folder, they apply from the next sync on
+///
+/// See `IgnoreMatcher` for the pattern syntax. Invalid patterns are rejected.
+pub fn set_ignore_rules(folder_id: u64, rules: &IgnoreRules) -> Result<(), SDError> {
+    ::ignore::save_rules(folder_id, rules)
 }
 
 pub fn get_sync_folders(token: &Token) -> Result<Vec<RegisteredFolder>, SDError> {
@@ -469,95 +563,203 @@ pub fn get_sync_sessions(token: &Token) -> Result<Vec<SyncSession>, SDError> {
     Ok(v)
 }
 
-pub fn remove_sync_session(token: &Token,
-                           session_id: u64) -> Result<(), SDError> {
-    match delete_session(token, session_id) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(SDError::from(e)),
-    }
+/// list the files, directories and links recorded in a sync session, without restoring it
+pub fn list_session_entries(token: &Token,
+                            folder_id: u64,
+                            session_name: &str,
+                            main_key: &Key) -> Result<Vec<SessionEntry>, SDError> {
+    let backend = SafeDriveBackend::new(token);
+
+    list_session_entries_with_backend(&backend, folder_id, session_name, main_key)
 }
 
-pub fn clean_sync_sessions(token: &Token, schedule: SyncCleaningSchedule) -> Result<(), SDError> {
-    use ::chrono::{Local, Utc, Timelike};
+pub fn list_session_entries_with_backend(backend: &StorageBackend,
+                                         folder_id: u64,
+                                         session_name: &str,
+                                         main_key: &Key) -> Result<Vec<SessionEntry>, SDError> {
+    let session = ::browse::read_session(backend, folder_id, session_name, main_key)?;
 
-    let utc_time = Utc::now();
-    let local_time = utc_time.with_timezone(&Local);
-    let midnight = local_time.with_hour(0).unwrap().with_minute(0).unwrap().with_second(0).unwrap().with_nanosecond(0).unwrap();
+    ::browse::session_entries(&session)
+}
 
-    match schedule {
-        SyncCleaningSchedule::Auto => {
+/// the ignore rules a session was created with, including the patterns from the folder's
+/// `.sdignore` file at the time, or `None` for sessions that don't record them
+pub fn get_session_ignore_rules(token: &Token,
+                                folder_id: u64,
+                                session_name: &str,
+                                main_key: &Key) -> Result<Option<IgnoreRules>, SDError> {
+    let backend = SafeDriveBackend::new(token);
 
-            Err(SDError::Internal("not implemented".to_string()))
+    get_session_ignore_rules_with_backend(&backend, folder_id, session_name, main_key)
+}
 
-        },
-        SyncCleaningSchedule::ExactDateRFC3339 { date } => {
-            let date = match ::chrono::DateTime::parse_from_rfc3339(&date) {
-                Ok(dt) => dt,
-                Err(e) => {
-                    return Err(SDError::Internal(format!("{}", e)));
-                },
-            };
+pub fn get_session_ignore_rules_with_backend(backend: &StorageBackend,
+                                             folder_id: u64,
+                                             session_name: &str,
+                                             main_key: &Key) -> Result<Option<IgnoreRules>, SDError> {
+    let session = ::browse::read_session(backend, folder_id, session_name, main_key)?;
 
-            remove_sync_sessions_before(token, ::util::timestamp_to_ms(date.timestamp(), date.timestamp_subsec_millis()))
+    ::browse::session_ignore_rules(&session)
+}
 
-        },
-        SyncCleaningSchedule::ExactDateRFC2822 { date } => {
-            let date = match ::chrono::DateTime::parse_from_rfc2822(&date) {
-                Ok(dt) => dt,
-                Err(e) => {
-                    return Err(SDError::Internal(format!("{}", e)));
-                },
-            };
+/// compare two sessions of the same folder, `session_a` being the older one
+pub fn diff_sessions(token: &Token,
+                     folder_id: u64,
+                     session_a: &str,
+                     session_b: &str,
+                     main_key: &Key) -> Result<SessionDiff, SDError> {
+    let backend = SafeDriveBackend::new(token);
 
-            remove_sync_sessions_before(token, ::util::timestamp_to_ms(date.timestamp(), date.timestamp_subsec_millis()))
+    diff_sessions_with_backend(&backend, folder_id, session_a, session_b, main_key)
+}
 
-        },
-        SyncCleaningSchedule::All => {
+pub fn diff_sessions_with_backend(backend: &StorageBackend,
+                                  folder_id: u64,
+                                  session_a: &str,
+                                  session_b: &str,
+                                  main_key: &Key) -> Result<SessionDiff, SDError> {
+    let a = ::browse::read_session(backend, folder_id, session_a, main_key)?;
+    let b = ::browse::read_session(backend, folder_id, session_b, main_key)?;
 
-            remove_sync_sessions_before(token, ::util::timestamp_to_ms(local_time.timestamp(), local_time.timestamp_subsec_millis()))
+    ::browse::diff_sessions(&a, &b)
+}
 
-        },
-        SyncCleaningSchedule::BeforeToday => {
+/// mount every session of every folder read-only at `mountpoint`, see `SessionFS` for the
+/// layout. Blocks until the filesystem is unmounted.
+#[cfg(feature = "sessionfs")]
+pub fn mount_sessions(token: &Token,
+                      main_key: &Key,
+                      mountpoint: &Path) -> Result<(), SDError> {
+    let backend = Arc::new(SafeDriveBackend::new(token));
 
-            remove_sync_sessions_before(token, ::util::timestamp_to_ms(midnight.timestamp(), midnight.timestamp_subsec_millis()))
+    mount_sessions_with_backend(backend, main_key, mountpoint)
+}
 
-        },
-        SyncCleaningSchedule::BeforeThisWeek => {
+#[cfg(feature = "sessionfs")]
+pub fn mount_sessions_with_backend(backend: Arc<StorageBackend>,
+                                   main_key: &Key,
+                                   mountpoint: &Path) -> Result<(), SDError> {
+    let fs = ::sessionfs::SessionFS::new(backend, main_key)?;
 
-            Err(SDError::Internal("not implemented".to_string()))
+    fs.mount(mountpoint)
+}
 
-        },
-        SyncCleaningSchedule::BeforeThisMonth => {
+/// serve the local web UI on `port` until the process exits, `ready` is given the address to
+/// open in a browser to log in to it, see `::webui::serve`
+#[cfg(feature = "webui")]
+pub fn start_webui<R>(token: &Token,
+                      keyset: &Keyset,
+                      port: u16,
+                      ready: &mut R) -> Result<(), SDError>
+    where R: FnMut(&str) {
 
-            Err(SDError::Internal("not implemented".to_string()))
+    ::webui::serve(token, keyset, port, ready)
+}
 
-        },
-        SyncCleaningSchedule::BeforeThisYear => {
+pub fn remove_sync_session(token: &Token,
+                           session_id: u64) -> Result<(), SDError> {
+    match delete_session(token, session_id) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(SDError::from(e)),
+    }
+}
 
-            Err(SDError::Internal("not implemented".to_string()))
+pub fn clean_sync_sessions(token: &Token, schedule: SyncCleaningSchedule) -> Result<(), SDError> {
+    let now = ::chrono::Local::now();
 
-        },
-        SyncCleaningSchedule::OneDay => {
+    clean_sync_sessions_at(token, schedule, &now, false).map(|_| ())
+}
 
-            Err(SDError::Internal("not implemented".to_string()))
+/// clean sync sessions according to a schedule, as of `now`
+///
+/// Returns the sessions that were removed. When `dry_run` is set nothing is removed, and the
+/// sessions that would have been are returned instead.
+pub fn clean_sync_sessions_at<Tz: TimeZone + 'static>(token: &Token,
+                                                      schedule: SyncCleaningSchedule,
+                                                      now: &DateTime<Tz>,
+                                                      dry_run: bool) -> Result<Vec<SyncSession>, SDError> {
+    let sessions = get_sync_sessions(token)?;
 
-        },
-        SyncCleaningSchedule::OneWeek => {
+    let remove = ::retention::clean_plan(&schedule, &sessions, now)?;
 
-            Err(SDError::Internal("not implemented".to_string()))
+    if dry_run {
+        return Ok(remove);
+    }
 
+    match ::retention::clean_before(&schedule, now)? {
+        Some(cutoff) => {
+            // the server can remove everything before a date in one request
+            remove_sync_sessions_before(token, cutoff)?;
         },
-        SyncCleaningSchedule::OneMonth => {
+        None => {
+            for session in &remove {
+                let id = match session.id {
+                    Some(id) => id,
+                    None => continue,
+                };
 
-            Err(SDError::Internal("not implemented".to_string()))
+                debug!("cleaning sync session {} ({})", session.name, id);
 
+                remove_sync_session(token, id)?;
+            }
         },
-        SyncCleaningSchedule::OneYear => {
+    }
 
-            Err(SDError::Internal("not implemented".to_string()))
+    Ok(remove)
+}
 
-        },
-    }
+/// remove the sessions of every folder that the `Auto` cleaning schedule doesn't keep, as of `now`
+///
+/// Returns the sessions that were removed. When `dry_run` is set nothing is removed, and the
+/// sessions that would have been are returned instead.
+pub fn auto_clean_sync_sessions<Tz: TimeZone + 'static>(token: &Token,
+                                                        now: &DateTime<Tz>,
+                                                        dry_run: bool) -> Result<Vec<SyncSession>, SDError> {
+    clean_sync_sessions_at(token, SyncCleaningSchedule::Auto, now, dry_run)
+}
+
+/// check that a sync session can be restored, see `verify::verify_session` for the checks done
+pub fn verify_session(token: &Token,
+                      folder_id: u64,
+                      session_name: &str,
+                      main_key: &Key,
+                      hmac_key: &Key,
+                      deep: bool) -> Result<SessionVerification, SDError> {
+    let backend = SafeDriveBackend::new(token);
+
+    verify_session_with_backend(&backend, folder_id, session_name, main_key, hmac_key, deep)
+}
+
+pub fn verify_session_with_backend(backend: &StorageBackend,
+                                   folder_id: u64,
+                                   session_name: &str,
+                                   main_key: &Key,
+                                   hmac_key: &Key,
+                                   deep: bool) -> Result<SessionVerification, SDError> {
+    ::verify::verify_session(backend, folder_id, session_name, main_key, hmac_key, deep)
+}
+
+/// remove blocks that no remaining sync session uses, see `gc::collect_garbage` for the rules
+///
+/// Garbage collection isn't supported for SafeDrive storage, the API has no way to list or remove
+/// blocks. For an account only the local block cache is collected, and the `storage` part of the
+/// report is `None`. Storage is only collected through `collect_garbage_with_backend` with a
+/// backend that can list its blocks, such as `LocalBackend`.
+pub fn collect_garbage(token: &Token,
+                       main_key: &Key,
+                       dry_run: bool) -> Result<GarbageReport, SDError> {
+    let backend = SafeDriveBackend::new(token);
+
+    collect_garbage_with_backend(&backend, main_key, dry_run)
+}
+
+pub fn collect_garbage_with_backend(backend: &StorageBackend,
+                                    main_key: &Key,
+                                    dry_run: bool) -> Result<GarbageReport, SDError> {
+    let now = ::chrono::Utc::now();
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64;
+
+    ::gc::collect_garbage(backend, main_key, dry_run, now_ms)
 }
 
 pub fn remove_sync_sessions_before(token: &Token,
@@ -637,7 +839,16 @@ pub fn remote_mv(host: &str, port: u16, remote_path: &Path, new_path: &Path, use
 
 
 
-pub fn sync<B, I, P>(token: &Token,
+/// the name of a sync session for a folder that was cancelled or interrupted before it finished
+///
+/// Syncing the folder again with this session name resumes the session, skipping blocks that
+/// were already stored and files that were already completely uploaded. Syncing with any other
+/// name starts over.
+pub fn interrupted_sync_session(folder_id: u64) -> Option<String> {
+    ::journal::interrupted_session(folder_id)
+}
+
+pub fn sync<B, E, I, P>(token: &Token,
                      session_name: &str,
                      main_key: &Key,
                      hmac_key: &Key,
@@ -645,10 +856,30 @@ pub fn sync<B, I, P>(token: &Token,
                      folder_id: u64,
                      progress: &mut P,
                      bandwidth: &mut B,
-                     issue: &mut I) -> Result<(), SDError>
-    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str) {
+                     issue: &mut I,
+                     event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let backend = Arc::new(SafeDriveBackend::new(token));
+
+    sync_with_backend(backend, session_name, main_key, hmac_key, tweak_key, folder_id, progress, bandwidth, issue, event)
+}
+
+pub fn sync_with_backend<B, E, I, P>(backend: Arc<StorageBackend>,
+                                  session_name: &str,
+                                  main_key: &Key,
+                                  hmac_key: &Key,
+                                  tweak_key: &Key,
+                                  folder_id: u64,
+                                  progress: &mut P,
+                                  bandwidth: &mut B,
+                                  issue: &mut I,
+                                  event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let sync_status_receive = ::sync::sync(backend, ::SYNC_VERSION, session_name, main_key, hmac_key, tweak_key, folder_id);
 
-    let sync_status_receive = ::sync::sync(token, session_name, main_key, hmac_key, tweak_key, folder_id);
+    let mut summary = SyncSummary::default();
 
     loop {
         match sync_status_receive.recv() {
@@ -667,6 +898,10 @@ pub fn sync<B, I, P>(token: &Token,
                     SyncStatus::Bandwidth(speed) => {
                         bandwidth(speed);
                     },
+                    SyncStatus::Event(e) => {
+                        summary.record(&e);
+                        event(&e);
+                    },
                     SyncStatus::Err(err) => return Err(err),
                 };
             },
@@ -678,11 +913,13 @@ pub fn sync<B, I, P>(token: &Token,
         };
     }
 
+    event(&SyncEvent::Summary(summary));
+
     Ok(())
 }
 
 
-pub fn restore<B, I, P>(token: &Token,
+pub fn restore<B, E, I, P>(token: &Token,
                         session_name: &str,
                         main_key: &Key,
                         folder_id: u64,
@@ -690,10 +927,143 @@ pub fn restore<B, I, P>(token: &Token,
                         session_size: u64,
                         progress: &mut P,
                         bandwidth: &mut B,
-                        issue: &mut I) -> Result<(), SDError>
-    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str) {
+                        issue: &mut I,
+                        event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let backend = Arc::new(SafeDriveBackend::new(token));
+
+    restore_with_backend(backend, session_name, main_key, folder_id, destination, session_size, progress, bandwidth, issue, event)
+}
+
+pub fn restore_with_backend<B, E, I, P>(backend: Arc<StorageBackend>,
+                                     session_name: &str,
+                                     main_key: &Key,
+                                     folder_id: u64,
+                                     destination: PathBuf,
+                                     session_size: u64,
+                                     progress: &mut P,
+                                     bandwidth: &mut B,
+                                     issue: &mut I,
+                                     event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    restore_filtered(backend, session_name, main_key, folder_id, destination, session_size, RestoreFilter::all(), None, progress, bandwidth, issue, event)
+}
 
-    let sync_status_receive = ::restore::restore(token, session_name, main_key, folder_id, destination, session_size);
+/// restore only the entries of a session matching the `include` globs and none of the `exclude`
+/// globs, see `RestoreFilter` for how they are matched
+///
+/// Only the blocks needed by the matching entries are downloaded.
+pub fn restore_paths<B, E, I, P>(token: &Token,
+                              session_name: &str,
+                              main_key: &Key,
+                              folder_id: u64,
+                              destination: PathBuf,
+                              session_size: u64,
+                              include: &[&str],
+                              exclude: &[&str],
+                              progress: &mut P,
+                              bandwidth: &mut B,
+                              issue: &mut I,
+                              event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let backend = Arc::new(SafeDriveBackend::new(token));
+
+    restore_paths_with_backend(backend, session_name, main_key, folder_id, destination, session_size, include, exclude, progress, bandwidth, issue, event)
+}
+
+pub fn restore_paths_with_backend<B, E, I, P>(backend: Arc<StorageBackend>,
+                                           session_name: &str,
+                                           main_key: &Key,
+                                           folder_id: u64,
+                                           destination: PathBuf,
+                                           session_size: u64,
+                                           include: &[&str],
+                                           exclude: &[&str],
+                                           progress: &mut P,
+                                           bandwidth: &mut B,
+                                           issue: &mut I,
+                                           event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let filter = RestoreFilter::new(include, exclude)?;
+
+    restore_filtered(backend, session_name, main_key, folder_id, destination, session_size, filter, None, progress, bandwidth, issue, event)
+}
+
+/// restore a session into a destination that may already hold some of it, like one where an
+/// earlier restore was interrupted
+///
+/// Each file already in the destination is chunked with `hmac_key` and `tweak_key` and compared
+/// with the session entry. Files that match are left alone, and in the others only the blocks
+/// that differ are downloaded and written in place, so most of the work done before is kept.
+/// The `include` and `exclude` globs work the same way as in `restore_paths`.
+pub fn restore_resume<B, E, I, P>(token: &Token,
+                               session_name: &str,
+                               main_key: &Key,
+                               hmac_key: &Key,
+                               tweak_key: &Key,
+                               folder_id: u64,
+                               destination: PathBuf,
+                               session_size: u64,
+                               include: &[&str],
+                               exclude: &[&str],
+                               progress: &mut P,
+                               bandwidth: &mut B,
+                               issue: &mut I,
+                               event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let backend = Arc::new(SafeDriveBackend::new(token));
+
+    restore_resume_with_backend(backend, session_name, main_key, hmac_key, tweak_key, folder_id, destination, session_size, include, exclude, progress, bandwidth, issue, event)
+}
+
+pub fn restore_resume_with_backend<B, E, I, P>(backend: Arc<StorageBackend>,
+                                            session_name: &str,
+                                            main_key: &Key,
+                                            hmac_key: &Key,
+                                            tweak_key: &Key,
+                                            folder_id: u64,
+                                            destination: PathBuf,
+                                            session_size: u64,
+                                            include: &[&str],
+                                            exclude: &[&str],
+                                            progress: &mut P,
+                                            bandwidth: &mut B,
+                                            issue: &mut I,
+                                            event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let filter = RestoreFilter::new(include, exclude)?;
+
+    let resume = ResumeKeys {
+        hmac_key: hmac_key.clone(),
+        tweak_key: tweak_key.clone(),
+    };
+
+    restore_filtered(backend, session_name, main_key, folder_id, destination, session_size, filter, Some(resume), progress, bandwidth, issue, event)
+}
+
+fn restore_filtered<B, E, I, P>(backend: Arc<StorageBackend>,
+                             session_name: &str,
+                             main_key: &Key,
+                             folder_id: u64,
+                             destination: PathBuf,
+                             session_size: u64,
+                             filter: RestoreFilter,
+                             resume: Option<ResumeKeys>,
+                             progress: &mut P,
+                             bandwidth: &mut B,
+                             issue: &mut I,
+                             event: &mut E) -> Result<(), SDError>
+    where P: FnMut(u64, u64, u64), B: FnMut(u64), I: FnMut(&str), E: FnMut(&SyncEvent) {
+
+    let sync_status_receive = ::restore::restore(backend, session_name, main_key, folder_id, destination, session_size, filter, resume);
+
+    let mut summary = SyncSummary::default();
 
     loop {
         match sync_status_receive.recv() {
@@ -712,6 +1082,10 @@ pub fn restore<B, I, P>(token: &Token,
                     SyncStatus::Bandwidth(speed) => {
                         bandwidth(speed);
                     },
+                    SyncStatus::Event(e) => {
+                        summary.record(&e);
+                        event(&e);
+                    },
                     SyncStatus::Err(err) => return Err(err),
                 };
             },
@@ -723,5 +1097,7 @@ pub fn restore<B, I, P>(token: &Token,
         };
     }
 
+    event(&SyncEvent::Summary(summary));
+
     Ok(())
 }
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::time::{Duration, Instant};
+
+// internal imports
+
+use backend::StorageBackend;
+use control::ControlServer;
+use error::SDError;
+use ignore::IgnoreMatcher;
+use keys::Key;
+use models::{DaemonEvent, DaemonTask, RegisteredFolder, SyncSchedule};
+use watch::FolderWatcher;
+
+/// a folder is synced once no change has been seen in it for this long
+pub const DAEMON_DEBOUNCE_SECS: u64 = 10;
+
+/// a folder that keeps changing is synced anyway once its first unsynced change is this old
+pub const DAEMON_MAX_DELAY_SECS: u64 = 10 * 60;
+
+/// after a sync fails, the folder isn't tried again for this long
+pub const DAEMON_RETRY_DELAY_SECS: u64 = 5 * 60;
+
+/// how often the list of registered folders is read again, to pick up added and removed folders
+pub const DAEMON_FOLDER_REFRESH_SECS: u64 = 5 * 60;
+
+/// how often the block cache is cleaned down to its limit
+pub const DAEMON_CACHE_CLEAN_SECS: u64 = 60 * 60;
+
+static DAEMON_STOP: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[derive(Debug)]
+struct FolderState {
+    folder: RegisteredFolder,
+    // the first and the latest change seen since the folder was last synced
+    first_change: Option<Instant>,
+    last_change: Option<Instant>,
+    // set after a failed sync, the folder isn't synced again before then
+    retry_at: Option<Instant>,
+    // when the folder is synced even if nothing changed, in ms since the epoch
+    scheduled_ms: u64,
+}
+
+impl FolderState {
+    fn new(folder: RegisteredFolder, scheduled_ms: u64) -> FolderState {
+        FolderState {
+            folder: folder,
+            first_change: None,
+            last_change: None,
+            retry_at: None,
+            scheduled_ms: scheduled_ms,
+        }
+    }
+
+    fn changed(&mut self, now: Instant) {
+        if self.first_change.is_none() {
+            self.first_change = Some(now);
+        }
+
+        self.last_change = Some(now);
+    }
+
+    fn is_due(&self, now: Instant, now_ms: u64) -> bool {
+        if let Some(retry_at) = self.retry_at {
+            if now < retry_at {
+                return false;
+            }
+        }
+
+        if now_ms >= self.scheduled_ms {
+            return true;
+        }
+
+        match (self.first_change, self.last_change) {
+            (Some(first), Some(last)) => {
+                now.duration_since(last) >= Duration::from_secs(DAEMON_DEBOUNCE_SECS) || now.duration_since(first) >= Duration::from_secs(DAEMON_MAX_DELAY_SECS)
+            },
+            _ => false,
+        }
+    }
+
+    fn synced(&mut self, now_ms: u64, schedule: SyncSchedule) {
+        self.first_change = None;
+        self.last_change = None;
+        self.retry_at = None;
+        self.scheduled_ms = now_ms + duration_ms(schedule.interval());
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.retry_at = Some(now + Duration::from_secs(DAEMON_RETRY_DELAY_SECS));
+    }
+}
+
+/// sync folders when they change, until `stop_daemon` is called
+///
+/// Every registered folder that is encrypted and has syncing turned on is watched for changes,
+/// and synced once it has been quiet for `DAEMON_DEBOUNCE_SECS`. A folder that keeps changing is
+/// synced no later than `DAEMON_MAX_DELAY_SECS` after its first unsynced change. Folders are also
+/// synced when `schedule` says they're due, even if no change was seen, counting from their most
+/// recent session. Changes made while the daemon wasn't running are picked up that way, and an
+/// interrupted session is resumed shortly after the daemon starts.
+///
+/// One folder is synced at a time. Changes made during a sync are synced again afterwards, since
+/// the session may or may not include them.
+///
+/// The block cache is cleaned down to `cache_limit` bytes every `DAEMON_CACHE_CLEAN_SECS`.
+///
+/// Other processes can control the daemon through its control socket, see `ControlServer`.
+/// Syncs they ask for run between the ones the daemon starts itself, and every sync the daemon
+/// runs is reported to them as `DaemonEvent`s.
+///
+/// Failed syncs and anything else that goes wrong with a single folder are reported through
+/// `issue` and the daemon keeps running. It only returns an error if changes can't be watched
+/// at all, or if another daemon is already running.
+pub fn run_daemon<I>(backend: Arc<StorageBackend>,
+                     main_key: &Key,
+                     hmac_key: &Key,
+                     tweak_key: &Key,
+                     schedule: SyncSchedule,
+                     cache_limit: u64,
+                     issue: &mut I) -> Result<(), SDError>
+    where I: FnMut(&str) {
+
+    DAEMON_STOP.store(false, Ordering::SeqCst);
+
+    let mut watcher = FolderWatcher::new()?;
+    let control = ControlServer::start(backend.clone(), main_key, hmac_key, tweak_key, cache_limit)?;
+
+    let mut folders: HashMap<u64, FolderState> = HashMap::new();
+
+    let mut next_refresh = Instant::now();
+    let mut next_clean = Instant::now() + Duration::from_secs(DAEMON_CACHE_CLEAN_SECS);
+
+    info!("daemon started, syncing folders at least {}", schedule);
+
+    loop {
+        if DAEMON_STOP.load(Ordering::SeqCst) {
+            info!("daemon stopped");
+
+            return Ok(());
+        }
+
+        if Instant::now() >= next_refresh {
+            match backend.read_folders() {
+                Ok(registered) => refresh_folders(&*backend, registered, &mut watcher, &mut folders, schedule, issue),
+                Err(e) => issue(&format!("not able to read registered folders: {}", e)),
+            }
+
+            next_refresh = Instant::now() + Duration::from_secs(DAEMON_FOLDER_REFRESH_SECS);
+        }
+
+        let now = Instant::now();
+
+        for folder_id in watcher.wait(Duration::from_secs(1))? {
+            if let Some(state) = folders.get_mut(&folder_id) {
+                state.changed(now);
+            }
+        }
+
+        for task in control.take_sync_requests() {
+            let folder = match folders.get(&task.folder_id) {
+                Some(state) => Ok(state.folder.clone()),
+                None => ::backend::find_folder(&*backend, task.folder_id).map_err(SDError::from),
+            };
+
+            let result = match folder {
+                Ok(folder) => sync_folder(backend.clone(), main_key, hmac_key, tweak_key, &folder, &task.session_name, &control, issue),
+                Err(e) => {
+                    control.publish(&DaemonEvent::Failed { session_name: task.session_name.clone(), message: format!("{}", e) });
+
+                    Err(e)
+                },
+            };
+
+            match result {
+                Ok(()) => {
+                    if let Some(state) = folders.get_mut(&task.folder_id) {
+                        state.synced(current_time_ms(), schedule);
+                    }
+                },
+                Err(e) => {
+                    issue(&format!("folder {}: requested sync failed: {}", task.folder_id, e));
+                },
+            }
+        }
+
+        let now = Instant::now();
+        let now_ms = current_time_ms();
+
+        let mut due: Vec<u64> = folders.values().filter(|state| state.is_due(now, now_ms)).map(|state| state.folder.id).collect();
+        due.sort();
+
+        for folder_id in due {
+            if DAEMON_STOP.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let state = match folders.get_mut(&folder_id) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            // finish an interrupted session rather than starting over
+            let session_name = match ::journal::interrupted_session(folder_id) {
+                Some(name) => name,
+                None => ::uuid::Uuid::new_v4().hyphenated().to_string(),
+            };
+
+            match sync_folder(backend.clone(), main_key, hmac_key, tweak_key, &state.folder, &session_name, &control, issue) {
+                Ok(()) => {
+                    state.synced(current_time_ms(), schedule);
+                },
+                Err(e) => {
+                    issue(&format!("{}: sync failed: {}", state.folder.folderName, e));
+
+                    state.failed(Instant::now());
+                },
+            }
+        }
+
+        if Instant::now() >= next_clean {
+            match ::cache::clean_cache(cache_limit) {
+                Ok(deleted) => debug!("daemon cleaned {} from the cache", ::util::pretty_bytes(deleted as f64)),
+                Err(e) => issue(&format!("not able to clean cache: {}", e)),
+            }
+
+            next_clean = Instant::now() + Duration::from_secs(DAEMON_CACHE_CLEAN_SECS);
+        }
+    }
+}
+
+/// ask a running daemon to stop
+///
+/// A sync that is already running is finished first.
+pub fn stop_daemon() {
+    DAEMON_STOP.store(true, Ordering::SeqCst);
+}
+
+// start watching newly registered folders and stop watching removed ones
+fn refresh_folders<I>(backend: &StorageBackend,
+                      registered: Vec<RegisteredFolder>,
+                      watcher: &mut FolderWatcher,
+                      folders: &mut HashMap<u64, FolderState>,
+                      schedule: SyncSchedule,
+                      issue: &mut I) where I: FnMut(&str) {
+
+    // unencrypted folders are not synced by sddk
+    let registered: Vec<RegisteredFolder> = registered.into_iter().filter(|f| f.syncing && f.encrypted).collect();
+
+    let removed: Vec<u64> = folders.keys().filter(|id| !registered.iter().any(|f| f.id == **id)).cloned().collect();
+
+    for folder_id in removed {
+        debug!("no longer watching folder {}", folder_id);
+
+        watcher.unwatch_folder(folder_id);
+        folders.remove(&folder_id);
+    }
+
+    let mut last_sessions: Option<HashMap<u64, u64>> = None;
+
+    for folder in registered {
+        let path_changed = if let Some(state) = folders.get_mut(&folder.id) {
+            let path_changed = state.folder.folderPath != folder.folderPath;
+
+            state.folder = folder.clone();
+
+            Some(path_changed)
+        } else {
+            None
+        };
+
+        let path_changed = match path_changed {
+            Some(path_changed) => path_changed,
+            None => {
+                if last_sessions.is_none() {
+                    last_sessions = Some(last_session_times(backend, issue));
+                }
+
+                let interval = duration_ms(schedule.interval());
+
+                let scheduled_ms = match last_sessions.as_ref().and_then(|times| times.get(&folder.id)) {
+                    Some(time) => time + interval,
+                    None => 0,
+                };
+
+                let mut state = FolderState::new(folder.clone(), scheduled_ms);
+
+                if ::journal::interrupted_session(folder.id).is_some() {
+                    state.changed(Instant::now());
+                }
+
+                folders.insert(folder.id, state);
+
+                true
+            },
+        };
+
+        if path_changed {
+            let folder_path = PathBuf::from(&folder.folderPath);
+
+            match watch(watcher, folder.id, &folder_path) {
+                Ok(()) => debug!("watching folder {} at {}", folder.id, folder_path.display()),
+                Err(e) => issue(&format!("{}: not able to watch for changes, it will only be synced {}: {}", folder.folderName, schedule, e)),
+            }
+        }
+    }
+}
+
+fn watch(watcher: &mut FolderWatcher, folder_id: u64, folder_path: &Path) -> Result<(), SDError> {
+    let ignore = IgnoreMatcher::for_folder(folder_id, folder_path)?;
+
+    watcher.watch_folder(folder_id, folder_path, ignore)
+}
+
+// the time of the most recent session of each folder, in ms since the epoch
+fn last_session_times<I>(backend: &StorageBackend, issue: &mut I) -> HashMap<u64, u64> where I: FnMut(&str) {
+    let mut times = HashMap::new();
+
+    let mut sessions = match backend.read_sessions() {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            issue(&format!("not able to read sync sessions: {}", e));
+
+            return times;
+        },
+    };
+
+    if let Some(details) = sessions.remove("sessionDetails") {
+        for (folder_id, folder_sessions) in details {
+            if let Some(time) = folder_sessions.iter().filter_map(|session| session.time).max() {
+                times.insert(folder_id, time);
+            }
+        }
+    }
+
+    times
+}
+
+fn sync_folder<I>(backend: Arc<StorageBackend>,
+                  main_key: &Key,
+                  hmac_key: &Key,
+                  tweak_key: &Key,
+                  folder: &RegisteredFolder,
+                  session_name: &str,
+                  control: &ControlServer,
+                  issue: &mut I) -> Result<(), SDError> where I: FnMut(&str) {
+
+    info!("daemon syncing {} as session {}", folder.folderName, session_name);
+
+    let task = DaemonTask {
+        folder_id: folder.id,
+        session_name: session_name.to_string(),
+        restore: false,
+    };
+
+    control.begin_task(&task);
+
+    let result = ::core::sync_with_backend(backend,
+                                           session_name,
+                                           main_key,
+                                           hmac_key,
+                                           tweak_key,
+                                           folder.id,
+                                           &mut |total, current, new| {
+                                               control.publish(&DaemonEvent::Progress { session_name: session_name.to_string(), total: total, current: current, new: new });
+                                           },
+                                           &mut |speed| {
+                                               control.publish(&DaemonEvent::Bandwidth { session_name: session_name.to_string(), speed: speed });
+                                           },
+                                           &mut |message| {
+                                               control.publish(&DaemonEvent::Issue { session_name: session_name.to_string(), message: message.to_string() });
+
+                                               issue(&format!("{}: {}", folder.folderName, message));
+                                           },
+                                           &mut |_| {});
+
+    control.end_task(session_name, &result);
+
+    result
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+fn current_time_ms() -> u64 {
+    let now = ::chrono::Utc::now();
+
+    ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64
+}
+
+#[test]
+fn daemon_schedule_test() {
+    let folder = RegisteredFolder {
+        id: 1,
+        folderName: "test".to_string(),
+        folderPath: "/tmp/test".to_string(),
+        addedDate: 0,
+        encrypted: true,
+        syncing: true,
+    };
+
+    let start = Instant::now();
+    let start_ms: u64 = 1_000_000;
+
+    let mut state = FolderState::new(folder, start_ms + duration_ms(SyncSchedule::Hourly.interval()));
+
+    assert!(!state.is_due(start, start_ms));
+
+    // debounced until the folder is quiet
+    state.changed(start);
+    assert!(!state.is_due(start + Duration::from_secs(5), start_ms));
+    state.changed(start + Duration::from_secs(5));
+    assert!(!state.is_due(start + Duration::from_secs(14), start_ms));
+    assert!(state.is_due(start + Duration::from_secs(15), start_ms));
+
+    // a folder that never goes quiet is synced anyway
+    let mut t = start;
+    while t < start + Duration::from_secs(DAEMON_MAX_DELAY_SECS) {
+        state.changed(t);
+        assert!(!state.is_due(t, start_ms));
+        t += Duration::from_secs(5);
+    }
+    state.changed(t);
+    assert!(state.is_due(t, start_ms));
+
+    // failures wait before trying again
+    state.failed(t);
+    assert!(!state.is_due(t + Duration::from_secs(60), start_ms));
+    assert!(state.is_due(t + Duration::from_secs(DAEMON_RETRY_DELAY_SECS), start_ms));
+
+    // the schedule is an upper bound when nothing changes
+    state.synced(start_ms, SyncSchedule::Hourly);
+    assert!(!state.is_due(t, start_ms + 59 * 60 * 1000));
+    assert!(state.is_due(t, start_ms + 60 * 60 * 1000));
+
+    assert!(SyncSchedule::Daily.interval() == Duration::from_secs(24 * 60 * 60));
+}
@@ -211,6 +211,7 @@ pub enum SDError {
     ExceededRetries(u64),
     Cancelled,
     FolderMissing,
+    OpLogUnreadable,
 }
 
 impl std::error::Error for SDError {
@@ -239,6 +240,7 @@ impl std::error::Error for SDError {
             SDError::ExceededRetries(_) => localized_str!("exceeded retry count", ""),
             SDError::Cancelled => localized_str!("cancelled sync/restore", ""),
             SDError::FolderMissing => localized_str!("folder missing", ""),
+            SDError::OpLogUnreadable => localized_str!("operation log cannot be used", ""),
         }
     }
 
@@ -267,6 +269,7 @@ impl std::error::Error for SDError {
             SDError::ExceededRetries(_) => None,
             SDError::Cancelled => None,
             SDError::FolderMissing => None,
+            SDError::OpLogUnreadable => None,
         }
     }
 }
@@ -315,6 +318,7 @@ impl std::fmt::Display for SDError {
             },
             SDError::Cancelled => write!(f, "{}", localized_str!("Cancelled sync/restore", "")),
             SDError::FolderMissing => write!(f, "{}", localized_str!("Folder missing", "")),
+            SDError::OpLogUnreadable => write!(f, "{}", localized_str!("Operation log cannot be used", "")),
         }
     }
 }
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::{thread, time};
+
+// external crate imports
+
+use parking_lot::Mutex;
+use parking_lot_mpsc::{Receiver, Sender, SyncSender};
+use rand::distributions::{IndependentSample, Range};
+use rustc_serialize::hex::ToHex;
+
+// internal imports
+
+use backend::StorageBackend;
+use block::{Block, WrappedBlock};
+use error::{SDAPIError, SDError};
+use keys::Key;
+
+use sync_state::is_sync_task_cancelled;
+
+struct BlockRequest {
+    index: usize,
+    hmac: Vec<u8>,
+}
+
+struct BlockResponse {
+    index: usize,
+    result: Result<Block, SDError>,
+}
+
+/// Downloads the blocks needed by a restore ahead of time, using several threads at once
+///
+/// The fetcher is given the HMAC of every block in the session in the order they will be
+/// written, and keeps up to `window` of them in flight ahead of the block the restore is
+/// currently waiting on. Blocks are read from the cache when possible, otherwise they're
+/// downloaded, stored in the cache and unwrapped by the worker threads, so the restore
+/// thread only has to write them out in order.
+pub struct BlockFetcher {
+    hmacs: Vec<Vec<u8>>,
+    window: usize,
+    next_request: usize,
+    next_result: usize,
+    job_send: Option<SyncSender<BlockRequest>>,
+    result_receive: Receiver<BlockResponse>,
+    ready: HashMap<usize, Result<Block, SDError>>,
+}
+
+impl BlockFetcher {
+    /// start `worker_count` download threads for a list of block HMACs
+    pub fn new(backend: Arc<StorageBackend>, main_key: &Key, session_name: &str, hmacs: Vec<Vec<u8>>, worker_count: usize) -> BlockFetcher {
+        let worker_count = if worker_count == 0 { 1 } else { worker_count };
+
+        // enough blocks ahead to keep every worker busy while the restore thread writes
+        let window = worker_count * 4;
+
+        let (job_send, job_receive) = ::parking_lot_mpsc::sync_channel::<BlockRequest>(window);
+        let (result_send, result_receive) = ::parking_lot_mpsc::channel::<BlockResponse>();
+
+        let job_receive = Arc::new(Mutex::new(job_receive));
+
+        debug!("starting {} block download workers", worker_count);
+
+        for _ in 0..worker_count {
+            let job_receive = job_receive.clone();
+            let result_send: Sender<BlockResponse> = result_send.clone();
+            let backend = backend.clone();
+            let main_key = main_key.clone();
+            let session_name = session_name.to_string();
+
+            // workers exit once the job channel closes, we don't wait for them because one may
+            // be backing off after a failed request
+            thread::spawn(move || {
+                loop {
+                    let request = {
+                        let receiver = job_receive.lock();
+
+                        match receiver.recv() {
+                            Ok(request) => request,
+                            Err(_) => break,
+                        }
+                    };
+
+                    let response = BlockResponse {
+                        index: request.index,
+                        result: fetch_block(&*backend, &main_key, &session_name, &request.hmac),
+                    };
+
+                    if result_send.send(response).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        BlockFetcher {
+            hmacs: hmacs,
+            window: window,
+            next_request: 0,
+            next_result: 0,
+            job_send: Some(job_send),
+            result_receive: result_receive,
+            ready: HashMap::new(),
+        }
+    }
+
+    /// wait for the block at `index` in the list, which must have the given HMAC
+    ///
+    /// Blocks must be requested in increasing order, any blocks before `index` that haven't
+    /// been requested yet are skipped.
+    pub fn get(&mut self, index: usize, hmac: &[u8]) -> Result<Block, SDError> {
+        if index < self.next_result || index >= self.hmacs.len() {
+            return Err(SDError::Internal(format!("block {} requested out of order", index)));
+        }
+
+        if self.hmacs[index].as_slice() != hmac {
+            return Err(SDError::Internal(format!("block {} does not match the session", hmac.to_hex())));
+        }
+
+        while self.next_result < index {
+            self.ready.remove(&self.next_result);
+            self.next_result += 1;
+        }
+
+        self.request_blocks()?;
+
+        loop {
+            if let Some(result) = self.ready.remove(&index) {
+                self.next_result = index + 1;
+
+                self.request_blocks()?;
+
+                return result;
+            }
+
+            match self.result_receive.recv() {
+                Ok(response) => {
+                    // blocks that were skipped are no longer needed
+                    if response.index >= self.next_result {
+                        self.ready.insert(response.index, response.result);
+                    }
+                },
+                Err(_) => {
+                    return Err(SDError::Internal("block download workers stopped unexpectedly".to_string()));
+                },
+            }
+        }
+    }
+
+    fn request_blocks(&mut self) -> Result<(), SDError> {
+        let job_send = match self.job_send {
+            Some(ref job_send) => job_send,
+            None => return Err(SDError::Internal("block fetcher is closed".to_string())),
+        };
+
+        if self.next_request < self.next_result {
+            self.next_request = self.next_result;
+        }
+
+        while self.next_request < self.hmacs.len() && self.next_request < self.next_result + self.window {
+            let request = BlockRequest {
+                index: self.next_request,
+                hmac: self.hmacs[self.next_request].clone(),
+            };
+
+            if job_send.send(request).is_err() {
+                return Err(SDError::Internal("block download workers stopped unexpectedly".to_string()));
+            }
+
+            self.next_request += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BlockFetcher {
+    fn drop(&mut self) {
+        self.job_send = None;
+    }
+}
+
+pub fn fetch_block(backend: &StorageBackend, main_key: &Key, session_name: &str, hmac: &[u8]) -> Result<Block, SDError> {
+    let block_hmac_hex = hmac.to_hex();
+
+    debug!("processing block {}", &block_hmac_hex);
+
+    // get block from cache if possible
+    let wrapped_block = match ::cache::read_block(&block_hmac_hex) {
+        Ok(br) => {
+            debug!("cache provided block: {}", &block_hmac_hex);
+
+            br
+        },
+        Err(_) => download_block(backend, session_name, hmac)?,
+    };
+
+    let block_unwrap_time = ::std::time::Instant::now();
+
+    let block = wrapped_block.to_block(main_key)?;
+
+    trace!("Block unwrapping took {} seconds", block_unwrap_time.elapsed().as_secs());
+
+    Ok(block)
+}
+
+fn download_block(backend: &StorageBackend, session_name: &str, hmac: &[u8]) -> Result<WrappedBlock, SDError> {
+    let block_hmac_hex = hmac.to_hex();
+
+    let mut retries_left = 15.0;
+    let block_read_start_time = ::std::time::Instant::now();
+
+    loop {
+        if is_sync_task_cancelled(session_name.to_string()) {
+            return Err(SDError::Cancelled);
+        }
+
+        let failed_count = 15.0 - retries_left;
+        let mut rng = ::rand::thread_rng();
+
+        // we pick a multiplier randomly to avoid a bunch of clients trying again
+        // at the same 2/4/8/16 back off time over and over if the server
+        // is overloaded or down
+        let backoff_multiplier = Range::new(0.0, 1.5).ind_sample(&mut rng);
+
+        if failed_count >= 2.0 && retries_left > 0.0 {
+            // back off significantly every time a call fails but only after the
+            // second try
+            let backoff_time = backoff_multiplier * (failed_count * failed_count);
+            let delay = time::Duration::from_millis((backoff_time * 1000.0) as u64);
+            debug!("backing off for {:?}", delay);
+
+            thread::sleep(delay);
+        }
+
+        // get block from the server
+        match backend.read_block(&block_hmac_hex) {
+            Ok(rb) => {
+                trace!("Block read took {} seconds", block_read_start_time.elapsed().as_secs());
+
+                debug!("server provided block: {}", &block_hmac_hex);
+
+                let wb = match WrappedBlock::from(rb, hmac.to_vec()) {
+                    Ok(wb) => wb,
+                    Err(e) => {
+                        debug!("block failed validation: {}", &block_hmac_hex);
+
+                        return Err(e);
+                    },
+                };
+
+                let block_cache_write_time = ::std::time::Instant::now();
+
+                match ::cache::write_binary(&wb) {
+                    _ => {},
+                };
+                trace!("Block write to cache took {} seconds", block_cache_write_time.elapsed().as_secs());
+
+                return Ok(wb);
+            },
+            Err(SDAPIError::Authentication) => {
+                return Err(SDError::Authentication);
+            },
+            Err(e) => {
+                retries_left = retries_left - 1.0;
+
+                debug!("block read failed for {}: {}", &block_hmac_hex, e);
+
+                if retries_left <= 0.0 {
+                    return match e {
+                        SDAPIError::RequestFailed(err) => Err(SDError::RequestFailure(err)),
+                        e => Err(SDError::from(e)),
+                    };
+                }
+            },
+        };
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// external crate imports
+
+use rustc_serialize::hex::{ToHex, FromHex};
+
+// internal imports
+
+use error::SDError;
+use keys::Key;
+use models::SyncVersion;
+use constants::HMAC_SIZE;
+
+use STORAGE_DIR;
+
+/// The file index remembers what each regular file in a folder looked like the last time it was
+/// synced, along with the HMAC bag that was written to the session for it.
+///
+/// When a file still has the same size, modification time and inode during the next sync, the
+/// previous HMAC bag is placed in the new session as-is and the file is not read again. The
+/// blocks named in the bag are already in storage because the session they were written for
+/// finished successfully, which is the only time an index is saved.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileIndex {
+    folder_id: u64,
+    version: String,
+    key_id: String,
+    session_name: Option<String>,
+    files: HashMap<String, FileIndexEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct FileIndexEntry {
+    size: u64,
+    modified: u64,
+    modified_nanos: u32,
+    inode: u64,
+    hmacs: String,
+    /// real size of each block in the HMAC bag, not recorded by older indexes
+    #[serde(default)]
+    block_sizes: Vec<u64>,
+}
+
+impl FileIndexEntry {
+    fn new(md: &Metadata, hmac_bag: &[u8], block_sizes: &[u64]) -> Option<FileIndexEntry> {
+        let (modified, modified_nanos) = match modified_time(md) {
+            Some(m) => m,
+            None => return None,
+        };
+
+        Some(FileIndexEntry {
+            size: md.len(),
+            modified: modified,
+            modified_nanos: modified_nanos,
+            inode: inode(md),
+            hmacs: hmac_bag.to_hex(),
+            block_sizes: block_sizes.to_vec(),
+        })
+    }
+
+    fn matches(&self, md: &Metadata) -> bool {
+        let (modified, modified_nanos) = match modified_time(md) {
+            Some(m) => m,
+            None => return false,
+        };
+
+        self.size == md.len() && self.modified == modified && self.modified_nanos == modified_nanos && self.inode == inode(md)
+    }
+}
+
+impl FileIndex {
+    /// create an empty index for a folder
+    pub fn new(folder_id: u64, hmac_key: &Key, version: SyncVersion) -> FileIndex {
+        FileIndex {
+            folder_id: folder_id,
+            version: format!("{}", version),
+            key_id: key_id(hmac_key),
+            session_name: None,
+            files: HashMap::new(),
+        }
+    }
+
+    /// load the saved index for a folder
+    ///
+    /// An empty index is returned if there isn't one yet, if it can't be read, or if it was
+    /// created with a different HMAC key or sync version, since none of the HMAC bags it holds
+    /// would be valid for a new session in that case.
+    pub fn load(folder_id: u64, hmac_key: &Key, version: SyncVersion) -> FileIndex {
+        let path = match index_path(folder_id) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("file index unavailable for folder {}: {}", folder_id, e);
+
+                return FileIndex::new(folder_id, hmac_key, version);
+            },
+        };
+
+        FileIndex::load_from(&path, folder_id, hmac_key, version)
+    }
+
+    fn load_from(path: &Path, folder_id: u64, hmac_key: &Key, version: SyncVersion) -> FileIndex {
+        let empty = FileIndex::new(folder_id, hmac_key, version);
+
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return empty,
+        };
+
+        let mut data = Vec::new();
+
+        if let Err(e) = f.read_to_end(&mut data) {
+            debug!("failed to read file index {}: {}", path.display(), e);
+
+            return empty;
+        }
+
+        let index: FileIndex = match ::serde_json::from_slice(&data) {
+            Ok(index) => index,
+            Err(e) => {
+                debug!("failed to parse file index {}: {}", path.display(), e);
+
+                return empty;
+            },
+        };
+
+        if index.folder_id != empty.folder_id || index.version != empty.version || index.key_id != empty.key_id {
+            debug!("file index {} does not match the current keys or sync version, ignoring it", path.display());
+
+            return empty;
+        }
+
+        index
+    }
+
+    /// save the index for the folder it was created for, replacing any previous index
+    pub fn save(&self) -> Result<(), SDError> {
+        let path = index_path(self.folder_id)?;
+
+        self.save_to(&path)
+    }
+
+    fn save_to(&self, path: &Path) -> Result<(), SDError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let data = ::serde_json::to_vec(self)?;
+
+        let temp_path = path.with_extension("json.tmp");
+
+        {
+            let mut f = File::create(&temp_path)?;
+            f.write_all(&data)?;
+            f.sync_all()?;
+        }
+
+        fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+
+    /// the session the index was last saved for, if any
+    pub fn session_name(&self) -> Option<&str> {
+        match self.session_name {
+            Some(ref name) => Some(name),
+            None => None,
+        }
+    }
+
+    pub fn set_session_name(&mut self, name: &str) {
+        self.session_name = Some(name.to_string());
+    }
+
+    /// number of files in the index
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// find the HMAC bag and block sizes for a file, as long as the file has not changed since it
+    /// was indexed
+    pub fn lookup(&self, relative_path: &Path, md: &Metadata) -> Option<(Vec<u8>, Vec<u64>)> {
+        let key = match relative_path.to_str() {
+            Some(k) => k,
+            None => return None,
+        };
+
+        let entry = match self.files.get(key) {
+            Some(entry) => entry,
+            None => return None,
+        };
+
+        if !entry.matches(md) {
+            return None;
+        }
+
+        let hmac_bag = match entry.hmacs.from_hex() {
+            Ok(h) => h,
+            Err(_) => return None,
+        };
+
+        if hmac_bag.is_empty() || hmac_bag.len() % HMAC_SIZE != 0 {
+            return None;
+        }
+
+        Some((hmac_bag, entry.block_sizes.clone()))
+    }
+
+    /// record the HMAC bag and block sizes for a file, along with the metadata used to detect
+    /// changes to it
+    ///
+    /// Files with paths that can't be represented as UTF-8 are not indexed, and will simply be
+    /// chunked again on every sync.
+    pub fn insert(&mut self, relative_path: &Path, md: &Metadata, hmac_bag: &[u8], block_sizes: &[u64]) {
+        let key = match relative_path.to_str() {
+            Some(k) => k.to_string(),
+            None => return,
+        };
+
+        if let Some(entry) = FileIndexEntry::new(md, hmac_bag, block_sizes) {
+            self.files.insert(key, entry);
+        }
+    }
+}
+
+/// remove the saved index for a folder, so the next sync reads every file again
+pub fn remove_index(folder_id: u64) -> Result<(), SDError> {
+    let path = index_path(folder_id)?;
+
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(SDError::from(e)),
+    }
+}
+
+fn index_path(folder_id: u64) -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push("index");
+    p.push(format!("{}.json", folder_id));
+
+    Ok(p)
+}
+
+// identifies the HMAC key without storing anything that could be used to recover it
+pub fn key_id(hmac_key: &Key) -> String {
+    let hash = ::blake2_rfc::blake2b::blake2b(32, hmac_key.as_blake2_256(), b"safedrive file index");
+
+    hash.as_bytes().to_hex()
+}
+
+fn modified_time(md: &Metadata) -> Option<(u64, u32)> {
+    let modified = match md.modified() {
+        Ok(m) => m,
+        Err(_) => return None,
+    };
+
+    match modified.duration_since(::std::time::UNIX_EPOCH) {
+        Ok(d) => Some((d.as_secs(), d.subsec_nanos())),
+        Err(_) => None,
+    }
+}
+
+#[cfg(unix)]
+fn inode(md: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    md.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> u64 {
+    0
+}
+
+#[test]
+fn file_index_roundtrip_test() {
+    let hmac_key = Key::new(::keys::KeyType::HMAC);
+
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-index-{}", ::util::generate_uuid()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file_path = dir.join("file");
+    {
+        let mut f = File::create(&file_path).unwrap();
+        f.write_all(b"some test data").unwrap();
+    }
+    let md = fs::symlink_metadata(&file_path).unwrap();
+
+    let hmac_bag = vec![7u8; HMAC_SIZE * 2];
+
+    let mut index = FileIndex::new(1, &hmac_key, SyncVersion::Version2);
+    index.insert(Path::new("file"), &md, &hmac_bag, &[10, 4]);
+    index.set_session_name("session");
+
+    let index_path = dir.join("index.json");
+    index.save_to(&index_path).unwrap();
+
+    let loaded = FileIndex::load_from(&index_path, 1, &hmac_key, SyncVersion::Version2);
+    assert!(loaded.session_name() == Some("session"));
+    assert!(loaded.lookup(Path::new("file"), &md) == Some((hmac_bag, vec![10, 4])));
+    assert!(loaded.lookup(Path::new("missing"), &md) == None);
+
+    // a different key or sync version must not reuse anything from the index
+    let other_key = Key::new(::keys::KeyType::HMAC);
+    let other = FileIndex::load_from(&index_path, 1, &other_key, SyncVersion::Version2);
+    assert!(other.len() == 0);
+
+    let other = FileIndex::load_from(&index_path, 1, &hmac_key, SyncVersion::Version1);
+    assert!(other.len() == 0);
+
+    // a changed file must not match
+    {
+        let mut f = ::std::fs::OpenOptions::new().append(true).open(&file_path).unwrap();
+        f.write_all(b" and more").unwrap();
+    }
+    let md = fs::symlink_metadata(&file_path).unwrap();
+    assert!(loaded.lookup(Path::new("file"), &md) == None);
+}
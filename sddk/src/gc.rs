@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+// external crate imports
+
+use rustc_serialize::hex::ToHex;
+
+// internal imports
+
+use backend::{StorageBackend, StoredBlock};
+use error::SDError;
+use keys::Key;
+use models::{GarbageCount, GarbageReport};
+
+/// blocks written more recently than this are never collected, a sync that is still running may
+/// have uploaded them before the session that uses them is finished. A sync journal older than
+/// this no longer trusts the blocks it recorded as stored, see `SyncJournal::open`
+pub const GC_GRACE_PERIOD_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// remove blocks that no remaining sync session uses, as of `now_ms`
+///
+/// Every session for the account is read and the HMACs in each file entry, along with the blocks
+/// a version 3 session stores its archive in, are collected into a set of live blocks. Any block
+/// in storage or in the local cache that isn't in that set, and wasn't written within
+/// `GC_GRACE_PERIOD_MS` of `now_ms`, is garbage.
+///
+/// If any session can't be read the whole pass stops with an error, since the blocks it uses
+/// can't be known and removing them would make the session impossible to restore.
+///
+/// With `dry_run` set nothing is removed, the report only counts what would be.
+pub fn collect_garbage(backend: &StorageBackend, main_key: &Key, dry_run: bool, now_ms: u64) -> Result<GarbageReport, SDError> {
+    let mut sessions_read: u64 = 0;
+    let mut live: HashSet<String> = HashSet::new();
+
+    let mut sessions = backend.read_sessions()?;
+
+    if let Some(details) = sessions.remove("sessionDetails") {
+        for (folder_id, folder_sessions) in details {
+            for session in folder_sessions {
+                debug!("reading session {} to find live blocks", session.name);
+
+                let s = ::browse::read_session(backend, folder_id, &session.name, main_key)?;
+                let contents = ::browse::session_contents(&s)?;
+
+                for hmac in ::browse::block_set(&contents) {
+                    live.insert(hmac.to_hex());
+                }
+
+                for hmac in s.manifest_hmacs() {
+                    live.insert(hmac.to_hex());
+                }
+
+                sessions_read += 1;
+            }
+        }
+    }
+
+    debug!("{} sessions use {} blocks", sessions_read, live.len());
+
+    let storage = match backend.list_blocks()? {
+        Some(blocks) => {
+            let (names, count) = unused_blocks(blocks, &live, now_ms);
+
+            debug!("{} unused blocks in storage ({})", count.blocks, ::util::pretty_bytes(count.bytes as f64));
+
+            if !dry_run && !names.is_empty() {
+                backend.remove_blocks(&names)?;
+            }
+
+            Some(count)
+        },
+        None => {
+            debug!("garbage collection is not supported for this storage, only collecting the local cache");
+
+            None
+        },
+    };
+
+    let (names, cache) = unused_blocks(::cache::cached_blocks()?, &live, now_ms);
+
+    debug!("{} unused blocks in the cache ({})", cache.blocks, ::util::pretty_bytes(cache.bytes as f64));
+
+    if !dry_run {
+        for name in &names {
+            ::cache::remove_block(name)?;
+        }
+    }
+
+    Ok(GarbageReport {
+        sessions: sessions_read,
+        live_blocks: live.len() as u64,
+        storage: storage,
+        cache: cache,
+        dry_run: dry_run,
+    })
+}
+
+fn unused_blocks(blocks: Vec<StoredBlock>, live: &HashSet<String>, now_ms: u64) -> (Vec<String>, GarbageCount) {
+    let mut names = Vec::new();
+    let mut count = GarbageCount::default();
+
+    for block in blocks {
+        if live.contains(&block.name) {
+            continue;
+        }
+
+        // a block with no known write time could be brand new, so it's never collected
+        match block.modified {
+            Some(modified) if modified + GC_GRACE_PERIOD_MS <= now_ms => {},
+            _ => continue,
+        }
+
+        count.blocks += 1;
+        count.bytes += block.size;
+
+        names.push(block.name);
+    }
+
+    (names, count)
+}
+
+#[test]
+fn unused_blocks_test() {
+    let now = 10 * GC_GRACE_PERIOD_MS;
+
+    let block = |name: &str, modified: Option<u64>| {
+        StoredBlock {
+            name: name.to_string(),
+            size: 100,
+            modified: modified,
+        }
+    };
+
+    let blocks = vec![
+        block("aa", Some(0)),
+        block("bb", Some(0)),
+        block("cc", Some(now - 1)),
+        block("dd", None),
+        block("ee", Some(now - GC_GRACE_PERIOD_MS)),
+    ];
+
+    let mut live = HashSet::new();
+    live.insert("aa".to_string());
+
+    let (names, count) = unused_blocks(blocks, &live, now);
+
+    assert!(names == vec!["bb".to_string(), "ee".to_string()]);
+    assert!(count.blocks == 2);
+    assert!(count.bytes == 200);
+}
+
+#[test]
+fn collect_garbage_test() {
+    use std::io::Read;
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    folder.write("kept", b"kept");
+    folder.write("deleted", b"deleted");
+
+    let (repository, folder_id) = folder.local();
+
+    let old_session = ::util::generate_uuid();
+    ::core::sync_with_backend(repository.clone(), &old_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    ::std::fs::remove_file(folder.source.join("deleted")).unwrap();
+
+    let new_session = ::util::generate_uuid();
+    ::core::sync_with_backend(repository.clone(), &new_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    // each session archive is stored in blocks of its own as well
+    let manifest_blocks = |name: &str| -> u64 {
+        ::browse::read_session(&*repository, folder_id, name, &folder.main).expect("failed to read session").manifest_hmacs().len() as u64
+    };
+
+    let old_manifest = manifest_blocks(&old_session);
+    let new_manifest = manifest_blocks(&new_session);
+
+    let stored = repository.list_blocks().unwrap().unwrap().len() as u64;
+    assert!(stored == 2 + old_manifest + new_manifest);
+
+    let sessions = repository.read_sessions().expect("failed to read sessions");
+    let old_id = sessions["sessionDetails"][&folder_id].iter().find(|s| s.name == old_session).unwrap().id.unwrap();
+    repository.delete_session(old_id).expect("failed to delete session");
+
+    let now = ::chrono::Utc::now();
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64;
+
+    // the orphaned blocks were only just written, so they're still inside the grace period
+    let report = collect_garbage(&*repository, &folder.main, false, now_ms).expect("gc failed");
+    assert!(report.sessions == 1);
+    assert!(report.live_blocks == 1 + new_manifest);
+    assert!(report.storage.unwrap().blocks == 0);
+
+    let later = now_ms + 2 * GC_GRACE_PERIOD_MS;
+
+    let report = collect_garbage(&*repository, &folder.main, true, later).expect("gc failed");
+    assert!(report.dry_run);
+    assert!(report.storage.as_ref().unwrap().blocks == 1 + old_manifest);
+    assert!(report.storage.as_ref().unwrap().bytes > 0);
+    assert!(repository.list_blocks().unwrap().unwrap().len() as u64 == stored);
+
+    let report = collect_garbage(&*repository, &folder.main, false, later).expect("gc failed");
+    assert!(report.storage.unwrap().blocks == 1 + old_manifest);
+    assert!(repository.list_blocks().unwrap().unwrap().len() as u64 == 1 + new_manifest);
+
+    // the remaining session must still restore completely
+    let destination = folder.destination();
+
+    ::core::restore_with_backend(repository.clone(), &new_session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    let mut kept = String::new();
+    ::std::fs::File::open(destination.join("kept")).unwrap().read_to_string(&mut kept).unwrap();
+    assert!(kept == "kept");
+}
@@ -0,0 +1,370 @@
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// external crate imports
+
+use glob::{Pattern, MatchOptions};
+
+// internal imports
+
+use error::SDError;
+use models::IgnoreRules;
+
+use STORAGE_DIR;
+
+/// name of the file in the root of a folder that holds extra ignore patterns for it
+pub static IGNORE_FILE: &'static str = ".sdignore";
+
+/// Decides which items in a folder are left out of a sync session
+///
+/// Patterns follow gitignore syntax:
+///
+/// * blank lines and lines starting with `#` are skipped
+/// * a pattern starting with `!` includes again anything an earlier pattern ignored
+/// * a pattern ending with `/` only matches directories
+/// * a pattern with a `/` anywhere else is matched against the whole path relative to the root of
+///   the folder, otherwise it's matched against the name of the item at any depth
+/// * `*` and `?` don't match `/`, `**` matches any number of directories
+///
+/// The last pattern that matches an item decides whether it's ignored. Nothing inside an ignored
+/// directory is looked at, so as with git, a file can't be included again once a directory above
+/// it is ignored.
+///
+/// Sockets, FIFOs and devices are always ignored, since a session has no way to store them.
+#[derive(Debug)]
+pub struct IgnoreMatcher {
+    rules: IgnoreRules,
+    patterns: Vec<IgnorePattern>,
+}
+
+#[derive(Debug)]
+struct IgnorePattern {
+    pattern: Pattern,
+    negated: bool,
+    directory_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreMatcher {
+    pub fn new(rules: IgnoreRules) -> Result<IgnoreMatcher, SDError> {
+        let mut patterns = Vec::new();
+
+        for line in &rules.patterns {
+            if let Some(pattern) = parse_pattern(line)? {
+                patterns.push(pattern);
+            }
+        }
+
+        Ok(IgnoreMatcher {
+            rules: rules,
+            patterns: patterns,
+        })
+    }
+
+    /// combine the rules stored for a folder with the patterns in its `.sdignore` file
+    pub fn for_folder(folder_id: u64, folder_path: &Path) -> Result<IgnoreMatcher, SDError> {
+        let mut rules = load_rules(folder_id)?;
+
+        match File::open(folder_path.join(IGNORE_FILE)) {
+            Ok(mut f) => {
+                let mut contents = String::new();
+                f.read_to_string(&mut contents)?;
+
+                rules.patterns.extend(contents.lines().map(|line| line.to_string()));
+            },
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(SDError::from(e)),
+        }
+
+        IgnoreMatcher::new(rules)
+    }
+
+    /// the rules the matcher was built from, including any patterns read from `.sdignore`
+    pub fn rules(&self) -> &IgnoreRules {
+        &self.rules
+    }
+
+    /// whether the item at `relative_path` in the folder should be left out of the session
+    pub fn is_ignored(&self, relative_path: &Path, md: &Metadata) -> bool {
+        // the root of the folder itself
+        if relative_path.as_os_str().is_empty() {
+            return false;
+        }
+
+        let file_type = md.file_type();
+        let is_dir = file_type.is_dir();
+
+        if !file_type.is_file() && !is_dir && !file_type.is_symlink() {
+            return true;
+        }
+
+        if let Some(max_file_size) = self.rules.max_file_size {
+            if file_type.is_file() && md.len() > max_file_size {
+                return true;
+            }
+        }
+
+        let name = match relative_path.file_name() {
+            Some(name) => Path::new(name),
+            None => return false,
+        };
+
+        if self.rules.skip_hidden && name.to_string_lossy().starts_with('.') {
+            return true;
+        }
+
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        let mut ignored = false;
+
+        for pattern in &self.patterns {
+            if pattern.directory_only && !is_dir {
+                continue;
+            }
+
+            let matched = if pattern.anchored {
+                pattern.pattern.matches_path_with(relative_path, &options)
+            } else {
+                pattern.pattern.matches_path_with(name, &options)
+            };
+
+            if matched {
+                ignored = !pattern.negated;
+            }
+        }
+
+        ignored
+    }
+}
+
+fn parse_pattern(line: &str) -> Result<Option<IgnorePattern>, SDError> {
+    let mut p = line.trim_right();
+
+    if p.is_empty() || p.starts_with('#') {
+        return Ok(None);
+    }
+
+    let negated = p.starts_with('!');
+
+    if negated {
+        p = &p[1..];
+    } else if p.starts_with("\\#") || p.starts_with("\\!") {
+        p = &p[1..];
+    }
+
+    let directory_only = p.ends_with('/');
+
+    let p = p.trim_right_matches('/');
+
+    let anchored = p.contains('/');
+
+    let p = p.trim_left_matches('/');
+
+    if p.is_empty() {
+        return Ok(None);
+    }
+
+    match Pattern::new(p) {
+        Ok(pattern) => {
+            Ok(Some(IgnorePattern {
+                pattern: pattern,
+                negated: negated,
+                directory_only: directory_only,
+                anchored: anchored,
+            }))
+        },
+        Err(e) => Err(SDError::Internal(format!("invalid ignore pattern {}: {}", line, e))),
+    }
+}
+
+/// the ignore rules stored for a folder, or the default rules that ignore nothing if none were
+/// stored
+pub fn load_rules(folder_id: u64) -> Result<IgnoreRules, SDError> {
+    let path = rules_path(folder_id)?;
+
+    let mut f = match File::open(&path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(IgnoreRules::default()),
+        Err(e) => return Err(SDError::from(e)),
+    };
+
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+
+    let rules: IgnoreRules = ::serde_json::from_slice(&data)?;
+
+    Ok(rules)
+}
+
+/// store the ignore rules for a folder, replacing the previous ones
+///
+/// The rules are checked first, so invalid patterns are rejected here rather than during the
+/// next sync.
+pub fn save_rules(folder_id: u64, rules: &IgnoreRules) -> Result<(), SDError> {
+    IgnoreMatcher::new(rules.clone())?;
+
+    let path = rules_path(folder_id)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let data = ::serde_json::to_vec(rules)?;
+
+    let temp_path = path.with_extension("json.tmp");
+
+    {
+        let mut f = File::create(&temp_path)?;
+        f.write_all(&data)?;
+        f.sync_all()?;
+    }
+
+    fs::rename(&temp_path, &path)?;
+
+    Ok(())
+}
+
+/// remove the stored ignore rules for a folder
+pub fn remove_rules(folder_id: u64) -> Result<(), SDError> {
+    let path = rules_path(folder_id)?;
+
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(SDError::from(e)),
+    }
+}
+
+fn rules_path(folder_id: u64) -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push("rules");
+    p.push(format!("{}.json", folder_id));
+
+    Ok(p)
+}
+
+#[test]
+fn ignore_patterns_test() {
+    let dir = ::std::env::temp_dir().join(format!("sddk-ignore-{}", ::util::generate_uuid()));
+    fs::create_dir_all(dir.join("build")).unwrap();
+
+    File::create(dir.join("notes.txt")).unwrap().write_all(b"notes").unwrap();
+    File::create(dir.join("large.bin")).unwrap().write_all(&[0u8; 100]).unwrap();
+
+    let file = fs::symlink_metadata(dir.join("notes.txt")).unwrap();
+    let large = fs::symlink_metadata(dir.join("large.bin")).unwrap();
+    let directory = fs::symlink_metadata(dir.join("build")).unwrap();
+
+    let rules = IgnoreRules {
+        patterns: vec![
+            "# comment".to_string(),
+            "".to_string(),
+            "*.log".to_string(),
+            "!keep.log".to_string(),
+            "build/".to_string(),
+            "/top.txt".to_string(),
+            "docs/**/*.tmp".to_string(),
+            "\\#hash".to_string(),
+        ],
+        max_file_size: Some(50),
+        skip_hidden: true,
+    };
+
+    let matcher = IgnoreMatcher::new(rules).unwrap();
+
+    assert!(!matcher.is_ignored(Path::new(""), &directory));
+
+    assert!(matcher.is_ignored(Path::new("error.log"), &file));
+    assert!(matcher.is_ignored(Path::new("a/b/error.log"), &file));
+    assert!(!matcher.is_ignored(Path::new("a/keep.log"), &file));
+
+    assert!(matcher.is_ignored(Path::new("build"), &directory));
+    assert!(matcher.is_ignored(Path::new("src/build"), &directory));
+    assert!(!matcher.is_ignored(Path::new("src/build"), &file));
+
+    assert!(matcher.is_ignored(Path::new("top.txt"), &file));
+    assert!(!matcher.is_ignored(Path::new("sub/top.txt"), &file));
+
+    assert!(matcher.is_ignored(Path::new("docs/a/b/draft.tmp"), &file));
+    assert!(!matcher.is_ignored(Path::new("other/draft.tmp"), &file));
+
+    assert!(matcher.is_ignored(Path::new("#hash"), &file));
+
+    assert!(matcher.is_ignored(Path::new(".cache"), &directory));
+    assert!(matcher.is_ignored(Path::new("large.bin"), &large));
+    assert!(!matcher.is_ignored(Path::new("notes.txt"), &file));
+
+    assert!(IgnoreMatcher::new(IgnoreRules { patterns: vec!["[unclosed".to_string()], max_file_size: None, skip_hidden: false }).is_err());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sync_ignore_rules_test() {
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    folder.write(".sdignore", b"# build logs\n*.log\n!keep.log\n");
+    folder.write("node_modules/left-pad/index.js", b"module.exports = {}");
+    folder.write("src/main.rs", b"fn main() {}");
+    folder.write("src/build.log", b"log");
+    folder.write("keep.log", b"keep");
+    folder.write("large.bin", &[0u8; 2000]);
+
+    let (_server, token, folder_id) = folder.serve();
+
+    assert!(::core::get_ignore_rules(folder_id).unwrap() == IgnoreRules::default());
+
+    let rules = IgnoreRules {
+        patterns: vec!["node_modules/".to_string()],
+        max_file_size: Some(1000),
+        skip_hidden: false,
+    };
+
+    ::core::set_ignore_rules(folder_id, &rules).expect("failed to set ignore rules");
+    assert!(::core::get_ignore_rules(folder_id).unwrap() == rules);
+
+    let invalid = IgnoreRules {
+        patterns: vec!["[unclosed".to_string()],
+        max_file_size: None,
+        skip_hidden: false,
+    };
+    assert!(::core::set_ignore_rules(folder_id, &invalid).is_err());
+
+    let session_name = ::util::generate_uuid();
+    ::core::sync(&token, &session_name, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    let entries = ::core::list_session_entries(&token, folder_id, &session_name, &folder.main).expect("failed to list session entries");
+
+    let mut paths: Vec<String> = entries.into_iter().map(|entry| entry.path).collect();
+    paths.sort();
+
+    assert!(paths == vec![".sdignore".to_string(), "keep.log".to_string(), "src".to_string(), "src/main.rs".to_string()]);
+
+    let applied = ::core::get_session_ignore_rules(&token, folder_id, &session_name, &folder.main).expect("failed to read session ignore rules").unwrap();
+    assert!(applied.patterns == vec!["node_modules/".to_string(), "# build logs".to_string(), "*.log".to_string(), "!keep.log".to_string()]);
+    assert!(applied.max_file_size == Some(1000));
+
+    // the record of the rules isn't restored as an entry of its own
+    let destination = folder.destination();
+
+    let mut issues: Vec<String> = Vec::new();
+
+    ::core::restore(&token, &session_name, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |message| { issues.push(message.to_string()); }, &mut |_| {}).expect("restore failed");
+
+    assert!(issues.is_empty());
+    assert!(destination.join("src").join("main.rs").exists());
+    assert!(!destination.join("node_modules").exists());
+}
@@ -0,0 +1,467 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+// external crate imports
+
+use parking_lot::Mutex;
+use tar::{Archive, EntryType};
+
+// internal imports
+
+use constants::HMAC_SIZE;
+use error::SDError;
+use keys::Key;
+use models::SyncVersion;
+
+use STORAGE_DIR;
+
+static INFO_FILE: &'static str = "journal.json";
+static BLOCKS_FILE: &'static str = "blocks";
+static ARCHIVE_FILE: &'static str = "session.tar";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JournalInfo {
+    session_name: String,
+    version: String,
+    key_id: String,
+    /// when the session was first started, in seconds
+    started: u64,
+}
+
+/// The sync journal records the progress of a sync session while it runs, so that a session
+/// interrupted by cancellation or a crash can be finished later without uploading everything
+/// again.
+///
+/// It keeps the names of the blocks storage has acknowledged, and the session archive as it is
+/// being built. When a sync is started again with the same session name, acknowledged blocks are
+/// not sent again, and regular files that were already completely uploaded and haven't changed
+/// since the session was first started reuse their HMAC bag from the partial archive instead of
+/// being read again.
+///
+/// Layout, one directory for each folder:
+///
+///     <storage dir>/journal/<folder id>/journal.json
+///     <storage dir>/journal/<folder id>/blocks
+///     <storage dir>/journal/<folder id>/session.tar
+///
+pub struct SyncJournal {
+    dir: PathBuf,
+    started: u64,
+    resumed: bool,
+    acknowledged: Mutex<HashSet<String>>,
+    blocks_file: Mutex<Option<File>>,
+    previous_files: HashMap<String, (u64, u64, Vec<u8>, Vec<u64>)>,
+}
+
+impl SyncJournal {
+    /// open the journal for a session, resuming it if the journal was left behind by an earlier
+    /// attempt at the same session, otherwise replacing whatever journal the folder had
+    ///
+    /// A journal started more than `GC_GRACE_PERIOD_MS` ago is resumed without any of the blocks
+    /// it recorded, since garbage collection may have removed them in the meantime.
+    pub fn open(folder_id: u64, session_name: &str, hmac_key: &Key, version: SyncVersion) -> Result<SyncJournal, SDError> {
+        let dir = journal_dir(folder_id)?;
+
+        SyncJournal::open_in(dir, session_name, hmac_key, version)
+    }
+
+    fn open_in(dir: PathBuf, session_name: &str, hmac_key: &Key, version: SyncVersion) -> Result<SyncJournal, SDError> {
+        let key_id = ::fileindex::key_id(hmac_key);
+
+        let previous = match read_info(&dir) {
+            Some(info) => {
+                if info.session_name == session_name && info.version == format!("{}", version) && info.key_id == key_id {
+                    Some(info)
+                } else {
+                    debug!("discarding sync journal for session {}", info.session_name);
+
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let now = ::chrono::Utc::now().timestamp() as u64;
+
+        let (started, resumed, acknowledged, previous_files) = match previous {
+            // blocks older than the grace period can be removed by garbage collection at any
+            // time, since no finished session uses them yet, so nothing the journal says was
+            // stored can be trusted any more and the session starts its clock again
+            Some(mut info) => if now.saturating_sub(info.started) * 1000 > ::gc::GC_GRACE_PERIOD_MS {
+                debug!("resuming session {} after the garbage collection grace period, checking every block again", session_name);
+
+                info.started = now;
+                write_info(&dir, &info)?;
+
+                File::create(dir.join(BLOCKS_FILE))?;
+
+                (now, true, HashSet::new(), HashMap::new())
+            } else {
+                let acknowledged = read_acknowledged(&dir.join(BLOCKS_FILE));
+                let previous_files = read_partial_archive(&dir.join(ARCHIVE_FILE));
+
+                debug!("resuming session {}: {} blocks already stored, {} files already archived", session_name, acknowledged.len(), previous_files.len());
+
+                (info.started, true, acknowledged, previous_files)
+            },
+            None => {
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                }
+
+                fs::create_dir_all(&dir)?;
+
+                let info = JournalInfo {
+                    session_name: session_name.to_string(),
+                    version: format!("{}", version),
+                    key_id: key_id,
+                    started: now,
+                };
+
+                write_info(&dir, &info)?;
+
+                (now, false, HashSet::new(), HashMap::new())
+            },
+        };
+
+        let blocks_file = OpenOptions::new().create(true).append(true).open(dir.join(BLOCKS_FILE))?;
+
+        Ok(SyncJournal {
+            dir: dir,
+            started: started,
+            resumed: resumed,
+            acknowledged: Mutex::new(acknowledged),
+            blocks_file: Mutex::new(Some(blocks_file)),
+            previous_files: previous_files,
+        })
+    }
+
+    /// whether this journal continues an earlier attempt at the same session
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// create the file the session archive is written to as it is built, replacing the partial
+    /// archive from an earlier attempt
+    pub fn archive(&self) -> Result<File, SDError> {
+        let f = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.dir.join(ARCHIVE_FILE))?;
+
+        Ok(f)
+    }
+
+    /// where the session archive is written by `archive()`
+    pub fn archive_path(&self) -> PathBuf {
+        self.dir.join(ARCHIVE_FILE)
+    }
+
+    /// record blocks that storage has acknowledged
+    pub fn acknowledge(&self, names: &[String]) -> Result<(), SDError> {
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = String::new();
+
+        for name in names {
+            lines.push_str(name);
+            lines.push('\n');
+        }
+
+        {
+            let mut blocks_file = self.blocks_file.lock();
+
+            if let Some(ref mut f) = *blocks_file {
+                f.write_all(lines.as_bytes())?;
+            }
+        }
+
+        let mut acknowledged = self.acknowledged.lock();
+
+        for name in names {
+            acknowledged.insert(name.clone());
+        }
+
+        Ok(())
+    }
+
+    /// whether storage has already acknowledged a block during this session
+    pub fn is_acknowledged(&self, name: &str) -> bool {
+        let acknowledged = self.acknowledged.lock();
+
+        acknowledged.contains(name)
+    }
+
+    /// find the HMAC bag and block sizes an earlier attempt at this session archived for a file, as
+    /// long as every block in it was acknowledged and the file hasn't been modified since the
+    /// session started
+    pub fn lookup(&self, relative_path: &Path, md: &Metadata) -> Option<(Vec<u8>, Vec<u64>)> {
+        let key = match relative_path.to_str() {
+            Some(k) => k,
+            None => return None,
+        };
+
+        let &(size, mtime, ref hmac_bag, ref block_sizes) = match self.previous_files.get(key) {
+            Some(entry) => entry,
+            None => return None,
+        };
+
+        let modified = match md.modified().ok().and_then(|m| m.duration_since(::std::time::UNIX_EPOCH).ok()) {
+            Some(d) => d.as_secs(),
+            None => return None,
+        };
+
+        // the archive only has whole seconds, a file modified in the same second the session
+        // started could have changed without its size or time changing
+        if size != md.len() || mtime != modified || modified >= self.started {
+            return None;
+        }
+
+        let acknowledged = self.acknowledged.lock();
+
+        if !hmac_bag.chunks(HMAC_SIZE).all(|hmac| acknowledged.contains(&::rustc_serialize::hex::ToHex::to_hex(hmac))) {
+            return None;
+        }
+
+        Some((hmac_bag.clone(), block_sizes.clone()))
+    }
+
+    /// remove the journal once the session has been stored
+    pub fn remove(&self) -> Result<(), SDError> {
+        // close the block list first, some platforms can't remove open files
+        {
+            let mut blocks_file = self.blocks_file.lock();
+            *blocks_file = None;
+        }
+
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+}
+
+/// the name of a session that was interrupted before it finished, and that the next sync of the
+/// folder can resume by using the same session name
+pub fn interrupted_session(folder_id: u64) -> Option<String> {
+    let dir = match journal_dir(folder_id) {
+        Ok(dir) => dir,
+        Err(_) => return None,
+    };
+
+    read_info(&dir).map(|info| info.session_name)
+}
+
+fn journal_dir(folder_id: u64) -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push("journal");
+    p.push(format!("{}", folder_id));
+
+    Ok(p)
+}
+
+fn read_info(dir: &Path) -> Option<JournalInfo> {
+    let mut f = match File::open(dir.join(INFO_FILE)) {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+
+    let mut data = Vec::new();
+
+    if let Err(e) = f.read_to_end(&mut data) {
+        debug!("failed to read sync journal {}: {}", dir.display(), e);
+
+        return None;
+    }
+
+    match ::serde_json::from_slice(&data) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            debug!("failed to parse sync journal {}: {}", dir.display(), e);
+
+            None
+        },
+    }
+}
+
+fn write_info(dir: &Path, info: &JournalInfo) -> Result<(), SDError> {
+    let data = ::serde_json::to_vec(info)?;
+
+    let mut f = File::create(dir.join(INFO_FILE))?;
+    f.write_all(&data)?;
+    f.sync_all()?;
+
+    Ok(())
+}
+
+fn read_acknowledged(path: &Path) -> HashSet<String> {
+    let mut acknowledged = HashSet::new();
+
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return acknowledged,
+    };
+
+    // the last line may be incomplete if the process died while writing it
+    for line in BufReader::new(f).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if line.len() == HMAC_SIZE * 2 && line.chars().all(|c| c.is_digit(16)) {
+            acknowledged.insert(line);
+        }
+    }
+
+    acknowledged
+}
+
+/// read the regular files from a partial session archive, stopping at the first entry that
+/// can't be read since the archive may have been cut off in the middle of one
+fn read_partial_archive(path: &Path) -> HashMap<String, (u64, u64, Vec<u8>, Vec<u64>)> {
+    let mut files = HashMap::new();
+
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return files,
+    };
+
+    let mut ar = Archive::new(f);
+
+    let entries = match ar.entries() {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+
+    for item in entries {
+        let mut entry = match item {
+            Ok(entry) => entry,
+            Err(_) => break,
+        };
+
+        let path = match entry.path() {
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(_) => break,
+        };
+
+        let (size, mtime) = {
+            let header = entry.header();
+
+            if header.entry_type() != EntryType::Regular {
+                continue;
+            }
+
+            match (::metadata::real_size(header), header.mtime()) {
+                (Some(size), Ok(mtime)) => (size, mtime),
+                _ => continue,
+            }
+        };
+
+        let block_sizes = ::metadata::block_sizes(&mut entry).unwrap_or(Vec::new());
+
+        let mut hmac_bag = Vec::new();
+
+        if entry.read_to_end(&mut hmac_bag).is_err() {
+            break;
+        }
+
+        if hmac_bag.is_empty() || hmac_bag.len() % HMAC_SIZE != 0 {
+            continue;
+        }
+
+        files.insert(path, (size, mtime, hmac_bag, block_sizes));
+    }
+
+    files
+}
+
+#[test]
+fn sync_journal_resume_test() {
+    use tar::{Builder, Header};
+    use keys::KeyType;
+
+    let hmac_key = Key::new(KeyType::HMAC);
+
+    let mut storage = ::std::env::temp_dir();
+    storage.push(format!("sddk-journal-{}", ::util::generate_uuid()));
+    fs::create_dir_all(&storage).unwrap();
+
+    let dir = storage.join("journal");
+
+    let file_path = storage.join("file");
+    {
+        let mut f = File::create(&file_path).unwrap();
+        f.write_all(b"some test data").unwrap();
+    }
+
+    // pretend the file was last modified well before the session started
+    let earlier = ::filetime::FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    ::filetime::set_file_times(&file_path, earlier, earlier).unwrap();
+    let md = fs::symlink_metadata(&file_path).unwrap();
+
+    let hmac_bag = vec![7u8; HMAC_SIZE * 2];
+    let name = ::rustc_serialize::hex::ToHex::to_hex(&hmac_bag[0..HMAC_SIZE]);
+
+    {
+        let journal = SyncJournal::open_in(dir.clone(), "session", &hmac_key, SyncVersion::Version2).unwrap();
+        assert!(!journal.is_resumed());
+
+        let mut ar = Builder::new(journal.archive().unwrap());
+        let mut header = Header::new_gnu();
+        header.set_metadata(&md);
+        ::metadata::set_real_size(&mut header, md.len());
+        header.set_size(hmac_bag.len() as u64);
+        header.set_cksum();
+
+        let mut pax_records = Vec::new();
+        ::metadata::append_block_sizes(&mut pax_records, &[7, 7]);
+        ::metadata::append_entry(&mut ar, &mut header, "file", &hmac_bag, &pax_records).unwrap();
+
+        journal.acknowledge(&[name.clone()]).unwrap();
+
+        // the archive is left without its final blocks, as if the process died here
+        ::std::mem::forget(ar);
+    }
+
+    assert!(read_info(&dir).map(|info| info.session_name) == Some("session".to_string()));
+
+    let journal = SyncJournal::open_in(dir.clone(), "session", &hmac_key, SyncVersion::Version2).unwrap();
+    assert!(journal.is_resumed());
+    assert!(journal.is_acknowledged(&name));
+
+    // both hmacs are the same block, so the whole file is stored
+    assert!(journal.lookup(Path::new("file"), &md) == Some((hmac_bag.clone(), vec![7, 7])));
+    assert!(journal.lookup(Path::new("missing"), &md) == None);
+
+    // a session left for longer than the gc grace period can't trust its stored blocks
+    drop(journal);
+
+    let mut info = read_info(&dir).unwrap();
+    info.started -= ::gc::GC_GRACE_PERIOD_MS / 1000 + 60;
+    write_info(&dir, &info).unwrap();
+
+    let journal = SyncJournal::open_in(dir.clone(), "session", &hmac_key, SyncVersion::Version2).unwrap();
+    assert!(journal.is_resumed());
+    assert!(!journal.is_acknowledged(&name));
+    assert!(journal.lookup(Path::new("file"), &md) == None);
+
+    // a different session replaces the journal
+    drop(journal);
+
+    let journal = SyncJournal::open_in(dir.clone(), "other", &hmac_key, SyncVersion::Version2).unwrap();
+    assert!(!journal.is_resumed());
+    assert!(!journal.is_acknowledged(&name));
+    assert!(journal.lookup(Path::new("file"), &md) == None);
+
+    journal.remove().unwrap();
+    assert!(read_info(&dir).is_none());
+
+    let _ = fs::remove_dir_all(&storage);
+}
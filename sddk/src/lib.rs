@@ -37,6 +37,18 @@ extern crate reed_solomon;
 
 extern crate parking_lot_mpsc;
 
+extern crate num_cpus;
+
+extern crate filetime;
+
+extern crate glob;
+
+#[cfg(unix)]
+extern crate xattr;
+
+#[cfg(unix)]
+extern crate libc;
+
 #[macro_use]
 mod localized;
 
@@ -52,9 +64,26 @@ mod error;
 mod keys;
 mod util;
 mod sdapi;
+mod backend;
+mod local_backend;
+#[cfg(any(test, feature = "mock_api"))]
+mod mockapi;
 mod state;
 mod binformat;
 mod cache;
+mod fileindex;
+mod ignore;
+mod journal;
+mod watch;
+mod daemon;
+mod control;
+mod pipeline;
+mod fetch;
+mod browse;
+mod retention;
+mod gc;
+mod verify;
+mod metadata;
 mod block;
 mod session;
 mod lock;
@@ -76,11 +105,20 @@ pub use c_api::*;
 pub use core::*;
 pub use sync_state::*;
 pub use constants::*;
-pub use error::SDError;
-pub use models::{SyncCleaningSchedule, SyncStatus, SyncVersion, Token, RegisteredFolder, AccountStatus, AccountDetails, SoftwareClient};
+pub use error::{SDError, SDAPIError};
+pub use models::{SyncCleaningSchedule, SyncSchedule, SyncStatus, SyncEvent, SyncPhase, SkipReason, SyncSummary, SyncVersion, Token, RegisteredFolder, AccountStatus, AccountDetails, SoftwareClient, SyncSessionResponse, SessionEntry, SessionEntryType, SessionDiff, GarbageReport, GarbageCount, SessionVerification, BrokenFile, IgnoreRules, DaemonEvent, DaemonTask, CacheStats};
 pub use keys::{Key, Keyset, KeyType};
-pub use session::SyncSession;
+pub use session::{SyncSession, WrappedSyncSession, SessionFile, write_session_file, read_session_file, manifest_root};
+pub use block::WrappedBlock;
+pub use backend::{StorageBackend, StoredBlock, SafeDriveBackend};
+pub use local_backend::LocalBackend;
+#[cfg(feature = "mock_api")]
+pub use mockapi::MockServer;
 pub use chunk::{ChunkGenerator, BlockGenerator, BlockGeneratorStats};
+pub use restore::RestoreFilter;
+pub use ignore::{IgnoreMatcher, IGNORE_FILE};
+pub use control::{DaemonClient, RestoreRequest, CONTROL_SOCKET};
+pub use oplog::{OpLog, Change, TreeState, FileNode, OpType, FileType, replay, remove_oplog};
 pub use keychain::KeychainService;
 
 #[cfg(feature = "sessionfs")]
@@ -141,7 +179,11 @@ extern crate lazy_static;
 #[macro_use(defer)]
 extern crate scopeguard;
 
-pub static SYNC_VERSION: ::models::SyncVersion = ::models::SyncVersion::Version2;
+/// the version new sessions are written with, blocks use `SYNC_VERSION.block_version()`
+///
+/// Version 2 sessions are still read, but no longer written since they hold the whole session
+/// archive in memory.
+pub static SYNC_VERSION: ::models::SyncVersion = ::models::SyncVersion::Version3;
 
 /// global config, can only be set once at runtime
 
@@ -203,6 +245,14 @@ lazy_static! {
     static ref CURRENT_USER: ::parking_lot::RwLock<::std::string::String> = ::parking_lot::RwLock::new(::std::string::String::new());
 }
 
+lazy_static! {
+    static ref SYNC_WORKERS: ::parking_lot::RwLock<usize> = ::parking_lot::RwLock::new(0);
+}
+
+lazy_static! {
+    static ref RESTORE_WORKERS: ::parking_lot::RwLock<usize> = ::parking_lot::RwLock::new(8);
+}
+
 lazy_static! {
     static ref CANCEL_LIST: ::parking_lot::RwLock<Vec<String>> = ::parking_lot::RwLock::new(Vec::new());
 }
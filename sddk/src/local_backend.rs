@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// external crate imports
+
+use parking_lot::Mutex;
+
+/// internal imports
+
+use error::SDAPIError;
+use models::{RegisteredFolder, SyncSessionResponse};
+use session::{SyncSession, SessionFile};
+use block::WrappedBlock;
+use keys::WrappedKeyset;
+use binformat::BinaryWriter;
+use backend::{StorageBackend, StoredBlock, BandwidthCallback};
+
+static INDEX_FILE: &'static str = "index.json";
+static BLOCK_DIR: &'static str = "blocks";
+static SESSION_DIR: &'static str = "sessions";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LocalSession {
+    id: u64,
+    folder_id: u64,
+    name: String,
+    size: Option<u64>,
+    time: Option<u64>,
+    finished: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LocalIndex {
+    next_folder_id: u64,
+    next_session_id: u64,
+    folders: Vec<RegisteredFolder>,
+    sessions: Vec<LocalSession>,
+    keyset: Option<WrappedKeyset>,
+}
+
+/// A repository in a local directory, such as an external disk or a mounted NAS share
+///
+/// Blocks and sessions are stored in the same binary format the SafeDrive API stores, one file
+/// each, so a repository can be copied around or inspected with the usual tools. Folders, session
+/// registrations and the wrapped keyset are kept in an index file at the root of the repository.
+///
+/// Layout:
+///
+///     index.json
+///     blocks/<first 2 characters of block name>/<block name>
+///     sessions/<folder id>/<session name>
+///
+pub struct LocalBackend {
+    root: PathBuf,
+    index: Mutex<LocalIndex>,
+}
+
+impl LocalBackend {
+    /// open the repository at `root`, creating it if it doesn't exist yet
+    pub fn open(root: &Path) -> Result<LocalBackend, SDAPIError> {
+        fs::create_dir_all(root.join(BLOCK_DIR))?;
+        fs::create_dir_all(root.join(SESSION_DIR))?;
+
+        let index_path = root.join(INDEX_FILE);
+
+        let index = if index_path.exists() {
+            let mut f = File::open(&index_path)?;
+            let mut buffer = Vec::new();
+            f.read_to_end(&mut buffer)?;
+
+            let index: LocalIndex = ::serde_json::from_slice(&buffer)?;
+
+            index
+        } else {
+            debug!("creating new local repository at {}", root.display());
+
+            LocalIndex {
+                next_folder_id: 1,
+                next_session_id: 1,
+                folders: Vec::new(),
+                sessions: Vec::new(),
+                keyset: None,
+            }
+        };
+
+        let backend = LocalBackend {
+            root: root.to_owned(),
+            index: Mutex::new(index),
+        };
+
+        {
+            let index = backend.index.lock();
+            backend.save_index(&index)?;
+        }
+
+        Ok(backend)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn create_folder(&self, path: &str, name: &str, encrypted: bool) -> Result<u64, SDAPIError> {
+        let mut index = self.index.lock();
+
+        for folder in &index.folders {
+            if folder.folderName == name || folder.folderPath == path {
+                return Err(SDAPIError::Conflict);
+            }
+        }
+
+        let folder_id = index.next_folder_id;
+        index.next_folder_id += 1;
+
+        index.folders.push(RegisteredFolder {
+            id: folder_id,
+            folderName: name.to_string(),
+            folderPath: path.to_string(),
+            addedDate: now_ms(),
+            encrypted: encrypted,
+            syncing: true,
+        });
+
+        self.save_index(&index)?;
+
+        Ok(folder_id)
+    }
+
+    pub fn update_folder(&self, path: &str, name: &str, syncing: bool, folder_id: u64) -> Result<(), SDAPIError> {
+        let mut index = self.index.lock();
+
+        {
+            let folder = match index.folders.iter_mut().find(|folder| folder.id == folder_id) {
+                Some(folder) => folder,
+                None => return Err(SDAPIError::Internal(format!("unexpected failure to find folder_id {}", folder_id))),
+            };
+
+            folder.folderPath = path.to_string();
+            folder.folderName = name.to_string();
+            folder.syncing = syncing;
+        }
+
+        self.save_index(&index)
+    }
+
+    /// remove a folder and its sessions from the index, blocks are left in place
+    pub fn delete_folder(&self, folder_id: u64) -> Result<(), SDAPIError> {
+        let mut index = self.index.lock();
+
+        index.folders.retain(|folder| folder.id != folder_id);
+        index.sessions.retain(|session| session.folder_id != folder_id);
+
+        self.save_index(&index)?;
+
+        let session_dir = self.root.join(SESSION_DIR).join(format!("{}", folder_id));
+
+        if session_dir.exists() {
+            fs::remove_dir_all(&session_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// remove a finished session from the index, blocks are left in place
+    pub fn delete_session(&self, session_id: u64) -> Result<(), SDAPIError> {
+        let mut index = self.index.lock();
+
+        let position = match index.sessions.iter().position(|session| session.id == session_id) {
+            Some(position) => position,
+            None => return Err(SDAPIError::SessionMissing),
+        };
+
+        let session = index.sessions.remove(position);
+
+        self.save_index(&index)?;
+
+        let session_path = self.session_path(session.folder_id, &session.name)?;
+
+        if session_path.exists() {
+            fs::remove_file(&session_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn save_index(&self, index: &LocalIndex) -> Result<(), SDAPIError> {
+        let index_path = self.root.join(INDEX_FILE);
+        let temp_path = self.root.join(format!("{}.tmp", INDEX_FILE));
+
+        let data = ::serde_json::to_vec(index)?;
+
+        {
+            let mut f = File::create(&temp_path)?;
+            f.write_all(&data)?;
+            f.sync_all()?;
+        }
+
+        fs::rename(&temp_path, &index_path)?;
+
+        Ok(())
+    }
+
+    fn block_path(&self, name: &str) -> Result<PathBuf, SDAPIError> {
+        if name.len() < 2 || !name.chars().all(|c| c.is_digit(16)) {
+            return Err(SDAPIError::Internal(format!("invalid block name: {}", name)));
+        }
+
+        let mut p = self.root.join(BLOCK_DIR);
+        p.push(&name[0..2]);
+        p.push(name);
+
+        Ok(p)
+    }
+
+    fn session_path(&self, folder_id: u64, name: &str) -> Result<PathBuf, SDAPIError> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+            return Err(SDAPIError::Internal(format!("invalid session name: {}", name)));
+        }
+
+        let mut p = self.root.join(SESSION_DIR);
+        p.push(format!("{}", folder_id));
+        p.push(name);
+
+        Ok(p)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn account_key(&self, new_wrapped_keyset: &WrappedKeyset) -> Result<WrappedKeyset, SDAPIError> {
+        let mut index = self.index.lock();
+
+        // same rules as the server: the first keyset stored wins, every later call gets it back
+        if let Some(ref keyset) = index.keyset {
+            return Ok(keyset.clone());
+        }
+
+        index.keyset = Some(new_wrapped_keyset.clone());
+
+        self.save_index(&index)?;
+
+        Ok(new_wrapped_keyset.clone())
+    }
+
+    fn read_folders(&self) -> Result<Vec<RegisteredFolder>, SDAPIError> {
+        let index = self.index.lock();
+
+        let folders = index.folders.clone();
+
+        Ok(folders)
+    }
+
+    fn read_sessions(&self) -> Result<HashMap<String, HashMap<u64, Vec<SyncSession>>>, SDAPIError> {
+        let index = self.index.lock();
+
+        let mut details: HashMap<u64, Vec<&LocalSession>> = HashMap::new();
+
+        for session in index.sessions.iter().filter(|session| session.finished) {
+            details.entry(session.folder_id).or_insert_with(Vec::new).push(session);
+        }
+
+        let mut response: HashMap<&str, HashMap<u64, Vec<&LocalSession>>> = HashMap::new();
+        response.insert("sessionDetails", details);
+
+        // go through the same representation the API returns, SyncSession is only ever built
+        // by deserializing it
+        let raw = ::serde_json::to_string(&response)?;
+        let sessions: HashMap<String, HashMap<u64, Vec<SyncSession>>> = ::serde_json::from_str(&raw)?;
+
+        Ok(sessions)
+    }
+
+    fn register_sync_session(&self, folder_id: u64, name: &str, _encrypted: bool) -> Result<(), SDAPIError> {
+        let mut index = self.index.lock();
+
+        if !index.folders.iter().any(|folder| folder.id == folder_id) {
+            return Err(SDAPIError::Internal(format!("unexpected failure to find folder_id {}", folder_id)));
+        }
+
+        if index.sessions.iter().any(|session| session.name == name) {
+            return Err(SDAPIError::Conflict);
+        }
+
+        let session_id = index.next_session_id;
+        index.next_session_id += 1;
+
+        index.sessions.push(LocalSession {
+            id: session_id,
+            folder_id: folder_id,
+            name: name.to_string(),
+            size: None,
+            time: None,
+            finished: false,
+        });
+
+        self.save_index(&index)
+    }
+
+    fn finish_sync_session(&self, folder_id: u64, _encrypted: bool, session: &SessionFile, size: usize, mut progress: BandwidthCallback) -> Result<(), SDAPIError> {
+        let name = &session.name;
+
+        let session_path = self.session_path(folder_id, name)?;
+
+        let mut index = self.index.lock();
+
+        let position = match index.sessions.iter().position(|s| s.name == *name && s.folder_id == folder_id) {
+            Some(position) => position,
+            None => return Err(SDAPIError::SessionMissing),
+        };
+
+        if let Some(parent) = session_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let written = write_atomic(&session_path, session.open()?)?;
+
+        progress(written);
+
+        {
+            let s = &mut index.sessions[position];
+            s.size = Some(size as u64);
+            s.time = Some(now_ms());
+            s.finished = true;
+        }
+
+        self.save_index(&index)
+    }
+
+    fn read_session<'a>(&self, folder_id: u64, name: &'a str, _encrypted: bool) -> Result<SyncSessionResponse<'a>, SDAPIError> {
+        {
+            let index = self.index.lock();
+
+            if !index.sessions.iter().any(|s| s.name == name && s.folder_id == folder_id && s.finished) {
+                return Err(SDAPIError::SessionMissing);
+            }
+        }
+
+        let session_path = self.session_path(folder_id, name)?;
+
+        let f = match File::open(&session_path) {
+            Ok(f) => f,
+            Err(_) => return Err(SDAPIError::SessionMissing),
+        };
+
+        Ok(SyncSessionResponse {
+            name: name,
+            body: Box::new(f),
+            folder_id: folder_id,
+        })
+    }
+
+    fn check_block(&self, name: &str) -> Result<bool, SDAPIError> {
+        let block_path = self.block_path(name)?;
+
+        Ok(block_path.exists())
+    }
+
+    fn write_blocks(&self, _session: &str, blocks: &[WrappedBlock], mut progress: BandwidthCallback) -> Result<Vec<String>, SDAPIError> {
+        let mut missing: Vec<String> = Vec::new();
+
+        for block in blocks {
+            let name = block.name();
+            let block_path = self.block_path(&name)?;
+
+            if block_path.exists() {
+                continue;
+            }
+
+            // same as the server: a block sent without data that we don't already have gets
+            // reported back so the caller can send it again with the data included
+            if !block.should_include_data() {
+                missing.push(name);
+                continue;
+            }
+
+            if let Some(parent) = block_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let data = block.as_binary();
+
+            write_atomic(&block_path, data.as_slice())?;
+
+            progress(data.len() as u64);
+        }
+
+        Ok(missing)
+    }
+
+    fn read_block(&self, name: &str) -> Result<Vec<u8>, SDAPIError> {
+        let block_path = self.block_path(name)?;
+
+        let mut f = match File::open(&block_path) {
+            Ok(f) => f,
+            Err(_) => return Err(SDAPIError::BlockMissing),
+        };
+
+        let mut buffer = Vec::new();
+        f.read_to_end(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn list_blocks(&self) -> Result<Option<Vec<StoredBlock>>, SDAPIError> {
+        let mut blocks = Vec::new();
+
+        for prefix in fs::read_dir(self.root.join(BLOCK_DIR))? {
+            let prefix = prefix?;
+
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+
+            for item in fs::read_dir(prefix.path())? {
+                let item = item?;
+
+                let name = match item.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+
+                // skip blocks that are still being written
+                if name.starts_with('.') {
+                    continue;
+                }
+
+                let md = item.metadata()?;
+
+                let modified = md.modified().ok()
+                    .and_then(|m| m.duration_since(::std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000);
+
+                blocks.push(StoredBlock {
+                    name: name,
+                    size: md.len(),
+                    modified: modified,
+                });
+            }
+        }
+
+        Ok(Some(blocks))
+    }
+
+    fn remove_blocks(&self, names: &[String]) -> Result<(), SDAPIError> {
+        for name in names {
+            let block_path = self.block_path(name)?;
+
+            match fs::remove_file(&block_path) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(SDAPIError::from(e)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// write a file under a temporary name first so readers never see a partial file
+fn write_atomic<R: Read>(path: &Path, mut data: R) -> Result<u64, SDAPIError> {
+    let temp_name = format!(".{}.tmp", ::util::generate_uuid());
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = {
+        let mut f = File::create(&temp_path)?;
+        let written = ::std::io::copy(&mut data, &mut f)?;
+        f.sync_all()?;
+
+        written
+    };
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(written)
+}
+
+fn now_ms() -> u64 {
+    let now = ::chrono::Utc::now();
+
+    ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64
+}
+
+#[cfg(test)]
+fn test_repository() -> LocalBackend {
+    let mut p = ::std::env::temp_dir();
+    p.push(format!("sddk-local-{}", ::util::generate_uuid()));
+
+    LocalBackend::open(&p).expect("failed to open local repository")
+}
+
+#[test]
+fn local_write_read_block_test() {
+    use block::Block;
+    use keys::{Key, KeyType};
+    use models::SyncVersion;
+
+    let main = Key::new(KeyType::Main);
+    let hmac = Key::new(KeyType::HMAC);
+
+    let repository = test_repository();
+
+    let block = Block::new(SyncVersion::Version2, &hmac, vec![7u8; 1024]);
+    let name = block.name();
+    let mut wrapped_block = block.to_wrapped(&main).expect("failed to wrap block");
+
+    // without data, a block that isn't stored yet must be reported missing
+    let missing = repository.write_blocks("session", &[wrapped_block.clone()], Box::new(|_| {})).expect("failed to write blocks");
+    assert!(missing == vec![name.clone()]);
+    assert!(repository.check_block(&name).unwrap() == false);
+
+    wrapped_block.needs_upload();
+
+    let missing = repository.write_blocks("session", &[wrapped_block], Box::new(|_| {})).expect("failed to write blocks");
+    assert!(missing.len() == 0);
+    assert!(repository.check_block(&name).unwrap() == true);
+
+    let raw = repository.read_block(&name).expect("failed to read block");
+    let read_block = WrappedBlock::from(raw, block_hmac_bytes(&name)).expect("failed to parse block");
+    let unwrapped = read_block.to_block(&main).expect("failed to unwrap block");
+
+    assert!(unwrapped.as_ref() == vec![7u8; 1024].as_slice());
+
+    let _ = fs::remove_dir_all(repository.root());
+}
+
+#[test]
+fn local_session_test() {
+    use keys::{Key, KeyType};
+    use models::SyncVersion;
+
+    let main = Key::new(KeyType::Main);
+
+    let repository = test_repository();
+
+    let folder_id = repository.create_folder("/tmp/test", "test", true).expect("failed to create folder");
+
+    assert!(repository.register_sync_session(folder_id, "test-session", true).is_ok());
+
+    // sessions aren't listed until they're finished
+    let sessions = repository.read_sessions().expect("failed to read sessions");
+    assert!(sessions["sessionDetails"].len() == 0);
+
+    let archive = vec![3u8; 2048];
+    let session_file = ::session::write_session_file_in(repository.root(), SyncVersion::Version3, folder_id, "test-session", &main, archive.as_slice()).expect("failed to write session");
+
+    repository.finish_sync_session(folder_id, true, &session_file, 0, Box::new(|_| {})).expect("failed to finish session");
+
+    let sessions = repository.read_sessions().expect("failed to read sessions");
+    assert!(sessions["sessionDetails"][&folder_id].len() == 1);
+
+    let body = repository.read_session(folder_id, "test-session", true).expect("failed to read session");
+    let unwrapped = ::session::read_session_file_in(repository.root(), body, &main).expect("failed to read session");
+
+    let mut data = Vec::new();
+    unwrapped.reader().expect("failed to open session").read_to_end(&mut data).expect("failed to read session data");
+
+    assert!(data == archive);
+
+    let _ = fs::remove_dir_all(repository.root());
+}
+
+#[cfg(test)]
+fn block_hmac_bytes(name: &str) -> Vec<u8> {
+    use rustc_serialize::hex::FromHex;
+
+    name.from_hex().unwrap()
+}
@@ -0,0 +1,498 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str;
+
+// external crate imports
+
+use filetime::FileTime;
+use tar::{Builder, Entry, EntryType, Header};
+
+// internal imports
+
+use error::SDError;
+
+/// prefix used for extended attributes in PAX records, the same one GNU tar and star use
+static PAX_XATTR_PREFIX: &'static str = "SCHILY.xattr.";
+
+/// prefix used for the PAX records describing a whole session
+static PAX_SESSION_PREFIX: &'static str = "SAFEDRIVE.";
+
+/// PAX record holding the real size of each block of a file, in the order of its HMAC list
+static PAX_BLOCK_SIZES: &'static str = "SAFEDRIVE.blocksizes";
+
+/// Metadata for a session entry, applied to the restored file once its contents are written
+///
+/// Permissions, ownership and times come from the tar header. Extended attributes come from
+/// the PAX records written in front of the entry during sync, which on Linux also carry POSIX
+/// ACLs, since those are stored in the `system.posix_acl_access` and `system.posix_acl_default`
+/// attributes.
+#[derive(Debug, Clone)]
+pub struct EntryMetadata {
+    entry_type: EntryType,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u64>,
+    atime: Option<u64>,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl EntryMetadata {
+    pub fn from_entry<'a, R: Read>(entry: &mut Entry<'a, R>) -> EntryMetadata {
+        let mut xattrs = Vec::new();
+
+        if let Ok(Some(extensions)) = entry.pax_extensions() {
+            for extension in extensions {
+                let extension = match extension {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+
+                let key = match extension.key() {
+                    Ok(k) => k,
+                    Err(_) => continue,
+                };
+
+                if key.starts_with(PAX_XATTR_PREFIX) {
+                    xattrs.push((key[PAX_XATTR_PREFIX.len()..].to_string(), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+
+        let header = entry.header();
+
+        let mtime = header.mtime().ok().map(|m| m as u64);
+
+        // not every header records an access time, use the modification time when it's missing
+        let atime = match header.as_gnu().and_then(|gnu| gnu.atime().ok()) {
+            Some(atime) if atime > 0 => Some(atime as u64),
+            _ => mtime,
+        };
+
+        EntryMetadata {
+            entry_type: header.entry_type(),
+            mode: header.mode().ok().map(|m| m as u32 & 0o7777),
+            uid: header.uid().ok().map(|u| u as u32),
+            gid: header.gid().ok().map(|g| g as u32),
+            mtime: mtime,
+            atime: atime,
+            xattrs: xattrs,
+        }
+    }
+
+    /// apply the metadata to a restored item
+    ///
+    /// Every part is attempted even if an earlier one fails, and the first failure is returned.
+    /// Ownership is only changed when running as root, and permissions and times are not
+    /// applied to symlinks since most platforms would apply them to the link target instead.
+    pub fn apply(&self, path: &Path) -> Result<(), SDError> {
+        let mut result: Result<(), SDError> = Ok(());
+
+        let is_symlink = self.entry_type == EntryType::Symlink;
+
+        if let Err(e) = self.apply_xattrs(path) {
+            result = result.and(Err(e));
+        }
+
+        if let Err(e) = self.apply_ownership(path) {
+            result = result.and(Err(e));
+        }
+
+        if !is_symlink {
+            if let Err(e) = self.apply_mode(path) {
+                result = result.and(Err(e));
+            }
+
+            // times go last, everything else can change them
+            if let (Some(atime), Some(mtime)) = (self.atime, self.mtime) {
+                let atime = FileTime::from_seconds_since_1970(atime, 0);
+                let mtime = FileTime::from_seconds_since_1970(mtime, 0);
+
+                if let Err(e) = ::filetime::set_file_times(path, atime, mtime) {
+                    result = result.and(Err(SDError::from(e)));
+                }
+            }
+        }
+
+        result
+    }
+
+    #[cfg(unix)]
+    fn apply_xattrs(&self, path: &Path) -> Result<(), SDError> {
+        let mut result: Result<(), SDError> = Ok(());
+
+        for &(ref name, ref value) in &self.xattrs {
+            if let Err(e) = ::xattr::set(path, name, value) {
+                debug!("failed to set extended attribute {} on {}: {}", name, path.display(), e);
+
+                result = result.and(Err(SDError::from(e)));
+            }
+        }
+
+        result
+    }
+
+    #[cfg(not(unix))]
+    fn apply_xattrs(&self, _: &Path) -> Result<(), SDError> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn apply_ownership(&self, path: &Path) -> Result<(), SDError> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        if unsafe { ::libc::geteuid() } != 0 {
+            return Ok(());
+        }
+
+        let (uid, gid) = match (self.uid, self.gid) {
+            (Some(uid), Some(gid)) => (uid, gid),
+            _ => return Ok(()),
+        };
+
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_) => return Err(SDError::Internal(format!("invalid path {}", path.display()))),
+        };
+
+        if unsafe { ::libc::lchown(c_path.as_ptr(), uid as ::libc::uid_t, gid as ::libc::gid_t) } != 0 {
+            return Err(SDError::from(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_ownership(&self, _: &Path) -> Result<(), SDError> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn apply_mode(&self, path: &Path) -> Result<(), SDError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = self.mode {
+            ::std::fs::set_permissions(path, ::std::fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_mode(&self, path: &Path) -> Result<(), SDError> {
+        // the closest thing to a mode on other platforms is the read only flag
+        if let Some(mode) = self.mode {
+            let mut permissions = ::std::fs::metadata(path)?.permissions();
+            permissions.set_readonly(mode & 0o222 == 0);
+            ::std::fs::set_permissions(path, permissions)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// build PAX records holding the extended attributes of a file, without following symlinks
+///
+/// Returns an empty list if the file has no extended attributes or they can't be read.
+#[cfg(unix)]
+pub fn pax_records(path: &Path) -> Vec<u8> {
+    let mut records = Vec::new();
+
+    let names = match ::xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return records,
+    };
+
+    for name in names {
+        let name = match name.to_str() {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+
+        let value = match ::xattr::get(path, &name) {
+            Ok(Some(v)) => v,
+            _ => continue,
+        };
+
+        let key = format!("{}{}", PAX_XATTR_PREFIX, name);
+
+        append_pax_record(&mut records, &key, &value);
+    }
+
+    records
+}
+
+#[cfg(not(unix))]
+pub fn pax_records(_: &Path) -> Vec<u8> {
+    Vec::new()
+}
+
+// each record is "<length> <key>=<value>\n", where the length includes itself
+fn append_pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3; // space, equals sign and newline
+
+    let mut length = rest + 1;
+    while format!("{}", length).len() + rest != length {
+        length += 1;
+    }
+
+    records.extend_from_slice(format!("{} {}=", length, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// add a record of the real size of each block of a file to its PAX records
+///
+/// The sizes are written as a comma separated list of decimal numbers, so that a file can be read
+/// from the middle without downloading the blocks in front of it first.
+pub fn append_block_sizes(records: &mut Vec<u8>, sizes: &[u64]) {
+    if sizes.is_empty() {
+        return;
+    }
+
+    let value: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+
+    append_pax_record(records, PAX_BLOCK_SIZES, value.join(",").as_bytes());
+}
+
+/// read the block sizes recorded by `append_block_sizes()` for an entry, if there are any
+///
+/// Older sessions don't record block sizes, and neither does anything that isn't a regular file.
+pub fn block_sizes<'a, R: Read>(entry: &mut Entry<'a, R>) -> Option<Vec<u64>> {
+    let extensions = match entry.pax_extensions() {
+        Ok(Some(extensions)) => extensions,
+        _ => return None,
+    };
+
+    for extension in extensions {
+        let extension = match extension {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        match extension.key() {
+            Ok(key) if key == PAX_BLOCK_SIZES => {},
+            _ => continue,
+        }
+
+        let value = match extension.value() {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+
+        return value.split(',').map(|size| size.parse().ok()).collect();
+    }
+
+    None
+}
+
+/// record the size of a file's contents in its session entry header
+///
+/// The size field of a session entry holds the length of its HMAC list, so the real size goes in
+/// the GNU `realsize` field, which is otherwise only used for sparse files. Sizes too large for
+/// octal use the GNU base-256 encoding.
+pub fn set_real_size(header: &mut Header, size: u64) {
+    let gnu = match header.as_gnu_mut() {
+        Some(gnu) => gnu,
+        None => return,
+    };
+
+    let field = &mut gnu.realsize;
+
+    let octal = format!("{:011o}", size);
+
+    if octal.len() <= 11 {
+        field[..11].copy_from_slice(octal.as_bytes());
+        field[11] = 0;
+    } else {
+        field[0] = 0x80;
+        for i in 0..8 {
+            field[11 - i] = (size >> (i * 8)) as u8;
+        }
+        for b in field[1..4].iter_mut() {
+            *b = 0;
+        }
+    }
+}
+
+/// read the size recorded by `set_real_size()`, if there is one
+pub fn real_size(header: &Header) -> Option<u64> {
+    let gnu = match header.as_gnu() {
+        Some(gnu) => gnu,
+        None => return None,
+    };
+
+    let field = &gnu.realsize;
+
+    if field[0] & 0x80 != 0 {
+        let mut size: u64 = 0;
+        for b in &field[4..] {
+            size = (size << 8) | *b as u64;
+        }
+        return Some(size);
+    }
+
+    let digits: String = field.iter().take_while(|b| **b != 0 && **b != b' ').map(|b| *b as char).collect();
+
+    if digits.is_empty() {
+        return None;
+    }
+
+    u64::from_str_radix(&digits, 8).ok()
+}
+
+/// append an entry to a session, preceded by its PAX records if it has any
+pub fn append_entry<W: Write, P: AsRef<Path>>(ar: &mut Builder<W>, header: &mut Header, path: P, data: &[u8], pax_records: &[u8]) -> io::Result<()> {
+    if !pax_records.is_empty() {
+        let mut pax_header = Header::new_gnu();
+        pax_header.set_path("PaxHeader")?;
+        pax_header.set_entry_type(EntryType::XHeader);
+        pax_header.set_mode(0o644);
+        pax_header.set_size(pax_records.len() as u64);
+        pax_header.set_cksum();
+
+        ar.append(&pax_header, pax_records)?;
+    }
+
+    ar.append_data(header, path, data)
+}
+
+/// start a session with a PAX global header holding information about the session as a whole
+///
+/// Keys are stored with a `SAFEDRIVE.` prefix. The header has to come before any entry, and
+/// readers of the session skip it since it doesn't describe anything in the folder.
+pub fn append_session_records<W: Write>(ar: &mut Builder<W>, records: &[(&str, &[u8])]) -> io::Result<()> {
+    let mut data = Vec::new();
+
+    for &(key, value) in records {
+        append_pax_record(&mut data, &format!("{}{}", PAX_SESSION_PREFIX, key), value);
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_path("GlobalHead")?;
+    header.set_entry_type(EntryType::XGlobalHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+
+    ar.append(&header, data.as_slice())
+}
+
+/// find a record written by `append_session_records()` in the data of a global header entry
+pub fn session_record(data: &[u8], key: &str) -> Option<Vec<u8>> {
+    let key = format!("{}{}", PAX_SESSION_PREFIX, key);
+
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let space = match rest.iter().position(|b| *b == b' ') {
+            Some(i) => i,
+            None => return None,
+        };
+
+        let length: usize = match str::from_utf8(&rest[..space]).ok().and_then(|l| l.parse().ok()) {
+            Some(l) => l,
+            None => return None,
+        };
+
+        if length <= space + 1 || length > rest.len() {
+            return None;
+        }
+
+        // without the length, the space and the trailing newline
+        let record = &rest[space + 1..length - 1];
+
+        if let Some(equals) = record.iter().position(|b| *b == b'=') {
+            if &record[..equals] == key.as_bytes() {
+                return Some(record[equals + 1..].to_vec());
+            }
+        }
+
+        rest = &rest[length..];
+    }
+
+    None
+}
+
+#[test]
+fn real_size_test() {
+    let mut header = Header::new_gnu();
+    assert!(real_size(&header) == None);
+
+    set_real_size(&mut header, 1234567);
+    assert!(real_size(&header) == Some(1234567));
+
+    // larger than 11 octal digits can hold
+    let large = 20_000_000_000_000u64;
+    set_real_size(&mut header, large);
+    assert!(real_size(&header) == Some(large));
+}
+
+#[test]
+fn pax_record_length_test() {
+    let mut records = Vec::new();
+    append_pax_record(&mut records, "SCHILY.xattr.user.test", b"value");
+
+    let record = String::from_utf8(records.clone()).unwrap();
+    let length: usize = record.split(' ').next().unwrap().parse().unwrap();
+
+    assert!(length == records.len());
+
+    // a record whose length gains a digit once the length itself is counted
+    let mut records = Vec::new();
+    append_pax_record(&mut records, "k", &[b'v'; 93]);
+
+    let record = String::from_utf8(records.clone()).unwrap();
+    let length: usize = record.split(' ').next().unwrap().parse().unwrap();
+
+    assert!(length == records.len());
+}
+
+#[test]
+fn session_record_test() {
+    let mut ar = Builder::new(Vec::new());
+    append_session_records(&mut ar, &[("rules", "{\"skip_hidden\":true}".as_bytes()), ("empty", "".as_bytes())]).unwrap();
+    let data = ar.into_inner().unwrap();
+
+    let mut archive = ::tar::Archive::new(data.as_slice());
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert!(entry.header().entry_type() == EntryType::XGlobalHeader);
+
+    let mut records = Vec::new();
+    entry.read_to_end(&mut records).unwrap();
+
+    assert!(session_record(&records, "rules") == Some(b"{\"skip_hidden\":true}".to_vec()));
+    assert!(session_record(&records, "empty") == Some(Vec::new()));
+    assert!(session_record(&records, "missing") == None);
+}
+
+#[test]
+fn block_sizes_test() {
+    let mut ar = Builder::new(Vec::new());
+
+    let mut records = Vec::new();
+    append_pax_record(&mut records, "SCHILY.xattr.user.test", b"value");
+    append_block_sizes(&mut records, &[65536, 1024, 7]);
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(4);
+
+    append_entry(&mut ar, &mut header, "sized", b"data", &records).unwrap();
+    append_entry(&mut ar, &mut header, "unsized", b"data", &[]).unwrap();
+
+    let data = ar.into_inner().unwrap();
+
+    let mut archive = ::tar::Archive::new(data.as_slice());
+    let mut entries = archive.entries().unwrap();
+
+    let mut entry = entries.next().unwrap().unwrap();
+    assert!(entry.path().unwrap().to_str() == Some("sized"));
+    assert!(block_sizes(&mut entry) == Some(vec![65536, 1024, 7]));
+
+    let mut entry = entries.next().unwrap().unwrap();
+    assert!(entry.path().unwrap().to_str() == Some("unsized"));
+    assert!(block_sizes(&mut entry) == None);
+}
@@ -0,0 +1,945 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// external crate imports
+
+use parking_lot::Mutex;
+use serde_json::Value;
+
+/// internal imports
+
+use constants::{Configuration, MULTIPART_BOUNDARY};
+use models::{RegisteredFolder, Token};
+
+struct MockSession {
+    id: u64,
+    folder_id: u64,
+    name: String,
+    size: Option<u64>,
+    time: Option<u64>,
+    data: Option<Vec<u8>>,
+}
+
+struct MockState {
+    tokens: Vec<String>,
+    keyset: Option<Value>,
+    folders: Vec<RegisteredFolder>,
+    sessions: Vec<MockSession>,
+    blocks: HashMap<String, Vec<u8>>,
+    next_id: u64,
+    failures: Vec<u16>,
+    requests: u64,
+    block_writes: u64,
+    reject_sessions: bool,
+}
+
+impl MockState {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        id
+    }
+}
+
+struct MockRequest {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct MockResponse {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl MockResponse {
+    fn new(status: u16, body: Vec<u8>) -> MockResponse {
+        MockResponse {
+            status: status,
+            body: body,
+        }
+    }
+
+    fn empty(status: u16) -> MockResponse {
+        MockResponse::new(status, Vec::new())
+    }
+
+    fn json(status: u16, value: &Value) -> MockResponse {
+        MockResponse::new(status, ::serde_json::to_vec(value).expect("failed to serialize mock response"))
+    }
+
+    fn error(status: u16, message: &str) -> MockResponse {
+        let mut m = ::serde_json::Map::new();
+        m.insert("message".to_string(), Value::String(message.to_string()));
+
+        MockResponse::json(status, &Value::Object(m))
+    }
+}
+
+/// An in-process stand-in for the SafeDrive API
+///
+/// Implements the `/api/1/...` routes used by `sdapi` closely enough to sync, restore, and
+/// manage folders and sessions without the real service, on a random port on the loopback
+/// interface. State is kept in memory and discarded when the server is dropped.
+///
+/// Point the SDK at it by setting the configuration returned by `configuration()`.
+///
+/// Failures can be injected with `fail_requests()`, which makes the next requests return a
+/// specific HTTP status, for example 503 to exercise the retry paths.
+pub struct MockServer {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock api server");
+        let address = listener.local_addr().expect("failed to get mock api server address");
+
+        let state = Arc::new(Mutex::new(MockState {
+            tokens: Vec::new(),
+            keyset: None,
+            folders: Vec::new(),
+            sessions: Vec::new(),
+            blocks: HashMap::new(),
+            next_id: 1,
+            failures: Vec::new(),
+            requests: 0,
+            block_writes: 0,
+            reject_sessions: false,
+        }));
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let l_state = state.clone();
+        let l_stop = stop.clone();
+
+        thread::spawn(move || {
+            debug!("mock api server listening on {}", address);
+
+            for stream in listener.incoming() {
+                if l_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("mock api server connection failed: {}", e);
+                        continue;
+                    },
+                };
+
+                let c_state = l_state.clone();
+
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &c_state) {
+                        debug!("mock api server request failed: {}", e);
+                    }
+                });
+            }
+
+            debug!("mock api server stopped");
+        });
+
+        MockServer {
+            address: address,
+            state: state,
+            stop: stop,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// the configuration that points `sdapi` at this server
+    pub fn configuration(&self) -> Configuration {
+        Configuration::Custom {
+            api_domain: format!("{}", self.address),
+            secure: false,
+        }
+    }
+
+    /// make the next `count` requests fail with the HTTP status `status`
+    pub fn fail_requests(&self, count: usize, status: u16) {
+        let mut state = self.state.lock();
+
+        for _ in 0..count {
+            state.failures.push(status);
+        }
+    }
+
+    /// register a token without going through the client registration route
+    pub fn token(&self) -> Token {
+        let token = ::util::generate_uuid();
+
+        let mut state = self.state.lock();
+        state.tokens.push(token.clone());
+
+        Token {
+            token: token,
+        }
+    }
+
+    /// drop a stored block, so that later requests for it behave as if it was never uploaded
+    pub fn remove_block(&self, name: &str) -> bool {
+        let mut state = self.state.lock();
+
+        state.blocks.remove(name).is_some()
+    }
+
+    pub fn block_count(&self) -> usize {
+        let state = self.state.lock();
+
+        state.blocks.len()
+    }
+
+    pub fn session_count(&self) -> usize {
+        let state = self.state.lock();
+
+        state.sessions.iter().filter(|s| s.data.is_some()).count()
+    }
+
+    pub fn request_count(&self) -> u64 {
+        let state = self.state.lock();
+
+        state.requests
+    }
+
+    /// number of block write requests received, whether or not they carried block data
+    pub fn block_write_count(&self) -> u64 {
+        let state = self.state.lock();
+
+        state.block_writes
+    }
+
+    /// make session uploads fail as if the service was unavailable, so a sync stops just before
+    /// finishing
+    pub fn reject_sessions(&self, reject: bool) {
+        let mut state = self.state.lock();
+
+        state.reject_sessions = reject;
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        // wake the listener up so it notices it should stop
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Arc<Mutex<MockState>>) -> ::std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let request = match read_request(&mut reader)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    trace!("mock api server: {} {}", request.method, request.path);
+
+    let response = route(&request, state);
+
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+
+    let content_type = if response.body.first() == Some(&b'{') || response.body.first() == Some(&b'[') {
+        "application/json"
+    } else {
+        "application/octet-stream"
+    };
+
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n", response.status, reason, response.body.len(), content_type);
+
+    writer.write_all(head.as_bytes())?;
+
+    if request.method != "HEAD" {
+        writer.write_all(&response.body)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> ::std::io::Result<Option<MockRequest>> {
+    let mut request_line = String::new();
+
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = request_line.trim_right().split(' ');
+
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").to_string();
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let line = line.trim_right();
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(i) = line.find(':') {
+            headers.insert(line[..i].trim().to_lowercase(), line[i + 1..].trim().to_string());
+        }
+    }
+
+    let mut body = Vec::new();
+
+    if headers.get("transfer-encoding").map(|te| te.to_lowercase() == "chunked").unwrap_or(false) {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+
+            let size = usize::from_str_radix(size_line.trim(), 16).unwrap_or(0);
+
+            if size == 0 {
+                let mut end = String::new();
+                reader.read_line(&mut end)?;
+                break;
+            }
+
+            let mut chunk = vec![0u8; size];
+            reader.read_exact(&mut chunk)?;
+            body.extend(chunk);
+
+            let mut end = String::new();
+            reader.read_line(&mut end)?;
+        }
+    } else if let Some(length) = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()) {
+        body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_string(), parse_query(&target[i + 1..])),
+        None => (target.clone(), HashMap::new()),
+    };
+
+    Ok(Some(MockRequest {
+        method: method,
+        path: path,
+        query: query,
+        headers: headers,
+        body: body,
+    }))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut q = HashMap::new();
+
+    for pair in query.split('&') {
+        let mut kv = pair.splitn(2, '=');
+
+        if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+            q.insert(k.to_string(), v.to_string());
+        }
+    }
+
+    q
+}
+
+/// split a body produced by `sdapi::multipart_for_binary` into (filename, data) pairs
+fn parse_multipart(body: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let delimiter = format!("\r\n--{}", MULTIPART_BOUNDARY);
+    let delimiter = delimiter.as_bytes();
+
+    let mut positions: Vec<usize> = Vec::new();
+    let mut i = 0;
+
+    while i + delimiter.len() <= body.len() {
+        if &body[i..i + delimiter.len()] == delimiter {
+            positions.push(i);
+            i += delimiter.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut parts = Vec::new();
+
+    for window in positions.windows(2) {
+        let part = &body[window[0] + delimiter.len()..window[1]];
+
+        // skip the line break after the boundary, then split headers from data
+        let part = if part.starts_with(b"\r\n") { &part[2..] } else { part };
+
+        let header_end = match part.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(p) => p,
+            None => continue,
+        };
+
+        let headers = String::from_utf8_lossy(&part[..header_end]).to_string();
+        let data = part[header_end + 4..].to_vec();
+
+        let filename = match headers.find("filename=\"") {
+            Some(start) => {
+                let rest = &headers[start + 10..];
+                match rest.find('"') {
+                    Some(end) => rest[..end].to_string(),
+                    None => continue,
+                }
+            },
+            None => continue,
+        };
+
+        parts.push((filename, data));
+    }
+
+    parts
+}
+
+fn now_ms() -> u64 {
+    let now = ::chrono::Utc::now();
+
+    ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64
+}
+
+fn route(request: &MockRequest, state: &Arc<Mutex<MockState>>) -> MockResponse {
+    let mut state = state.lock();
+
+    state.requests += 1;
+
+    if !state.failures.is_empty() {
+        let status = state.failures.remove(0);
+
+        return MockResponse::empty(status);
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    if segments.len() < 2 || segments[0] != "api" || segments[1] != "1" {
+        return MockResponse::empty(404);
+    }
+
+    let count = segments.len() - 2;
+
+    // pad the path so it can be matched as a tuple, the longest route has 5 segments after "/api/1"
+    let mut s: Vec<&str> = segments[2..].to_vec();
+    while s.len() < 5 {
+        s.push("");
+    }
+
+    let method: &str = &request.method;
+
+    // routes that don't need a token
+    match (method, count, s[0], s[1]) {
+        ("POST", 2, "client", "register") => {
+            let token = ::util::generate_uuid();
+            state.tokens.push(token.clone());
+
+            let mut m = ::serde_json::Map::new();
+            m.insert("token".to_string(), Value::String(token));
+
+            return MockResponse::json(200, &Value::Object(m));
+        },
+        ("POST", 2, "error", "log") => return MockResponse::empty(200),
+        _ => {},
+    }
+
+    let authenticated = match request.headers.get("sd-auth-token") {
+        Some(token) => state.tokens.contains(token),
+        None => false,
+    };
+
+    if !authenticated {
+        return MockResponse::error(401, "authentication failed");
+    }
+
+    let body: Value = ::serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+
+    match (method, count, s[0], s[1], s[2], s[3]) {
+        ("POST", 2, "client", "unRegister", _, _) => MockResponse::empty(200),
+        ("GET", 2, "account", "status", _, _) => {
+            let status = ::serde_json::from_str::<Value>(r#"{"status": "active", "host": "127.0.0.1", "port": 22, "userName": "mock", "time": null}"#).unwrap();
+
+            MockResponse::json(200, &status)
+        },
+        ("GET", 2, "account", "details", _, _) => {
+            let details = ::serde_json::from_str::<Value>(r#"{"assignedStorage": 107374182400, "usedStorage": 0, "lowFreeStorageThreshold": 1073741824, "expirationDate": 0}"#).unwrap();
+
+            MockResponse::json(200, &details)
+        },
+        ("POST", 2, "account", "key", _, _) => {
+            if state.keyset.is_none() {
+                state.keyset = Some(body);
+            }
+
+            let keyset = state.keyset.clone().unwrap();
+
+            MockResponse::json(200, &keyset)
+        },
+        ("GET", 1, "folder", _, _, _) => {
+            let folders = ::serde_json::to_value(&state.folders).unwrap();
+
+            MockResponse::json(200, &folders)
+        },
+        ("POST", 1, "folder", _, _, _) => {
+            let name = body["folderName"].as_str().unwrap_or("").to_string();
+            let path = body["folderPath"].as_str().unwrap_or("").to_string();
+
+            if state.folders.iter().any(|f| f.folderName == name || f.folderPath == path) {
+                return MockResponse::error(409, "folder already exists");
+            }
+
+            let id = state.next_id();
+
+            state.folders.push(RegisteredFolder {
+                id: id,
+                folderName: name,
+                folderPath: path,
+                addedDate: now_ms(),
+                encrypted: body["encrypted"].as_bool().unwrap_or(true),
+                syncing: body["syncing"].as_bool().unwrap_or(true),
+            });
+
+            let mut m = ::serde_json::Map::new();
+            m.insert("id".to_string(), Value::from(id));
+
+            MockResponse::json(200, &Value::Object(m))
+        },
+        ("PUT", 1, "folder", _, _, _) => {
+            let id = body["id"].as_u64().unwrap_or(0);
+
+            match state.folders.iter_mut().find(|f| f.id == id) {
+                Some(folder) => {
+                    folder.folderName = body["folderName"].as_str().unwrap_or("").to_string();
+                    folder.folderPath = body["folderPath"].as_str().unwrap_or("").to_string();
+                    folder.syncing = body["syncing"].as_bool().unwrap_or(true);
+
+                    MockResponse::empty(200)
+                },
+                None => MockResponse::error(400, "folder not found"),
+            }
+        },
+        ("DELETE", 1, "folder", _, _, _) => {
+            let ids: Vec<u64> = request.query.get("folderIds").map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect()).unwrap_or(Vec::new());
+
+            state.folders.retain(|f| !ids.contains(&f.id));
+            state.sessions.retain(|s| !ids.contains(&s.folder_id));
+
+            MockResponse::empty(200)
+        },
+        ("POST", 5, "sync", "session", "register", folder_id) => {
+            let folder_id: u64 = folder_id.parse().unwrap_or(0);
+            let name = s[4];
+
+            if !state.folders.iter().any(|f| f.id == folder_id) {
+                return MockResponse::error(400, "folder not found");
+            }
+
+            if state.sessions.iter().any(|session| session.name == name) {
+                return MockResponse::error(409, "session already exists");
+            }
+
+            let id = state.next_id();
+
+            state.sessions.push(MockSession {
+                id: id,
+                folder_id: folder_id,
+                name: name.to_string(),
+                size: None,
+                time: None,
+                data: None,
+            });
+
+            MockResponse::empty(201)
+        },
+        ("GET", 2, "sync", "session", _, _) => {
+            let mut details: HashMap<String, Vec<Value>> = HashMap::new();
+
+            for session in state.sessions.iter().filter(|session| session.data.is_some()) {
+                let mut m = ::serde_json::Map::new();
+                m.insert("id".to_string(), Value::from(session.id));
+                m.insert("name".to_string(), Value::String(session.name.clone()));
+                m.insert("size".to_string(), Value::from(session.size.unwrap_or(0)));
+                m.insert("time".to_string(), Value::from(session.time.unwrap_or(0)));
+
+                details.entry(format!("{}", session.folder_id)).or_insert_with(Vec::new).push(Value::Object(m));
+            }
+
+            let mut response: HashMap<String, HashMap<String, Vec<Value>>> = HashMap::new();
+            response.insert("sessionDetails".to_string(), details);
+
+            MockResponse::json(200, &::serde_json::to_value(&response).unwrap())
+        },
+        ("DELETE", 3, "sync", "session", "ids", _) => {
+            let ids: Vec<u64> = request.query.get("sessionIds").map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect()).unwrap_or(Vec::new());
+
+            state.sessions.retain(|s| !ids.contains(&s.id));
+
+            MockResponse::empty(200)
+        },
+        ("DELETE", 3, "sync", "session", "date", _) => {
+            let date: u64 = request.query.get("date").and_then(|d| d.parse().ok()).unwrap_or(0);
+
+            state.sessions.retain(|s| s.time.map(|t| t >= date).unwrap_or(true));
+
+            MockResponse::empty(200)
+        },
+        ("GET", 3, "sync", "session", name, _) => {
+            match state.sessions.iter().find(|session| session.name == name) {
+                Some(&MockSession { data: Some(ref data), .. }) => MockResponse::new(200, data.clone()),
+                _ => MockResponse::empty(404),
+            }
+        },
+        ("POST", 4, "sync", "session", name, size) => {
+            if state.reject_sessions {
+                return MockResponse::empty(503);
+            }
+
+            let size: u64 = size.parse().unwrap_or(0);
+
+            let data = match parse_multipart(&request.body).into_iter().next() {
+                Some((_, data)) => data,
+                None => return MockResponse::error(400, "no session data"),
+            };
+
+            match state.sessions.iter_mut().find(|session| session.name == name) {
+                Some(session) => {
+                    session.size = Some(size);
+                    session.time = Some(now_ms());
+                    session.data = Some(data);
+
+                    MockResponse::empty(201)
+                },
+                None => MockResponse::error(400, "session not registered"),
+            }
+        },
+        ("HEAD", 3, "sync", "block", name, _) => {
+            if state.blocks.contains_key(name) {
+                MockResponse::empty(200)
+            } else {
+                MockResponse::empty(404)
+            }
+        },
+        ("GET", 3, "sync", "block", name, _) => {
+            match state.blocks.get(name) {
+                Some(data) => MockResponse::new(200, data.clone()),
+                None => MockResponse::empty(404),
+            }
+        },
+        ("POST", 4, "sync", "blocks", "multi", _) => {
+            state.block_writes += 1;
+
+            let mut missing: Vec<Value> = Vec::new();
+
+            for (name, data) in parse_multipart(&request.body) {
+                if state.blocks.contains_key(&name) {
+                    continue;
+                }
+
+                if data.is_empty() {
+                    missing.push(Value::String(name));
+                } else {
+                    state.blocks.insert(name, data);
+                }
+            }
+
+            MockResponse::json(200, &Value::Array(missing))
+        },
+        _ => MockResponse::empty(404),
+    }
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref MOCK_TEST_LOCK: ::parking_lot::Mutex<()> = ::parking_lot::Mutex::new(());
+}
+
+/// give the test its own storage and cache directories
+#[cfg(test)]
+fn configure_storage() -> ::std::path::PathBuf {
+    let mut storage = ::std::env::temp_dir();
+    storage.push(format!("sddk-mock-{}", ::util::generate_uuid()));
+
+    let mut cache = storage.clone();
+    cache.push("cache");
+
+    ::std::fs::create_dir_all(&cache).expect("failed to create test cache directory");
+
+    *::STORAGE_DIR.write() = storage.to_str().unwrap().to_string();
+    *::CACHE_DIR.write() = cache.to_str().unwrap().to_string();
+
+    storage
+}
+
+/// point the global configuration at `server` and give the test its own cache directory
+#[cfg(test)]
+fn configure_for(server: &MockServer) -> ::std::path::PathBuf {
+    *::CONFIGURATION.write() = server.configuration();
+
+    configure_storage()
+}
+
+/// The setup shared by tests that sync a folder: a storage directory holding an empty `source`
+/// folder, and a new set of keys
+///
+/// The configuration, storage and cache directories are global, so the test lock is held for as
+/// long as the folder is alive. The storage directory is removed when it's dropped.
+#[cfg(test)]
+pub struct TestFolder {
+    pub storage: ::std::path::PathBuf,
+    pub source: ::std::path::PathBuf,
+    pub main: ::keys::Key,
+    pub hmac: ::keys::Key,
+    pub tweak: ::keys::Key,
+    _guard: ::parking_lot::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl TestFolder {
+    pub fn new() -> TestFolder {
+        use keys::{Key, KeyType};
+
+        let guard = MOCK_TEST_LOCK.lock();
+
+        let storage = configure_storage();
+
+        let mut source = storage.clone();
+        source.push("source");
+        ::std::fs::create_dir_all(&source).unwrap();
+
+        TestFolder {
+            storage: storage,
+            source: source,
+            main: Key::new(KeyType::Main),
+            hmac: Key::new(KeyType::HMAC),
+            tweak: Key::new(KeyType::Tweak),
+            _guard: guard,
+        }
+    }
+
+    /// write `contents` to `path` in the source folder, creating any directories it needs
+    pub fn write(&self, path: &str, contents: &[u8]) {
+        let path = self.source.join(path);
+
+        if let Some(parent) = path.parent() {
+            ::std::fs::create_dir_all(parent).unwrap();
+        }
+
+        let mut f = ::std::fs::File::create(&path).unwrap();
+        f.write_all(contents).unwrap();
+    }
+
+    /// start a mock server, point the SDK at it and register the source folder with it
+    pub fn serve(&self) -> (MockServer, Token, u64) {
+        let server = MockServer::start();
+        *::CONFIGURATION.write() = server.configuration();
+
+        let token = server.token();
+
+        let folder_id = ::sdapi::create_folder(&token, self.source.to_str().unwrap(), "source", true).expect("failed to create folder");
+
+        (server, token, folder_id)
+    }
+
+    /// open a local repository in the storage directory and register the source folder in it
+    pub fn local(&self) -> (Arc<::local_backend::LocalBackend>, u64) {
+        let repository = ::local_backend::LocalBackend::open(&self.storage.join("repository")).expect("failed to open local repository");
+
+        let folder_id = repository.create_folder(self.source.to_str().unwrap(), "source", true).expect("failed to create folder");
+
+        (Arc::new(repository), folder_id)
+    }
+
+    /// an empty path to restore to
+    pub fn destination(&self) -> ::std::path::PathBuf {
+        self.storage.join("destination")
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestFolder {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.storage);
+    }
+}
+
+#[test]
+fn mock_folder_test() {
+    let _guard = MOCK_TEST_LOCK.lock();
+
+    let server = MockServer::start();
+    let storage = configure_for(&server);
+    let token = server.token();
+
+    let folder_id = ::sdapi::create_folder(&token, "/tmp/mock", "mock", true).expect("failed to create folder");
+
+    let folders = ::sdapi::read_folders(&token).expect("failed to read folders");
+    assert!(folders.len() == 1);
+    assert!(folders[0].id == folder_id);
+
+    match ::sdapi::create_folder(&token, "/tmp/mock", "mock", true) {
+        Ok(_) => assert!(true == false),
+        Err(_) => {},
+    }
+
+    let bad_token = Token { token: "invalid".to_string() };
+
+    match ::sdapi::read_folders(&bad_token) {
+        Err(::error::SDAPIError::Authentication) => {},
+        _ => assert!(true == false),
+    }
+
+    let _ = ::std::fs::remove_dir_all(&storage);
+}
+
+#[test]
+fn mock_service_unavailable_test() {
+    let _guard = MOCK_TEST_LOCK.lock();
+
+    let server = MockServer::start();
+    let storage = configure_for(&server);
+    let token = server.token();
+
+    // fewer failures than retries, the request should still succeed
+    server.fail_requests(2, 503);
+
+    assert!(::sdapi::read_folders(&token).is_ok());
+    assert!(server.request_count() == 3);
+
+    // more failures than retries
+    server.fail_requests(3, 503);
+
+    match ::sdapi::read_folders(&token) {
+        Err(::error::SDAPIError::ServiceUnavailable) => {},
+        _ => assert!(true == false),
+    }
+
+    let _ = ::std::fs::remove_dir_all(&storage);
+}
+
+#[test]
+fn mock_missing_block_test() {
+    use block::Block;
+    use keys::{Key, KeyType};
+    use models::SyncVersion;
+    use binformat::BinaryWriter;
+
+    let _guard = MOCK_TEST_LOCK.lock();
+
+    let server = MockServer::start();
+    let storage = configure_for(&server);
+    let token = server.token();
+
+    let main = Key::new(KeyType::Main);
+    let hmac = Key::new(KeyType::HMAC);
+
+    let block = Block::new(SyncVersion::Version2, &hmac, vec![5u8; 4096]);
+    let mut wrapped_block = block.to_wrapped(&main).expect("failed to wrap block");
+    let name = wrapped_block.name();
+
+    let missing = ::sdapi::write_blocks(&token, "session", &[wrapped_block.clone()], Box::new(|_| {})).expect("failed to write blocks");
+    assert!(missing == vec![name.clone()]);
+
+    wrapped_block.needs_upload();
+
+    let missing = ::sdapi::write_blocks(&token, "session", &[wrapped_block], Box::new(|_| {})).expect("failed to write blocks");
+    assert!(missing.len() == 0);
+
+    assert!(::sdapi::check_block(&token, &name).unwrap() == true);
+    assert!(server.block_count() == 1);
+
+    let _ = ::std::fs::remove_dir_all(&storage);
+}
+
+#[test]
+fn mock_sync_restore_test() {
+    let folder = TestFolder::new();
+
+    folder.write("small.txt", b"safedrive");
+
+    let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    folder.write("nested/large.bin", &data);
+
+    let source = &folder.source;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        ::std::fs::set_permissions(source.join("small.txt"), ::std::fs::Permissions::from_mode(0o640)).unwrap();
+        ::std::fs::set_permissions(source.join("nested"), ::std::fs::Permissions::from_mode(0o750)).unwrap();
+    }
+
+    let old_time = ::filetime::FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    ::filetime::set_file_times(source.join("nested").join("large.bin"), old_time, old_time).unwrap();
+    ::filetime::set_file_times(source.join("nested"), old_time, old_time).unwrap();
+
+    let (server, token, folder_id) = folder.serve();
+
+    let session_name = ::util::generate_uuid();
+
+    ::core::sync(&token, &session_name, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    assert!(server.session_count() == 1);
+    assert!(server.block_count() > 0);
+
+    let destination = folder.destination();
+
+    ::core::restore(&token, &session_name, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    let mut restored = Vec::new();
+    ::std::fs::File::open(destination.join("nested").join("large.bin")).unwrap().read_to_end(&mut restored).unwrap();
+
+    assert!(restored == data);
+
+    let mut small = String::new();
+    ::std::fs::File::open(destination.join("small.txt")).unwrap().read_to_string(&mut small).unwrap();
+
+    assert!(small == "safedrive");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let md = ::std::fs::metadata(destination.join("small.txt")).unwrap();
+        assert!(md.permissions().mode() & 0o7777 == 0o640);
+
+        let md = ::std::fs::metadata(destination.join("nested")).unwrap();
+        assert!(md.permissions().mode() & 0o7777 == 0o750);
+    }
+
+    let md = ::std::fs::metadata(destination.join("nested").join("large.bin")).unwrap();
+    assert!(::filetime::FileTime::from_last_modification_time(&md) == old_time);
+
+    // directory times are applied after the directory contents are written
+    let md = ::std::fs::metadata(destination.join("nested")).unwrap();
+    assert!(::filetime::FileTime::from_last_modification_time(&md) == old_time);
+}
@@ -5,9 +5,12 @@ use std;
 
 use error::SDError;
 
+/// How often the daemon syncs a folder that hasn't changed
+///
+/// Changes to a folder are synced shortly after they happen, so this is only an upper bound on
+/// the time between two sessions of the same folder.
 #[derive(Debug)]
-#[derive(Copy, Clone)]
-#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum SyncSchedule {
     Hourly,
     Daily,
@@ -15,6 +18,44 @@ pub enum SyncSchedule {
     Monthly,
 }
 
+impl SyncSchedule {
+    pub fn interval(&self) -> ::std::time::Duration {
+        let hour = 60 * 60;
+
+        match *self {
+            SyncSchedule::Hourly => ::std::time::Duration::from_secs(hour),
+            SyncSchedule::Daily => ::std::time::Duration::from_secs(hour * 24),
+            SyncSchedule::Weekly => ::std::time::Duration::from_secs(hour * 24 * 7),
+            SyncSchedule::Monthly => ::std::time::Duration::from_secs(hour * 24 * 30),
+        }
+    }
+}
+
+impl std::fmt::Display for SyncSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match *self {
+            SyncSchedule::Hourly => {
+                write!(f, "hourly")
+            },
+            SyncSchedule::Daily => {
+                write!(f, "daily")
+            },
+            SyncSchedule::Weekly => {
+                write!(f, "weekly")
+            },
+            SyncSchedule::Monthly => {
+                write!(f, "monthly")
+            },
+        }
+    }
+}
+
+/// How old sync sessions are chosen for cleaning
+///
+/// The schedules that keep one session for each period of time, `Auto` and `Custom`, both keep
+/// the oldest session in the period. That session stays the one kept as newer sessions are added
+/// to the period, so running a cleaning job again never removes a session kept by the last one
+/// just because the folder was synced in the meantime.
 #[derive(Debug)]
 pub enum SyncCleaningSchedule {
     /// Clean sync sessions on a structured schedule.
@@ -85,6 +126,14 @@ pub enum SyncCleaningSchedule {
     OneWeek,         // 7 days
     OneMonth,        // 30 days
     OneYear,         // 365 days
+
+    /// Keep the oldest session in each of the most recent `hourly` hours, `daily` days, `weekly`
+    /// weeks, `monthly` months and `yearly` years that have a session, and clean the rest
+    ///
+    /// Only periods that have a session count towards the limits, and each folder is cleaned on
+    /// its own. A session kept for one period may also be the one kept for a longer period, for
+    /// example the oldest session of the first day of a month is also the oldest of the month.
+    Custom { hourly: u32, daily: u32, weekly: u32, monthly: u32, yearly: u32 },
 }
 
 impl std::fmt::Display for SyncCleaningSchedule {
@@ -126,6 +175,9 @@ impl std::fmt::Display for SyncCleaningSchedule {
             SyncCleaningSchedule::OneYear => {
                 write!(f, "older than 365 days")
             },
+            SyncCleaningSchedule::Custom { hourly, daily, weekly, monthly, yearly } => {
+                write!(f, "keep {} hourly, {} daily, {} weekly, {} monthly and {} yearly", hourly, daily, weekly, monthly, yearly)
+            },
         }
     }
 }
@@ -144,6 +196,7 @@ pub enum SyncVersion {
     Version0, // doesn't exist
     Version1, // testing format
     Version2, // production
+    Version3, // version 2 blocks, with the session archive stored as blocks too
 }
 
 impl SyncVersion {
@@ -151,7 +204,7 @@ impl SyncVersion {
         match *self {
             SyncVersion::Version0 => panic!("invalid version"),
             SyncVersion::Version1 => 18 as usize,
-            SyncVersion::Version2 => 18 as usize,
+            SyncVersion::Version2 | SyncVersion::Version3 => 18 as usize,
         }
     }
 
@@ -159,7 +212,7 @@ impl SyncVersion {
         match *self {
             SyncVersion::Version0 => panic!("invalid version"),
             SyncVersion::Version1 => 6,
-            SyncVersion::Version2 => 6,
+            SyncVersion::Version2 | SyncVersion::Version3 => 6,
         }
     }
 
@@ -167,7 +220,7 @@ impl SyncVersion {
         match *self {
             SyncVersion::Version0 => panic!("invalid version"),
             SyncVersion::Version1 => CompressionType::None,
-            SyncVersion::Version2 => CompressionType::Lz4,
+            SyncVersion::Version2 | SyncVersion::Version3 => CompressionType::Lz4,
         }
     }
 
@@ -175,7 +228,7 @@ impl SyncVersion {
         match *self {
             SyncVersion::Version0 => panic!("invalid version"),
             SyncVersion::Version1 => 0,
-            SyncVersion::Version2 => 2048,
+            SyncVersion::Version2 | SyncVersion::Version3 => 2048,
         }
     }
 
@@ -183,7 +236,30 @@ impl SyncVersion {
         match *self {
             SyncVersion::Version0 => panic!("invalid version"),
             SyncVersion::Version1 => ::std::usize::MAX,
-            SyncVersion::Version2 => 1_000_000,
+            SyncVersion::Version2 | SyncVersion::Version3 => 1_000_000,
+        }
+    }
+
+    /// the version of the blocks written by a sync creating a session of this version
+    ///
+    /// Version 3 only changes how the session is stored, its blocks are version 2 blocks so they
+    /// can be shared with version 2 sessions and read by clients that don't know version 3.
+    pub fn block_version(&self) -> SyncVersion {
+        match *self {
+            SyncVersion::Version0 => panic!("invalid version"),
+            SyncVersion::Version1 => SyncVersion::Version1,
+            SyncVersion::Version2 | SyncVersion::Version3 => SyncVersion::Version2,
+        }
+    }
+
+    /// whether the session archive is chunked and stored as blocks, with the session itself only
+    /// holding the list of them, see `session::manifest_root`
+    pub fn chunked_manifest(&self) -> bool {
+        match *self {
+            SyncVersion::Version0 => panic!("invalid version"),
+            SyncVersion::Version1 => false,
+            SyncVersion::Version2 => false,
+            SyncVersion::Version3 => true,
         }
     }
 }
@@ -200,6 +276,7 @@ impl AsRef<[u8]> for SyncVersion {
             SyncVersion::Version0 => "00".as_bytes(),
             SyncVersion::Version1 => "01".as_bytes(),
             SyncVersion::Version2 => "02".as_bytes(),
+            SyncVersion::Version3 => "03".as_bytes(),
         }
     }
 }
@@ -210,6 +287,7 @@ impl std::fmt::Display for SyncVersion {
             SyncVersion::Version0 => write!(f, "0"),
             SyncVersion::Version1 => write!(f, "1"),
             SyncVersion::Version2 => write!(f, "2"),
+            SyncVersion::Version3 => write!(f, "3"),
         }
     }
 }
@@ -225,6 +303,8 @@ bitflags! {
         const Beta       = 0b00000100;
         const Nightly    = 0b00001000;
         const Compressed = 0b00010000;
+        /// the data is a series of separately encrypted chunks, see `session::write_session_file`
+        const Chunked    = 0b00100000;
     }
 }
 
@@ -334,10 +414,11 @@ pub struct WrappedKeysetBody {
     pub tweak: String,
 }
 
+/// A session being downloaded, `body` reads the stored form of the session as it arrives
 pub struct SyncSessionResponse<'a> {
     pub name: &'a str,
     pub folder_id: u64,
-    pub chunk_data: Vec<u8>,
+    pub body: Box<std::io::Read + Send>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -345,7 +426,7 @@ pub struct ServerErrorResponse {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegisteredFolder {
     pub id: u64,
     pub folderName: String,
@@ -355,6 +436,124 @@ pub struct RegisteredFolder {
     pub syncing: bool,
 }
 
+// session browsing
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SessionEntryType {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    Other,
+}
+
+/// A single file, directory or link recorded in a sync session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionEntry {
+    /// path relative to the root of the folder
+    pub path: String,
+    pub entry_type: SessionEntryType,
+    /// size of the file when it was synced, not recorded by older sessions
+    pub size: Option<u64>,
+    pub mode: u32,
+    pub mtime: u64,
+    /// destination of a symlink or hard link
+    pub link_target: Option<String>,
+    pub block_count: u64,
+}
+
+/// The differences between two sessions of the same folder, from the older session `a` to the
+/// newer session `b`
+///
+/// Entries that exist in both sessions are reported from session `b`. An entry is modified when
+/// its contents (the HMAC list), type or link target changed, and metadata-only changed when
+/// only its mode or modification time did.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionDiff {
+    pub added: Vec<SessionEntry>,
+    pub removed: Vec<SessionEntry>,
+    pub modified: Vec<SessionEntry>,
+    pub metadata_changed: Vec<SessionEntry>,
+    /// number of distinct blocks used by session `a` that session `b` does not use
+    pub a_unique_blocks: u64,
+    /// number of distinct blocks used by session `b` that session `a` does not use
+    pub b_unique_blocks: u64,
+}
+
+/// A file in a session that can't be restored completely
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrokenFile {
+    pub path: String,
+    /// number of blocks the file uses that storage doesn't have
+    pub missing_blocks: u64,
+    /// number of blocks that couldn't be decrypted or don't match their name, only found by a
+    /// deep check
+    pub corrupt_blocks: u64,
+}
+
+/// The result of checking that a sync session can be restored
+///
+/// A shallow check only confirms that storage has every block the session uses, a deep check
+/// also downloads, decrypts and hashes each of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionVerification {
+    pub folder_id: u64,
+    pub session_name: String,
+    pub deep: bool,
+    /// number of files in the session
+    pub files: u64,
+    /// number of distinct blocks checked
+    pub blocks: u64,
+    pub broken: Vec<BrokenFile>,
+}
+
+impl SessionVerification {
+    pub fn is_restorable(&self) -> bool {
+        self.broken.is_empty()
+    }
+}
+
+/// Blocks found by garbage collection that no remaining session uses
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GarbageCount {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// The result of a garbage collection pass
+///
+/// `storage` is `None` when the storage backend has no way to list its blocks, in which case only
+/// the local block cache was collected. When `dry_run` is set nothing was removed, and the counts
+/// are what a real pass would reclaim.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GarbageReport {
+    /// number of sessions that were read to build the set of live blocks
+    pub sessions: u64,
+    /// number of distinct blocks used by those sessions
+    pub live_blocks: u64,
+    /// `None` when garbage collection isn't supported for the storage, as with SafeDrive storage
+    pub storage: Option<GarbageCount>,
+    pub cache: GarbageCount,
+    pub dry_run: bool,
+}
+
+/// Rules that keep files in a registered folder out of its sync sessions
+///
+/// These are stored locally for each folder. The folder's own `.sdignore` file adds to
+/// `patterns`, and each session records the combined rules it was created with.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct IgnoreRules {
+    /// gitignore style patterns, matched against paths relative to the root of the folder
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// regular files larger than this many bytes are skipped
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// skip files and directories with names starting with a dot
+    #[serde(default)]
+    pub skip_hidden: bool,
+}
+
 // sync progress
 
 #[derive(Debug)]
@@ -362,5 +561,149 @@ pub enum SyncStatus {
     Progress(u64, u64, u64),
     Bandwidth(u64),
     Issue(String),
+    Event(SyncEvent),
     Err(SDError),
 }
+
+/// The part of a sync or restore that is running
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    /// looking through the folder, or reading the session and the destination
+    Scan,
+    Upload,
+    Download,
+    /// waiting for the last blocks and storing the session, or restoring directory metadata
+    Finalize,
+}
+
+/// Why a file was left out of a sync or restore
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// the file was removed while the sync was running
+    NotFound,
+    PermissionDenied,
+    /// the contents of the file couldn't be read
+    ReadFailed,
+    /// the file couldn't be created or written in the restore destination
+    WriteFailed,
+    /// the entry is a type that can't be restored here, or a link without a destination
+    Unsupported,
+    Other,
+}
+
+impl<'a> From<&'a std::io::Error> for SkipReason {
+    fn from(err: &'a std::io::Error) -> SkipReason {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => SkipReason::NotFound,
+            std::io::ErrorKind::PermissionDenied => SkipReason::PermissionDenied,
+            _ => SkipReason::Other,
+        }
+    }
+}
+
+/// Totals for a finished sync or restore, built from the events sent while it ran
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SyncSummary {
+    /// files that were completely synced or restored
+    pub files: u64,
+    /// total size of those files in bytes
+    pub size: u64,
+    pub skipped: u64,
+    /// blocks sent to storage, only counted by a sync
+    pub blocks_uploaded: u64,
+    /// total size of the uploaded blocks in bytes, after compression and encryption
+    pub uploaded_size: u64,
+    /// blocks that were already in storage and didn't have to be sent again
+    pub blocks_deduplicated: u64,
+}
+
+impl SyncSummary {
+    pub fn record(&mut self, event: &SyncEvent) {
+        match *event {
+            SyncEvent::FileFinished { size, .. } => {
+                self.files += 1;
+                self.size += size;
+            },
+            SyncEvent::FileSkipped { .. } => {
+                self.skipped += 1;
+            },
+            SyncEvent::BlockUploaded { size, .. } => {
+                self.blocks_uploaded += 1;
+                self.uploaded_size += size;
+            },
+            SyncEvent::BlockDeduplicated { .. } => {
+                self.blocks_deduplicated += 1;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Something that happened during a sync or restore
+///
+/// Paths are relative to the root of the folder. Unlike the messages sent to the `issue`
+/// callback, these are meant to be read by programs, so a UI can show which file is being worked
+/// on or which ones failed and why.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncEvent {
+    PhaseChanged { phase: SyncPhase },
+    FileStarted { path: String, size: u64 },
+    FileFinished { path: String, size: u64 },
+    FileSkipped { path: String, reason: SkipReason, message: String },
+    /// `size` is the size of the block as stored, after compression and encryption
+    BlockUploaded { name: String, size: u64 },
+    BlockDeduplicated { name: String, size: u64 },
+    /// always the last event of a sync or restore that finished, not sent if it failed
+    Summary(SyncSummary),
+}
+
+// daemon
+
+/// Something that happened to a sync or restore run by the daemon
+///
+/// Sent to every client of the daemon's control socket that subscribed to events. Each one names
+/// the session it belongs to, which is how clients tell concurrent tasks apart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    Started { folder_id: u64, session_name: String, restore: bool },
+    Progress { session_name: String, total: u64, current: u64, new: u64 },
+    Bandwidth { session_name: String, speed: u64 },
+    Issue { session_name: String, message: String },
+    Finished { session_name: String },
+    Failed { session_name: String, message: String },
+}
+
+impl DaemonEvent {
+    pub fn session_name(&self) -> &str {
+        match *self {
+            DaemonEvent::Started { ref session_name, .. } => session_name,
+            DaemonEvent::Progress { ref session_name, .. } => session_name,
+            DaemonEvent::Bandwidth { ref session_name, .. } => session_name,
+            DaemonEvent::Issue { ref session_name, .. } => session_name,
+            DaemonEvent::Finished { ref session_name } => session_name,
+            DaemonEvent::Failed { ref session_name, .. } => session_name,
+        }
+    }
+}
+
+/// A sync or restore the daemon is running or has queued
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonTask {
+    pub folder_id: u64,
+    pub session_name: String,
+    pub restore: bool,
+}
+
+/// Size of the local block cache
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    pub blocks: u64,
+    /// total size of the cached blocks in bytes
+    pub size: u64,
+    /// size in bytes the cache is cleaned down to
+    pub limit: u64,
+}
@@ -3,11 +3,34 @@
 #![allow(unused_variables)]
 
 use std;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
+// external crate imports
+
+use byteorder::{ByteOrder, LittleEndian};
 use nom::{rest, le_u8, le_u64};
+use nom::IResult::*;
 
+// internal imports
 
-#[derive(Debug, PartialEq, Eq)]
+use constants::HMAC_SIZE;
+use error::SDError;
+use keys::Key;
+use models::SessionEntryType;
+use session::SyncSession;
+
+use STORAGE_DIR;
+
+static OPLOG_FILE: &'static str = "ops";
+static OPLOG_MAGIC: &'static [u8; 4] = b"sdol";
+
+/// size of the header in front of the data of every op
+const OP_HEADER_SIZE: usize = 22;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[repr(u8)]
 pub enum OpType {
     Initial,
@@ -25,7 +48,30 @@ pub enum OpType {
     UpdateData,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl OpType {
+    pub fn from_u8(value: u8) -> Option<OpType> {
+        let op_type = match value {
+            0 => OpType::Initial,
+            1 => OpType::Commit,
+            2 => OpType::Rollback,
+            3 => OpType::FileCreate,
+            4 => OpType::FileDelete,
+            5 => OpType::UpdateCTime,
+            6 => OpType::UpdateATime,
+            7 => OpType::UpdateMTime,
+            8 => OpType::UpdateName,
+            9 => OpType::UpdatePermissions,
+            10 => OpType::InsertData,
+            11 => OpType::DeleteData,
+            12 => OpType::UpdateData,
+            _ => return None,
+        };
+
+        Some(op_type)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[repr(u8)]
 pub enum FileType {
     Regular,
@@ -34,6 +80,31 @@ pub enum FileType {
     HardLink,
 }
 
+impl FileType {
+    pub fn from_u8(value: u8) -> Option<FileType> {
+        let file_type = match value {
+            0 => FileType::Regular,
+            1 => FileType::Directory,
+            2 => FileType::Symlink,
+            3 => FileType::HardLink,
+            _ => return None,
+        };
+
+        Some(file_type)
+    }
+
+    /// the file type of a session entry, if it is one an op log can describe
+    pub fn from_entry_type(entry_type: SessionEntryType) -> Option<FileType> {
+        match entry_type {
+            SessionEntryType::File => Some(FileType::Regular),
+            SessionEntryType::Directory => Some(FileType::Directory),
+            SessionEntryType::Symlink => Some(FileType::Symlink),
+            SessionEntryType::HardLink => Some(FileType::HardLink),
+            SessionEntryType::Other => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Op<'a> {
     pub sequence: u64,
@@ -46,6 +117,49 @@ pub struct Op<'a> {
     pub data_pointers: &'a [u8],
 }
 
+impl<'a> Op<'a> {
+    pub fn new(sequence: u64, operation_type: OpType, file_type: FileType, file_id: u64, data_pointers: &'a [u8]) -> Op<'a> {
+        Op {
+            sequence: sequence,
+            operation_type: operation_type as u8,
+            file_type: file_type as u8,
+            file_id: file_id,
+            data_pointers: data_pointers,
+        }
+    }
+
+    pub fn from_binary(data: &'a [u8]) -> Result<Op<'a>, SDError> {
+        match op_parse(data) {
+            Done(_, op) => Ok(op),
+            Error(e) => {
+                debug!("failed to parse op: {}", e);
+
+                Err(SDError::OpLogUnreadable)
+            },
+            Incomplete(_) => Err(SDError::OpLogUnreadable),
+        }
+    }
+
+    /// serialize the op in the form `op_parse` reads
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = vec![0u8; OP_HEADER_SIZE];
+
+        binary[0..4].copy_from_slice(b"sdop");
+        LittleEndian::write_u64(&mut binary[4..12], self.sequence);
+        binary[12] = self.operation_type;
+        binary[13] = self.file_type;
+        LittleEndian::write_u64(&mut binary[14..22], self.file_id);
+
+        binary.extend_from_slice(self.data_pointers);
+
+        binary
+    }
+
+    pub fn op_type(&self) -> Option<OpType> {
+        OpType::from_u8(self.operation_type)
+    }
+}
+
 impl<'a> std::fmt::Display for Op<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(f, "Op <#{}| type:{}, file_id:{}, data_pointers:{}>", self.sequence, self.operation_type, self.file_id, self.data_pointers.len())
@@ -70,3 +184,927 @@ named!(op_parse<&[u8], (Op)>,
         })
     )
 );
+
+/// A change to a single file, the typed form of the ops that change files
+///
+/// File contents are tracked as lists of blocks: `hmacs` holds block HMACs back to back, `index`
+/// counts blocks from the start of the file, and `size` is the size of the whole file after the
+/// change, since it can't be worked out from the blocks alone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// `path` is relative to the root of the folder, `link_target` is only used for links
+    Create { path: String, link_target: Option<String> },
+    Delete,
+    Rename { path: String },
+    SetCTime(u64),
+    SetATime(u64),
+    SetMTime(u64),
+    SetPermissions(u32),
+    InsertData { index: u64, size: u64, hmacs: Vec<u8> },
+    DeleteData { index: u64, count: u64, size: u64 },
+    UpdateData { index: u64, size: u64, hmacs: Vec<u8> },
+}
+
+impl Change {
+    pub fn op_type(&self) -> OpType {
+        match *self {
+            Change::Create { .. } => OpType::FileCreate,
+            Change::Delete => OpType::FileDelete,
+            Change::Rename { .. } => OpType::UpdateName,
+            Change::SetCTime(_) => OpType::UpdateCTime,
+            Change::SetATime(_) => OpType::UpdateATime,
+            Change::SetMTime(_) => OpType::UpdateMTime,
+            Change::SetPermissions(_) => OpType::UpdatePermissions,
+            Change::InsertData { .. } => OpType::InsertData,
+            Change::DeleteData { .. } => OpType::DeleteData,
+            Change::UpdateData { .. } => OpType::UpdateData,
+        }
+    }
+
+    /// the data stored in the op for this change
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        match *self {
+            Change::Create { ref path, ref link_target } => {
+                data.extend_from_slice(path.as_bytes());
+
+                // paths can't contain a NUL, so it separates the path from the link target
+                if let Some(ref target) = *link_target {
+                    data.push(0);
+                    data.extend_from_slice(target.as_bytes());
+                }
+            },
+            Change::Delete => {},
+            Change::Rename { ref path } => {
+                data.extend_from_slice(path.as_bytes());
+            },
+            Change::SetCTime(time) | Change::SetATime(time) | Change::SetMTime(time) => {
+                push_u64(&mut data, time);
+            },
+            Change::SetPermissions(mode) => {
+                let mut buf = [0u8; 4];
+                LittleEndian::write_u32(&mut buf, mode);
+                data.extend_from_slice(&buf);
+            },
+            Change::InsertData { index, size, ref hmacs } | Change::UpdateData { index, size, ref hmacs } => {
+                push_u64(&mut data, index);
+                push_u64(&mut data, size);
+                data.extend_from_slice(hmacs);
+            },
+            Change::DeleteData { index, count, size } => {
+                push_u64(&mut data, index);
+                push_u64(&mut data, count);
+                push_u64(&mut data, size);
+            },
+        }
+
+        data
+    }
+
+    /// read the change stored in an op, `None` for ops that don't change a file
+    pub fn decode(op_type: OpType, data: &[u8]) -> Result<Option<Change>, SDError> {
+        let change = match op_type {
+            OpType::Initial | OpType::Commit | OpType::Rollback => return Ok(None),
+            OpType::FileCreate => {
+                let mut parts = data.splitn(2, |b| *b == 0);
+
+                let path = read_string(parts.next().unwrap_or(&[]))?;
+                let link_target = match parts.next() {
+                    Some(target) => Some(read_string(target)?),
+                    None => None,
+                };
+
+                Change::Create { path: path, link_target: link_target }
+            },
+            OpType::FileDelete => Change::Delete,
+            OpType::UpdateName => Change::Rename { path: read_string(data)? },
+            OpType::UpdateCTime => Change::SetCTime(read_u64(data, 0)?),
+            OpType::UpdateATime => Change::SetATime(read_u64(data, 0)?),
+            OpType::UpdateMTime => Change::SetMTime(read_u64(data, 0)?),
+            OpType::UpdatePermissions => {
+                if data.len() != 4 {
+                    return Err(SDError::OpLogUnreadable);
+                }
+
+                Change::SetPermissions(LittleEndian::read_u32(data))
+            },
+            OpType::InsertData | OpType::UpdateData => {
+                let index = read_u64(data, 0)?;
+                let size = read_u64(data, 8)?;
+                let hmacs = data[16..].to_vec();
+
+                if hmacs.len() % HMAC_SIZE != 0 {
+                    return Err(SDError::OpLogUnreadable);
+                }
+
+                if op_type == OpType::InsertData {
+                    Change::InsertData { index: index, size: size, hmacs: hmacs }
+                } else {
+                    Change::UpdateData { index: index, size: size, hmacs: hmacs }
+                }
+            },
+            OpType::DeleteData => {
+                Change::DeleteData {
+                    index: read_u64(data, 0)?,
+                    count: read_u64(data, 8)?,
+                    size: read_u64(data, 16)?,
+                }
+            },
+        };
+
+        Ok(Some(change))
+    }
+}
+
+/// The operation log records changes to a folder as they happen, so a sync can send only what
+/// changed instead of building a new session archive every time.
+///
+/// It starts from a session of the folder, recorded in an `Initial` op, and every op after that
+/// changes one file. Ops are grouped into transactions: the ops written since the last `Commit`
+/// or `Rollback` only take effect once `commit()` is called, and `rollback()` discards them.
+/// Ops left without either, like those written just before a crash, are treated as rolled back.
+///
+/// Files are identified by a file ID. The entries of the initial session are numbered from 1 in
+/// the order they are stored in it, files created later use IDs above those, see `TreeState`.
+///
+/// The log is only ever appended to. Each op is encrypted with the main key on its own, and
+/// stored as a little endian `u32` length followed by the nonce and the encrypted op. A record
+/// cut short by a crash is dropped the next time the log is opened.
+///
+/// Layout, one directory for each folder:
+///
+///     <storage dir>/oplog/<folder id>/ops
+///
+pub struct OpLog {
+    path: PathBuf,
+    file: File,
+    main_key: Key,
+    session_name: String,
+    next_sequence: u64,
+    /// ops written since the last commit or rollback
+    pending: u64,
+}
+
+impl OpLog {
+    /// start a new op log for a folder from one of its sessions, replacing any log the folder had
+    pub fn create(folder_id: u64, main_key: &Key, session_name: &str) -> Result<OpLog, SDError> {
+        let dir = oplog_dir(folder_id)?;
+
+        OpLog::create_in(dir, main_key, session_name)
+    }
+
+    /// open the op log of a folder to add more ops to it
+    pub fn open(folder_id: u64, main_key: &Key) -> Result<OpLog, SDError> {
+        let dir = oplog_dir(folder_id)?;
+
+        OpLog::open_in(dir, main_key)
+    }
+
+    fn create_in(dir: PathBuf, main_key: &Key, session_name: &str) -> Result<OpLog, SDError> {
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(OPLOG_FILE);
+
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        file.write_all(OPLOG_MAGIC)?;
+
+        let mut oplog = OpLog {
+            path: path,
+            file: file,
+            main_key: main_key.clone(),
+            session_name: session_name.to_string(),
+            next_sequence: 0,
+            pending: 0,
+        };
+
+        oplog.append(OpType::Initial, FileType::Directory, 0, session_name.as_bytes())?;
+        oplog.pending = 0;
+        oplog.file.sync_all()?;
+
+        Ok(oplog)
+    }
+
+    fn open_in(dir: PathBuf, main_key: &Key) -> Result<OpLog, SDError> {
+        let path = dir.join(OPLOG_FILE);
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+
+        let (records, valid_length) = read_records(&mut file, main_key)?;
+
+        let (session_name, last_sequence, pending) = {
+            let ops = parse_records(&records)?;
+
+            let session_name = initial_session(&ops)?;
+
+            let mut pending = 0;
+
+            for op in &ops {
+                match op.op_type() {
+                    Some(OpType::Commit) | Some(OpType::Rollback) | Some(OpType::Initial) => pending = 0,
+                    _ => pending += 1,
+                }
+            }
+
+            (session_name, ops[ops.len() - 1].sequence, pending)
+        };
+
+        // drop a record that was only partly written, so new ones don't end up behind it
+        file.set_len(valid_length)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let mut oplog = OpLog {
+            path: path,
+            file: file,
+            main_key: main_key.clone(),
+            session_name: session_name,
+            next_sequence: last_sequence + 1,
+            pending: pending,
+        };
+
+        if oplog.pending > 0 {
+            debug!("rolling back {} ops left uncommitted in op log", oplog.pending);
+
+            oplog.rollback()?;
+        }
+
+        Ok(oplog)
+    }
+
+    /// the session the log starts from
+    pub fn session_name(&self) -> &str {
+        &self.session_name
+    }
+
+    /// whether ops have been written since the last commit or rollback
+    pub fn has_pending(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// add a change to a file to the current transaction, returning the sequence number of its op
+    pub fn record(&mut self, file_id: u64, file_type: FileType, change: &Change) -> Result<u64, SDError> {
+        if file_id == 0 {
+            return Err(SDError::Internal("file id 0 is reserved for ops that don't change a file".to_string()));
+        }
+
+        let data = change.encode();
+
+        let sequence = self.append(change.op_type(), file_type, file_id, &data)?;
+
+        Ok(sequence)
+    }
+
+    /// make the ops written since the last commit or rollback take effect
+    pub fn commit(&mut self) -> Result<(), SDError> {
+        self.append(OpType::Commit, FileType::Regular, 0, &[])?;
+        self.pending = 0;
+
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// discard the ops written since the last commit or rollback
+    pub fn rollback(&mut self) -> Result<(), SDError> {
+        self.append(OpType::Rollback, FileType::Regular, 0, &[])?;
+        self.pending = 0;
+
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn append(&mut self, op_type: OpType, file_type: FileType, file_id: u64, data: &[u8]) -> Result<u64, SDError> {
+        let sequence = self.next_sequence;
+
+        let op = Op::new(sequence, op_type, file_type, file_id, data);
+
+        let record = seal_record(&op.to_binary(), &self.main_key);
+
+        // a single write, so a crash can only ever leave the last record incomplete
+        self.file.write_all(&record)?;
+
+        self.next_sequence += 1;
+        self.pending += 1;
+
+        trace!("appended {} to op log {}", op, self.path.display());
+
+        Ok(sequence)
+    }
+}
+
+/// remove the op log of a folder, if it has one
+pub fn remove_oplog(folder_id: u64) -> Result<(), SDError> {
+    let dir = oplog_dir(folder_id)?;
+
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+
+    Ok(())
+}
+
+/// A file in the tree described by a session and an op log
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileNode {
+    /// path relative to the root of the folder
+    pub path: String,
+    pub file_type: FileType,
+    /// size of a regular file, not recorded by older sessions
+    pub size: Option<u64>,
+    pub mode: u32,
+    pub mtime: u64,
+    pub atime: u64,
+    pub ctime: u64,
+    /// destination of a symlink or hard link
+    pub link_target: Option<String>,
+    /// the block HMACs of a regular file, back to back
+    pub hmacs: Vec<u8>,
+}
+
+/// The state of a folder after replaying the committed ops of its op log on top of the session
+/// the log starts from
+#[derive(Debug, Clone)]
+pub struct TreeState {
+    session_name: String,
+    files: BTreeMap<u64, FileNode>,
+    next_file_id: u64,
+}
+
+impl TreeState {
+    /// the tree recorded in a session, with file IDs numbered from 1 in the order the entries
+    /// are stored in it
+    ///
+    /// Entries that aren't files, directories or links still use up an ID, so the numbering
+    /// only depends on the session.
+    pub fn from_session(session: &SyncSession) -> Result<TreeState, SDError> {
+        let contents = ::browse::session_contents(session)?;
+
+        let mut files = BTreeMap::new();
+        let mut file_id: u64 = 0;
+
+        for (entry, hmacs) in contents {
+            file_id += 1;
+
+            let file_type = match FileType::from_entry_type(entry.entry_type) {
+                Some(file_type) => file_type,
+                None => continue,
+            };
+
+            files.insert(file_id, FileNode {
+                path: entry.path.trim_right_matches('/').to_string(),
+                file_type: file_type,
+                size: entry.size,
+                mode: entry.mode,
+                mtime: entry.mtime,
+                atime: entry.mtime,
+                ctime: entry.mtime,
+                link_target: entry.link_target,
+                hmacs: hmacs,
+            });
+        }
+
+        Ok(TreeState {
+            session_name: session.name.clone(),
+            files: files,
+            next_file_id: file_id + 1,
+        })
+    }
+
+    /// the session the tree was built from
+    pub fn session_name(&self) -> &str {
+        &self.session_name
+    }
+
+    pub fn file(&self, file_id: u64) -> Option<&FileNode> {
+        self.files.get(&file_id)
+    }
+
+    pub fn file_id(&self, path: &str) -> Option<u64> {
+        let path = path.trim_right_matches('/');
+
+        self.files.iter().find(|&(_, file)| file.path == path).map(|(id, _)| *id)
+    }
+
+    /// the lowest ID no file in the tree has used, for the next file created
+    pub fn next_file_id(&self) -> u64 {
+        self.next_file_id
+    }
+
+    /// every file in the tree with its ID, sorted by path
+    pub fn files(&self) -> Vec<(u64, &FileNode)> {
+        let mut files: Vec<(u64, &FileNode)> = self.files.iter().map(|(id, file)| (*id, file)).collect();
+
+        files.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+
+        files
+    }
+
+    /// apply a single change, failing if it doesn't fit the current tree
+    pub fn apply(&mut self, file_id: u64, file_type: FileType, change: &Change) -> Result<(), SDError> {
+        if let Change::Create { ref path, ref link_target } = *change {
+            let path = path.trim_right_matches('/');
+
+            if self.files.contains_key(&file_id) {
+                return Err(invalid_op(file_id, "file id already used"));
+            }
+
+            self.check_new_path(file_id, path)?;
+
+            let mode = match file_type {
+                FileType::Regular | FileType::HardLink => 0o644,
+                FileType::Directory => 0o755,
+                FileType::Symlink => 0o777,
+            };
+
+            self.files.insert(file_id, FileNode {
+                path: path.to_string(),
+                file_type: file_type,
+                size: if file_type == FileType::Regular { Some(0) } else { None },
+                mode: mode,
+                mtime: 0,
+                atime: 0,
+                ctime: 0,
+                link_target: link_target.clone(),
+                hmacs: Vec::new(),
+            });
+
+            if file_id >= self.next_file_id {
+                self.next_file_id = file_id + 1;
+            }
+
+            return Ok(());
+        }
+
+        let (old_path, is_directory) = match self.files.get(&file_id) {
+            Some(file) => {
+                if file.file_type != file_type {
+                    return Err(invalid_op(file_id, "file type doesn't match"));
+                }
+
+                (file.path.clone(), file.file_type == FileType::Directory)
+            },
+            None => return Err(invalid_op(file_id, "no such file")),
+        };
+
+        let prefix = format!("{}/", old_path);
+
+        match *change {
+            Change::Create { .. } => unreachable!(),
+            Change::Delete => {
+                self.files.remove(&file_id);
+
+                if is_directory {
+                    self.files.retain(|_, file| !file.path.starts_with(&prefix));
+                }
+            },
+            Change::Rename { ref path } => {
+                let path = path.trim_right_matches('/');
+
+                self.check_new_path(file_id, path)?;
+
+                if is_directory {
+                    if path.starts_with(&prefix) {
+                        return Err(invalid_op(file_id, "directory moved inside itself"));
+                    }
+
+                    for file in self.files.values_mut() {
+                        if file.path.starts_with(&prefix) {
+                            file.path = format!("{}/{}", path, &file.path[prefix.len()..]);
+                        }
+                    }
+                }
+
+                self.file_mut(file_id).path = path.to_string();
+            },
+            Change::SetCTime(time) => self.file_mut(file_id).ctime = time,
+            Change::SetATime(time) => self.file_mut(file_id).atime = time,
+            Change::SetMTime(time) => self.file_mut(file_id).mtime = time,
+            Change::SetPermissions(mode) => self.file_mut(file_id).mode = mode,
+            Change::InsertData { index, size, ref hmacs } => {
+                let file = self.data_file_mut(file_id)?;
+
+                let offset = block_offset(file, file_id, index)?;
+
+                let tail = file.hmacs.split_off(offset);
+                file.hmacs.extend_from_slice(hmacs);
+                file.hmacs.extend_from_slice(&tail);
+                file.size = Some(size);
+            },
+            Change::DeleteData { index, count, size } => {
+                let file = self.data_file_mut(file_id)?;
+
+                let start = block_offset(file, file_id, index)?;
+                let end = block_offset(file, file_id, index + count)?;
+
+                file.hmacs.drain(start..end);
+                file.size = Some(size);
+            },
+            Change::UpdateData { index, size, ref hmacs } => {
+                let file = self.data_file_mut(file_id)?;
+
+                let start = block_offset(file, file_id, index)?;
+                let end = block_offset(file, file_id, index + (hmacs.len() / HMAC_SIZE) as u64)?;
+
+                file.hmacs[start..end].copy_from_slice(hmacs);
+                file.size = Some(size);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// a file can only be created or moved to a path nothing else uses, inside a directory
+    fn check_new_path(&self, file_id: u64, path: &str) -> Result<(), SDError> {
+        if path.is_empty() {
+            return Err(invalid_op(file_id, "empty path"));
+        }
+
+        if self.files.iter().any(|(id, file)| *id != file_id && file.path == path) {
+            return Err(invalid_op(file_id, "path already used"));
+        }
+
+        if let Some(separator) = path.rfind('/') {
+            let parent = &path[..separator];
+
+            if !self.files.values().any(|file| file.path == parent && file.file_type == FileType::Directory) {
+                return Err(invalid_op(file_id, "parent directory missing"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file_mut(&mut self, file_id: u64) -> &mut FileNode {
+        self.files.get_mut(&file_id).expect("file checked before changing it")
+    }
+
+    fn data_file_mut(&mut self, file_id: u64) -> Result<&mut FileNode, SDError> {
+        let file = self.file_mut(file_id);
+
+        if file.file_type != FileType::Regular {
+            return Err(invalid_op(file_id, "only regular files have data"));
+        }
+
+        Ok(file)
+    }
+}
+
+/// rebuild the tree of a folder from the session its op log starts from and the committed ops
+/// in the log
+pub fn replay(folder_id: u64, main_key: &Key, session: &SyncSession) -> Result<TreeState, SDError> {
+    let dir = oplog_dir(folder_id)?;
+
+    replay_in(&dir, main_key, session)
+}
+
+fn replay_in(dir: &Path, main_key: &Key, session: &SyncSession) -> Result<TreeState, SDError> {
+    let mut file = File::open(dir.join(OPLOG_FILE))?;
+
+    let (records, _) = read_records(&mut file, main_key)?;
+
+    let ops = parse_records(&records)?;
+
+    let session_name = initial_session(&ops)?;
+
+    if session_name != session.name {
+        return Err(SDError::Internal(format!("op log starts from session {}, not {}", session_name, session.name)));
+    }
+
+    let mut state = TreeState::from_session(session)?;
+
+    let mut transaction: Vec<(u64, FileType, Change)> = Vec::new();
+
+    for op in &ops[1..] {
+        let op_type = match op.op_type() {
+            Some(op_type) => op_type,
+            None => {
+                debug!("unknown op type in {}", op);
+
+                return Err(SDError::OpLogUnreadable);
+            },
+        };
+
+        match op_type {
+            OpType::Initial => {
+                debug!("second initial op in op log: {}", op);
+
+                return Err(SDError::OpLogUnreadable);
+            },
+            OpType::Commit => {
+                for (file_id, file_type, change) in transaction.drain(..) {
+                    state.apply(file_id, file_type, &change)?;
+                }
+            },
+            OpType::Rollback => {
+                transaction.clear();
+            },
+            _ => {
+                let file_type = match FileType::from_u8(op.file_type) {
+                    Some(file_type) => file_type,
+                    None => {
+                        debug!("unknown file type in {}", op);
+
+                        return Err(SDError::OpLogUnreadable);
+                    },
+                };
+
+                let change = Change::decode(op_type, op.data_pointers)?.expect("op changes a file");
+
+                transaction.push((op.file_id, file_type, change));
+            },
+        }
+    }
+
+    if !transaction.is_empty() {
+        debug!("ignoring {} uncommitted ops at the end of the op log", transaction.len());
+    }
+
+    Ok(state)
+}
+
+fn oplog_dir(folder_id: u64) -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push("oplog");
+    p.push(format!("{}", folder_id));
+
+    Ok(p)
+}
+
+fn invalid_op(file_id: u64, reason: &str) -> SDError {
+    debug!("op log can't be replayed, op for file {}: {}", file_id, reason);
+
+    SDError::OpLogUnreadable
+}
+
+/// where a block starts in the HMAC list of a file
+fn block_offset(file: &FileNode, file_id: u64, index: u64) -> Result<usize, SDError> {
+    let offset = index as usize * HMAC_SIZE;
+
+    if offset > file.hmacs.len() {
+        return Err(invalid_op(file_id, "block index past the end of the file"));
+    }
+
+    Ok(offset)
+}
+
+fn seal_record(op: &[u8], main_key: &Key) -> Vec<u8> {
+    let nonce = ::sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let sealed = ::sodiumoxide::crypto::secretbox::seal(op, &nonce, &main_key.as_sodium_secretbox_key());
+
+    let mut record = vec![0u8; 4];
+    LittleEndian::write_u32(&mut record, (nonce.0.len() + sealed.len()) as u32);
+    record.extend_from_slice(&nonce.0);
+    record.extend_from_slice(&sealed);
+
+    record
+}
+
+/// read and decrypt every complete record in an op log, along with the length of the log up to
+/// the end of the last one
+fn read_records(file: &mut File, main_key: &Key) -> Result<(Vec<Vec<u8>>, u64), SDError> {
+    let mut data = Vec::new();
+
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+
+    if data.len() < OPLOG_MAGIC.len() || &data[0..OPLOG_MAGIC.len()] != OPLOG_MAGIC {
+        return Err(SDError::OpLogUnreadable);
+    }
+
+    let nonce_size = ::sodiumoxide::crypto::secretbox::NONCEBYTES;
+
+    let mut records = Vec::new();
+    let mut position = OPLOG_MAGIC.len();
+
+    while data.len() - position >= 4 {
+        let length = LittleEndian::read_u32(&data[position..position + 4]) as usize;
+
+        let start = position + 4;
+
+        // the process died while writing the last record
+        if data.len() - start < length {
+            debug!("op log ends with an incomplete record");
+
+            break;
+        }
+
+        if length < nonce_size {
+            return Err(SDError::OpLogUnreadable);
+        }
+
+        let nonce = match ::sodiumoxide::crypto::secretbox::Nonce::from_slice(&data[start..start + nonce_size]) {
+            Some(nonce) => nonce,
+            None => return Err(SDError::OpLogUnreadable),
+        };
+
+        let op = match ::sodiumoxide::crypto::secretbox::open(&data[start + nonce_size..start + length], &nonce, &main_key.as_sodium_secretbox_key()) {
+            Ok(op) => op,
+            Err(_) => {
+                debug!("failed to decrypt op log record at {}", position);
+
+                return Err(SDError::OpLogUnreadable);
+            },
+        };
+
+        records.push(op);
+
+        position = start + length;
+    }
+
+    Ok((records, position as u64))
+}
+
+/// parse decrypted records, checking that they are numbered one after another from 0
+fn parse_records(records: &[Vec<u8>]) -> Result<Vec<Op>, SDError> {
+    let mut ops = Vec::new();
+
+    for (sequence, record) in records.iter().enumerate() {
+        let op = Op::from_binary(record)?;
+
+        if op.sequence != sequence as u64 {
+            debug!("op log out of order, expected op {} but found {}", sequence, op);
+
+            return Err(SDError::OpLogUnreadable);
+        }
+
+        ops.push(op);
+    }
+
+    Ok(ops)
+}
+
+/// the session name recorded by the first op in a log
+fn initial_session(ops: &[Op]) -> Result<String, SDError> {
+    match ops.first() {
+        Some(op) if op.op_type() == Some(OpType::Initial) => read_string(op.data_pointers),
+        _ => {
+            debug!("op log doesn't start with an initial op");
+
+            Err(SDError::OpLogUnreadable)
+        },
+    }
+}
+
+fn push_u64(data: &mut Vec<u8>, value: u64) {
+    let mut buf = [0u8; 8];
+    LittleEndian::write_u64(&mut buf, value);
+    data.extend_from_slice(&buf);
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, SDError> {
+    if data.len() < offset + 8 {
+        return Err(SDError::OpLogUnreadable);
+    }
+
+    Ok(LittleEndian::read_u64(&data[offset..offset + 8]))
+}
+
+fn read_string(data: &[u8]) -> Result<String, SDError> {
+    match std::str::from_utf8(data) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err(SDError::OpLogUnreadable),
+    }
+}
+
+#[test]
+fn op_binary_test() {
+    let data = vec![9u8; HMAC_SIZE];
+
+    let op = Op::new(42, OpType::InsertData, FileType::Regular, 7, &data);
+    let binary = op.to_binary();
+
+    let parsed = Op::from_binary(&binary).unwrap();
+    assert!(parsed == op);
+    assert!(parsed.op_type() == Some(OpType::InsertData));
+
+    let change = Change::Create { path: "docs/link".to_string(), link_target: Some("../target".to_string()) };
+    assert!(Change::decode(OpType::FileCreate, &change.encode()).unwrap() == Some(change));
+
+    let change = Change::DeleteData { index: 3, count: 2, size: 1000 };
+    assert!(Change::decode(OpType::DeleteData, &change.encode()).unwrap() == Some(change));
+
+    assert!(Change::decode(OpType::UpdateMTime, &[1, 2, 3]).is_err());
+    assert!(Op::from_binary(b"sdo").is_err());
+}
+
+#[test]
+fn oplog_replay_test() {
+    use tar::{Builder, Header, EntryType};
+    use keys::KeyType;
+    use models::SyncVersion;
+
+    let main_key = Key::new(KeyType::Main);
+
+    let mut storage = ::std::env::temp_dir();
+    storage.push(format!("sddk-oplog-{}", ::util::generate_uuid()));
+
+    let dir = storage.join("oplog");
+
+    let first_hmacs = vec![1u8; HMAC_SIZE * 2];
+
+    let raw_session = {
+        let mut ar = Builder::new(Vec::new());
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        header.set_cksum();
+        ::metadata::append_entry(&mut ar, &mut header, "docs", &[], &[]).unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(1000);
+        ::metadata::set_real_size(&mut header, 200);
+        header.set_size(first_hmacs.len() as u64);
+        header.set_cksum();
+        ::metadata::append_entry(&mut ar, &mut header, "docs/first.txt", &first_hmacs, &[]).unwrap();
+
+        ar.into_inner().unwrap()
+    };
+
+    let session = SyncSession::new(SyncVersion::Version2, 1, "session".to_string(), None, None, raw_session);
+
+    let initial = TreeState::from_session(&session).unwrap();
+    assert!(initial.file_id("docs") == Some(1));
+    assert!(initial.file_id("docs/first.txt") == Some(2));
+    assert!(initial.next_file_id() == 3);
+
+    let new_hmacs = vec![2u8; HMAC_SIZE];
+
+    {
+        let mut oplog = OpLog::create_in(dir.clone(), &main_key, "session").unwrap();
+
+        oplog.record(3, FileType::Regular, &Change::Create { path: "docs/second.txt".to_string(), link_target: None }).unwrap();
+        oplog.record(3, FileType::Regular, &Change::InsertData { index: 0, size: 100, hmacs: new_hmacs.clone() }).unwrap();
+        oplog.record(2, FileType::Regular, &Change::UpdateData { index: 1, size: 200, hmacs: new_hmacs.clone() }).unwrap();
+        oplog.commit().unwrap();
+
+        // a rolled back transaction has no effect
+        oplog.record(2, FileType::Regular, &Change::Delete).unwrap();
+        oplog.rollback().unwrap();
+
+        oplog.record(1, FileType::Directory, &Change::Rename { path: "papers".to_string() }).unwrap();
+        oplog.commit().unwrap();
+
+        // left uncommitted, as if the process died before the transaction finished
+        oplog.record(2, FileType::Regular, &Change::SetMTime(5000)).unwrap();
+        assert!(oplog.has_pending());
+    }
+
+    // part of a record, as if the process died while writing it
+    {
+        let mut f = OpenOptions::new().append(true).open(dir.join(OPLOG_FILE)).unwrap();
+        f.write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+    }
+
+    {
+        let mut oplog = OpLog::open_in(dir.clone(), &main_key).unwrap();
+        assert!(oplog.session_name() == "session");
+        assert!(!oplog.has_pending());
+
+        oplog.record(2, FileType::Regular, &Change::SetPermissions(0o600)).unwrap();
+        oplog.commit().unwrap();
+    }
+
+    let state = replay_in(&dir, &main_key, &session).unwrap();
+
+    let first = state.file(2).unwrap();
+    assert!(first.path == "papers/first.txt");
+    assert!(first.mode == 0o600);
+    assert!(first.mtime == 1000);
+    assert!(&first.hmacs[0..HMAC_SIZE] == &first_hmacs[0..HMAC_SIZE]);
+    assert!(&first.hmacs[HMAC_SIZE..] == &new_hmacs[..]);
+
+    let second = state.file(3).unwrap();
+    assert!(second.path == "papers/second.txt");
+    assert!(second.hmacs == new_hmacs);
+    assert!(second.size == Some(100));
+
+    assert!(state.files().len() == 3);
+    assert!(state.next_file_id() == 4);
+
+    // ops that don't fit the tree can't be applied
+    let mut broken = state.clone();
+    assert!(broken.apply(9, FileType::Regular, &Change::Delete).is_err());
+    assert!(broken.apply(4, FileType::Regular, &Change::Create { path: "missing/file".to_string(), link_target: None }).is_err());
+    assert!(broken.apply(3, FileType::Regular, &Change::DeleteData { index: 0, count: 2, size: 0 }).is_err());
+
+    // the log can only be replayed on top of the session it started from
+    let other = SyncSession::new(SyncVersion::Version2, 1, "other".to_string(), None, None, Vec::new());
+    assert!(replay_in(&dir, &main_key, &other).is_err());
+
+    // or with the key it was written with
+    assert!(replay_in(&dir, &Key::new(KeyType::Main), &session).is_err());
+
+    let _ = fs::remove_dir_all(&storage);
+}
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// external crate imports
+
+use parking_lot::Mutex;
+use parking_lot_mpsc::{Receiver, Sender, SyncSender};
+
+// internal imports
+
+use block::{Block, WrappedBlock};
+use chunk::{BlockGenerator, BlockGeneratorStats};
+use error::SDError;
+use keys::Key;
+use models::SyncVersion;
+
+/// A block that has been hashed, compressed and encrypted by a pipeline worker
+pub struct ProcessedBlock {
+    pub hmac: Vec<u8>,
+    pub real_size: u64,
+    pub compressed_size: Option<u64>,
+    pub wrapped: WrappedBlock,
+}
+
+struct FileJob {
+    file: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+struct BlockJob {
+    file: u64,
+    sequence: u64,
+    data: Vec<u8>,
+}
+
+enum BlockOutput {
+    Block(Result<ProcessedBlock, SDError>),
+    /// every chunk in the file has been read, along with the stats from reading it
+    Finished(BlockGeneratorStats),
+}
+
+struct BlockResult {
+    file: u64,
+    sequence: u64,
+    output: BlockOutput,
+}
+
+/// A reader thread and a pool of worker threads that turn files into wrapped blocks
+///
+/// Finding chunk boundaries has to happen in order, so files are read one after another on the
+/// reader thread in the order they're queued. Everything done to a chunk after it has been read
+/// (the HMAC, compression and encryption) is independent of every other chunk, so those steps
+/// are handed to the workers. Since the reader moves straight on to the next queued file, blocks
+/// from several small files can be in flight at once; the results are put back in (file, block)
+/// order before being returned.
+pub struct BlockPipeline {
+    file_send: Option<Sender<FileJob>>,
+    credit_receive: Option<Receiver<()>>,
+    result_receive: Receiver<BlockResult>,
+    pending: BTreeMap<(u64, u64), BlockOutput>,
+    reader: Option<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+    in_flight_limit: usize,
+    next_file: u64,
+    current_file: u64,
+}
+
+impl BlockPipeline {
+    /// start a pipeline with `worker_count` threads, or one per CPU if `worker_count` is zero
+    pub fn new(worker_count: usize, main_key: &Key, hmac_key: &Key, tweak_key: &Key, version: SyncVersion) -> BlockPipeline {
+        let worker_count = match worker_count {
+            0 => ::num_cpus::get(),
+            count => count,
+        };
+
+        // keep every worker busy while the next chunk is being read, without holding too many
+        // chunks in memory at once
+        let in_flight_limit = worker_count * 2;
+
+        let (file_send, file_receive) = ::parking_lot_mpsc::channel::<FileJob>();
+        let (job_send, job_receive) = ::parking_lot_mpsc::sync_channel::<BlockJob>(in_flight_limit);
+        let (result_send, result_receive) = ::parking_lot_mpsc::channel::<BlockResult>();
+
+        // the reader takes a credit for every result it causes and the results are only handed
+        // back once the one before them has been returned, so this bounds the blocks held in
+        // memory, wherever they are in the pipeline
+        let (credit_send, credit_receive) = ::parking_lot_mpsc::sync_channel::<()>(in_flight_limit);
+
+        let job_receive = Arc::new(Mutex::new(job_receive));
+
+        let mut workers = Vec::with_capacity(worker_count);
+
+        debug!("starting block pipeline with {} workers", worker_count);
+
+        for _ in 0..worker_count {
+            let job_receive = job_receive.clone();
+            let result_send: Sender<BlockResult> = result_send.clone();
+            let main_key = main_key.clone();
+            let hmac_key = hmac_key.clone();
+
+            let worker = thread::spawn(move || {
+                loop {
+                    let job = {
+                        let receiver = job_receive.lock();
+
+                        match receiver.recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        }
+                    };
+
+                    let result = BlockResult {
+                        file: job.file,
+                        sequence: job.sequence,
+                        output: BlockOutput::Block(process_block(version, &main_key, &hmac_key, job.data)),
+                    };
+
+                    if result_send.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            workers.push(worker);
+        }
+
+        let main_key = main_key.clone();
+        let hmac_key = hmac_key.clone();
+        let tweak_key = tweak_key.clone();
+
+        let reader = thread::spawn(move || {
+            loop {
+                let job = match file_receive.recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+
+                let stopped = read_file(job, &main_key, &hmac_key, &tweak_key, version, &job_send, &result_send, &credit_send).is_err();
+
+                if stopped {
+                    break;
+                }
+            }
+        });
+
+        BlockPipeline {
+            file_send: Some(file_send),
+            credit_receive: Some(credit_receive),
+            result_receive: result_receive,
+            pending: BTreeMap::new(),
+            reader: Some(reader),
+            workers: workers,
+            in_flight_limit: in_flight_limit,
+            next_file: 0,
+            current_file: 0,
+        }
+    }
+
+    /// the number of blocks the pipeline works on at once
+    ///
+    /// Queueing this many files ahead of the one being returned keeps every worker busy even
+    /// when the files are small.
+    pub fn capacity(&self) -> usize {
+        self.in_flight_limit
+    }
+
+    /// queue a file of `size` bytes to be chunked, returning the number to pass to `blocks()`
+    pub fn queue(&mut self, path: &Path, size: u64) -> u64 {
+        let file = self.next_file;
+        self.next_file += 1;
+
+        let job = FileJob {
+            file: file,
+            path: path.to_path_buf(),
+            size: size,
+        };
+
+        if let Some(ref file_send) = self.file_send {
+            if file_send.send(job).is_err() {
+                debug!("block pipeline reader stopped before file {} was queued", file);
+            }
+        }
+
+        file
+    }
+
+    /// the blocks of a queued file, in the order they appear in the file
+    ///
+    /// Files have to be asked for in the order they were queued. Asking for a file gives up on
+    /// the rest of any file before it, so a caller can stop partway through a file.
+    pub fn blocks<'p>(&'p mut self, file: u64) -> PipelineBlocks<'p> {
+        self.current_file = file;
+
+        let abandoned: Vec<(u64, u64)> = self.pending.keys().cloned().filter(|&(f, _)| f < file).collect();
+
+        for key in abandoned {
+            self.pending.remove(&key);
+            self.release();
+        }
+
+        PipelineBlocks {
+            pipeline: self,
+            file: file,
+            next_sequence: 0,
+            compressed_size: 0,
+            stats: None,
+            finished: false,
+        }
+    }
+
+    /// wait for the result at `sequence` in `file`, or `None` if the threads have stopped
+    fn take(&mut self, file: u64, sequence: u64) -> Option<BlockOutput> {
+        loop {
+            if let Some(output) = self.pending.remove(&(file, sequence)) {
+                self.release();
+
+                return Some(output);
+            }
+
+            match self.result_receive.recv() {
+                Ok(result) => {
+                    // results for a file that was abandoned part way through are dropped
+                    if result.file < self.current_file {
+                        self.release();
+
+                        continue;
+                    }
+
+                    self.pending.insert((result.file, result.sequence), result.output);
+                },
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // every result the reader causes takes a credit before it's sent, so this never waits
+    fn release(&self) {
+        if let Some(ref credit_receive) = self.credit_receive {
+            let _ = credit_receive.try_recv();
+        }
+    }
+}
+
+impl Drop for BlockPipeline {
+    fn drop(&mut self) {
+        // closing the file queue and the credits stops the reader, which closes the job channel
+        // and tells the workers to exit once they finish what they're doing
+        self.file_send = None;
+        self.credit_receive = None;
+
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The blocks for a single file, in the order they appear in the file
+pub struct PipelineBlocks<'p> {
+    pipeline: &'p mut BlockPipeline,
+    file: u64,
+    next_sequence: u64,
+    compressed_size: u64,
+    stats: Option<BlockGeneratorStats>,
+    finished: bool,
+}
+
+impl<'p> PipelineBlocks<'p> {
+    /// the stats from chunking the file, once every block in it has been returned
+    pub fn stats(&self) -> Option<&BlockGeneratorStats> {
+        self.stats.as_ref()
+    }
+}
+
+impl<'p> Iterator for PipelineBlocks<'p> {
+    type Item = Result<ProcessedBlock, SDError>;
+
+    fn next(&mut self) -> Option<Result<ProcessedBlock, SDError>> {
+        if self.finished {
+            return None;
+        }
+
+        match self.pipeline.take(self.file, self.next_sequence) {
+            Some(BlockOutput::Block(result)) => {
+                self.next_sequence += 1;
+
+                match result {
+                    Ok(ref block) => {
+                        match block.compressed_size {
+                            Some(size) => self.compressed_size += size,
+                            None => self.compressed_size += block.real_size,
+                        }
+                    },
+                    // nothing more is read from a file after an error
+                    Err(_) => self.finished = true,
+                }
+
+                Some(result)
+            },
+            Some(BlockOutput::Finished(mut stats)) => {
+                stats.processed_size_compressed = self.compressed_size;
+
+                self.stats = Some(stats);
+                self.finished = true;
+
+                None
+            },
+            None => {
+                self.finished = true;
+
+                Some(Err(SDError::Internal("block pipeline stopped unexpectedly".to_string())))
+            },
+        }
+    }
+}
+
+/// chunk a file on the reader thread and hand each chunk to the workers, returning `Err` once
+/// the pipeline is being shut down
+fn read_file(job: FileJob,
+             main_key: &Key,
+             hmac_key: &Key,
+             tweak_key: &Key,
+             version: SyncVersion,
+             job_send: &SyncSender<BlockJob>,
+             result_send: &Sender<BlockResult>,
+             credit_send: &SyncSender<()>) -> Result<(), ()> {
+    let mut sequence = 0;
+
+    let output = match File::open(&job.path) {
+        Ok(_) => {
+            // the generator panics when the file can't be read, which has to fail this file
+            // rather than stop the reader with blocks still owed to the sync
+            let read = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut generator = BlockGenerator::new(&job.path, main_key, hmac_key, tweak_key, job.size, version);
+
+                loop {
+                    match generator.next_chunk() {
+                        Some(Ok(data)) => {
+                            let block_job = BlockJob {
+                                file: job.file,
+                                sequence: sequence,
+                                data: data,
+                            };
+
+                            if credit_send.send(()).is_err() || job_send.send(block_job).is_err() {
+                                return None;
+                            }
+
+                            sequence += 1;
+                        },
+                        Some(Err(e)) => return Some(BlockOutput::Block(Err(e))),
+                        None => return Some(BlockOutput::Finished(generator.stats())),
+                    }
+                }
+            }));
+
+            match read {
+                Ok(Some(output)) => output,
+                Ok(None) => return Err(()),
+                Err(_) => BlockOutput::Block(Err(SDError::Internal(format!("failed to read {}", job.path.display())))),
+            }
+        },
+        Err(e) => BlockOutput::Block(Err(SDError::from(e))),
+    };
+
+    let result = BlockResult {
+        file: job.file,
+        sequence: sequence,
+        output: output,
+    };
+
+    if credit_send.send(()).is_err() || result_send.send(result).is_err() {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn process_block(version: SyncVersion, main_key: &Key, hmac_key: &Key, data: Vec<u8>) -> Result<ProcessedBlock, SDError> {
+    let block = Block::new(version, hmac_key, data);
+
+    let hmac = block.get_hmac();
+    let real_size = block.real_size();
+    let compressed_size = block.compressed_size();
+
+    let wrapped = match block.to_wrapped(main_key) {
+        Ok(wb) => wb,
+        Err(e) => return Err(SDError::CryptoError(Box::new(e))),
+    };
+
+    Ok(ProcessedBlock {
+        hmac: hmac,
+        real_size: real_size,
+        compressed_size: compressed_size,
+        wrapped: wrapped,
+    })
+}
+
+#[test]
+fn pipeline_block_order_test() {
+    use std::io::Write;
+
+    let main_key = Key::new(::keys::KeyType::Main);
+    let hmac_key = Key::new(::keys::KeyType::HMAC);
+    let tweak_key = Key::new(::keys::KeyType::Tweak);
+
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-pipeline-{}", ::util::generate_uuid()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+
+    let large: Vec<u8> = (0..4_000_000u32).map(|i| ((i * 7) % 253) as u8).collect();
+
+    let mut files: Vec<(PathBuf, Vec<u8>)> = vec![(dir.join("large"), large.clone())];
+
+    // many small files back to back, each one a single block
+    for i in 0..20u32 {
+        files.push((dir.join(format!("small-{}", i)), format!("small file {}", i).into_bytes()));
+    }
+
+    files.push((dir.join("large-again"), large.clone()));
+
+    for &(ref path, ref data) in &files {
+        let mut f = File::create(path).unwrap();
+        f.write_all(data).unwrap();
+    }
+
+    let version = SyncVersion::Version2;
+
+    let expected_hmacs = |path: &Path, size: u64| -> Vec<Vec<u8>> {
+        let generator = BlockGenerator::new(path, &main_key, &hmac_key, &tweak_key, size, version);
+
+        generator.map(|block| block.unwrap().get_hmac()).collect()
+    };
+
+    let mut pipeline = BlockPipeline::new(4, &main_key, &hmac_key, &tweak_key, version);
+
+    // every file is queued before any of them is read back, so their blocks are in flight
+    // together, and nothing from one file can leak into another
+    let queued: Vec<u64> = files.iter().map(|&(ref path, ref data)| pipeline.queue(path, data.len() as u64)).collect();
+
+    for (&(ref path, ref data), &file) in files.iter().zip(queued.iter()) {
+        let mut hmacs: Vec<Vec<u8>> = Vec::new();
+        let mut restored: Vec<u8> = Vec::new();
+
+        let mut blocks = pipeline.blocks(file);
+
+        for block in &mut blocks {
+            let block = block.unwrap();
+            hmacs.push(block.hmac.clone());
+
+            let b = block.wrapped.to_block(&main_key).unwrap();
+            restored.extend_from_slice(b.as_ref());
+        }
+
+        assert!(hmacs == expected_hmacs(path, data.len() as u64));
+        assert!(&restored == data);
+        assert!(blocks.stats().unwrap().processed_size == data.len() as u64);
+    }
+
+    // a file given up on partway through doesn't hold up the one after it
+    let abandoned = pipeline.queue(&dir.join("large"), large.len() as u64);
+    let missing = pipeline.queue(&dir.join("missing"), 10);
+    let small = pipeline.queue(&files[1].0, files[1].1.len() as u64);
+
+    assert!(pipeline.blocks(abandoned).next().unwrap().is_ok());
+
+    {
+        let mut blocks = pipeline.blocks(missing);
+        assert!(blocks.next().unwrap().is_err());
+        assert!(blocks.next().is_none());
+    }
+
+    let hmacs: Vec<Vec<u8>> = pipeline.blocks(small).map(|block| block.unwrap().hmac).collect();
+    assert!(hmacs == expected_hmacs(&files[1].0, files[1].1.len() as u64));
+
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
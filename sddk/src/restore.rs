@@ -1,51 +1,67 @@
 use std::str;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::fs::File;
-use std::io::{BufWriter, Read, Write, Seek, SeekFrom};
-use std::{thread, time};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom};
+use std::sync::Arc;
+use std::thread;
 
 // external crate imports
-use rustc_serialize::hex::{ToHex, FromHex};
-use rand::distributions::{IndependentSample, Range};
 use tar::{Archive, EntryType};
+use glob::{Pattern, MatchOptions};
 use nom::IResult::*;
 
 // internal imports
 
 use models::*;
-use block::*;
-use sdapi::*;
 use keys::*;
 
-use core::get_sync_folder;
+use backend::{StorageBackend, find_folder};
 
 
 #[cfg(feature = "locking")]
 use lock::FolderLock;
 
-use error::{SDAPIError, SDError};
+use error::SDError;
 
-use session::{WrappedSyncSession};
+use session::SyncSession;
 
 use sync_state::is_sync_task_cancelled;
 
+use fetch::BlockFetcher;
+use metadata::EntryMetadata;
+use chunk::ChunkGenerator;
 
-pub fn restore(token: &Token,
+use constants::HMAC_SIZE;
+
+
+pub fn restore(backend: Arc<StorageBackend>,
                session_name: &str,
                main_key: &Key,
                folder_id: u64,
                destination: PathBuf,
-               session_size: u64) -> ::parking_lot_mpsc::Receiver<SyncStatus> {
+               session_size: u64,
+               filter: RestoreFilter,
+               resume: Option<ResumeKeys>) -> ::parking_lot_mpsc::Receiver<SyncStatus> {
     let (sync_status_send, sync_status_receive) = ::parking_lot_mpsc::sync_channel::<SyncStatus>(1000);
 
-    let token_local = token.to_owned();
     let session_name_local = session_name.to_owned();
     let main_key_local = main_key.to_owned();
 
     thread::spawn(move || {
         let restore_start_time = ::std::time::Instant::now();
 
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Scan });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+
 
         if let Err(err) = fs::create_dir_all(&destination) {
             let status_message = SyncStatus::Err(SDError::from(err));
@@ -77,7 +93,7 @@ pub fn restore(token: &Token,
             return;
         }
 
-        let folder = match get_sync_folder(&token_local, folder_id) {
+        let folder = match find_folder(&*backend, folder_id) {
             Ok(folder) => folder,
             Err(e) => {
                 let status_message = SyncStatus::Err(SDError::from(e));
@@ -107,10 +123,12 @@ pub fn restore(token: &Token,
 
         let read_session_start_time = ::std::time::Instant::now();
 
-        let session_body = match read_session(&token_local, folder_id, &session_name_local, true) {
-            Ok(session_data) => session_data,
+        // chunked sessions are opened as they arrive rather than read into memory first, and
+        // the archive of a version 3 session is put back together from its blocks
+        let session = match ::browse::read_session(&*backend, folder_id, &session_name_local, &main_key_local) {
+            Ok(s) => s,
             Err(e) => {
-                let status_message = SyncStatus::Err(SDError::from(e));
+                let status_message = SyncStatus::Err(e);
                 match sync_status_send.send(status_message) {
                     Ok(()) => {
 
@@ -129,30 +147,39 @@ pub fn restore(token: &Token,
         debug!("restoring session for: {} (folder id {})", folder_name, folder_id);
 
 
-        let session_processing_start_time = ::std::time::Instant::now();
+        let mut processed_size: u64 = 0;
 
-        let w_session = match WrappedSyncSession::from(session_body) {
-            Ok(ws) => ws,
-            Err(e) => {
-                let status_message = SyncStatus::Err(e);
-                match sync_status_send.send(status_message) {
-                    Ok(()) => {
+        // when resuming, work out how much of each file already in the destination can be kept
+        // before deciding which blocks have to be downloaded
+        let plans = match resume {
+            Some(ref keys) => {
+                let resume_plan_start_time = ::std::time::Instant::now();
 
-                    },
-                    Err(_) => {
+                let plans = plan_resume(&session, &filter, &destination, keys);
 
-                    },
-                }
-                return;
-            }
+                trace!("Resume planning took {} seconds", resume_plan_start_time.elapsed().as_secs());
+
+                debug!("{} existing files found, {} already restored", plans.len(), plans.values().filter(|plan| plan.complete).count());
+
+                plans
+            },
+            None => HashMap::new(),
         };
-        trace!("Session processing took {} seconds", session_processing_start_time.elapsed().as_secs());
 
+        // collect every block the session needs up front, so they can be downloaded ahead of
+        // the file currently being written
+        let block_hmacs = session_block_list(&session, &filter, &destination, &plans);
 
-        let session_unwrap_start_time = ::std::time::Instant::now();
+        debug!("session needs {} blocks", block_hmacs.len());
 
-        let session = match w_session.to_session(&main_key_local) {
-            Ok(s) => s,
+        let mut fetcher = BlockFetcher::new(backend.clone(), &main_key_local, &session_name_local, block_hmacs, *::RESTORE_WORKERS.read());
+
+        let mut block_index: usize = 0;
+
+        let mut directories: Vec<(PathBuf, EntryMetadata)> = Vec::new();
+
+        let mut ar = match session.reader() {
+            Ok(reader) => Archive::new(reader),
             Err(e) => {
                 let status_message = SyncStatus::Err(e);
                 match sync_status_send.send(status_message) {
@@ -167,15 +194,19 @@ pub fn restore(token: &Token,
             }
         };
 
-        trace!("Session unwrapping took {} seconds", session_unwrap_start_time.elapsed().as_secs());
+        let mut failed = 0;
 
-        let mut processed_size: u64 = 0;
+        let archive_reading_start_time = ::std::time::Instant::now();
 
-        let mut ar = Archive::new(session.as_ref());
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Download });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
 
-        let mut failed = 0;
+            },
+            Err(_) => {
 
-        let archive_reading_start_time = ::std::time::Instant::now();
+            },
+        }
 
         for item in ar.entries().unwrap() {
             if is_sync_task_cancelled(session_name_local.clone()) {
@@ -210,13 +241,29 @@ pub fn restore(token: &Token,
                 },
             };
 
+            // information about the session as a whole, not something to restore
+            if file_entry.header().entry_type() == EntryType::XGlobalHeader {
+                continue;
+            }
+
             let mut full_path = PathBuf::from(&destination);
 
+            // the path inside the session, used to tell the caller which file an event is about
+            let entry_name: String;
+
             match file_entry.path() {
                 Ok(ref entry_path) => {
+                    if !filter.matches(entry_path) {
+                        trace!("skipping {}", &entry_path.display());
+
+                        continue;
+                    }
+
                     debug!("examining {}", &entry_path.display());
 
                     full_path.push(entry_path);
+
+                    entry_name = entry_path.to_string_lossy().into_owned();
                 },
                 Err(e) => {
                     let status_message = SyncStatus::Issue(format!("cannot restore invalid path in session {}:", e));
@@ -251,10 +298,88 @@ pub fn restore(token: &Token,
 
             let entry_type = file_entry.header().entry_type();
 
+            let entry_metadata = EntryMetadata::from_entry(&mut file_entry);
+
+            // when only part of a session is restored, the directories above an entry may not
+            // have been restored by an earlier entry
+            if !filter.is_all() {
+                if let Some(parent) = full_path.parent() {
+                    if let Err(err) = fs::create_dir_all(parent) {
+                        let status_message = SyncStatus::Issue(format!("not able to create directory {}: {}", parent.display(), err));
+                        match sync_status_send.send(status_message) {
+                            Ok(()) => {
+
+                            },
+                            Err(_) => {
+
+                            },
+                        }
+                    }
+                }
+            }
+
             // process if not a directory or socket
             match entry_type {
                 EntryType::Regular => {
-                    let f = match File::create(&full_path) {
+                    let stream_length = file_entry.header().size().unwrap();
+
+                    let status_message = SyncStatus::Event(SyncEvent::FileStarted {
+                        path: entry_name.clone(),
+                        size: ::metadata::real_size(file_entry.header()).unwrap_or(0),
+                    });
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+
+                    let plan = plans.get(&full_path);
+
+                    if let Some(plan) = plan {
+                        if plan.complete {
+                            debug!("{} is already restored", full_path.display());
+
+                            processed_size += plan.reused_size();
+
+                            let status_message = SyncStatus::Progress(session_size, processed_size, plan.reused_size());
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+
+                            apply_metadata(&entry_metadata, &full_path, &sync_status_send);
+
+                            let status_message = SyncStatus::Event(SyncEvent::FileFinished {
+                                path: entry_name.clone(),
+                                size: plan.reused_size(),
+                            });
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+
+                            continue;
+                        }
+                    }
+
+                    let opened = match plan {
+                        // keep what a previous restore wrote, only the blocks that differ are replaced
+                        Some(_) => OpenOptions::new().write(true).create(true).open(&full_path),
+                        None => File::create(&full_path),
+                    };
+
+                    let f = match opened {
                         Ok(file) => file,
                         Err(err) => {
                             let status_message = SyncStatus::Issue(format!("not able to create file at {}: {}", full_path.display(), err));
@@ -267,15 +392,25 @@ pub fn restore(token: &Token,
                                 },
                             }
 
+                            skip_entry(&entry_name, SkipReason::WriteFailed, &err.to_string(), &sync_status_send);
+
+                            // the blocks for this file won't be used
+                            block_index += match plan {
+                                Some(plan) => plan.fetched(),
+                                None => (stream_length / HMAC_SIZE as u64) as usize,
+                            };
+
                             failed = failed +1;
                             continue;
                         },
                     };
 
-                    let stream_length = file_entry.header().size().unwrap();
-
                     trace!("entry has {} blocks", stream_length / 32);
 
+                    // bytes in the restored file, and whether all of them could be restored
+                    let mut written: u64 = 0;
+                    let mut incomplete = false;
+
                     if stream_length > 0 {
                         let mut stream = BufWriter::new(f);
 
@@ -314,7 +449,10 @@ pub fn restore(token: &Token,
                         };
 
 
-                        for block_hmac in block_hmac_list.iter() {
+                        // where the next block belongs in the file
+                        let mut position: u64 = 0;
+
+                        for (hmac_index, block_hmac) in block_hmac_list.iter().enumerate() {
                             if is_sync_task_cancelled(session_name_local.clone()) {
                                 let status_message = SyncStatus::Issue(format!("sync cancelled ({})", session_name_local));
                                 match sync_status_send.send(status_message) {
@@ -351,29 +489,16 @@ pub fn restore(token: &Token,
                                 },
                             }
 
-                            let mut should_retry = true;
-                            let mut retries_left = 15.0;
-
-                            let block_hmac_hex = block_hmac.to_hex();
-                            debug!("processing block {}", &block_hmac_hex);
-
-
-                            let mut wrapped_block: Option<WrappedBlock> = None;
-                            let block_read_start_time = ::std::time::Instant::now();
-
-                            // get block from cache if possible
-                            match ::cache::read_block(&block_hmac_hex) {
-                                Ok(br) => {
-                                    should_retry = false;
-                                    wrapped_block = Some(br);
-                                    debug!("cache provided block: {}", &block_hmac_hex);
-                                },
-                                _ => {},
+                            let kept = match plan {
+                                Some(plan) => plan.reuse[hmac_index],
+                                None => None,
                             };
 
-                            while should_retry {
-                                if is_sync_task_cancelled(session_name_local.clone()) {
-                                    let status_message = SyncStatus::Issue(format!("sync cancelled ({})", session_name_local));
+                            if let Some((offset, size)) = kept {
+                                if offset != position {
+                                    // the blocks written before this one didn't add up to the
+                                    // size recorded for the file, so the rest of it can't be kept
+                                    let status_message = SyncStatus::Issue(format!("not able to resume restoring {}: the file size in the session doesn't match its contents", full_path.display()));
                                     match sync_status_send.send(status_message) {
                                         Ok(()) => {
 
@@ -383,7 +508,21 @@ pub fn restore(token: &Token,
                                         },
                                     }
 
-                                    let status_message = SyncStatus::Err(SDError::Cancelled);
+                                    if let Some(plan) = plan {
+                                        block_index += plan.reuse[hmac_index..].iter().filter(|kept| kept.is_none()).count();
+                                    }
+
+                                    skip_entry(&entry_name, SkipReason::Other, "the file size in the session doesn't match its contents", &sync_status_send);
+
+                                    failed = failed + 1;
+                                    incomplete = true;
+                                    break;
+                                }
+
+                                trace!("keeping {} bytes already at {}", size, offset);
+
+                                if let Err(err) = stream.seek(SeekFrom::Current(size as i64)) {
+                                    let status_message = SyncStatus::Err(SDError::from(err));
                                     match sync_status_send.send(status_message) {
                                         Ok(()) => {
 
@@ -392,138 +531,71 @@ pub fn restore(token: &Token,
 
                                         },
                                     }
-
                                     return;
                                 }
 
-                                let failed_count = 15.0 - retries_left;
-                                let mut rng = ::rand::thread_rng();
-
-                                // we pick a multiplier randomly to avoid a bunch of clients trying again
-                                // at the same 2/4/8/16 back off time over and over if the server
-                                // is overloaded or down
-                                let backoff_multiplier = Range::new(0.0, 1.5).ind_sample(&mut rng);
-
-                                if failed_count >= 2.0 && retries_left > 0.0 {
-                                    // back off significantly every time a call fails but only after the
-                                    // second try, the first failure could be us not including the data
-                                    // when we should have
-                                    let backoff_time = backoff_multiplier * (failed_count * failed_count);
-                                    let delay = time::Duration::from_millis((backoff_time * 1000.0) as u64);
-                                    debug!("backing off for {:?}", delay);
-
-                                    thread::sleep(delay);
-                                }
-
-                                // get block from the server
-
-                                match ::sdapi::read_block(&token_local, &block_hmac_hex) {
-                                    Ok(rb) => {
-                                        trace!("Block read took {} seconds", block_read_start_time.elapsed().as_secs());
+                                position += size;
+                                processed_size += size;
 
-                                        // allow caller to tick the progress display, if one exists
-                                        let status_message = SyncStatus::Progress(session_size, processed_size, 0);
-                                        match sync_status_send.send(status_message) {
-                                            Ok(()) => {
-
-                                            },
-                                            Err(_) => {
-
-                                            },
-                                        }
-
-                                        should_retry = false;
-                                        debug!("server provided block: {}", &block_hmac_hex);
-                                        let block_processing_start_time = ::std::time::Instant::now();
-
-                                        let wb = match WrappedBlock::from(rb, (&block_hmac_hex).from_hex().unwrap()) {
-                                            Ok(wb) => wb,
-                                            Err(e) => {
-                                                debug!("block failed validation: {}", &block_hmac_hex);
-                                                let status_message = SyncStatus::Err(e);
-                                                match sync_status_send.send(status_message) {
-                                                    Ok(()) => {
-
-                                                    },
-                                                    Err(_) => {
-
-                                                    },
-                                                }
-                                                return;
-                                            },
-                                        };
-                                        trace!("Block processing took {} seconds", block_processing_start_time.elapsed().as_secs());
-
-                                        debug!("block processed: {}", &block_hmac_hex);
-
-                                        let block_cache_write_time = ::std::time::Instant::now();
-
-                                        match ::cache::write_binary(&wb) {
-                                            _ => {},
-                                        };
-                                        trace!("Block write to cache took {} seconds", block_cache_write_time.elapsed().as_secs());
+                                let status_message = SyncStatus::Progress(session_size, processed_size, size);
+                                match sync_status_send.send(status_message) {
+                                    Ok(()) => {
 
-                                        wrapped_block = Some(wb);
                                     },
-                                    Err(SDAPIError::Authentication) => {
-                                        let status_message = SyncStatus::Err(SDError::Authentication);
-                                        match sync_status_send.send(status_message) {
-                                            Ok(()) => {
-
-                                            },
-                                            Err(_) => {
+                                    Err(_) => {
 
-                                            },
-                                        }
-                                        return;
                                     },
-                                    Err(SDAPIError::RequestFailed(err)) => {
-                                        retries_left = retries_left - 1.0;
-
-                                        let status_message = SyncStatus::Progress(session_size, processed_size, 0);
-                                        match sync_status_send.send(status_message) {
-                                            Ok(()) => {
+                                }
 
-                                            },
-                                            Err(_) => {
+                                continue;
+                            }
 
-                                            },
-                                        }
+                            let block = match fetcher.get(block_index, block_hmac) {
+                                Ok(b) => b,
+                                Err(SDError::RequestFailure(err)) => {
+                                    let status_message = SyncStatus::Issue(format!("not able to retrieve part of {}: {}", full_path.display(), err));
+                                    match sync_status_send.send(status_message) {
+                                        Ok(()) => {
 
-                                        if retries_left <= 0.0 {
-                                            let status_message = SyncStatus::Issue(format!("not able to retrieve part of {}: {}", full_path.display(), err.description()));
-                                            match sync_status_send.send(status_message) {
-                                                Ok(()) => {
+                                        },
+                                        Err(_) => {
 
-                                                },
-                                                Err(_) => {
+                                        },
+                                    }
 
-                                                },
-                                            }
+                                    let status_message = SyncStatus::Err(SDError::RequestFailure(err));
+                                    match sync_status_send.send(status_message) {
+                                        Ok(()) => {
 
-                                            let status_message = SyncStatus::Err(SDError::RequestFailure(err));
-                                            match sync_status_send.send(status_message) {
-                                                Ok(()) => {
+                                        },
+                                        Err(_) => {
 
-                                                },
-                                                Err(_) => {
+                                        },
+                                    }
+                                    return;
+                                },
+                                Err(SDError::Cancelled) => {
+                                    let status_message = SyncStatus::Issue(format!("sync cancelled ({})", session_name_local));
+                                    match sync_status_send.send(status_message) {
+                                        Ok(()) => {
 
-                                                },
-                                            }
-                                            return;
-                                        }
-                                    },
-                                    _ => {},
-                                };
+                                        },
+                                        Err(_) => {
 
+                                        },
+                                    }
 
-                            }
+                                    let status_message = SyncStatus::Err(SDError::Cancelled);
+                                    match sync_status_send.send(status_message) {
+                                        Ok(()) => {
 
-                            let wrapped_block_s = wrapped_block.unwrap();
-                            let block_unwrap_time = ::std::time::Instant::now();
+                                        },
+                                        Err(_) => {
 
-                            let block = match wrapped_block_s.to_block(&main_key_local) {
-                                Ok(b) => b,
+                                        },
+                                    }
+                                    return;
+                                },
                                 Err(e) => {
                                     let status_message = SyncStatus::Err(e);
                                     match sync_status_send.send(status_message) {
@@ -535,10 +607,10 @@ pub fn restore(token: &Token,
                                         },
                                     }
                                     return;
-                                }
-
+                                },
                             };
-                            trace!("Block unwrapping took {} seconds", block_unwrap_time.elapsed().as_secs());
+
+                            block_index += 1;
 
                             debug!("block unwrapped");
 
@@ -574,6 +646,7 @@ pub fn restore(token: &Token,
 
                             trace!("Block total handling time took {} seconds", block_start_time.elapsed().as_secs());
 
+                            position += block.len() as u64;
                             processed_size += block.len() as u64;
 
                             let status_message = SyncStatus::Progress(session_size, processed_size, block.len() as u64);
@@ -588,10 +661,58 @@ pub fn restore(token: &Token,
 
                         }
 
+                        // the contents have to be on disk before the modification time is set
+                        if let Err(err) = stream.flush() {
+                            let status_message = SyncStatus::Err(SDError::from(err));
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+                            return;
+                        }
+
+                        written = position;
+
+                        // the file being resumed may have been longer than the restored one
+                        if plan.is_some() {
+                            if let Err(err) = stream.get_ref().set_len(position) {
+                                let status_message = SyncStatus::Err(SDError::from(err));
+                                match sync_status_send.send(status_message) {
+                                    Ok(()) => {
+
+                                    },
+                                    Err(_) => {
+
+                                    },
+                                }
+                                return;
+                            }
+                        }
                     } else {
                         // empty file, just write one out with the same metadata but no body
 
                     }
+
+                    apply_metadata(&entry_metadata, &full_path, &sync_status_send);
+
+                    if !incomplete {
+                        let status_message = SyncStatus::Event(SyncEvent::FileFinished {
+                            path: entry_name.clone(),
+                            size: written,
+                        });
+                        match sync_status_send.send(status_message) {
+                            Ok(()) => {
+
+                            },
+                            Err(_) => {
+
+                            },
+                        }
+                    }
                 },
                 EntryType::Directory => {
                     if let Err(err) = fs::create_dir_all(&full_path) {
@@ -606,6 +727,11 @@ pub fn restore(token: &Token,
                         }
                         return;
                     }
+
+                    // applied once everything inside the directory has been restored, otherwise
+                    // writing the children would change the modification time, and a read only
+                    // directory couldn't have children written to it at all
+                    directories.push((full_path.clone(), entry_metadata));
                 },
                 EntryType::Link => {
                     let src = match file_entry.link_name() {
@@ -622,6 +748,8 @@ pub fn restore(token: &Token,
 
                                         },
                                     }
+
+                                    skip_entry(&entry_name, SkipReason::Unsupported, "no link destination found", &sync_status_send);
                                     continue;
                                 },
                             }
@@ -636,6 +764,8 @@ pub fn restore(token: &Token,
 
                                 },
                             }
+
+                            skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                             continue;
                         },
                     };
@@ -653,6 +783,8 @@ pub fn restore(token: &Token,
 
                                 },
                             }
+
+                            skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                         },
                     }
 
@@ -672,6 +804,8 @@ pub fn restore(token: &Token,
 
                                         },
                                     }
+
+                                    skip_entry(&entry_name, SkipReason::Unsupported, "no link destination found", &sync_status_send);
                                     continue;
                                 },
                             }
@@ -686,6 +820,8 @@ pub fn restore(token: &Token,
 
                                 },
                             }
+
+                            skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                             continue;
                         },
                     };
@@ -700,12 +836,16 @@ pub fn restore(token: &Token,
 
                             },
                         }
+
+                        skip_entry(&entry_name, SkipReason::Unsupported, "no link destination found", &sync_status_send);
                         continue;
                     }
 
                     #[cfg(unix)]
                     match ::std::os::unix::fs::symlink(&src, &full_path) {
-                        Ok(()) => {},
+                        Ok(()) => {
+                            apply_metadata(&entry_metadata, &full_path, &sync_status_send);
+                        },
                         Err(e) => {
                             let status_message = SyncStatus::Issue(format!("not able to restore symlink {}: {}", full_path.display(), e));
                             match sync_status_send.send(status_message) {
@@ -716,6 +856,8 @@ pub fn restore(token: &Token,
 
                                 },
                             }
+
+                            skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                         },
                     }
 
@@ -732,6 +874,8 @@ pub fn restore(token: &Token,
 
                                     },
                                 }
+
+                                skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                                 continue;
                             },
                         };
@@ -751,6 +895,8 @@ pub fn restore(token: &Token,
 
                                         },
                                     }
+
+                                    skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                                 },
                             }
                         } else {
@@ -766,6 +912,8 @@ pub fn restore(token: &Token,
 
                                         },
                                     }
+
+                                    skip_entry(&entry_name, SkipReason::from(&e), &e.to_string(), &sync_status_send);
                                 },
                             }
                         }
@@ -783,6 +931,8 @@ pub fn restore(token: &Token,
 
                         },
                     }
+
+                    skip_entry(&entry_name, SkipReason::Unsupported, "unsupported file type", &sync_status_send);
                 },
             }
 
@@ -790,6 +940,21 @@ pub fn restore(token: &Token,
         }
         trace!("Archive read total time took {} seconds", archive_reading_start_time.elapsed().as_secs());
 
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Finalize });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+
+        // deepest directories first, so restoring a parent's metadata can't be undone by a child
+        for &(ref path, ref metadata) in directories.iter().rev() {
+            apply_metadata(metadata, path, &sync_status_send);
+        }
+
         trace!("Restore total time took {} seconds", restore_start_time.elapsed().as_secs());
 
         debug!("restoring session finished");
@@ -806,4 +971,508 @@ pub fn restore(token: &Token,
     });
 
     sync_status_receive
-}
\ No newline at end of file
+}
+
+/// Chooses which entries of a session are restored
+///
+/// Patterns are globs matched against paths relative to the root of the folder, like
+/// `Documents/*.pdf` or `Photos/**/*.jpg`. A pattern that matches a directory also matches
+/// everything inside it, so `Photos/2017` restores that whole subtree. An entry is restored if
+/// it matches at least one include pattern (or there are none) and no exclude pattern.
+#[derive(Debug, Clone)]
+pub struct RestoreFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl RestoreFilter {
+    /// a filter that restores every entry
+    pub fn all() -> RestoreFilter {
+        RestoreFilter {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<RestoreFilter, SDError> {
+        Ok(RestoreFilter {
+            include: parse_patterns(include)?,
+            exclude: parse_patterns(exclude)?,
+        })
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|pattern| pattern_matches(pattern, path));
+
+        included && !self.exclude.iter().any(|pattern| pattern_matches(pattern, path))
+    }
+}
+
+fn parse_patterns<S: AsRef<str>>(patterns: &[S]) -> Result<Vec<Pattern>, SDError> {
+    let mut parsed = Vec::new();
+
+    for pattern in patterns {
+        // session paths never start with a separator, so allow patterns that do
+        let p = pattern.as_ref().trim_left_matches('/');
+
+        match Pattern::new(p) {
+            Ok(pattern) => parsed.push(pattern),
+            Err(e) => return Err(SDError::Internal(format!("invalid path pattern {}: {}", pattern.as_ref(), e))),
+        }
+    }
+
+    Ok(parsed)
+}
+
+// a pattern matches a path if it matches the path itself or any directory above it
+fn pattern_matches(pattern: &Pattern, path: &Path) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+
+    let mut current = Some(path);
+
+    while let Some(p) = current {
+        if p.as_os_str().is_empty() {
+            break;
+        }
+
+        if pattern.matches_path_with(p, &options) {
+            return true;
+        }
+
+        current = p.parent();
+    }
+
+    false
+}
+
+fn apply_metadata(metadata: &EntryMetadata, path: &Path, sync_status_send: &::parking_lot_mpsc::SyncSender<SyncStatus>) {
+    if let Err(e) = metadata.apply(path) {
+        let status_message = SyncStatus::Issue(format!("not able to restore metadata for {}: {}", path.display(), e));
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+    }
+}
+
+/// tell the caller an entry of the session couldn't be restored
+fn skip_entry(path: &str, reason: SkipReason, message: &str, sync_status_send: &::parking_lot_mpsc::SyncSender<SyncStatus>) {
+    let status_message = SyncStatus::Event(SyncEvent::FileSkipped {
+        path: path.to_owned(),
+        reason: reason,
+        message: message.to_owned(),
+    });
+    match sync_status_send.send(status_message) {
+        Ok(()) => {
+
+        },
+        Err(_) => {
+
+        },
+    }
+}
+
+/// The keys a resumed restore uses to chunk files already in the destination the same way sync
+/// did, so they can be compared with the blocks in the session
+#[derive(Clone)]
+pub struct ResumeKeys {
+    pub hmac_key: Key,
+    pub tweak_key: Key,
+}
+
+/// How much of a file already in the destination a resumed restore can keep
+struct FilePlan {
+    /// the file already matches the session entry and doesn't need to be written at all
+    complete: bool,
+    /// for each block of the entry, the offset and size of an identical chunk already in the
+    /// file exactly where the block belongs, if there is one
+    reuse: Vec<Option<(u64, u64)>>,
+}
+
+impl FilePlan {
+    /// how many of the entry's blocks have to be downloaded
+    fn fetched(&self) -> usize {
+        self.reuse.iter().filter(|kept| kept.is_none()).count()
+    }
+
+    fn reused_size(&self) -> u64 {
+        self.reuse.iter().filter_map(|&kept| kept.map(|(_, size)| size)).sum()
+    }
+}
+
+/// compare every file already in the destination with the session entry that will replace it
+///
+/// The result is keyed by the full destination path. Empty entries are left out, recreating
+/// them costs nothing.
+fn plan_resume(session: &SyncSession, filter: &RestoreFilter, destination: &Path, keys: &ResumeKeys) -> HashMap<PathBuf, FilePlan> {
+    let mut plans = HashMap::new();
+
+    let version = session.version();
+
+    let mut ar = match session.reader() {
+        Ok(reader) => Archive::new(reader),
+        Err(_) => return plans,
+    };
+
+    let entries = match ar.entries() {
+        Ok(entries) => entries,
+        Err(_) => return plans,
+    };
+
+    for item in entries {
+        let mut file_entry = match item {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        let full_path = match file_entry.path() {
+            Ok(ref entry_path) => {
+                if !filter.matches(entry_path) {
+                    continue;
+                }
+
+                destination.join(entry_path)
+            },
+            Err(_) => continue,
+        };
+
+        if file_entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let real_size = ::metadata::real_size(file_entry.header());
+
+        let mut block_hmac_bag = Vec::new();
+
+        if file_entry.read_to_end(&mut block_hmac_bag).is_err() || block_hmac_bag.is_empty() {
+            continue;
+        }
+
+        let block_hmac_list = match ::binformat::parse_hmacs(&block_hmac_bag) {
+            Done(_, o) => o,
+            _ => break,
+        };
+
+        if let Some(plan) = plan_file(&full_path, &block_hmac_list, real_size, keys, version) {
+            trace!("{}: keeping {} of {} blocks", full_path.display(), block_hmac_list.len() - plan.fetched(), block_hmac_list.len());
+
+            plans.insert(full_path, plan);
+        }
+    }
+
+    plans
+}
+
+/// work out which blocks of a session entry are already in the file at `path`
+///
+/// The file is chunked with the same tweak key and sync version the session was created with,
+/// so anything a previous restore wrote produces the same chunks, with the same hmacs, as the
+/// blocks it came from. Blocks are kept from the start of the file up to the first one that
+/// differs. If the entry records the real size of the file, blocks are also kept working back
+/// from the end, so a change in the middle only costs the blocks it touched.
+///
+/// Returns `None` if there is no regular file to compare.
+fn plan_file(path: &Path, block_hmac_list: &[&[u8]], real_size: Option<u64>, keys: &ResumeKeys, version: SyncVersion) -> Option<FilePlan> {
+    let md = match fs::symlink_metadata(path) {
+        Ok(md) => md,
+        Err(_) => return None,
+    };
+
+    if !md.file_type().is_file() {
+        return None;
+    }
+
+    let existing_size = md.len();
+
+    let (search_file, mut chunk_file) = match (File::open(path), File::open(path)) {
+        (Ok(search_file), Ok(chunk_file)) => (BufReader::new(search_file), BufReader::new(chunk_file)),
+        _ => return None,
+    };
+
+    // a read error only ends the comparison early, everything after it is downloaded again
+    let byte_iter = search_file.bytes().take_while(|b| b.is_ok()).map(|b| b.unwrap());
+
+    let chunks = ChunkGenerator::new(byte_iter, &keys.tweak_key, existing_size, version);
+
+    // the hmac of the chunk starting at each offset, and the size of each chunk by hmac
+    let mut existing: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut sizes: HashMap<Vec<u8>, u64> = HashMap::new();
+
+    let mut offset: u64 = 0;
+
+    for chunk in chunks {
+        let mut data = Vec::with_capacity(chunk.size as usize);
+
+        if (&mut chunk_file).take(chunk.size).read_to_end(&mut data).is_err() {
+            break;
+        }
+
+        let hmac = ::block::block_hmac(version, &keys.hmac_key, &data);
+
+        existing.insert(offset, hmac.clone());
+        sizes.insert(hmac, chunk.size);
+
+        offset += chunk.size;
+    }
+
+    let mut reuse: Vec<Option<(u64, u64)>> = vec![None; block_hmac_list.len()];
+
+    let mut front: u64 = 0;
+    let mut first_changed = block_hmac_list.len();
+
+    for (index, block_hmac) in block_hmac_list.iter().enumerate() {
+        match existing.get(&front) {
+            Some(hmac) if hmac.as_slice() == *block_hmac => {
+                let size = sizes[hmac];
+
+                reuse[index] = Some((front, size));
+                front += size;
+            },
+            _ => {
+                first_changed = index;
+                break;
+            },
+        }
+    }
+
+    // a block's size can only be known if the same chunk is somewhere in the file, so the
+    // search from the end stops at the first block that isn't
+    if let Some(total) = real_size {
+        let mut back = total;
+
+        for index in (first_changed..block_hmac_list.len()).rev() {
+            let block_hmac = block_hmac_list[index];
+
+            let size = match sizes.get(block_hmac) {
+                Some(size) => *size,
+                None => break,
+            };
+
+            if size > back || back - size < front {
+                break;
+            }
+
+            match existing.get(&(back - size)) {
+                Some(hmac) if hmac.as_slice() == block_hmac => {
+                    back -= size;
+
+                    reuse[index] = Some((back, size));
+                },
+                _ => break,
+            }
+        }
+    }
+
+    let complete = first_changed == block_hmac_list.len() && existing_size == front && real_size.map_or(true, |size| size == front);
+
+    Some(FilePlan {
+        complete: complete,
+        reuse: reuse,
+    })
+}
+
+/// list the hmac of every block in a session, in the order the restore will need them
+///
+/// Blocks that `plans` says are already in the destination are left out.
+fn session_block_list(session: &SyncSession, filter: &RestoreFilter, destination: &Path, plans: &HashMap<PathBuf, FilePlan>) -> Vec<Vec<u8>> {
+    let mut block_hmacs: Vec<Vec<u8>> = Vec::new();
+
+    let mut ar = match session.reader() {
+        Ok(reader) => Archive::new(reader),
+        Err(_) => return block_hmacs,
+    };
+
+    let entries = match ar.entries() {
+        Ok(entries) => entries,
+        Err(_) => return block_hmacs,
+    };
+
+    for item in entries {
+        let mut file_entry = match item {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        let plan = match file_entry.path() {
+            Ok(ref entry_path) => {
+                if !filter.matches(entry_path) {
+                    continue;
+                }
+
+                plans.get(&destination.join(entry_path))
+            },
+            Err(_) => continue,
+        };
+
+        if file_entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let mut block_hmac_bag = Vec::new();
+
+        if file_entry.read_to_end(&mut block_hmac_bag).is_err() || block_hmac_bag.is_empty() {
+            continue;
+        }
+
+        match ::binformat::parse_hmacs(&block_hmac_bag) {
+            Done(_, o) => {
+                for (index, block_hmac) in o.into_iter().enumerate() {
+                    let kept = match plan {
+                        Some(plan) => plan.reuse[index].is_some(),
+                        None => false,
+                    };
+
+                    if !kept {
+                        block_hmacs.push(block_hmac.to_vec());
+                    }
+                }
+            },
+            _ => break,
+        };
+    }
+
+    block_hmacs
+}
+
+#[test]
+fn restore_filter_test() {
+    let filter = RestoreFilter::all();
+    assert!(filter.matches(Path::new("anything/at/all.txt")));
+
+    let filter = RestoreFilter::new(&["Photos/2017", "/notes.txt"], &["**/*.tmp"]).unwrap();
+    assert!(filter.matches(Path::new("Photos/2017")));
+    assert!(filter.matches(Path::new("Photos/2017/beach.jpg")));
+    assert!(filter.matches(Path::new("notes.txt")));
+    assert!(!filter.matches(Path::new("Photos/2016/beach.jpg")));
+    assert!(!filter.matches(Path::new("Photos")));
+    assert!(!filter.matches(Path::new("Photos/2017/upload.tmp")));
+
+    let filter = RestoreFilter::new(&["Documents/*.pdf"], &[]).unwrap();
+    assert!(filter.matches(Path::new("Documents/report.pdf")));
+    assert!(!filter.matches(Path::new("Documents/old/report.pdf")));
+
+    assert!(RestoreFilter::new(&["[unclosed"], &[]).is_err());
+}
+
+#[test]
+fn restore_paths_test() {
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    folder.write("Documents/report.pdf", b"report");
+    folder.write("Documents/old/draft.pdf", b"draft");
+    folder.write("Photos/beach.jpg", b"beach");
+
+    let (_server, token, folder_id) = folder.serve();
+
+    let session_name = ::util::generate_uuid();
+    ::core::sync(&token, &session_name, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    let destination = folder.destination();
+
+    ::core::restore_paths(&token, &session_name, &folder.main, folder_id, destination.clone(), 0, &["Documents"], &["Documents/old"], &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    let mut report = String::new();
+    File::open(destination.join("Documents").join("report.pdf")).unwrap().read_to_string(&mut report).unwrap();
+    assert!(report == "report");
+
+    assert!(!destination.join("Documents").join("old").exists());
+    assert!(!destination.join("Photos").exists());
+}
+
+#[test]
+fn resume_restore_test() {
+    use rustc_serialize::hex::ToHex;
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    let random = |seed: u32, size: usize| -> Vec<u8> {
+        let mut state = seed;
+
+        (0..size).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+
+            (state >> 16) as u8
+        }).collect()
+    };
+
+    let truncated = random(1, 2_000_000);
+    let changed = random(2, 2_000_000);
+
+    folder.write("truncated.bin", &truncated);
+    folder.write("changed.bin", &changed);
+    folder.write("small.txt", b"small");
+
+    let (server, token, folder_id) = folder.serve();
+
+    let session = ::util::generate_uuid();
+    ::core::sync(&token, &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    let destination = folder.destination();
+
+    ::core::restore(&token, &session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    // the name and size of each block a file was stored in
+    let block_names = |data: &[u8]| -> Vec<(String, u64)> {
+        let mut offset = 0;
+
+        ChunkGenerator::new(data.iter().cloned(), &folder.tweak, data.len() as u64, ::SYNC_VERSION.block_version()).map(|chunk| {
+            let name = ::block::block_hmac(::SYNC_VERSION.block_version(), &folder.hmac, &data[offset..offset + chunk.size as usize]).to_hex();
+            offset += chunk.size as usize;
+
+            (name, chunk.size)
+        }).collect()
+    };
+
+    let truncated_blocks = block_names(&truncated);
+    let changed_blocks = block_names(&changed);
+    assert!(truncated_blocks.len() > 2);
+    assert!(changed_blocks.len() > 2);
+
+    // a restore interrupted partway through the first file, and the start of the second file
+    // changed since
+    {
+        let f = OpenOptions::new().write(true).open(destination.join("truncated.bin")).unwrap();
+        f.set_len(truncated_blocks[0].1 + 10).unwrap();
+
+        let mut f = OpenOptions::new().write(true).open(destination.join("changed.bin")).unwrap();
+        f.write_all(b"changed").unwrap();
+    }
+
+    // blocks that a resumed restore must not need, from the start and end of the partial file,
+    // the end of the changed file, and the file that was restored completely
+    let small_block = ::block::block_hmac(::SYNC_VERSION.block_version(), &folder.hmac, b"small").to_hex();
+
+    for name in &[&truncated_blocks[0].0, &changed_blocks.last().unwrap().0, &small_block] {
+        assert!(server.remove_block(name));
+        let _ = ::cache::remove_block(name);
+    }
+
+    ::core::restore_resume(&token, &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, destination.clone(), 0, &[], &[], &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("resumed restore failed");
+
+    let mut restored = Vec::new();
+    File::open(destination.join("truncated.bin")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == truncated);
+
+    let mut restored = Vec::new();
+    File::open(destination.join("changed.bin")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == changed);
+
+    let mut small = String::new();
+    File::open(destination.join("small.txt")).unwrap().read_to_string(&mut small).unwrap();
+    assert!(small == "small");
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+
+// external crate imports
+
+use chrono::{Date, DateTime, Datelike, Duration, TimeZone, Timelike};
+
+// internal imports
+
+use error::SDError;
+use models::SyncCleaningSchedule;
+use session::SyncSession;
+
+const HOUR_MS: i64 = 60 * 60 * 1000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// A period of time in which at most one session is kept in each bucket
+struct RetentionRule {
+    start: Option<i64>,
+    end: i64,
+    bucket: Box<Fn(i64) -> i64>,
+}
+
+/// choose the sessions a cleaning schedule would remove, as of `now`
+pub fn clean_plan<Tz: TimeZone + 'static>(schedule: &SyncCleaningSchedule, sessions: &[SyncSession], now: &DateTime<Tz>) -> Result<Vec<SyncSession>, SDError> {
+    match *schedule {
+        SyncCleaningSchedule::Auto => Ok(auto_clean_plan(sessions, now)),
+        SyncCleaningSchedule::Custom { hourly, daily, weekly, monthly, yearly } => {
+            if hourly == 0 && daily == 0 && weekly == 0 && monthly == 0 && yearly == 0 {
+                return Err(SDError::Internal("a custom cleaning schedule must keep at least one session".to_string()));
+            }
+
+            Ok(custom_clean_plan(sessions, now, &[hourly, daily, weekly, monthly, yearly]))
+        },
+        _ => {
+            let cutoff = match clean_before(schedule, now)? {
+                Some(cutoff) => cutoff,
+                None => return Ok(Vec::new()),
+            };
+
+            Ok(sessions.iter().filter(|session| session.time.map(|t| (t as i64) < cutoff).unwrap_or(false)).cloned().collect())
+        },
+    }
+}
+
+/// the time in milliseconds before which a date based cleaning schedule removes every session,
+/// or `None` for schedules that choose sessions individually
+pub fn clean_before<Tz: TimeZone>(schedule: &SyncCleaningSchedule, now: &DateTime<Tz>) -> Result<Option<i64>, SDError> {
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis());
+
+    let today = now.date();
+
+    let cutoff = match *schedule {
+        SyncCleaningSchedule::Auto => return Ok(None),
+        SyncCleaningSchedule::Custom { .. } => return Ok(None),
+        SyncCleaningSchedule::ExactDateRFC3339 { ref date } => {
+            let date = match DateTime::parse_from_rfc3339(date) {
+                Ok(dt) => dt,
+                Err(e) => {
+                    return Err(SDError::Internal(format!("{}", e)));
+                },
+            };
+
+            ::util::timestamp_to_ms(date.timestamp(), date.timestamp_subsec_millis())
+        },
+        SyncCleaningSchedule::ExactDateRFC2822 { ref date } => {
+            let date = match DateTime::parse_from_rfc2822(date) {
+                Ok(dt) => dt,
+                Err(e) => {
+                    return Err(SDError::Internal(format!("{}", e)));
+                },
+            };
+
+            ::util::timestamp_to_ms(date.timestamp(), date.timestamp_subsec_millis())
+        },
+        SyncCleaningSchedule::All => now_ms,
+        SyncCleaningSchedule::BeforeToday => start_of_day(today),
+        SyncCleaningSchedule::BeforeThisWeek => {
+            // weeks start on monday
+            let days = today.weekday().num_days_from_monday() as i64;
+
+            start_of_day(today - Duration::days(days))
+        },
+        SyncCleaningSchedule::BeforeThisMonth => {
+            let days = today.day0() as i64;
+
+            start_of_day(today - Duration::days(days))
+        },
+        SyncCleaningSchedule::BeforeThisYear => {
+            let days = today.ordinal0() as i64;
+
+            start_of_day(today - Duration::days(days))
+        },
+        SyncCleaningSchedule::OneDay => now_ms - DAY_MS,
+        SyncCleaningSchedule::OneWeek => now_ms - 7 * DAY_MS,
+        SyncCleaningSchedule::OneMonth => now_ms - 30 * DAY_MS,
+        SyncCleaningSchedule::OneYear => now_ms - 365 * DAY_MS,
+    };
+
+    Ok(Some(cutoff))
+}
+
+/// choose the sessions the `Auto` cleaning schedule would remove, as of `now`
+///
+/// See `SyncCleaningSchedule::Auto` for the policy. Folders are considered separately, so a
+/// folder that is rarely synced never loses sessions because another folder was synced more
+/// recently. Hours, days and months are based on the time zone of `now`. Sessions without a
+/// time, or with a time after `now`, are always kept.
+pub fn auto_clean_plan<Tz: TimeZone + 'static>(sessions: &[SyncSession], now: &DateTime<Tz>) -> Vec<SyncSession> {
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis());
+
+    let midnight = start_of_day(now.date());
+
+    let hourly_start = now_ms - DAY_MS;
+    let daily_start = midnight - 7 * DAY_MS;
+    let weekly_start = daily_start - 24 * DAY_MS;
+
+    let hour_tz = now.timezone();
+    let day_tz = now.timezone();
+    let month_tz = now.timezone();
+
+    let rules = vec![
+        RetentionRule {
+            start: Some(hourly_start),
+            end: now_ms + 1,
+            bucket: Box::new(move |t| {
+                let dt = local_time(&hour_tz, t);
+
+                (t - dt.minute() as i64 * 60 * 1000 - dt.second() as i64 * 1000 - dt.nanosecond() as i64 / 1_000_000) / HOUR_MS
+            }),
+        },
+        RetentionRule {
+            start: Some(daily_start),
+            end: midnight,
+            bucket: Box::new(move |t| local_time(&day_tz, t).num_days_from_ce() as i64),
+        },
+        RetentionRule {
+            start: Some(weekly_start),
+            end: daily_start,
+            bucket: Box::new(move |t| (t - weekly_start) / (7 * DAY_MS)),
+        },
+        RetentionRule {
+            start: None,
+            end: weekly_start,
+            bucket: Box::new(move |t| {
+                let dt = local_time(&month_tz, t);
+
+                dt.year() as i64 * 12 + dt.month0() as i64
+            }),
+        },
+    ];
+
+    plan(sessions, now_ms, &rules)
+}
+
+// sessions are visited oldest first, so the session kept for each bucket is the oldest one in it
+fn plan(sessions: &[SyncSession], now_ms: i64, rules: &[RetentionRule]) -> Vec<SyncSession> {
+    let mut by_time: Vec<&SyncSession> = sessions.iter().collect();
+    by_time.sort_by_key(|session| session.time);
+
+    let mut used: HashSet<(Option<u64>, usize, i64)> = HashSet::new();
+
+    let mut remove = Vec::new();
+
+    for session in by_time {
+        let t = match session.time {
+            Some(t) if (t as i64) <= now_ms => t as i64,
+            _ => continue,
+        };
+
+        let mut keep = false;
+
+        for (index, rule) in rules.iter().enumerate() {
+            let in_rule = t < rule.end && match rule.start {
+                Some(start) => t >= start,
+                None => true,
+            };
+
+            if in_rule && used.insert((session.folder_id, index, (rule.bucket)(t))) {
+                keep = true;
+            }
+        }
+
+        if !keep {
+            remove.push(session.clone());
+        }
+    }
+
+    remove
+}
+
+/// choose the sessions a `Custom` cleaning schedule would remove, as of `now`
+///
+/// `counts` holds the number of hours, days, weeks, months and years to keep a session for. For
+/// each of them, the oldest session in each of the most recent periods that have a session is
+/// kept, so a folder that hasn't been synced for a while keeps the same number of sessions as one
+/// synced every day. Weeks start on monday.
+fn custom_clean_plan<Tz: TimeZone>(sessions: &[SyncSession], now: &DateTime<Tz>, counts: &[u32; 5]) -> Vec<SyncSession> {
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis());
+
+    let tz = now.timezone();
+
+    let mut by_time: Vec<(&SyncSession, [i64; 5])> = Vec::new();
+
+    for session in sessions {
+        let t = match session.time {
+            Some(t) if (t as i64) <= now_ms => t as i64,
+            _ => continue,
+        };
+
+        let dt = local_time(&tz, t);
+
+        // each bucket grows with time, so the most recent periods have the largest buckets
+        let buckets = [
+            dt.num_days_from_ce() as i64 * 24 + dt.hour() as i64,
+            dt.num_days_from_ce() as i64,
+            dt.iso_week().year() as i64 * 53 + dt.iso_week().week() as i64,
+            dt.year() as i64 * 12 + dt.month0() as i64,
+            dt.year() as i64,
+        ];
+
+        by_time.push((session, buckets));
+    }
+
+    by_time.sort_by_key(|&(session, _)| session.time);
+
+    // the most recent periods of each length that have a session, for each folder
+    let mut periods: HashMap<(Option<u64>, usize), Vec<i64>> = HashMap::new();
+
+    for &(session, ref buckets) in &by_time {
+        for (index, bucket) in buckets.iter().enumerate() {
+            periods.entry((session.folder_id, index)).or_insert_with(Vec::new).push(*bucket);
+        }
+    }
+
+    for (&(_, index), buckets) in periods.iter_mut() {
+        buckets.sort_by(|a, b| b.cmp(a));
+        buckets.dedup();
+        buckets.truncate(counts[index] as usize);
+    }
+
+    // sessions are visited oldest first, so the session kept for each period is the oldest one
+    let mut used: HashSet<(Option<u64>, usize, i64)> = HashSet::new();
+
+    let mut remove = Vec::new();
+
+    for (session, buckets) in by_time {
+        let mut keep = false;
+
+        for (index, bucket) in buckets.iter().enumerate() {
+            let recent = periods.get(&(session.folder_id, index)).map(|recent| recent.contains(bucket)).unwrap_or(false);
+
+            if recent && used.insert((session.folder_id, index, *bucket)) {
+                keep = true;
+            }
+        }
+
+        if !keep {
+            remove.push(session.clone());
+        }
+    }
+
+    remove
+}
+
+// midnight doesn't exist on some daylight saving time changes, use the first hour that does
+fn start_of_day<Tz: TimeZone>(date: Date<Tz>) -> i64 {
+    for hour in 0..24 {
+        if let Some(dt) = date.and_hms_opt(hour, 0, 0) {
+            return ::util::timestamp_to_ms(dt.timestamp(), 0);
+        }
+    }
+
+    ::util::timestamp_to_ms(date.and_hms(12, 0, 0).timestamp(), 0)
+}
+
+fn local_time<Tz: TimeZone>(tz: &Tz, t: i64) -> DateTime<Tz> {
+    tz.timestamp(t / 1000, 0) + Duration::milliseconds(t % 1000)
+}
+
+#[test]
+fn auto_clean_plan_test() {
+    use chrono::Utc;
+    use models::SyncVersion;
+
+    // the example from the `SyncCleaningSchedule::Auto` documentation, one session at the start of
+    // every hour since January 1st
+    let start = Utc.ymd(2017, 1, 1).and_hms(0, 0, 0);
+    let now = Utc.ymd(2017, 4, 1).and_hms(0, 30, 0);
+
+    let mut sessions = Vec::new();
+    let mut time = start;
+    let mut id = 0;
+
+    while time <= now {
+        let mut session = SyncSession::new(SyncVersion::Version1, 1, format!("{}", id), Some(0), Some(time.timestamp() as u64 * 1000), Vec::new());
+        session.id = Some(id);
+        sessions.push(session);
+
+        id += 1;
+        time = time + Duration::hours(1);
+    }
+
+    let remove = auto_clean_plan(&sessions, &now);
+
+    let removed: HashSet<u64> = remove.iter().map(|s| s.time.unwrap()).collect();
+    let kept: Vec<DateTime<Utc>> = sessions.iter().filter(|s| !removed.contains(&s.time.unwrap())).map(|s| Utc.timestamp(s.time.unwrap() as i64 / 1000, 0)).collect();
+
+    // 24 hourly, 7 daily, 4 weekly and 2 monthly
+    assert!(kept.len() == 37);
+
+    assert!(kept.contains(&Utc.ymd(2017, 1, 1).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 2, 1).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 1).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 8).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 15).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 22).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 25).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 31).and_hms(0, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 3, 31).and_hms(1, 0, 0)));
+    assert!(kept.contains(&Utc.ymd(2017, 4, 1).and_hms(0, 0, 0)));
+
+    assert!(!kept.contains(&Utc.ymd(2017, 1, 1).and_hms(1, 0, 0)));
+    assert!(!kept.contains(&Utc.ymd(2017, 3, 24).and_hms(0, 0, 0)));
+
+    // another folder is cleaned on its own
+    let mut other = SyncSession::new(SyncVersion::Version1, 2, "other".to_string(), Some(0), Some(start.timestamp() as u64 * 1000 + 1000), Vec::new());
+    other.id = Some(id);
+    sessions.push(other);
+
+    let remove = auto_clean_plan(&sessions, &now);
+    assert!(remove.len() == sessions.len() - 1 - 37);
+}
+
+#[test]
+fn custom_clean_plan_test() {
+    use chrono::Utc;
+    use models::SyncVersion;
+
+    // two sessions a day for 60 days
+    let now = Utc.ymd(2017, 4, 1).and_hms(23, 0, 0);
+
+    let mut sessions = Vec::new();
+
+    for day in 0..60 {
+        for &hour in &[6, 18] {
+            let time = Utc.ymd(2017, 4, 1).and_hms(hour, 0, 0) - Duration::days(day);
+
+            let mut session = SyncSession::new(SyncVersion::Version1, 1, format!("{}-{}", day, hour), Some(0), Some(time.timestamp() as u64 * 1000), Vec::new());
+            session.id = Some(sessions.len() as u64);
+            sessions.push(session);
+        }
+    }
+
+    let schedule = SyncCleaningSchedule::Custom { hourly: 0, daily: 7, weekly: 0, monthly: 3, yearly: 0 };
+
+    let remove = clean_plan(&schedule, &sessions, &now).unwrap();
+    let removed: HashSet<String> = remove.iter().map(|s| s.name.clone()).collect();
+    let kept: Vec<&SyncSession> = sessions.iter().filter(|s| !removed.contains(&s.name)).collect();
+
+    // the oldest session of each of the last 7 days, plus the oldest of march and of february,
+    // since the oldest of april is already kept as a daily session
+    assert!(kept.len() == 9);
+    assert!(kept.iter().any(|s| s.name == "0-6"));
+    assert!(kept.iter().any(|s| s.name == "6-6"));
+    assert!(!kept.iter().any(|s| s.name == "0-18"));
+
+    for &(year, month) in &[(2017, 3), (2017, 2)] {
+        let start_of_month = (Utc.ymd(2017, 4, 1).and_hms(6, 0, 0) - Utc.ymd(year, month, 1).and_hms(6, 0, 0)).num_days();
+        assert!(kept.iter().any(|s| s.name == format!("{}-6", start_of_month)));
+    }
+
+    // the same sessions are kept after another sync, like the `Auto` schedule does
+    let mut newer = SyncSession::new(SyncVersion::Version1, 1, "newer".to_string(), Some(0), Some((now - Duration::minutes(30)).timestamp() as u64 * 1000), Vec::new());
+    newer.id = Some(sessions.len() as u64);
+    sessions.push(newer);
+
+    let remove = clean_plan(&schedule, &sessions, &now).unwrap();
+    assert!(remove.len() == removed.len() + 1);
+    assert!(remove.iter().any(|s| s.name == "newer"));
+
+    let nothing = SyncCleaningSchedule::Custom { hourly: 0, daily: 0, weekly: 0, monthly: 0, yearly: 0 };
+    assert!(clean_plan(&nothing, &sessions, &now).is_err());
+
+    let one_week = clean_plan(&SyncCleaningSchedule::OneWeek, &sessions, &now).unwrap();
+    assert!(one_week.iter().all(|s| s.time.unwrap() < (now - Duration::days(7)).timestamp() as u64 * 1000));
+    assert!(one_week.len() == 120 - 14);
+
+    let this_month = clean_plan(&SyncCleaningSchedule::BeforeThisMonth, &sessions, &now).unwrap();
+    assert!(this_month.len() == 118);
+}
@@ -24,15 +24,14 @@ use session::*;
 use keys::*;
 use constants::*;
 use USER_AGENT;
-use binformat::BinaryWriter;
 
 header! { (SDAuthToken, "SD-Auth-Token") => [String] }
 
-type BandwidthCallback = Box<FnMut(u64) + Send + Sync + 'static>;
+pub type BandwidthCallback = Box<FnMut(u64) + Send + Sync + 'static>;
 
 #[allow(dead_code)]
 struct ProgressReader {
-    inner: ::std::io::Cursor<Vec<u8>>,
+    inner: Box<Read + Send>,
     callback: BandwidthCallback,
     content_length: u64,
     real_size: u64,
@@ -43,9 +42,9 @@ struct ProgressReader {
 
 impl ProgressReader {
     #[allow(dead_code)]
-    pub fn new(reader: Vec<u8>, callback: BandwidthCallback, content_length: u64, real_size: u64) -> ProgressReader {
+    pub fn new(reader: Box<Read + Send>, callback: BandwidthCallback, content_length: u64, real_size: u64) -> ProgressReader {
         ProgressReader {
-            inner: ::std::io::Cursor::new(reader),
+            inner: reader,
             callback: callback,
             content_length: content_length,
             real_size: real_size,
@@ -131,7 +130,7 @@ pub enum APIEndpoint<'a> {
     DeleteFolder { folder_id: u64 },
     RegisterSyncSession { folder_id: u64, name: &'a str, encrypted: bool },
     #[serde(skip_serializing)]
-    FinishSyncSession { folder_id: u64, encrypted: bool, size: usize, session: &'a WrappedSyncSession },
+    FinishSyncSession { folder_id: u64, encrypted: bool, size: usize, session: &'a SessionFile },
     ReadSyncSession { name: &'a str, encrypted: bool },
     ReadSyncSessions { encrypted: bool },
     DeleteSyncSession { session_id: u64 },
@@ -172,11 +171,11 @@ impl<'a> APIEndpoint<'a> {
     }
 
     pub fn domain(&self) -> String {
-        api_domain().to_string()
+        api_domain()
     }
 
     pub fn protocol(&self) -> String {
-        "https://".to_string()
+        api_protocol().to_string()
     }
 
     pub fn method(&self) -> ::reqwest::Method {
@@ -289,7 +288,7 @@ impl<'a> APIEndpoint<'a> {
                 format!("/api/1/sync/session/register/{}/{}", folder_id, name)
             },
             APIEndpoint::FinishSyncSession { size, session, .. } => {
-                format!("/api/1/sync/session/{}/{}", session.name(), size)
+                format!("/api/1/sync/session/{}/{}", session.name, size)
             },
             APIEndpoint::ReadSyncSession { name, .. } => {
                 format!("/api/1/sync/session/{}", name)
@@ -1393,9 +1392,9 @@ pub fn register_sync_session(token: &Token, folder_id: u64, name: &str, encrypte
         trace!("response: {}", response);
 
         match result.status() {
-            ::reqwest::StatusCode::Ok => return Ok(()),
-            ::reqwest::StatusCode::Created => {},
+            ::reqwest::StatusCode::Ok | ::reqwest::StatusCode::Created => return Ok(()),
             ::reqwest::StatusCode::Unauthorized => return Err(SDAPIError::Authentication),
+            ::reqwest::StatusCode::Conflict => return Err(SDAPIError::Conflict),
             ::reqwest::StatusCode::BadRequest => {
                 let error: ServerErrorResponse = ::serde_json::from_str(&response)?;
                 return Err(SDAPIError::Internal(error.message));
@@ -1416,13 +1415,13 @@ pub fn register_sync_session(token: &Token, folder_id: u64, name: &str, encrypte
 
 }
 
-pub fn finish_sync_session<'a>(token: &Token, folder_id: u64, encrypted: bool, session: &[WrappedSyncSession], size: usize, progress: BandwidthCallback) -> Result<(), SDAPIError> {
+pub fn finish_sync_session<'a>(token: &Token, folder_id: u64, encrypted: bool, session: &SessionFile, size: usize, progress: BandwidthCallback) -> Result<(), SDAPIError> {
 
     let endpoint = APIEndpoint::FinishSyncSession {
         folder_id: folder_id,
         encrypted: encrypted,
         size: size,
-        session: &session[0],
+        session: session,
     };
 
     let user_agent = &**USER_AGENT.read();
@@ -1437,16 +1436,24 @@ pub fn finish_sync_session<'a>(token: &Token, folder_id: u64, encrypted: bool, s
     r.header(SDAuthToken(token.token.to_owned()));
     r.header(agent);
 
-    let (multipart_body, content_length, real_size) = multipart_for_binary(session, "file");
+    // the session file is streamed between the multipart headers rather than read into memory
+    let (multipart_head, multipart_tail) = multipart_for_file(&session.name, "file");
+
+    let session_file = session.open()?;
+
+    let real_size = session.len();
+    let content_length = multipart_head.len() as u64 + real_size + multipart_tail.len() as u64;
 
     let t = format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY.to_owned());
 
     let m: ::reqwest::mime::Mime = t.parse().unwrap();
 
     r.header(ContentType(m));
-    r.header(ContentLength(content_length as u64));
+    r.header(ContentLength(content_length));
 
-    let progress = ProgressReader::new(multipart_body.clone(), progress, content_length as u64, real_size as u64);
+    let multipart_body = ::std::io::Cursor::new(multipart_head).chain(session_file).chain(::std::io::Cursor::new(multipart_tail));
+
+    let progress = ProgressReader::new(Box::new(multipart_body), progress, content_length, real_size);
 
     r.body(progress);
 
@@ -1534,15 +1541,11 @@ pub fn read_session<'a>(token: &Token, folder_id: u64, name: &'a str, encrypted:
 
         match result.status() {
             ::reqwest::StatusCode::Ok => {
-                let mut buffer = Vec::new();
-
-                trace!("reading data");
-                result.read_to_end(&mut buffer)?;
-
+                // the caller reads the session as it arrives, sessions can be too large to buffer
                 trace!("returning data");
                 return Ok(SyncSessionResponse {
                               name: name,
-                              chunk_data: buffer,
+                              body: Box::new(result),
                               folder_id: folder_id,
                           });
             },
@@ -1827,7 +1830,7 @@ pub fn write_blocks<T>(token: &Token, session: &str, blocks: &[T], progress: Ban
     r.header(ContentType(m));
     r.header(ContentLength(content_length as u64));
 
-    let progress = ProgressReader::new(multipart_body.clone(), progress, content_length as u64, real_size as u64);
+    let progress = ProgressReader::new(Box::new(::std::io::Cursor::new(multipart_body)), progress, content_length as u64, real_size as u64);
 
     r.body(progress);
 
@@ -1947,6 +1950,39 @@ pub fn read_block(token: &Token, name: &str) -> Result<Vec<u8>, SDAPIError> {
 
 }
 
+/// the multipart body that goes before and after a single file's data, for bodies that stream
+/// the data from somewhere else instead of copying it in
+fn multipart_for_file(name: &str, field_name: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut head = Vec::new();
+    let mut tail = Vec::new();
+
+    let rn = b"\r\n";
+    let body_boundary = br"--SAFEDRIVEBINARY";
+    let end_boundary =  br"--SAFEDRIVEBINARY--";
+    let enc = br"Content-Transfer-Encoding: binary";
+    let disp = format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"", field_name, name);
+    let content_type = br"Content-Type: application/octet-stream";
+
+    head.extend(rn);
+    head.extend(rn);
+    head.extend(body_boundary.as_ref());
+    head.extend(rn);
+    head.extend(disp.as_bytes());
+    head.extend(rn);
+    head.extend(content_type.as_ref());
+    head.extend(rn);
+    head.extend(enc.as_ref());
+    head.extend(rn);
+    head.extend(rn);
+
+    tail.extend(rn);
+    tail.extend(end_boundary.as_ref());
+    tail.extend(rn);
+    tail.extend(rn);
+
+    (head, tail)
+}
+
 fn multipart_for_binary<T>(items: &[T], field_name: &str) -> (Vec<u8>, usize, usize) where T: ::binformat::BinaryWriter {
     let mut body = Vec::new();
     // these are compile time optimizations
@@ -1,13 +1,19 @@
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
 
 use error::{CryptoError, SDError};
-use binformat::BinaryFormat;
+use binformat::{BinaryFormat, BinaryWriter};
 use nom::IResult::*;
 use keys::{Key, WrappedKey, KeyType};
 use models::*;
 
 use constants::*;
 use CHANNEL;
+use STORAGE_DIR;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct SyncSession {
@@ -23,13 +29,15 @@ pub struct SyncSession {
     #[serde(skip_deserializing)]
     production: bool,
     #[serde(skip_deserializing)]
-    data: Vec<u8>,
+    data: SessionData,
     #[serde(skip_deserializing)]
     compressed: bool,
     #[serde(skip_deserializing)]
     real_size: u64,
     #[serde(skip_deserializing)]
     compressed_size: Option<u64>,
+    #[serde(skip_deserializing)]
+    manifest: Vec<u8>,
 }
 
 impl SyncSession {
@@ -37,48 +45,13 @@ impl SyncSession {
         let real_size = data.len() as u64;
 
         match version {
-            SyncVersion::Version1 | SyncVersion::Version2 => {},
+            SyncVersion::Version1 | SyncVersion::Version2 | SyncVersion::Version3 => {},
             _ => {
                 panic!("Attempted to create invalid session version");
             },
         };
 
-        let (compressed, maybe_compressed_data, maybe_compressed_size) = match version {
-            SyncVersion::Version1 => {
-                // no compression
-
-                (false, data, None)
-            },
-            SyncVersion::Version2 => {
-                let buf = Vec::new();
-                let mut encoder = ::lz4::EncoderBuilder::new().level(16).build(buf).unwrap();
-                encoder.write(data.as_slice()).unwrap();
-                let (mut compressed_data, result) = encoder.finish();
-                result.unwrap();
-
-                compressed_data.shrink_to_fit();
-
-                let compressed_size = compressed_data.len() as u64;
-
-                // don't use the compressed data if it's not smaller, means lz4 couldn't compress it
-                if compressed_size < real_size {
-                    (true, compressed_data, Some(compressed_size))
-                } else {
-                    (false, data, None)
-                }
-            },
-            _ => {
-                panic!("Attempted to create invalid session version");
-            },
-        };
-
-        let c = CHANNEL.read();
-
-        let channel = match *c {
-            Channel::Stable => Channel::Stable,
-            Channel::Beta => Channel::Beta,
-            Channel::Nightly => Channel::Nightly,
-        };
+        let channel = current_channel();
 
         let production = is_production();
 
@@ -88,13 +61,14 @@ impl SyncSession {
             name: name,
             size: size,
             time: time,
-            data: maybe_compressed_data,
-            compressed: compressed,
+            data: SessionData::Memory(data),
+            compressed: false,
             id: None,
             production: production,
             channel: channel,
             real_size: real_size,
-            compressed_size: maybe_compressed_size,
+            compressed_size: None,
+            manifest: Vec::new(),
         }
     }
 
@@ -110,15 +84,35 @@ impl SyncSession {
         // this is safe because using the same nonce with 2 different keys is not nonce reuse
         let session_nonce = ::sodiumoxide::crypto::secretbox::gen_nonce();
 
+        let data = match self.data {
+            SessionData::Memory(data) => data,
+            SessionData::File(_) => {
+                panic!("Attempted to wrap a file backed session, these are written with write_session_file");
+            },
+        };
+
         // get the session data, padded and prefixed with a u32 length as little endian
-        let to_encrypt = match self.version {
+        let (compressed, to_encrypt) = match self.version {
             SyncVersion::Version1 => {
                 // version 1 directly inserts the data before encryption
-                self.data
+                (false, data)
             },
             SyncVersion::Version2 => {
+                let buf = Vec::new();
+                let mut encoder = ::lz4::EncoderBuilder::new().level(16).build(buf).unwrap();
+                encoder.write_all(data.as_slice()).unwrap();
+                let (compressed_data, result) = encoder.finish();
+                result.unwrap();
+
+                // don't use the compressed data if it's not smaller, means lz4 couldn't compress it
+                let (compressed, maybe_compressed_data) = if compressed_data.len() < data.len() {
+                    (true, compressed_data)
+                } else {
+                    (false, data)
+                };
+
                 // version 2 has padded and prefixed data segments
-                ::util::pad_and_prefix_length(self.data.as_slice())
+                (compressed, ::util::pad_and_prefix_length(maybe_compressed_data.as_slice()))
             },
             _ => {
                 panic!("Attempted to wrap invalid session version");
@@ -143,7 +137,8 @@ impl SyncSession {
                wrapped_data: wrapped_data,
                wrapped_key: wrapped_session_key,
                nonce: session_nonce,
-               compressed: self.compressed,
+               compressed: compressed,
+               chunked: false,
                production: self.production,
                channel: self.channel,
            })
@@ -153,6 +148,10 @@ impl SyncSession {
         self.compressed
     }
 
+    pub fn version(&self) -> SyncVersion {
+        self.version
+    }
+
     pub fn real_size(&self) -> u64 {
         self.real_size
     }
@@ -160,14 +159,100 @@ impl SyncSession {
     pub fn compressed_size(&self) -> Option<u64> {
         self.compressed_size
     }
-}
 
-impl AsRef<[u8]> for SyncSession {
-    fn as_ref(&self) -> &[u8] {
-        self.data.as_ref()
+    /// read the unwrapped session archive from the start
+    pub fn reader<'a>(&'a self) -> Result<Box<Read + 'a>, SDError> {
+        match self.data {
+            SessionData::Memory(ref data) => Ok(Box::new(data.as_slice())),
+            SessionData::File(ref file) => Ok(Box::new(File::open(&file.path)?)),
+        }
+    }
+
+    /// the HMACs of the blocks a version 3 session stores its archive in, in archive order
+    ///
+    /// Empty for earlier versions, and for version 3 sessions until `read_manifest` has been used.
+    pub fn manifest_hmacs(&self) -> Vec<&[u8]> {
+        self.manifest.chunks(HMAC_SIZE).collect()
+    }
+
+    /// rebuild the archive of a version 3 session from the blocks listed in its root
+    ///
+    /// `fetch` is called with the HMAC of each block in turn and returns the block's data.
+    pub fn read_manifest<F>(self, fetch: F) -> Result<SyncSession, SDError> where F: FnMut(&[u8]) -> Result<Vec<u8>, SDError> {
+        let dir = session_dir()?;
+
+        self.read_manifest_in(&dir, fetch)
+    }
+
+    /// same as `read_manifest`, with the archive in `dir` rather than `STORAGE_DIR`
+    pub fn read_manifest_in<F>(self, dir: &Path, mut fetch: F) -> Result<SyncSession, SDError> where F: FnMut(&[u8]) -> Result<Vec<u8>, SDError> {
+        let mut root = Vec::new();
+        self.reader()?.read_to_end(&mut root)?;
+
+        if root.len() < 8 || (root.len() - 8) % HMAC_SIZE != 0 {
+            debug!("session manifest root has an invalid size: {}", root.len());
+
+            return Err(SDError::SessionUnreadable);
+        }
+
+        let archive_size = LittleEndian::read_u64(&root[0..8]);
+        let manifest = root[8..].to_vec();
+
+        let (file, mut f) = TempFile::create_in(dir, "tar")?;
+
+        let mut real_size: u64 = 0;
+
+        for hmac in manifest.chunks(HMAC_SIZE) {
+            let data = fetch(hmac)?;
+
+            f.write_all(&data)?;
+
+            real_size += data.len() as u64;
+        }
+
+        f.flush()?;
+
+        if real_size != archive_size {
+            debug!("session manifest blocks hold {} bytes, expected {}", real_size, archive_size);
+
+            return Err(SDError::SessionUnreadable);
+        }
+
+        Ok(SyncSession {
+            version: self.version,
+            folder_id: self.folder_id,
+            name: self.name,
+            size: self.size,
+            time: self.time,
+            id: self.id,
+            channel: self.channel,
+            production: self.production,
+            data: SessionData::File(Arc::new(file)),
+            compressed: self.compressed,
+            real_size: real_size,
+            compressed_size: self.compressed_size,
+            manifest: manifest,
+        })
     }
 }
 
+/// Build the root of a version 3 session
+///
+/// Version 3 sessions don't hold the session archive, it's chunked and stored as ordinary blocks
+/// so the parts of it that don't change between syncs are deduplicated like file data. The
+/// session data is only this root: the size of the archive as a little endian u64, followed by
+/// the HMAC of every block of the archive in order.
+pub fn manifest_root(archive_size: u64, hmacs: &[u8]) -> Vec<u8> {
+    assert!(hmacs.len() % HMAC_SIZE == 0);
+
+    let mut root = vec![0u8; 8];
+    LittleEndian::write_u64(&mut root[0..8], archive_size);
+
+    root.extend_from_slice(hmacs);
+
+    root
+}
+
 impl<'a> ::std::fmt::Display for SyncSession {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
         write!(f, "SyncSession <version:{}, channel:{}, production:{}, compressed:{}>", self.version, self.channel, self.production, self.compressed)
@@ -188,6 +273,7 @@ pub struct WrappedSyncSession {
     nonce: ::sodiumoxide::crypto::secretbox::Nonce,
     wrapped_key: WrappedKey,
     compressed: bool,
+    chunked: bool,
 }
 
 
@@ -199,6 +285,28 @@ impl WrappedSyncSession {
             Err(_) => return Err(SDError::CryptoError(Box::new(CryptoError::SessionDecryptFailed))),
         };
 
+        if self.chunked {
+            let mut data = Vec::new();
+
+            let (real_size, compressed_size) = open_chunks(self.wrapped_data.as_slice(), &session_key, self.nonce, &mut data)?;
+
+            return Ok(SyncSession {
+                version: self.version,
+                folder_id: self.folder_id,
+                name: self.name,
+                size: self.size,
+                real_size: real_size,
+                compressed_size: Some(compressed_size),
+                time: self.time,
+                data: SessionData::Memory(data),
+                compressed: true,
+                id: None,
+                production: self.production,
+                channel: self.channel,
+                manifest: Vec::new(),
+            });
+        }
+
         let session_raw = match ::sodiumoxide::crypto::secretbox::open(&self.wrapped_data, &self.nonce, &session_key.as_sodium_secretbox_key()) {
             Ok(s) => s,
             Err(_) => return Err(SDError::CryptoError(Box::new(CryptoError::SessionDecryptFailed))),
@@ -276,55 +384,34 @@ impl WrappedSyncSession {
                real_size: real_size as u64,
                compressed_size: maybe_compressed_size,
                time: self.time,
-               data: maybe_uncompressed_data,
+               data: SessionData::Memory(maybe_uncompressed_data),
                compressed: self.compressed,
                id: None,
                production: self.production,
                channel: self.channel,
+               manifest: Vec::new(),
            })
     }
 
+    /// read a whole session into memory, `read_session_file` avoids that for chunked sessions
     pub fn from(body: SyncSessionResponse) -> Result<WrappedSyncSession, SDError> {
+        let mut reader = body.body;
 
-        let raw_session: BinaryFormat = match ::binformat::binary_parse(&body.chunk_data) {
-            Done(_, o) => o,
-            Error(e) => {
-                debug!("session parsing failed: {}", &e);
-
-                match e {
-                    ::nom::verbose_errors::Err::Code(ref kind) => {
-                        debug!("session parsing failure kind: {:?}", kind);
-
-                    },
-                    ::nom::verbose_errors::Err::Node(ref kind, ref err) => {
-                        debug!("session parsing failure node: {:?}, {:?}", kind, err);
-
-                    },
-                    ::nom::verbose_errors::Err::Position(ref kind, ref position) => {
-                        debug!("session parsing failure position: {:?}: {:?}", kind, position);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
 
-                    },
-                    ::nom::verbose_errors::Err::NodePosition(ref kind, ref position, ref err) => {
-                        debug!("session parsing failure kind: {:?}: {:?}, {:?}", kind, position, err);
+        WrappedSyncSession::from_binary(body.folder_id, body.name, &data)
+    }
 
-                    },
-                };
+    pub fn from_binary(folder_id: u64, name: &str, data: &[u8]) -> Result<WrappedSyncSession, SDError> {
 
-                return Err(SDError::SessionUnreadable);
-            },
-            Incomplete(_) => {
-                debug!("session file cannot be parsed, this should never happen");
-                return Err(SDError::SessionUnreadable);
-            },
-        };
+        let raw_session = parse_session(data)?;
 
         debug!("got valid binary file: {}", &raw_session);
 
-        let session_ver = match raw_session.version {
-            "01" => SyncVersion::Version1,
-            "02" => SyncVersion::Version2,
-            _ => panic!("Invalid binary session version"),
-        };
+        let session_ver = session_version(raw_session.version)?;
+
+        check_chunked(session_ver, raw_session.chunked)?;
         let wrapped_session_key_raw = raw_session.wrapped_key.to_vec();
         let nonce_raw = raw_session.nonce;
 
@@ -339,17 +426,19 @@ impl WrappedSyncSession {
 
         let production = raw_session.production;
         let compressed = raw_session.compressed;
+        let chunked = raw_session.chunked;
 
         let wrapped_session = WrappedSyncSession {
             version: session_ver,
-            folder_id: Some(body.folder_id),
-            name: body.name.to_string(),
+            folder_id: Some(folder_id),
+            name: name.to_string(),
             size: None,
             time: None,
             wrapped_key: wrapped_session_key,
             wrapped_data: wrapped_session_raw,
             nonce: session_nonce,
             compressed: compressed,
+            chunked: chunked,
             channel: channel,
             production: production,
         };
@@ -380,50 +469,18 @@ impl ::binformat::BinaryWriter for WrappedSyncSession {
 
     fn as_binary(&self) -> Vec<u8> {
 
-        let mut binary_data = Vec::new();
-
-        // first 8 bytes are the file ID, type, version, flags, and 2 byte reserved area
-        let magic: &'static [u8; 2] = br"sd";
-        let file_type: &'static [u8; 1] = br"s";
-        let version = self.version.as_ref();
-        let reserved: &'static [u8; 2] = br"00";
-
         let mut flags = Empty;
 
-        match self.channel {
-            Channel::Stable => flags.insert(Stable),
-            Channel::Beta => {
-                flags.insert(Beta);
-            },
-            Channel::Nightly => {
-                flags.insert(Nightly);
-            },
-        };
-
-        if self.production {
-            flags.insert(Production);
-        } else {
-        }
-
         if self.compressed() {
             flags.insert(Compressed);
         } else {
         }
 
-        let flag_ref: &[u8] = &[flags.bits()];
-
-        binary_data.extend(magic.as_ref());
-        binary_data.extend(file_type.as_ref());
-        binary_data.extend(version);
-        binary_data.extend(flag_ref);
-        binary_data.extend(reserved.as_ref());
-
-        // next 48 bytes will be the wrapped session key
-        binary_data.extend(self.wrapped_key.as_ref());
+        if self.chunked {
+            flags.insert(Chunked);
+        }
 
-        // next 24 bytes will be the nonce
-        binary_data.extend(self.nonce.as_ref());
-        assert_eq!(binary_data.len(), magic.len() + file_type.len() + version.len() + flag_ref.len() + reserved.len() + (SECRETBOX_KEY_SIZE + SECRETBOX_MAC_SIZE) + SECRETBOX_NONCE_SIZE);
+        let mut binary_data = session_header(self.version, flags, &self.channel, self.production, &self.wrapped_key, &self.nonce);
 
         // remainder will be the encrypted session data
         binary_data.extend(self.wrapped_data.as_slice());
@@ -443,3 +500,943 @@ impl<'a> ::std::fmt::Display for WrappedSyncSession {
         write!(f, "WrappedSyncSession <version:{}, channel:{}, production:{}, compressed:{}>", self.version, self.channel, self.production, self.compressed)
     }
 }
+
+/// Where the unwrapped session archive is kept
+#[derive(Debug, Clone)]
+enum SessionData {
+    Memory(Vec<u8>),
+    /// chunked sessions are read into a temporary file rather than memory
+    File(Arc<TempFile>),
+}
+
+impl Default for SessionData {
+    fn default() -> SessionData {
+        SessionData::Memory(Vec::new())
+    }
+}
+
+/// A temporary session file, removed again when dropped
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create_in(dir: &Path, suffix: &str) -> Result<(TempFile, File), SDError> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!("{}.{}", ::util::generate_uuid(), suffix));
+
+        let f = File::create(&path)?;
+
+        Ok((TempFile { path: path }, f))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("not able to remove session file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A session written by `write_session_file`, kept on disk until it's dropped
+#[derive(Debug)]
+pub struct SessionFile {
+    pub folder_id: u64,
+    pub name: String,
+    file: TempFile,
+    len: u64,
+    real_size: u64,
+    compressed_size: u64,
+}
+
+impl SessionFile {
+    /// open the stored form of the session, header included, ready to be uploaded
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.file.path)
+    }
+
+    /// size of the stored form of the session
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// size of the session archive before compression
+    pub fn real_size(&self) -> u64 {
+        self.real_size
+    }
+
+    /// size of the session archive after compression, before it was padded and sealed
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+}
+
+/// Write a session archive to a temporary file in the stored form of a chunked session
+///
+/// Only version 3 sessions are written this way. Earlier versions seal the archive whole, which
+/// needs all of it in memory at once, so those sessions are only ever read now.
+///
+/// The header is the same as for any other session, with the `Chunked` flag set. The archive is
+/// lz4 compressed as it is read, and the compressed stream is cut into `SESSION_CHUNK_SIZE`
+/// pieces. Each piece is padded and prefixed the same way version 2 session data is, behind a
+/// single byte that is 1 for the last chunk and 0 otherwise, then sealed with the session key and
+/// written out behind its sealed length as a little endian u32.
+///
+/// Chunk `n` (counting from 0) is sealed with the session nonce plus `n + 1`, treating the first 8
+/// bytes of the nonce as a little endian u64, so no chunk shares a nonce with another and the
+/// chunks can't be reordered. The flag on the last chunk means a session cut short is noticed
+/// even when it ends right on a chunk boundary.
+pub fn write_session_file<R: Read>(version: SyncVersion, folder_id: u64, name: &str, main_key: &Key, archive: R) -> Result<SessionFile, SDError> {
+    let dir = session_dir()?;
+
+    write_session_file_in(&dir, version, folder_id, name, main_key, archive)
+}
+
+/// same as `write_session_file`, with the file in `dir` rather than `STORAGE_DIR`
+pub fn write_session_file_in<R: Read>(dir: &Path, version: SyncVersion, folder_id: u64, name: &str, main_key: &Key, mut archive: R) -> Result<SessionFile, SDError> {
+    match version {
+        SyncVersion::Version3 => {},
+        _ => {
+            panic!("Attempted to create invalid chunked session version");
+        },
+    };
+
+    let session_key = Key::new(KeyType::Session);
+
+    // same as to_wrapped, the nonce is used for both the session key and the chunks
+    let session_nonce = ::sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let wrapped_session_key = session_key.to_wrapped(main_key, Some(&session_nonce))?;
+
+    let mut flags = Empty;
+    flags.insert(Compressed);
+    flags.insert(Chunked);
+
+    let header = session_header(version, flags, &current_channel(), is_production(), &wrapped_session_key, &session_nonce);
+
+    let (file, mut f) = TempFile::create_in(dir, "session")?;
+
+    f.write_all(&header)?;
+
+    let sealer = ChunkSealer::new(f, session_key.as_sodium_secretbox_key(), session_nonce);
+
+    let mut encoder = ::lz4::EncoderBuilder::new().level(16).build(sealer)?;
+
+    let real_size = io::copy(&mut archive, &mut encoder)?;
+
+    let (sealer, result) = encoder.finish();
+    result?;
+
+    let compressed_size = sealer.written;
+
+    let f = sealer.finish()?;
+    f.sync_all()?;
+
+    let len = f.metadata()?.len();
+
+    debug!("wrote chunked session {}: {} bytes, {} compressed, {} stored", name, real_size, compressed_size, len);
+
+    Ok(SessionFile {
+        folder_id: folder_id,
+        name: name.to_string(),
+        file: file,
+        len: len,
+        real_size: real_size,
+        compressed_size: compressed_size,
+    })
+}
+
+/// Write a session archive to a temporary file in the stored form of a chunked session
+///
+/// Only version 3 sessions are written this way. Earlier versions seal the archive whole, which
+/// needs all of it in memory at once, so those sessions are only ever read now.
+///
+/// The header is the same as for any other session, with the `Chunked` flag set. The archive is
+/// lz4 compressed as it is read, and the compressed stream is cut into `SESSION_CHUNK_SIZE`
+/// pieces. Each piece is padded and prefixed the same way version 2 session data is, behind a
+/// single byte that is 1 for the last chunk and 0 otherwise, then sealed with the session key and
+/// written out behind its sealed length as a little endian u32.
+///
+/// Chunk `n` (counting from 0) is sealed with the session nonce plus `n + 1`, treating the first 8
+/// bytes of the nonce as a little endian u64, so no chunk shares a nonce with another and the
+/// chunks can't be reordered. The flag on the last chunk means a session cut short is noticed
+/// even when it ends right on a chunk boundary.
+pub fn write_session_file<R: Read>(version: SyncVersion, folder_id: u64, name: &str, main_key: &Key, archive: R) -> Result<SessionFile, SDError> {
+    let dir = session_dir()?;
+
+    write_session_file_in(&dir, version, folder_id, name, main_key, archive)
+}
+
+/// same as `write_session_file`, with the file in `dir` rather than `STORAGE_DIR`
+pub fn write_session_file_in<R: Read>(dir: &Path, version: SyncVersion, folder_id: u64, name: &str, main_key: &Key, mut archive: R) -> Result<SessionFile, SDError> {
+    match version {
+        SyncVersion::Version3 => {},
+        _ => {
+            panic!("Attempted to create invalid chunked session version");
+        },
+    };
+
+    let session_key = Key::new(KeyType::Session);
+
+    // same as to_wrapped, the nonce is used for both the session key and the chunks
+    let session_nonce = ::sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let wrapped_session_key = session_key.to_wrapped(main_key, Some(&session_nonce))?;
+
+    let mut flags = Empty;
+    flags.insert(Compressed);
+    flags.insert(Chunked);
+
+    let header = session_header(version, flags, &current_channel(), is_production(), &wrapped_session_key, &session_nonce);
+
+    let (file, mut f) = TempFile::create_in(dir, "session")?;
+
+    f.write_all(&header)?;
+
+    let sealer = ChunkSealer::new(f, session_key.as_sodium_secretbox_key(), session_nonce);
+
+    let mut encoder = ::lz4::EncoderBuilder::new().level(16).build(sealer)?;
+
+    let real_size = io::copy(&mut archive, &mut encoder)?;
+
+    let (sealer, result) = encoder.finish();
+    result?;
+
+    let compressed_size = sealer.written;
+
+    let f = sealer.finish()?;
+    f.sync_all()?;
+
+    let len = f.metadata()?.len();
+
+    debug!("wrote chunked session {}: {} bytes, {} compressed, {} stored", name, real_size, compressed_size, len);
+
+    Ok(SessionFile {
+        folder_id: folder_id,
+        name: name.to_string(),
+        file: file,
+        len: len,
+        real_size: real_size,
+        compressed_size: compressed_size,
+    })
+}
+
+/// write a session that isn't chunked, sealed whole the way `to_wrapped` does it
+fn write_sealed_session_file_in<R: Read>(dir: &Path, version: SyncVersion, folder_id: u64, name: &str, main_key: &Key, mut archive: R) -> Result<SessionFile, SDError> {
+    let mut data = Vec::new();
+    archive.read_to_end(&mut data)?;
+
+    let real_size = data.len() as u64;
+
+    let session = SyncSession::new(version, folder_id, name.to_string(), Some(real_size), None, data);
+
+    let wrapped_session = match session.to_wrapped(main_key) {
+        Ok(ws) => ws,
+        Err(e) => return Err(SDError::CryptoError(Box::new(e))),
+    };
+
+    // the sealed data is padded, so this is as close as we can get to the compressed size
+    let compressed_size = (wrapped_session.len() - SECRETBOX_MAC_SIZE) as u64;
+
+    let (file, mut f) = TempFile::create_in(dir, "session")?;
+
+    f.write_all(&wrapped_session.as_binary())?;
+    f.sync_all()?;
+
+    let len = f.metadata()?.len();
+
+    debug!("wrote session {}: {} bytes, {} compressed, {} stored", name, real_size, compressed_size, len);
+
+    Ok(SessionFile {
+        folder_id: folder_id,
+        name: name.to_string(),
+        file: file,
+        len: len,
+        real_size: real_size,
+        compressed_size: compressed_size,
+    })
+}
+
+
+/// Read a session as it arrives from storage
+///
+/// Chunked sessions are opened one chunk at a time and unpacked into a temporary file, older
+/// sessions are read into memory and unwrapped the same way they always were.
+pub fn read_session_file(body: SyncSessionResponse, main_key: &Key) -> Result<SyncSession, SDError> {
+    let dir = session_dir()?;
+
+    read_session_file_in(&dir, body, main_key)
+}
+
+/// same as `read_session_file`, with any temporary file in `dir` rather than `STORAGE_DIR`
+pub fn read_session_file_in(dir: &Path, body: SyncSessionResponse, main_key: &Key) -> Result<SyncSession, SDError> {
+    let folder_id = body.folder_id;
+    let name = body.name;
+    let mut reader = body.body;
+
+    // the header is the same size for every session
+    let header_size = 8 + (SECRETBOX_KEY_SIZE + SECRETBOX_MAC_SIZE) + SECRETBOX_NONCE_SIZE;
+
+    let mut data = vec![0u8; header_size];
+
+    if let Err(e) = reader.read_exact(&mut data) {
+        return Err(read_error(e));
+    }
+
+    let chunked = parse_session(&data)?.chunked;
+
+    if !chunked {
+        reader.read_to_end(&mut data)?;
+
+        let w_session = WrappedSyncSession::from_binary(folder_id, name, &data)?;
+
+        return w_session.to_session(main_key);
+    }
+
+    let (session_ver, channel, production, wrapped_session_key, session_nonce) = {
+        let raw_session = parse_session(&data)?;
+
+        debug!("got valid chunked binary file: {}", &raw_session);
+
+        let session_ver = session_version(raw_session.version)?;
+
+        check_chunked(session_ver, true)?;
+
+        let session_nonce = ::sodiumoxide::crypto::secretbox::Nonce::from_slice(raw_session.nonce)
+            .expect("failed to get nonce");
+
+        (session_ver, raw_session.channel, raw_session.production, WrappedKey::from(raw_session.wrapped_key.to_vec(), KeyType::Session), session_nonce)
+    };
+
+    let session_key = match wrapped_session_key.to_key(main_key, Some(&session_nonce)) {
+        Ok(k) => k,
+        Err(_) => return Err(SDError::CryptoError(Box::new(CryptoError::SessionDecryptFailed))),
+    };
+
+    let (file, mut f) = TempFile::create_in(dir, "tar")?;
+
+    let (real_size, compressed_size) = open_chunks(reader, &session_key, session_nonce, &mut f)?;
+
+    f.flush()?;
+
+    Ok(SyncSession {
+        version: session_ver,
+        folder_id: Some(folder_id),
+        name: name.to_string(),
+        size: None,
+        time: None,
+        id: None,
+        channel: channel,
+        production: production,
+        data: SessionData::File(Arc::new(file)),
+        compressed: true,
+        real_size: real_size,
+        compressed_size: Some(compressed_size),
+        manifest: Vec::new(),
+    })
+}
+
+/// open the chunks of a chunked session and write the archive they hold to `out`, returning the
+/// size of the archive and of the compressed stream it was stored as
+fn open_chunks<R: Read, W: Write>(reader: R, session_key: &Key, session_nonce: ::sodiumoxide::crypto::secretbox::Nonce, out: &mut W) -> Result<(u64, u64), SDError> {
+    let opener = ChunkOpener::new(reader, session_key.as_sodium_secretbox_key(), session_nonce);
+
+    let mut decoder = match ::lz4::Decoder::new(opener) {
+        Ok(d) => d,
+        Err(e) => return Err(read_error(e)),
+    };
+
+    let real_size = match io::copy(&mut decoder, out) {
+        Ok(size) => size,
+        Err(e) => return Err(read_error(e)),
+    };
+
+    let (mut opener, result) = decoder.finish();
+
+    if let Err(e) = result {
+        return Err(read_error(e));
+    }
+
+    // the compressed stream can end before the last chunk has been opened, it still has to be
+    // there or the session was cut short
+    if let Err(e) = io::copy(&mut opener, &mut io::sink()) {
+        return Err(read_error(e));
+    }
+
+    Ok((real_size, opener.read))
+}
+
+/// only version 3 sessions are chunked, a chunked session of any other version was not written by
+/// this library and older clients would misread it
+fn check_chunked(version: SyncVersion, chunked: bool) -> Result<(), SDError> {
+    if chunked != version.chunked_manifest() {
+        debug!("version {} session has the wrong chunked flag: {}", version, chunked);
+
+        return Err(SDError::SessionUnreadable);
+    }
+
+    Ok(())
+}
+
+/// Seals everything written to it as the chunks of a chunked session, see `write_session_file`
+struct ChunkSealer<W: Write> {
+    inner: W,
+    key: ::sodiumoxide::crypto::secretbox::Key,
+    nonce: ::sodiumoxide::crypto::secretbox::Nonce,
+    index: u64,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl<W: Write> ChunkSealer<W> {
+    fn new(inner: W, key: ::sodiumoxide::crypto::secretbox::Key, nonce: ::sodiumoxide::crypto::secretbox::Nonce) -> ChunkSealer<W> {
+        ChunkSealer {
+            inner: inner,
+            key: key,
+            nonce: nonce,
+            index: 0,
+            buffer: Vec::new(),
+            written: 0,
+        }
+    }
+
+    fn seal(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let mut to_encrypt = vec![if last { 1u8 } else { 0u8 }];
+        to_encrypt.extend(::util::pad_and_prefix_length(&self.buffer[..len]));
+
+        let sealed = ::sodiumoxide::crypto::secretbox::seal(&to_encrypt, &chunk_nonce(&self.nonce, self.index), &self.key);
+
+        let mut size_buf = [0; 4];
+        LittleEndian::write_u32(&mut size_buf, sealed.len() as u32);
+
+        self.inner.write_all(&size_buf)?;
+        self.inner.write_all(&sealed)?;
+
+        self.buffer.drain(..len);
+        self.index += 1;
+
+        Ok(())
+    }
+
+    /// seal whatever is left as the last chunk, even if that's nothing
+    fn finish(mut self) -> io::Result<W> {
+        let len = self.buffer.len();
+        self.seal(len, true)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkSealer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.written += buf.len() as u64;
+
+        // keep anything up to a full chunk back, it might turn out to be the last one
+        while self.buffer.len() > SESSION_CHUNK_SIZE {
+            self.seal(SESSION_CHUNK_SIZE, false)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Opens the chunks of a chunked session as they are read, see `write_session_file`
+struct ChunkOpener<R: Read> {
+    inner: R,
+    key: ::sodiumoxide::crypto::secretbox::Key,
+    nonce: ::sodiumoxide::crypto::secretbox::Nonce,
+    index: u64,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+    read: u64,
+}
+
+impl<R: Read> ChunkOpener<R> {
+    fn new(inner: R, key: ::sodiumoxide::crypto::secretbox::Key, nonce: ::sodiumoxide::crypto::secretbox::Nonce) -> ChunkOpener<R> {
+        ChunkOpener {
+            inner: inner,
+            key: key,
+            nonce: nonce,
+            index: 0,
+            buffer: Vec::new(),
+            position: 0,
+            finished: false,
+            read: 0,
+        }
+    }
+
+    fn open_next(&mut self) -> io::Result<()> {
+        let mut size_buf = [0u8; 4];
+
+        if let Err(e) = self.inner.read_exact(&mut size_buf) {
+            return Err(chunk_read_error(e));
+        }
+
+        let size = LittleEndian::read_u32(&size_buf) as usize;
+
+        // padding never comes close to doubling a chunk, anything bigger isn't one of ours
+        if size > SESSION_CHUNK_SIZE * 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "session chunk is too large"));
+        }
+
+        let mut sealed = vec![0u8; size];
+
+        if let Err(e) = self.inner.read_exact(&mut sealed) {
+            return Err(chunk_read_error(e));
+        }
+
+        let opened = match ::sodiumoxide::crypto::secretbox::open(&sealed, &chunk_nonce(&self.nonce, self.index), &self.key) {
+            Ok(opened) => opened,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "session chunk cannot be decrypted")),
+        };
+
+        if opened.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "session chunk is empty"));
+        }
+
+        let data = match ::binformat::remove_padding(&opened[1..]) {
+            Done(_, o) => o.to_vec(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "session chunk padding cannot be removed")),
+        };
+
+        self.finished = opened[0] == 1;
+        self.read += data.len() as u64;
+        self.buffer = data;
+        self.position = 0;
+        self.index += 1;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkOpener<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.open_next()?;
+        }
+
+        let count = ::std::cmp::min(buf.len(), self.buffer.len() - self.position);
+
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+fn chunk_nonce(nonce: &::sodiumoxide::crypto::secretbox::Nonce, index: u64) -> ::sodiumoxide::crypto::secretbox::Nonce {
+    let mut bytes = nonce.0;
+
+    let counter = LittleEndian::read_u64(&bytes[0..8]).wrapping_add(index + 1);
+    LittleEndian::write_u64(&mut bytes[0..8], counter);
+
+    ::sodiumoxide::crypto::secretbox::Nonce(bytes)
+}
+
+fn chunk_read_error(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData, "session ends before its last chunk"),
+        _ => e,
+    }
+}
+
+/// anything wrong with the session data itself means the session can't be used, anything else
+/// is a problem reading it
+fn read_error(e: io::Error) -> SDError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            debug!("session data cannot be read: {}", e);
+
+            SDError::SessionUnreadable
+        },
+        _ => SDError::from(e),
+    }
+}
+
+fn parse_session(data: &[u8]) -> Result<BinaryFormat, SDError> {
+    match ::binformat::binary_parse(data) {
+        Done(_, o) => Ok(o),
+        Error(e) => {
+            debug!("session parsing failed: {}", &e);
+
+            match e {
+                ::nom::verbose_errors::Err::Code(ref kind) => {
+                    debug!("session parsing failure kind: {:?}", kind);
+
+                },
+                ::nom::verbose_errors::Err::Node(ref kind, ref err) => {
+                    debug!("session parsing failure node: {:?}, {:?}", kind, err);
+
+                },
+                ::nom::verbose_errors::Err::Position(ref kind, ref position) => {
+                    debug!("session parsing failure position: {:?}: {:?}", kind, position);
+
+                },
+                ::nom::verbose_errors::Err::NodePosition(ref kind, ref position, ref err) => {
+                    debug!("session parsing failure kind: {:?}: {:?}, {:?}", kind, position, err);
+
+                },
+            };
+
+            Err(SDError::SessionUnreadable)
+        },
+        Incomplete(_) => {
+            debug!("session file cannot be parsed, this should never happen");
+            Err(SDError::SessionUnreadable)
+        },
+    }
+}
+
+/// where session files are kept while they're being uploaded or read
+fn session_dir() -> Result<PathBuf, SDError> {
+    let sd = STORAGE_DIR.read();
+
+    if sd.is_empty() {
+        return Err(SDError::Internal("storage directory has not been set".to_string()));
+    }
+
+    let mut p = PathBuf::from(&*sd);
+    p.push("sessions");
+
+    Ok(p)
+}
+
+fn session_version(version: &str) -> Result<SyncVersion, SDError> {
+    match version {
+        "01" => Ok(SyncVersion::Version1),
+        "02" => Ok(SyncVersion::Version2),
+        "03" => Ok(SyncVersion::Version3),
+        _ => {
+            debug!("session has an unknown version: {}", version);
+
+            Err(SDError::SessionUnreadable)
+        },
+    }
+}
+
+fn current_channel() -> Channel {
+    let c = CHANNEL.read();
+
+    match *c {
+        Channel::Stable => Channel::Stable,
+        Channel::Beta => Channel::Beta,
+        Channel::Nightly => Channel::Nightly,
+    }
+}
+
+/// everything in the stored form of a session before the session data
+fn session_header(version: SyncVersion, mut flags: BinaryFlags, channel: &Channel, production: bool, wrapped_key: &WrappedKey, nonce: &::sodiumoxide::crypto::secretbox::Nonce) -> Vec<u8> {
+    let mut binary_data = Vec::new();
+
+    // first 8 bytes are the file ID, type, version, flags, and 2 byte reserved area
+    let magic: &'static [u8; 2] = br"sd";
+    let file_type: &'static [u8; 1] = br"s";
+    let version = version.as_ref();
+    let reserved: &'static [u8; 2] = br"00";
+
+    match *channel {
+        Channel::Stable => flags.insert(Stable),
+        Channel::Beta => {
+            flags.insert(Beta);
+        },
+        Channel::Nightly => {
+            flags.insert(Nightly);
+        },
+    };
+
+    if production {
+        flags.insert(Production);
+    } else {
+    }
+
+    let flag_ref: &[u8] = &[flags.bits()];
+
+    binary_data.extend(magic.as_ref());
+    binary_data.extend(file_type.as_ref());
+    binary_data.extend(version);
+    binary_data.extend(flag_ref);
+    binary_data.extend(reserved.as_ref());
+
+    // next 48 bytes will be the wrapped session key
+    binary_data.extend(wrapped_key.as_ref());
+
+    // next 24 bytes will be the nonce
+    binary_data.extend(nonce.as_ref());
+    assert_eq!(binary_data.len(), magic.len() + file_type.len() + version.len() + flag_ref.len() + reserved.len() + (SECRETBOX_KEY_SIZE + SECRETBOX_MAC_SIZE) + SECRETBOX_NONCE_SIZE);
+
+    binary_data
+}
+
+#[test]
+fn chunked_session_test() {
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-session-{}", ::util::generate_uuid()));
+
+    let main = Key::new(KeyType::Main);
+
+    // random data doesn't compress, so this still needs more than one chunk after lz4
+    let archive = ::sodiumoxide::randombytes::randombytes(SESSION_CHUNK_SIZE * 2 + 1000);
+
+    let session_file = write_session_file_in(&dir, SyncVersion::Version3, 1, "chunked", &main, archive.as_slice()).expect("failed to write session");
+    assert!(session_file.real_size() == archive.len() as u64);
+
+    let mut stored = Vec::new();
+    session_file.open().expect("failed to open session").read_to_end(&mut stored).expect("failed to read session");
+    assert!(stored.len() as u64 == session_file.len());
+
+    let body = SyncSessionResponse {
+        name: "chunked",
+        folder_id: 1,
+        body: Box::new(io::Cursor::new(stored.clone())),
+    };
+
+    let session = read_session_file_in(&dir, body, &main).expect("failed to read chunked session");
+    assert!(session.compressed());
+
+    let mut restored = Vec::new();
+    session.reader().expect("failed to open session").read_to_end(&mut restored).expect("failed to read session");
+    assert!(restored == archive);
+
+    // the whole session can be read into memory the same way older sessions are
+    let wrapped = WrappedSyncSession::from_binary(1, "chunked", &stored).expect("failed to read chunked session");
+    assert!(wrapped.as_binary() == stored);
+
+    let session = wrapped.to_session(&main).expect("failed to unwrap chunked session");
+
+    let mut restored = Vec::new();
+    session.reader().expect("failed to open session").read_to_end(&mut restored).expect("failed to read session");
+    assert!(restored == archive);
+
+    // a session missing the end of its last chunk can't be used
+    let truncated = stored[..stored.len() - 100].to_vec();
+
+    let body = SyncSessionResponse {
+        name: "chunked",
+        folder_id: 1,
+        body: Box::new(io::Cursor::new(truncated)),
+    };
+
+    assert!(read_session_file_in(&dir, body, &main).is_err());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn legacy_session_test() {
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-session-{}", ::util::generate_uuid()));
+
+    let main = Key::new(KeyType::Main);
+
+    let archive = vec![7u8; 100_000];
+
+    // version 2 sessions are no longer written, but the ones already stored must still be read
+    let stored = SyncSession::new(SyncVersion::Version2, 1, "sealed".to_string(), None, None, archive.clone())
+        .to_wrapped(&main).expect("failed to wrap session")
+        .as_binary();
+
+    assert!(!parse_session(&stored).expect("failed to parse session").chunked);
+
+    let body = SyncSessionResponse {
+        name: "sealed",
+        folder_id: 1,
+        body: Box::new(io::Cursor::new(stored.clone())),
+    };
+
+    let session = read_session_file_in(&dir, body, &main).expect("failed to read sealed session");
+    assert!(session.compressed());
+
+    let mut restored = Vec::new();
+    session.reader().expect("failed to open session").read_to_end(&mut restored).expect("failed to read session");
+    assert!(restored == archive);
+
+    // a session with a version we don't know about can't be used
+    let mut unknown = stored.clone();
+    unknown[3] = b'9';
+    unknown[4] = b'9';
+
+    match WrappedSyncSession::from_binary(1, "sealed", &unknown) {
+        Err(SDError::SessionUnreadable) => {},
+        _ => panic!("session with an unknown version was not rejected"),
+    }
+
+    // nor can a version 2 session claiming to be chunked, older clients would misread it
+    let mut chunked = stored.clone();
+    chunked[5] |= Chunked.bits();
+
+    match WrappedSyncSession::from_binary(1, "sealed", &chunked) {
+        Err(SDError::SessionUnreadable) => {},
+        _ => panic!("chunked version 2 session was not rejected"),
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// a reader that makes up `remaining` bytes of data that won't compress, and remembers the most
+/// it was asked for at once
+#[cfg(test)]
+struct LargestRead {
+    remaining: u64,
+    state: u32,
+    largest: usize,
+}
+
+#[cfg(test)]
+impl Read for LargestRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() > self.largest {
+            self.largest = buf.len();
+        }
+
+        let size = ::std::cmp::min(buf.len() as u64, self.remaining) as usize;
+
+        for b in buf[..size].iter_mut() {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            *b = self.state as u8;
+        }
+
+        self.remaining -= size as u64;
+
+        Ok(size)
+    }
+}
+
+#[test]
+fn session_memory_test() {
+    use SYNC_VERSION;
+
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-session-{}", ::util::generate_uuid()));
+
+    let main = Key::new(KeyType::Main);
+
+    let size = (SESSION_CHUNK_SIZE * 16) as u64;
+
+    // the archive is read a piece at a time while it's written, never all at once
+    let mut archive = LargestRead { remaining: size, state: 2463534242, largest: 0 };
+
+    let session_file = write_session_file_in(&dir, SYNC_VERSION, 1, "large", &main, &mut archive).expect("failed to write session");
+    assert!(session_file.real_size() == size);
+    assert!(archive.largest <= SESSION_CHUNK_SIZE);
+
+    // and the same when it's read back, a chunk at a time
+    let (body, largest) = {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Tracked<R: Read> {
+            inner: R,
+            largest: Arc<AtomicUsize>,
+        }
+
+        impl<R: Read> Read for Tracked<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if buf.len() > self.largest.load(Ordering::SeqCst) {
+                    self.largest.store(buf.len(), Ordering::SeqCst);
+                }
+
+                self.inner.read(buf)
+            }
+        }
+
+        let largest = Arc::new(AtomicUsize::new(0));
+
+        (Tracked { inner: session_file.open().expect("failed to open session"), largest: largest.clone() }, largest)
+    };
+
+    let response = SyncSessionResponse {
+        name: "large",
+        folder_id: 1,
+        body: Box::new(body),
+    };
+
+    let session = read_session_file_in(&dir, response, &main).expect("failed to read session");
+    assert!(session.real_size() == size);
+    assert!(largest.load(::std::sync::atomic::Ordering::SeqCst) <= SESSION_CHUNK_SIZE * 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn manifest_session_test() {
+    use std::collections::HashMap;
+    use block::{Block, WrappedBlock};
+    use chunk::ChunkGenerator;
+
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("sddk-session-{}", ::util::generate_uuid()));
+
+    let main = Key::new(KeyType::Main);
+    let hmac = Key::new(KeyType::HMAC);
+    let tweak = Key::new(KeyType::Tweak);
+
+    let archive = ::sodiumoxide::randombytes::randombytes(600_000);
+
+    // store the archive the way sync stores any other data
+    let mut stored: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut hmacs = Vec::new();
+    let mut offset = 0;
+
+    for chunk in ChunkGenerator::new(archive.iter().cloned(), &tweak, archive.len() as u64, SyncVersion::Version3.block_version()) {
+        let block = Block::new(SyncVersion::Version3.block_version(), &hmac, archive[offset..offset + chunk.size as usize].to_vec());
+        offset += chunk.size as usize;
+
+        let block_hmac = block.get_hmac();
+        hmacs.extend_from_slice(&block_hmac);
+
+        stored.insert(block_hmac, block.to_wrapped(&main).expect("failed to wrap block").as_binary());
+    }
+
+    let root = manifest_root(archive.len() as u64, &hmacs);
+
+    let session_file = write_session_file_in(&dir, SyncVersion::Version3, 1, "manifest", &main, root.as_slice()).expect("failed to write session");
+
+    let mut data = Vec::new();
+    session_file.open().expect("failed to open session").read_to_end(&mut data).expect("failed to read session");
+
+    let body = SyncSessionResponse {
+        name: "manifest",
+        folder_id: 1,
+        body: Box::new(io::Cursor::new(data)),
+    };
+
+    let session = read_session_file_in(&dir, body, &main).expect("failed to read session");
+    assert!(session.version().chunked_manifest());
+
+    let fetch = |block_hmac: &[u8]| -> Result<Vec<u8>, SDError> {
+        let raw = stored[block_hmac].clone();
+        let block = WrappedBlock::from(raw, block_hmac.to_vec())?.to_block(&main)?;
+
+        Ok(block.as_ref().to_vec())
+    };
+
+    let session = session.read_manifest_in(&dir, fetch).expect("failed to read manifest");
+    assert!(session.manifest_hmacs().len() == stored.len());
+
+    let mut restored = Vec::new();
+    session.reader().expect("failed to open session").read_to_end(&mut restored).expect("failed to read session");
+    assert!(restored == archive);
+
+    // a root that doesn't match its blocks can't be used
+    let short_root = manifest_root(archive.len() as u64 + 1, &hmacs);
+
+    let session = SyncSession::new(SyncVersion::Version3, 1, "short".to_string(), None, None, short_root);
+    assert!(session.read_manifest_in(&dir, |_| Ok(Vec::new())).is_err());
+
+    let _ = fs::remove_dir_all(&dir);
+}
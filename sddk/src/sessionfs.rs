@@ -1 +1,705 @@
 #![cfg(feature = "sessionfs")]
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+// external crate imports
+
+use chrono::{Local, TimeZone};
+use fuse_mt::{DirectoryEntry, FileAttr, FilesystemMT, FuseMT, RequestInfo, ResultData, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir};
+use fuse_mt::FileType as FuseFileType;
+use libc;
+use parking_lot::Mutex;
+use time::Timespec;
+
+// internal imports
+
+use backend::StorageBackend;
+use block::Block;
+use constants::HMAC_SIZE;
+use error::SDError;
+use keys::Key;
+use models::{SessionEntry, SessionEntryType};
+
+/// how long the kernel may cache attributes and lookups, nothing in a session ever changes
+const TTL_SECS: i64 = 60;
+
+/// unwrapped blocks kept in memory for each open file
+const RECENT_BLOCKS: usize = 4;
+
+/// downloaded sessions kept in memory, the least recently used one is dropped to make room
+const CACHED_SESSIONS: usize = 8;
+
+/// tells the kernel to pass reads through even past the size it was given for a file
+const FOPEN_DIRECT_IO: u32 = 1;
+
+/// A read-only filesystem showing every sync session of every folder, without restoring them
+///
+/// The layout is one directory for each folder, named after the folder, containing one
+/// directory for each of its sessions, named after the time of the session:
+///
+///     <mountpoint>/<folder name>/<session time>/<files in the session>
+///
+/// Folders and sessions are listed when the filesystem is created. A session is downloaded the
+/// first time something inside it is looked at, and file contents are read lazily, one block at
+/// a time, through the same block cache restores use.
+///
+/// Blocks vary in size, so reading from the middle of a file needs the size of every block
+/// before it. Sessions record those sizes, older sessions don't, and for their files the sizes
+/// are learned as blocks are read and kept for as long as the file is open.
+pub struct SessionFS {
+    backend: Arc<StorageBackend>,
+    main_key: Key,
+    folders: Vec<FolderDir>,
+    /// the most recently used session last
+    trees: Mutex<Vec<(String, Arc<SessionTree>)>>,
+    handles: Mutex<HashMap<u64, Arc<Mutex<OpenFile>>>>,
+    next_handle: Mutex<u64>,
+    uid: u32,
+    gid: u32,
+    mount_time: Timespec,
+}
+
+struct FolderDir {
+    id: u64,
+    dir_name: String,
+    sessions: Vec<SessionDir>,
+}
+
+struct SessionDir {
+    name: String,
+    dir_name: String,
+    /// seconds since the epoch
+    time: u64,
+}
+
+/// The entries of one session, keyed by path without a trailing separator, along with the HMAC
+/// list and block sizes of each file
+struct SessionTree {
+    entries: HashMap<String, (SessionEntry, Vec<u8>, Vec<u64>)>,
+    /// the names in every directory, including directories that only exist as parents of
+    /// other entries
+    children: HashMap<String, Vec<String>>,
+}
+
+struct OpenFile {
+    session_name: String,
+    hmacs: Vec<Vec<u8>>,
+    /// sizes of the blocks at the start of the file, every block when the session recorded them,
+    /// otherwise the ones that have been read so far
+    block_sizes: Vec<u64>,
+    recent: Vec<(usize, Arc<Block>)>,
+}
+
+/// what a path in the filesystem refers to
+enum Node {
+    Root,
+    Folder(usize),
+    Session(usize, usize),
+    Entry(usize, usize, String),
+}
+
+impl SessionFS {
+    /// list the folders and sessions the filesystem will show
+    pub fn new(backend: Arc<StorageBackend>, main_key: &Key) -> Result<SessionFS, SDError> {
+        let registered_folders = backend.read_folders()?;
+
+        let mut session_details = backend.read_sessions()?;
+
+        let mut sessions_by_folder = match session_details.remove("sessionDetails") {
+            Some(s) => s,
+            None => return Err(SDError::Internal("failed to get sessionDetails from sessions response".to_string())),
+        };
+
+        let mut folders = Vec::new();
+        let mut folder_names = HashSet::new();
+
+        for folder in registered_folders {
+            let dir_name = unique_name(&mut folder_names, safe_name(&folder.folderName), folder.id);
+
+            let mut sessions = Vec::new();
+            let mut session_names = HashSet::new();
+
+            let mut folder_sessions = sessions_by_folder.remove(&folder.id).unwrap_or(Vec::new());
+            folder_sessions.sort_by_key(|session| session.time);
+
+            for session in folder_sessions {
+                let time = session.time.unwrap_or(0) / 1000;
+
+                let session_dir_name = match session.time {
+                    Some(_) => unique_name(&mut session_names, Local.timestamp(time as i64, 0).format("%Y-%m-%d %H-%M-%S").to_string(), &session.name),
+                    None => unique_name(&mut session_names, session.name.clone(), &session.name),
+                };
+
+                sessions.push(SessionDir {
+                    name: session.name,
+                    dir_name: session_dir_name,
+                    time: time,
+                });
+            }
+
+            folders.push(FolderDir {
+                id: folder.id,
+                dir_name: dir_name,
+                sessions: sessions,
+            });
+        }
+
+        Ok(SessionFS {
+            backend: backend,
+            main_key: main_key.clone(),
+            folders: folders,
+            trees: Mutex::new(Vec::new()),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: Mutex::new(1),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            mount_time: ::time::get_time(),
+        })
+    }
+
+    /// mount the filesystem at `mountpoint`, returning once it has been unmounted
+    pub fn mount(self, mountpoint: &Path) -> Result<(), SDError> {
+        let options = [OsStr::new("-o"), OsStr::new("ro,fsname=safedrive")];
+
+        debug!("mounting sessions at {}", mountpoint.display());
+
+        ::fuse_mt::mount(FuseMT::new(self, ::num_cpus::get()), &mountpoint, &options)?;
+
+        Ok(())
+    }
+
+    fn node(&self, path: &Path) -> Result<Node, libc::c_int> {
+        let mut names = Vec::new();
+
+        for component in path.components() {
+            match component {
+                Component::RootDir => {},
+                Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                _ => return Err(libc::ENOENT),
+            }
+        }
+
+        if names.is_empty() {
+            return Ok(Node::Root);
+        }
+
+        let folder_index = match self.folders.iter().position(|folder| folder.dir_name == names[0]) {
+            Some(index) => index,
+            None => return Err(libc::ENOENT),
+        };
+
+        if names.len() == 1 {
+            return Ok(Node::Folder(folder_index));
+        }
+
+        let session_index = match self.folders[folder_index].sessions.iter().position(|session| session.dir_name == names[1]) {
+            Some(index) => index,
+            None => return Err(libc::ENOENT),
+        };
+
+        if names.len() == 2 {
+            return Ok(Node::Session(folder_index, session_index));
+        }
+
+        Ok(Node::Entry(folder_index, session_index, names[2..].join("/")))
+    }
+
+    /// the entries of a session, downloading it if it isn't one of the recently used sessions
+    fn tree(&self, folder_index: usize, session_index: usize) -> Result<Arc<SessionTree>, libc::c_int> {
+        let folder = &self.folders[folder_index];
+        let session = &folder.sessions[session_index];
+
+        {
+            let mut trees = self.trees.lock();
+
+            if let Some(position) = trees.iter().position(|&(ref name, _)| name == &session.name) {
+                let cached = trees.remove(position);
+                let tree = cached.1.clone();
+
+                trees.push(cached);
+
+                return Ok(tree);
+            }
+        }
+
+        // not holding the lock while downloading, so other sessions can still be browsed
+        debug!("loading session {} for folder {}", session.name, folder.id);
+
+        let contents = match ::browse::read_session(&*self.backend, folder.id, &session.name, &self.main_key).and_then(|s| ::browse::session_contents_with_block_sizes(&s)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("failed to load session {}: {}", session.name, e);
+
+                return Err(libc::EIO);
+            },
+        };
+
+        let tree = Arc::new(SessionTree::new(contents));
+
+        let mut trees = self.trees.lock();
+
+        // another thread may have loaded the same session in the meantime
+        trees.retain(|&(ref name, _)| name != &session.name);
+
+        if trees.len() >= CACHED_SESSIONS {
+            trees.remove(0);
+        }
+
+        trees.push((session.name.clone(), tree.clone()));
+
+        Ok(tree)
+    }
+
+    fn dir_attr(&self, time: Timespec) -> FileAttr {
+        FileAttr {
+            size: 0,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FuseFileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn entry_attr(&self, tree: &SessionTree, session: &SessionDir, path: &str) -> Result<FileAttr, libc::c_int> {
+        let entry = match tree.entries.get(path) {
+            Some(&(ref entry, _, _)) => entry,
+            None if tree.children.contains_key(path) => return Ok(self.dir_attr(Timespec::new(session.time as i64, 0))),
+            None => return Err(libc::ENOENT),
+        };
+
+        let time = Timespec::new(entry.mtime as i64, 0);
+
+        let (kind, size) = match entry.entry_type {
+            SessionEntryType::Directory => return Ok(self.dir_attr(time)),
+            SessionEntryType::File => (FuseFileType::RegularFile, entry.size.unwrap_or(0)),
+            SessionEntryType::Symlink => (FuseFileType::Symlink, entry.link_target.as_ref().map(|t| t.len() as u64).unwrap_or(0)),
+            SessionEntryType::HardLink => {
+                let size = match tree.file(path) {
+                    Some(&(ref target, _, _)) => target.size.unwrap_or(0),
+                    None => 0,
+                };
+
+                (FuseFileType::RegularFile, size)
+            },
+            SessionEntryType::Other => (FuseFileType::RegularFile, 0),
+        };
+
+        Ok(FileAttr {
+            size: size,
+            blocks: (size + 511) / 512,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: kind,
+            // nothing can be changed, so nothing is writable either
+            perm: (entry.mode & 0o7555) as u16,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        })
+    }
+}
+
+impl FilesystemMT for SessionFS {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        let ttl = Timespec::new(TTL_SECS, 0);
+
+        let attr = match self.node(path)? {
+            Node::Root => self.dir_attr(self.mount_time),
+            Node::Folder(folder_index) => {
+                let time = match self.folders[folder_index].sessions.last() {
+                    Some(session) => Timespec::new(session.time as i64, 0),
+                    None => self.mount_time,
+                };
+
+                self.dir_attr(time)
+            },
+            Node::Session(folder_index, session_index) => {
+                let session = &self.folders[folder_index].sessions[session_index];
+
+                self.dir_attr(Timespec::new(session.time as i64, 0))
+            },
+            Node::Entry(folder_index, session_index, entry_path) => {
+                let tree = self.tree(folder_index, session_index)?;
+
+                let session = &self.folders[folder_index].sessions[session_index];
+
+                self.entry_attr(&tree, session, &entry_path)?
+            },
+        };
+
+        Ok((ttl, attr))
+    }
+
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        let (folder_index, session_index, entry_path) = match self.node(path)? {
+            Node::Entry(folder_index, session_index, entry_path) => (folder_index, session_index, entry_path),
+            _ => return Err(libc::EINVAL),
+        };
+
+        let tree = self.tree(folder_index, session_index)?;
+
+        match tree.entries.get(&entry_path) {
+            Some(&(ref entry, _, _)) if entry.entry_type == SessionEntryType::Symlink => {
+                match entry.link_target {
+                    Some(ref target) => Ok(target.as_bytes().to_vec()),
+                    None => Err(libc::EIO),
+                }
+            },
+            Some(_) => Err(libc::EINVAL),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        if flags & (libc::O_WRONLY | libc::O_RDWR) as u32 != 0 {
+            return Err(libc::EROFS);
+        }
+
+        let (folder_index, session_index, entry_path) = match self.node(path)? {
+            Node::Entry(folder_index, session_index, entry_path) => (folder_index, session_index, entry_path),
+            _ => return Err(libc::EISDIR),
+        };
+
+        let tree = self.tree(folder_index, session_index)?;
+
+        let (size, hmacs, block_sizes) = match tree.file(&entry_path) {
+            Some(&(ref entry, ref hmac_bag, ref block_sizes)) => (entry.size, hmac_bag.chunks(HMAC_SIZE).map(|hmac| hmac.to_vec()).collect(), block_sizes.clone()),
+            None if tree.children.contains_key(&entry_path) => return Err(libc::EISDIR),
+            None => return Err(libc::ENOENT),
+        };
+
+        let file = OpenFile {
+            session_name: self.folders[folder_index].sessions[session_index].name.clone(),
+            hmacs: hmacs,
+            block_sizes: block_sizes,
+            recent: Vec::new(),
+        };
+
+        let fh = {
+            let mut next_handle = self.next_handle.lock();
+            let fh = *next_handle;
+            *next_handle += 1;
+
+            fh
+        };
+
+        self.handles.lock().insert(fh, Arc::new(Mutex::new(file)));
+
+        // older sessions don't record file sizes, so the size we report can't be relied on
+        let open_flags = match size {
+            Some(_) => 0,
+            None => FOPEN_DIRECT_IO,
+        };
+
+        Ok((fh, open_flags))
+    }
+
+    fn read(&self, _req: RequestInfo, _path: &Path, fh: u64, offset: u64, size: u32) -> ResultData {
+        let file = match self.handles.lock().get(&fh) {
+            Some(file) => file.clone(),
+            None => return Err(libc::EBADF),
+        };
+
+        let mut file = file.lock();
+
+        match file.read_at(&*self.backend, &self.main_key, offset, size) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                error!("failed to read from session {}: {}", file.session_name, e);
+
+                Err(libc::EIO)
+            },
+        }
+    }
+
+    fn release(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        self.handles.lock().remove(&fh);
+
+        Ok(())
+    }
+
+    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        match self.node(path)? {
+            Node::Entry(folder_index, session_index, entry_path) => {
+                let tree = self.tree(folder_index, session_index)?;
+
+                if !tree.children.contains_key(&entry_path) {
+                    return Err(libc::ENOTDIR);
+                }
+            },
+            _ => {},
+        }
+
+        Ok((0, 0))
+    }
+
+    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        let mut entries = vec![
+            DirectoryEntry { name: OsString::from("."), kind: FuseFileType::Directory },
+            DirectoryEntry { name: OsString::from(".."), kind: FuseFileType::Directory },
+        ];
+
+        match self.node(path)? {
+            Node::Root => {
+                for folder in &self.folders {
+                    entries.push(DirectoryEntry { name: OsString::from(&folder.dir_name), kind: FuseFileType::Directory });
+                }
+            },
+            Node::Folder(folder_index) => {
+                for session in &self.folders[folder_index].sessions {
+                    entries.push(DirectoryEntry { name: OsString::from(&session.dir_name), kind: FuseFileType::Directory });
+                }
+            },
+            Node::Session(folder_index, session_index) => {
+                let tree = self.tree(folder_index, session_index)?;
+
+                tree.list(&mut entries, "");
+            },
+            Node::Entry(folder_index, session_index, entry_path) => {
+                let tree = self.tree(folder_index, session_index)?;
+
+                if !tree.children.contains_key(&entry_path) {
+                    return Err(libc::ENOTDIR);
+                }
+
+                tree.list(&mut entries, &entry_path);
+            },
+        }
+
+        Ok(entries)
+    }
+
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32) -> ResultEmpty {
+        Ok(())
+    }
+}
+
+impl SessionTree {
+    fn new(contents: Vec<(SessionEntry, Vec<u8>, Vec<u64>)>) -> SessionTree {
+        let mut entries = HashMap::new();
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+
+        children.insert(String::new(), Vec::new());
+
+        for (entry, hmac_bag, block_sizes) in contents {
+            let path = entry.path.trim_right_matches('/').to_string();
+
+            if path.is_empty() || entries.contains_key(&path) {
+                continue;
+            }
+
+            if entry.entry_type == SessionEntryType::Directory {
+                children.entry(path.clone()).or_insert(Vec::new());
+            }
+
+            // add the entry to its parent, and any parents that aren't in the session to theirs
+            let mut child = path.clone();
+
+            loop {
+                let (parent, name) = match child.rfind('/') {
+                    Some(separator) => (child[..separator].to_string(), child[separator + 1..].to_string()),
+                    None => (String::new(), child.clone()),
+                };
+
+                let is_new_parent = !children.contains_key(&parent);
+
+                let siblings = children.entry(parent.clone()).or_insert(Vec::new());
+
+                if !siblings.contains(&name) {
+                    siblings.push(name);
+                }
+
+                if !is_new_parent || parent.is_empty() {
+                    break;
+                }
+
+                child = parent;
+            }
+
+            entries.insert(path, (entry, hmac_bag, block_sizes));
+        }
+
+        SessionTree {
+            entries: entries,
+            children: children,
+        }
+    }
+
+    /// the regular file a path refers to, following a hard link to the file it links to
+    fn file(&self, path: &str) -> Option<&(SessionEntry, Vec<u8>, Vec<u64>)> {
+        match self.entries.get(path) {
+            Some(&(ref entry, _, _)) if entry.entry_type == SessionEntryType::HardLink => {
+                let target = match entry.link_target {
+                    Some(ref target) => target.trim_right_matches('/'),
+                    None => return None,
+                };
+
+                match self.entries.get(target) {
+                    Some(file) if file.0.entry_type == SessionEntryType::File => Some(file),
+                    _ => None,
+                }
+            },
+            Some(file) if file.0.entry_type == SessionEntryType::File => Some(file),
+            _ => None,
+        }
+    }
+
+    fn list(&self, entries: &mut Vec<DirectoryEntry>, dir: &str) {
+        let names = match self.children.get(dir) {
+            Some(names) => names,
+            None => return,
+        };
+
+        for name in names {
+            let path = if dir.is_empty() { name.clone() } else { format!("{}/{}", dir, name) };
+
+            let kind = match self.entries.get(&path) {
+                Some(&(ref entry, _, _)) => match entry.entry_type {
+                    SessionEntryType::Directory => FuseFileType::Directory,
+                    SessionEntryType::Symlink => FuseFileType::Symlink,
+                    _ => FuseFileType::RegularFile,
+                },
+                None => FuseFileType::Directory,
+            };
+
+            entries.push(DirectoryEntry { name: OsString::from(name), kind: kind });
+        }
+    }
+}
+
+impl OpenFile {
+    fn read_at(&mut self, backend: &StorageBackend, main_key: &Key, offset: u64, size: u32) -> Result<Vec<u8>, SDError> {
+        let end = offset + size as u64;
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut position = 0;
+
+        for index in 0..self.hmacs.len() {
+            if position >= end {
+                break;
+            }
+
+            let block_size = if index < self.block_sizes.len() {
+                self.block_sizes[index]
+            } else {
+                let block = self.block(backend, main_key, index)?;
+
+                self.block_sizes.push(block.len() as u64);
+
+                block.len() as u64
+            };
+
+            let block_end = position + block_size;
+
+            if block_end > offset {
+                let block = self.block(backend, main_key, index)?;
+
+                let from = if offset > position { (offset - position) as usize } else { 0 };
+                let to = if end < block_end { (end - position) as usize } else { block_size as usize };
+
+                data.extend_from_slice(&block.as_ref()[from..to]);
+            }
+
+            position = block_end;
+        }
+
+        Ok(data)
+    }
+
+    fn block(&mut self, backend: &StorageBackend, main_key: &Key, index: usize) -> Result<Arc<Block>, SDError> {
+        if let Some(&(_, ref block)) = self.recent.iter().find(|&&(i, _)| i == index) {
+            return Ok(block.clone());
+        }
+
+        let block = Arc::new(::fetch::fetch_block(backend, main_key, &self.session_name, &self.hmacs[index])?);
+
+        if self.recent.len() >= RECENT_BLOCKS {
+            self.recent.remove(0);
+        }
+
+        self.recent.push((index, block.clone()));
+
+        Ok(block)
+    }
+}
+
+/// names can't contain a separator, and can't be empty
+fn safe_name(name: &str) -> String {
+    let name = name.replace('/', "-");
+
+    if name.is_empty() || name == "." || name == ".." {
+        return "folder".to_string();
+    }
+
+    name
+}
+
+/// the first of `name` or `name (suffix)` that hasn't been used yet
+fn unique_name<S: ::std::fmt::Display>(used: &mut HashSet<String>, name: String, suffix: S) -> String {
+    let name = if used.contains(&name) {
+        format!("{} ({})", name, suffix)
+    } else {
+        name
+    };
+
+    used.insert(name.clone());
+
+    name
+}
+
+#[test]
+fn session_tree_test() {
+    fn entry(path: &str, entry_type: SessionEntryType, link_target: Option<&str>) -> SessionEntry {
+        SessionEntry {
+            path: path.to_string(),
+            entry_type: entry_type,
+            size: if entry_type == SessionEntryType::File { Some(10) } else { None },
+            mode: 0o644,
+            mtime: 0,
+            link_target: link_target.map(|t| t.to_string()),
+            block_count: 0,
+        }
+    }
+
+    let hmacs = vec![1u8; HMAC_SIZE];
+
+    let tree = SessionTree::new(vec![
+        (entry("docs/", SessionEntryType::Directory, None), Vec::new(), Vec::new()),
+        (entry("docs/a.txt", SessionEntryType::File, None), hmacs.clone(), vec![10]),
+        (entry("docs/b.txt", SessionEntryType::HardLink, Some("docs/a.txt")), Vec::new(), Vec::new()),
+        // a parent directory that isn't in the session
+        (entry("music/albums/song.mp3", SessionEntryType::File, None), Vec::new(), Vec::new()),
+    ]);
+
+    assert!(tree.children.get("").unwrap() == &vec!["docs".to_string(), "music".to_string()]);
+    assert!(tree.children.get("docs").unwrap() == &vec!["a.txt".to_string(), "b.txt".to_string()]);
+    assert!(tree.children.get("music").unwrap() == &vec!["albums".to_string()]);
+    assert!(tree.children.get("music/albums").unwrap() == &vec!["song.mp3".to_string()]);
+
+    assert!(tree.file("docs/b.txt").unwrap().1 == hmacs);
+    assert!(tree.file("docs/b.txt").unwrap().2 == vec![10]);
+    assert!(tree.file("docs").is_none());
+
+    let mut used = HashSet::new();
+    assert!(unique_name(&mut used, safe_name("a/b"), 1) == "a-b");
+    assert!(unique_name(&mut used, safe_name("a/b"), 2) == "a-b (2)");
+}
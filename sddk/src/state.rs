@@ -1,7 +1,7 @@
 /// internal imports
 
 use keys::Key;
-use models::Token;
+use models::{SessionEntry, Token};
 
 #[derive(Debug)]
 pub struct State {
@@ -17,6 +17,7 @@ pub struct State {
     pub main_key: Option<Key>,
     pub hmac_key: Option<Key>,
     pub tweak_key: Option<Key>,
+    pub session_entries: Option<(u64, String, Vec<SessionEntry>)>,
 }
 
 impl State {
@@ -34,6 +35,7 @@ impl State {
             main_key: None,
             hmac_key: None,
             tweak_key: None,
+            session_entries: None,
         }
     }
 
@@ -179,4 +181,17 @@ impl State {
         };
         key
     }
+
+    /// entries of the session most recently listed, kept so each page doesn't download the
+    /// session again
+    pub fn get_session_entries(&self, folder_id: u64, session_name: &str) -> Option<&[SessionEntry]> {
+        match self.session_entries {
+            Some((id, ref name, ref entries)) if id == folder_id && name == session_name => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn set_session_entries(&mut self, folder_id: u64, session_name: &str, entries: Vec<SessionEntry>) {
+        self.session_entries = Some((folder_id, session_name.to_string(), entries));
+    }
 }
@@ -1,32 +1,41 @@
 use std::str;
+use std::collections::VecDeque;
+use std::fs::{File, Metadata};
+use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{thread, time};
 
 // external crate imports
 use tar::{Builder, Header};
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 // internal imports
 
 use models::*;
 use constants::*;
-use sdapi::*;
 use keys::*;
 use binformat::BinaryWriter;
 
-use core::get_sync_folder;
+use backend::{StorageBackend, find_folder};
 
 #[cfg(feature = "locking")]
 use lock::FolderLock;
 
 use error::{SDAPIError, SDError};
-use SYNC_VERSION;
 
-use session::{SyncSession, WrappedSyncSession};
+use session::{write_session_file, manifest_root};
 
 use sync_state::is_sync_task_cancelled;
 
-pub fn sync(token: &Token,
+use fileindex::FileIndex;
+use ignore::IgnoreMatcher;
+use journal::SyncJournal;
+use pipeline::BlockPipeline;
+use metadata::append_entry;
+
+pub fn sync(backend: Arc<StorageBackend>,
+            version: SyncVersion,
             session_name: &str,
             main_key: &Key,
             hmac_key: &Key,
@@ -35,7 +44,6 @@ pub fn sync(token: &Token,
 
     let (sync_status_send, sync_status_receive) = ::parking_lot_mpsc::sync_channel::<SyncStatus>(1000);
 
-    let token_local = token.to_owned();
     let session_name_local = session_name.to_owned();
     let main_key_local = main_key.to_owned();
     let hmac_key_local = hmac_key.to_owned();
@@ -43,9 +51,26 @@ pub fn sync(token: &Token,
 
 
     thread::spawn( move || {
-        debug!("creating version {} sync session", SYNC_VERSION);
+        debug!("creating version {} sync session", version);
+
+        // the archive of earlier versions is sealed whole, which needs all of it in memory
+        if !version.chunked_manifest() {
+            let status_message = SyncStatus::Err(SDError::Internal(format!("version {} sync sessions can no longer be created", version)));
+            match sync_status_send.send(status_message) {
+                Ok(()) => {
 
-        let folder = match get_sync_folder(&token_local, folder_id) {
+                },
+                Err(_) => {
+
+                },
+            }
+            return;
+        }
+
+        // blocks are versioned separately from the session
+        let block_version = version.block_version();
+
+        let folder = match find_folder(&*backend, folder_id) {
             Ok(folder) => folder,
             Err(e) => {
                 let status_message = SyncStatus::Err(SDError::from(e));
@@ -91,33 +116,136 @@ pub fn sync(token: &Token,
             flock.unlock();
         });
 
-        if let Err(e) = register_sync_session(&token_local, folder_id, &session_name_local, true) {
-            let status_message = SyncStatus::Err(SDError::from(e));
-            match sync_status_send.send(status_message) {
-                Ok(()) => {
+        let ignore = match IgnoreMatcher::for_folder(folder_id, &folder_path) {
+            Ok(ignore) => ignore,
+            Err(e) => {
+                let status_message = SyncStatus::Err(e);
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
 
-                },
-                Err(_) => {
+                    },
+                    Err(_) => {
 
-                },
+                    },
+                }
+                return;
             }
-            return;
+        };
+
+        let journal = match SyncJournal::open(folder_id, &session_name_local, &hmac_key_local, version) {
+            Ok(journal) => Arc::new(journal),
+            Err(e) => {
+                let status_message = SyncStatus::Err(e);
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            }
+        };
+
+        match backend.register_sync_session(folder_id, &session_name_local, true) {
+            Ok(()) => {},
+            Err(SDAPIError::Conflict) if journal.is_resumed() => {
+                debug!("session {} was registered by an earlier attempt, resuming it", session_name_local);
+            },
+            Err(e) => {
+                let status_message = SyncStatus::Err(SDError::from(e));
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            },
         }
 
-        let archive_file = Vec::new();
+        let previous_index = load_file_index(&*backend, folder_id, &hmac_key_local, block_version);
+        let mut file_index = FileIndex::new(folder_id, &hmac_key_local, block_version);
+
+        debug!("file index has {} entries", previous_index.len());
+
+        // the session archive is written to the journal as it is built, so an interrupted
+        // session can be resumed
+        let archive_file = match journal.archive() {
+            Ok(f) => f,
+            Err(e) => {
+                let status_message = SyncStatus::Err(e);
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            }
+        };
 
         debug!("creating session for: {} (folder id {})", folder_name, folder_id);
 
 
         let mut ar = Builder::new(archive_file);
 
+        // record the rules the session was created with, so it's clear later why something
+        // isn't in it
+        let rules = match ::serde_json::to_vec(ignore.rules()) {
+            Ok(rules) => rules,
+            Err(e) => {
+                let status_message = SyncStatus::Err(SDError::from(e));
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            },
+        };
+
+        match ::metadata::append_session_records(&mut ar, &[("ignore", rules.as_slice())]) {
+            Ok(()) => {},
+            Err(e) => {
+                let status_message = SyncStatus::Err(SDError::from(e));
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            },
+        };
+
         let mut processed_size: u64 = 0;
         let mut processed_size_compressed: u64 = 0;
         let mut processed_size_padding: u64 = 0;
 
         let mut estimated_size: u64 = 0;
 
-        for item in WalkDir::new(&folder_path).into_iter().filter_map(|e| e.ok()) {
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Scan });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+
+        for item in walk_folder(&folder_path, &ignore) {
             let item_path = item.path();
 
             let md = match ::std::fs::symlink_metadata(&item_path) {
@@ -147,12 +275,43 @@ pub fn sync(token: &Token,
 
         let write_cache: ::cache::WriteCache = ::cache::WriteCache::new(item_limit, size_limit);
 
-        let (block_send, status_receive) = write_cache.upload_thread(&token_local, &session_name_local, sync_status_send.clone());
+        let (block_send, status_receive) = write_cache.upload_thread(backend.clone(), &session_name_local, journal.clone(), sync_status_send.clone());
+
+        let mut pipeline = BlockPipeline::new(::core::get_sync_workers(), &main_key_local, &hmac_key_local, &tweak_key_local, block_version);
 
         let mut failed = 0;
 
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Upload });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+
+
+        let mut walk = walk_folder(&folder_path, &ignore);
+        let mut ahead: VecDeque<SyncItem> = VecDeque::new();
+
+        loop {
+            // look at the items coming up and queue the files that need new blocks, so the
+            // pipeline isn't left waiting at the end of every file when they're small
+            while ahead.len() < pipeline.capacity() {
+                let entry = match walk.next() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+
+                ahead.push_back(SyncItem::new(entry, &folder_path, &previous_index, &journal, &mut pipeline));
+            }
+
+            let item = match ahead.pop_front() {
+                Some(item) => item,
+                None => break,
+            };
 
-        for item in WalkDir::new(&folder_path).into_iter().filter_map(|e| e.ok()) {
             if is_sync_task_cancelled(session_name_local.clone()) {
                 let cache_message = ::cache::WriteCacheMessage::new(None, true, None);
 
@@ -184,7 +343,7 @@ pub fn sync(token: &Token,
                 return;
             }
 
-            trace!("examining {}", item.path().display());
+            trace!("examining {}", item.entry.path().display());
 
             // call out to the library user with progress
             let status_message = SyncStatus::Progress(estimated_size, processed_size, 0);
@@ -197,15 +356,29 @@ pub fn sync(token: &Token,
                 },
             }
 
-            let full_path = item.path();
+            let full_path = item.entry.path();
             if &full_path == &folder_path {
                 continue; // don't bother doing anything more for the root directory of the folder
             }
             let relative_path = full_path.strip_prefix(&folder_path).expect("failed to unwrap relative path");
 
-            let md = match ::std::fs::symlink_metadata(&full_path) {
+            let md = match item.md {
                 Ok(m) => m,
-                Err(_) => {
+                Err(e) => {
+                    let status_message = SyncStatus::Event(SyncEvent::FileSkipped {
+                        path: relative_path.to_string_lossy().into_owned(),
+                        reason: SkipReason::from(&e),
+                        message: e.to_string(),
+                    });
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+
                     failed = failed + 1;
                     continue;
                 },
@@ -220,24 +393,95 @@ pub fn sync(token: &Token,
             let mut header = Header::new_gnu();
             header.set_metadata(&md);
 
+            if is_file {
+                ::metadata::set_real_size(&mut header, stream_length);
+            }
+
+            // extended attributes (and ACLs, where the platform stores them as attributes) are
+            // kept in PAX records in front of the entry
+            let mut pax_records = ::metadata::pax_records(&full_path);
+
             let mut hmac_bag: Vec<u8> = Vec::new();
 
             // chunk file if not a directory or socket
             if is_file {
-                if stream_length > 0 {
+                let status_message = SyncStatus::Event(SyncEvent::FileStarted {
+                    path: relative_path.to_string_lossy().into_owned(),
+                    size: stream_length,
+                });
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
 
-                    let mut block_generator = ::chunk::BlockGenerator::new(&full_path,
-                                                                           &main_key_local,
-                                                                           &hmac_key_local,
-                                                                           &tweak_key_local,
-                                                                           stream_length,
-                                                                           SYNC_VERSION);
+                if let Some((previous_hmac_bag, previous_block_sizes)) = item.unchanged {
+                    // the file hasn't changed since the last session, or since an earlier attempt
+                    // at this one, so the blocks it needs are already in storage and the previous
+                    // hmac list can be used as-is
+                    trace!("reusing {} hmacs for unchanged file {}", previous_hmac_bag.len() / HMAC_SIZE, full_path.display());
+
+                    file_index.insert(&relative_path, &md, &previous_hmac_bag, &previous_block_sizes);
+
+                    processed_size += stream_length;
+
+                    let status_message = SyncStatus::Progress(estimated_size, processed_size, stream_length);
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+
+                    header.set_size(previous_hmac_bag.len() as u64); // hmac list size
+                    header.set_cksum();
+
+                    // sessions from before block sizes were recorded won't have them to pass on
+                    ::metadata::append_block_sizes(&mut pax_records, &previous_block_sizes);
+
+                    match append_entry(&mut ar, &mut header, &relative_path, previous_hmac_bag.as_slice(), &pax_records) {
+                        Ok(()) => {},
+                        Err(e) => {
+                            let status_message = SyncStatus::Err(SDError::from(e));
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+                            return;
+                        },
+                    };
+
+                    let status_message = SyncStatus::Event(SyncEvent::FileFinished {
+                        path: relative_path.to_string_lossy().into_owned(),
+                        size: stream_length,
+                    });
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
 
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+                } else if let Some(file) = item.file {
                     let mut item_padding: u64 = 0;
 
+                    let mut block_sizes: Vec<u64> = Vec::new();
+
                     let mut block_failed = false;
 
-                    for block_result in block_generator.by_ref() {
+                    let mut blocks = pipeline.blocks(file);
+
+                    for block_result in &mut blocks {
                         if is_sync_task_cancelled(session_name_local.clone()) {
                             let cache_message = ::cache::WriteCacheMessage::new(None, true, None);
 
@@ -310,16 +554,28 @@ pub fn sync(token: &Token,
 
                         let block = match block_result {
                             Ok(b) => b,
+                            Err(SDError::CryptoError(e)) => {
+                                let status_message = SyncStatus::Err(SDError::CryptoError(e));
+                                match sync_status_send.send(status_message) {
+                                    Ok(()) => {
+
+                                    },
+                                    Err(_) => {
+
+                                    },
+                                }
+                                return;
+                            },
                             Err(_) => {
                                 block_failed = true;
                                 break;
                             },
                         };
 
-                        let block_real_size = block.real_size();
-                        let compressed = block.compressed();
+                        let block_real_size = block.real_size;
+                        let compressed = block.compressed_size.is_some();
 
-                        let block_compressed_size = match block.compressed_size() {
+                        let block_compressed_size = match block.compressed_size {
                             Some(size) => {
                                 processed_size_compressed += size;
 
@@ -332,24 +588,10 @@ pub fn sync(token: &Token,
                             },
                         };
 
-                        hmac_bag.extend_from_slice(&block.get_hmac());
-
-                        let wrapped_block = match block.to_wrapped(&main_key_local) {
-                            Ok(wb) => wb,
-                            Err(e) => {
-                                let status_message = SyncStatus::Err(SDError::CryptoError(Box::new(e)));
-                                match sync_status_send.send(status_message) {
-                                    Ok(()) => {
+                        hmac_bag.extend_from_slice(&block.hmac);
+                        block_sizes.push(block_real_size);
 
-                                    },
-                                    Err(_) => {
-
-                                    },
-                                }
-                                return;
-                            }
-
-                        };
+                        let wrapped_block = block.wrapped;
                         let block_padded_size = wrapped_block.len() as u64;
 
                         let padding_overhead = if compressed {
@@ -372,6 +614,26 @@ pub fn sync(token: &Token,
                             },
                         }
 
+                        // an earlier attempt at this session already stored the block
+                        if journal.is_acknowledged(&wrapped_block.name()) {
+                            trace!("skipping block already stored for this session: {}", wrapped_block.name());
+
+                            let status_message = SyncStatus::Event(SyncEvent::BlockDeduplicated {
+                                name: wrapped_block.name(),
+                                size: block_padded_size,
+                            });
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+
+                            continue;
+                        }
+
                         let cache_message = ::cache::WriteCacheMessage::new(Some(wrapped_block), false, None);
 
                         match block_send.send(cache_message) {
@@ -405,6 +667,20 @@ pub fn sync(token: &Token,
                             },
                         }
 
+                        let status_message = SyncStatus::Event(SyncEvent::FileSkipped {
+                            path: relative_path.to_string_lossy().into_owned(),
+                            reason: SkipReason::ReadFailed,
+                            message: "could not read from file".to_string(),
+                        });
+                        match sync_status_send.send(status_message) {
+                            Ok(()) => {
+
+                            },
+                            Err(_) => {
+
+                            },
+                        }
+
                         failed = failed +1;
                         continue;
                     }
@@ -412,7 +688,7 @@ pub fn sync(token: &Token,
                     processed_size_padding += item_padding;
 
 
-                    let stats = block_generator.stats();
+                    let stats = blocks.stats().expect("block pipeline finished a file without its stats");
                     if DEBUG_STATISTICS {
                         let compression_ratio = (stats.processed_size_compressed as f64 / stats.processed_size as f64 ) * 100.0;
 
@@ -434,23 +710,95 @@ pub fn sync(token: &Token,
                     assert!(stats.processed_size == stream_length);
                     trace!("calculated {} real bytes of blocks, matching stream size {}", stats.processed_size, stream_length);
 
+                    file_index.insert(&relative_path, &md, &hmac_bag, &block_sizes);
+
                     header.set_size(stats.discovered_chunk_count * HMAC_SIZE as u64); // hmac list size
                     header.set_cksum();
 
-                    ar.append_data(&mut header, &relative_path, hmac_bag.as_slice()).expect("failed to append session entry header");
+                    ::metadata::append_block_sizes(&mut pax_records, &block_sizes);
+
+                    match append_entry(&mut ar, &mut header, &relative_path, hmac_bag.as_slice(), &pax_records) {
+                        Ok(()) => {},
+                        Err(e) => {
+                            let status_message = SyncStatus::Err(SDError::from(e));
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+                            return;
+                        },
+                    };
+
+                    let status_message = SyncStatus::Event(SyncEvent::FileFinished {
+                        path: relative_path.to_string_lossy().into_owned(),
+                        size: stream_length,
+                    });
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
 
                 } else {
                     header.set_size(0); // hmac list size is zero when file has no actual data
                     header.set_cksum();
 
-                    ar.append_data(&mut header, &relative_path, hmac_bag.as_slice()).expect("failed to append zero length archive header");
+                    match append_entry(&mut ar, &mut header, &relative_path, hmac_bag.as_slice(), &pax_records) {
+                        Ok(()) => {},
+                        Err(e) => {
+                            let status_message = SyncStatus::Err(SDError::from(e));
+                            match sync_status_send.send(status_message) {
+                                Ok(()) => {
+
+                                },
+                                Err(_) => {
+
+                                },
+                            }
+                            return;
+                        },
+                    };
+
+                    let status_message = SyncStatus::Event(SyncEvent::FileFinished {
+                        path: relative_path.to_string_lossy().into_owned(),
+                        size: stream_length,
+                    });
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
                 }
             } else if is_dir {
                 // folder
                 header.set_size(0); // hmac list size is zero when file has no actual data
                 header.set_cksum();
 
-                ar.append_data(&mut header, &relative_path, hmac_bag.as_slice()).expect("failed to append folder to archive header");
+                match append_entry(&mut ar, &mut header, &relative_path, hmac_bag.as_slice(), &pax_records) {
+                    Ok(()) => {},
+                    Err(e) => {
+                        let status_message = SyncStatus::Err(SDError::from(e));
+                        match sync_status_send.send(status_message) {
+                            Ok(()) => {
+
+                            },
+                            Err(_) => {
+
+                            },
+                        }
+                        return;
+                    },
+                };
             } else if is_symlink {
                 // symlink
 
@@ -490,10 +838,122 @@ pub fn sync(token: &Token,
                 header.set_size(0); // hmac list size is zero when file has no actual data
                 header.set_cksum();
 
-                ar.append_data(&mut header, &relative_path, hmac_bag.as_slice()).expect("failed to append symlink to archive header");
+                match append_entry(&mut ar, &mut header, &relative_path, hmac_bag.as_slice(), &pax_records) {
+                    Ok(()) => {},
+                    Err(e) => {
+                        let status_message = SyncStatus::Err(SDError::from(e));
+                        match sync_status_send.send(status_message) {
+                            Ok(()) => {
+
+                            },
+                            Err(_) => {
+
+                            },
+                        }
+                        return;
+                    },
+                };
+            }
+        }
+
+        let status_message = SyncStatus::Event(SyncEvent::PhaseChanged { phase: SyncPhase::Finalize });
+        match sync_status_send.send(status_message) {
+            Ok(()) => {
+
+            },
+            Err(_) => {
+
+            },
+        }
+
+        let archive = match finish_archive(ar) {
+            Ok(archive) => archive,
+            Err(e) => {
+                let status_message = SyncStatus::Err(e);
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            }
+        };
+
+        // version 3 sessions store the archive as blocks too, so the parts of it that haven't
+        // changed since the last sync are deduplicated the same way file data is
+        let mut manifest: Vec<u8> = Vec::new();
+
+        let archive_size = match archive.metadata() {
+            Ok(md) => md.len(),
+            Err(e) => {
+                let status_message = SyncStatus::Err(SDError::from(e));
+                match sync_status_send.send(status_message) {
+                    Ok(()) => {
+
+                    },
+                    Err(_) => {
+
+                    },
+                }
+                return;
+            }
+        };
+
+        let manifest_file = pipeline.queue(&journal.archive_path(), archive_size);
+
+        for block_result in pipeline.blocks(manifest_file) {
+            let block = match block_result {
+                Ok(b) => b,
+                Err(e) => {
+                    let status_message = SyncStatus::Err(e);
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+                    return;
+                },
+            };
+
+            manifest.extend_from_slice(&block.hmac);
+
+            let wrapped_block = block.wrapped;
+
+            if journal.is_acknowledged(&wrapped_block.name()) {
+                trace!("skipping manifest block already stored for this session: {}", wrapped_block.name());
+
+                continue;
+            }
+
+            let cache_message = ::cache::WriteCacheMessage::new(Some(wrapped_block), false, None);
+
+            match block_send.send(cache_message) {
+                Ok(()) => {
+
+                },
+                Err(e) => {
+                    let status_message = SyncStatus::Err(SDError::Internal(format!("not able to write session manifest: {}", e)));
+                    match sync_status_send.send(status_message) {
+                        Ok(()) => {
+
+                        },
+                        Err(_) => {
+
+                        },
+                    }
+                    return;
+                },
             }
         }
 
+        debug!("session archive stored in {} manifest blocks", manifest.len() / HMAC_SIZE);
+
         debug!("signaling write cache we're finished");
 
         let cache_message = ::cache::WriteCacheMessage::new(None, false, None);
@@ -560,46 +1020,13 @@ pub fn sync(token: &Token,
 
         debug!("processing session and statistics");
 
-        // since we're writing to a buffer in memory there shouldn't be any errors here...
-        // unless the system is also completely out of memory, but there's nothing we can do about that,
-        // so if it proves to be an issue we'll have to look for anything else that might use a lot of
-        // memory and use temp files instead, where possible
-        let raw_session = ar.into_inner().unwrap();
-
-
-        let session = SyncSession::new(SYNC_VERSION,
-                                       folder_id,
-                                       session_name_local.clone(),
-                                       Some(processed_size),
-                                       None,
-                                       raw_session);
-
+        // the session itself only holds the list of blocks the archive was stored in
+        let root = manifest_root(archive_size, &manifest);
 
-        let compression_ratio = (processed_size_compressed as f64 / session.size.unwrap() as f64) * 100.0;
-        debug!("session data total: {}", session.size.unwrap());
-        debug!("session data compressed: {}", processed_size_compressed);
-        debug!("session data compression ratio: {}", compression_ratio);
-        let padding_ratio = (processed_size_padding as f64 / processed_size_compressed as f64 ) * 100.0;
-
-        debug!("session data padding overhead: {} ({}%)", processed_size_padding, padding_ratio);
-
-        debug!("session file total: {}", session.real_size());
-        match session.compressed_size() {
-            Some(size) => {
-                let compression_ratio = (size as f64 / session.real_size() as f64) * 100.0;
-                estimated_size += size;
-
-                debug!("session file total compressed: {}", size);
-                debug!("session file compression ratio: {}", compression_ratio);
-            },
-            None => {},
-        }
-
-
-        let wrapped_session = match session.to_wrapped(&main_key_local) {
-            Ok(ws) => ws,
+        let session = match write_session_file(version, folder_id, &session_name_local, &main_key_local, root.as_slice()) {
+            Ok(session) => session,
             Err(e) => {
-                let status_message = SyncStatus::Err(SDError::CryptoError(Box::new(e)));
+                let status_message = SyncStatus::Err(e);
                 match sync_status_send.send(status_message) {
                     Ok(()) => {
 
@@ -610,12 +1037,24 @@ pub fn sync(token: &Token,
                 }
                 return;
             }
-
         };
 
-        let mut s: Vec<WrappedSyncSession> = Vec::new();
 
-        s.push(wrapped_session);
+        let compression_ratio = (processed_size_compressed as f64 / processed_size as f64) * 100.0;
+        debug!("session data total: {}", processed_size);
+        debug!("session data compressed: {}", processed_size_compressed);
+        debug!("session data compression ratio: {}", compression_ratio);
+        let padding_ratio = (processed_size_padding as f64 / processed_size_compressed as f64 ) * 100.0;
+
+        debug!("session data padding overhead: {} ({}%)", processed_size_padding, padding_ratio);
+
+        debug!("session file total: {}", session.real_size());
+
+        let compression_ratio = (session.compressed_size() as f64 / session.real_size() as f64) * 100.0;
+        estimated_size += session.compressed_size();
+
+        debug!("session file total compressed: {}", session.compressed_size());
+        debug!("session file compression ratio: {}", compression_ratio);
 
         debug!("finishing sync session");
 
@@ -632,7 +1071,7 @@ pub fn sync(token: &Token,
 
         let l_sync_status_send = sync_status_send.clone();
 
-        match finish_sync_session(&token_local, folder_id, true, &s, processed_size as usize, Box::new(move |speed| {
+        match backend.finish_sync_session(folder_id, true, &session, processed_size as usize, Box::new(move |speed| {
             debug!("session upload speed: {}", speed);
             let status_message = ::models::SyncStatus::Bandwidth(speed);
             match l_sync_status_send.send(status_message) {
@@ -682,6 +1121,26 @@ pub fn sync(token: &Token,
             },
         };
 
+        if let Err(e) = journal.remove() {
+            debug!("not able to remove sync journal: {}", e);
+        }
+
+        // only save the index once the session is stored, otherwise the next sync could reuse
+        // hmacs for blocks that never made it to storage
+        file_index.set_session_name(&session_name_local);
+
+        if let Err(e) = file_index.save() {
+            let status_message = SyncStatus::Issue(format!("not able to save file index, next sync will read all files again: {}", e));
+            match sync_status_send.send(status_message) {
+                Ok(()) => {
+
+                },
+                Err(_) => {
+
+                },
+            }
+        }
+
         let status_message = SyncStatus::Progress(estimated_size, processed_size, 0);
         match sync_status_send.send(status_message) {
             Ok(()) => {
@@ -694,4 +1153,370 @@ pub fn sync(token: &Token,
     });
 
     sync_status_receive
-}
\ No newline at end of file
+}
+
+/// finish the session archive in the journal and rewind it, ready to be read back
+fn finish_archive(ar: Builder<File>) -> Result<File, SDError> {
+    let mut f = ar.into_inner()?;
+
+    f.seek(SeekFrom::Start(0))?;
+
+    Ok(f)
+}
+
+/// load the file index for a folder, but only if the session it was saved for still exists
+///
+/// Blocks are only guaranteed to be kept in storage while a session refers to them, so an index
+/// saved for a session that has since been removed can't be trusted to name blocks that exist.
+fn load_file_index(backend: &StorageBackend, folder_id: u64, hmac_key: &Key, version: SyncVersion) -> FileIndex {
+    let index = FileIndex::load(folder_id, hmac_key, version);
+
+    let index_session = match index.session_name() {
+        Some(name) => name.to_string(),
+        None => return FileIndex::new(folder_id, hmac_key, version),
+    };
+
+    let sessions = match backend.read_sessions() {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            debug!("not able to check sessions for file index, ignoring it: {}", e);
+
+            return FileIndex::new(folder_id, hmac_key, version);
+        },
+    };
+
+    let found = match sessions.get("sessionDetails").and_then(|s| s.get(&folder_id)) {
+        Some(folder_sessions) => folder_sessions.iter().any(|session| session.name == index_session),
+        None => false,
+    };
+
+    if !found {
+        debug!("session {} for file index no longer exists, ignoring it", index_session);
+
+        return FileIndex::new(folder_id, hmac_key, version);
+    }
+
+    index
+}
+
+// walk a folder, leaving out whatever the ignore rules match, and everything inside ignored
+// directories
+/// An item from the folder walk, looked at before its turn to be added to the session so a file
+/// that needs new blocks can be queued in the block pipeline early
+struct SyncItem {
+    entry: DirEntry,
+    md: ::std::io::Result<Metadata>,
+    /// the hmac list and block sizes of a file that hasn't changed since the last session, or
+    /// since an earlier attempt at this one
+    unchanged: Option<(Vec<u8>, Vec<u64>)>,
+    /// the pipeline's number for a file that is being chunked
+    file: Option<u64>,
+}
+
+impl SyncItem {
+    fn new(entry: DirEntry, folder_path: &Path, previous_index: &FileIndex, journal: &SyncJournal, pipeline: &mut BlockPipeline) -> SyncItem {
+        let md = ::std::fs::symlink_metadata(entry.path());
+
+        let mut unchanged = None;
+        let mut file = None;
+
+        if let Ok(ref md) = md {
+            if md.file_type().is_file() && md.len() > 0 {
+                if let Ok(relative_path) = entry.path().strip_prefix(folder_path) {
+                    unchanged = previous_index.lookup(relative_path, md).or_else(|| journal.lookup(relative_path, md));
+                }
+
+                if unchanged.is_none() {
+                    file = Some(pipeline.queue(entry.path(), md.len()));
+                }
+            }
+        }
+
+        SyncItem {
+            entry: entry,
+            md: md,
+            unchanged: unchanged,
+            file: file,
+        }
+    }
+}
+
+fn walk_folder<'a>(folder_path: &'a Path, ignore: &'a IgnoreMatcher) -> Box<Iterator<Item=DirEntry> + 'a> {
+    let walk = WalkDir::new(folder_path).into_iter().filter_entry(move |entry| {
+        let relative_path = match entry.path().strip_prefix(folder_path) {
+            Ok(p) => p,
+            Err(_) => return true,
+        };
+
+        match ::std::fs::symlink_metadata(entry.path()) {
+            Ok(md) => {
+                let ignored = ignore.is_ignored(relative_path, &md);
+
+                if ignored {
+                    trace!("ignoring {}", relative_path.display());
+                }
+
+                !ignored
+            },
+            // reported when the item is synced
+            Err(_) => true,
+        }
+    });
+
+    Box::new(walk.filter_map(|e| e.ok()))
+}
+
+#[test]
+fn sync_events_test() {
+    use rustc_serialize::hex::ToHex;
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    // not repeating, so no block is used twice in the first session
+    let mut seed: u32 = 2463534242;
+    let data: Vec<u8> = (0..800_000).map(|_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as u8
+    }).collect();
+
+    folder.write("first.bin", &data);
+
+    let (server, token, folder_id) = folder.serve();
+
+    let first_session = ::util::generate_uuid();
+    let mut events: Vec<SyncEvent> = Vec::new();
+    ::core::sync(&token, &first_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |event| { events.push(event.clone()); }).expect("sync failed");
+
+    assert!(events[0] == SyncEvent::PhaseChanged { phase: SyncPhase::Scan });
+    assert!(events.contains(&SyncEvent::PhaseChanged { phase: SyncPhase::Upload }));
+    assert!(events.contains(&SyncEvent::PhaseChanged { phase: SyncPhase::Finalize }));
+    assert!(events.contains(&SyncEvent::FileStarted { path: "first.bin".to_string(), size: data.len() as u64 }));
+    assert!(events.contains(&SyncEvent::FileFinished { path: "first.bin".to_string(), size: data.len() as u64 }));
+
+    let summary = match events.last() {
+        Some(&SyncEvent::Summary(ref summary)) => summary.clone(),
+        _ => panic!("sync didn't finish with a summary"),
+    };
+
+    assert!(summary.files == 1);
+    assert!(summary.size == data.len() as u64);
+    assert!(summary.skipped == 0);
+    assert!(summary.blocks_uploaded > 0);
+    assert!(summary.blocks_uploaded == server.block_count() as u64);
+    assert!(summary.blocks_deduplicated == 0);
+
+    // a copy has the same blocks, so none of them are sent again
+    ::std::fs::copy(folder.source.join("first.bin"), folder.source.join("second.bin")).unwrap();
+
+    let block_count = server.block_count();
+
+    let second_session = ::util::generate_uuid();
+    let mut events: Vec<SyncEvent> = Vec::new();
+    ::core::sync(&token, &second_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |event| { events.push(event.clone()); }).expect("sync failed");
+
+    let summary = match events.last() {
+        Some(&SyncEvent::Summary(ref summary)) => summary.clone(),
+        _ => panic!("sync didn't finish with a summary"),
+    };
+
+    assert!(summary.files == 2);
+    assert!(summary.blocks_uploaded == (server.block_count() - block_count) as u64);
+    assert!(summary.blocks_deduplicated > 0);
+
+    // the only new blocks hold the new session archive
+    let backend = ::backend::SafeDriveBackend::new(&token);
+    let session = ::browse::read_session(&backend, folder_id, &second_session, &folder.main).expect("failed to read session");
+    let manifest: Vec<String> = session.manifest_hmacs().iter().map(|hmac| hmac.to_hex()).collect();
+
+    for event in &events {
+        if let SyncEvent::BlockUploaded { ref name, .. } = *event {
+            assert!(manifest.contains(name));
+        }
+    }
+
+    let mut events: Vec<SyncEvent> = Vec::new();
+    ::core::restore(&token, &second_session, &folder.main, folder_id, folder.destination(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |event| { events.push(event.clone()); }).expect("restore failed");
+
+    assert!(events[0] == SyncEvent::PhaseChanged { phase: SyncPhase::Scan });
+    assert!(events.contains(&SyncEvent::PhaseChanged { phase: SyncPhase::Download }));
+    assert!(events.contains(&SyncEvent::FileFinished { path: "second.bin".to_string(), size: data.len() as u64 }));
+
+    match events.last() {
+        Some(&SyncEvent::Summary(ref summary)) => {
+            assert!(summary.files == 2);
+            assert!(summary.size == 2 * data.len() as u64);
+        },
+        _ => panic!("restore didn't finish with a summary"),
+    }
+}
+
+#[test]
+fn incremental_sync_test() {
+    use std::io::Read;
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 241) as u8).collect();
+    folder.write("unchanged.bin", &data);
+    folder.write("changed.txt", b"first");
+
+    let (server, token, folder_id) = folder.serve();
+
+    let first_session = ::util::generate_uuid();
+    ::core::sync(&token, &first_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    folder.write("changed.txt", b"second");
+
+    // the second sync must still produce a complete session even though only one file is read
+    let second_session = ::util::generate_uuid();
+    ::core::sync(&token, &second_session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    assert!(server.session_count() == 2);
+
+    // block sizes are recorded for files that were read, and carried over for files that weren't
+    let backend = ::backend::SafeDriveBackend::new(&token);
+
+    for session_name in &[&first_session, &second_session] {
+        let session = ::browse::read_session(&backend, folder_id, session_name, &folder.main).expect("failed to read session");
+        let contents = ::browse::session_contents_with_block_sizes(&session).expect("failed to list session");
+
+        let &(_, ref hmac_bag, ref block_sizes) = contents.iter().find(|&&(ref entry, _, _)| entry.path == "unchanged.bin").expect("file missing from session");
+        assert!(block_sizes.len() * HMAC_SIZE == hmac_bag.len());
+        assert!(block_sizes.iter().sum::<u64>() == data.len() as u64);
+    }
+
+    let destination = folder.destination();
+
+    ::core::restore(&token, &second_session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    let mut restored = Vec::new();
+    File::open(destination.join("unchanged.bin")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == data);
+
+    let mut changed = String::new();
+    File::open(destination.join("changed.txt")).unwrap().read_to_string(&mut changed).unwrap();
+    assert!(changed == "second");
+}
+
+#[test]
+fn version3_sync_test() {
+    use std::io::Read;
+    use mockapi::TestFolder;
+    use session::WrappedSyncSession;
+
+    let folder = TestFolder::new();
+
+    folder.write("first", b"first");
+    folder.write("second", b"second");
+
+    let (repository, folder_id) = folder.local();
+
+    let session = ::util::generate_uuid();
+    ::core::sync_with_backend(repository.clone(), &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    // the archive is stored as blocks alongside the blocks for the files
+    let s = ::browse::read_session(&*repository, folder_id, &session, &folder.main).expect("failed to read session");
+    assert!(s.version().chunked_manifest());
+
+    let manifest_blocks = s.manifest_hmacs().len();
+
+    // the stored session can be read whole too
+    let body = repository.read_session(folder_id, &session, true).expect("failed to read session");
+    let whole = WrappedSyncSession::from(body).expect("failed to read session").to_session(&folder.main).expect("failed to unwrap session");
+    assert!(whole.version().chunked_manifest());
+    assert!(manifest_blocks > 0);
+    assert!(repository.list_blocks().unwrap().unwrap().len() == 2 + manifest_blocks);
+
+    // the blocks are version 2 blocks, so they can be shared with sessions older clients read
+    for block in repository.list_blocks().unwrap().unwrap() {
+        let raw = repository.read_block(&block.name).expect("failed to read block");
+        assert!(&raw[3..5] == b"02");
+    }
+
+    let destination = folder.destination();
+
+    ::core::restore_with_backend(repository.clone(), &session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    for &(name, contents) in &[("first", "first"), ("second", "second")] {
+        let mut restored = String::new();
+        File::open(destination.join(name)).unwrap().read_to_string(&mut restored).unwrap();
+        assert!(restored == contents);
+    }
+
+    let now = ::chrono::Utc::now();
+    let now_ms = ::util::timestamp_to_ms(now.timestamp(), now.timestamp_subsec_millis()) as u64;
+    let later = now_ms + 2 * ::gc::GC_GRACE_PERIOD_MS;
+
+    // the blocks holding the archive are live as long as the session is
+    let report = ::gc::collect_garbage(&*repository, &folder.main, false, later).expect("gc failed");
+    assert!(report.sessions == 1);
+    assert!(report.live_blocks == 2 + manifest_blocks as u64);
+    assert!(report.storage.unwrap().blocks == 0);
+
+    let _ = ::std::fs::remove_dir_all(&destination);
+
+    ::core::restore_with_backend(repository.clone(), &session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore after gc failed");
+
+    let sessions = repository.read_sessions().expect("failed to read sessions");
+    let id = sessions["sessionDetails"][&folder_id].iter().find(|s| s.name == session).unwrap().id.unwrap();
+    repository.delete_session(id).expect("failed to delete session");
+
+    let report = ::gc::collect_garbage(&*repository, &folder.main, false, later).expect("gc failed");
+    assert!(report.storage.unwrap().blocks == 2 + manifest_blocks as u64);
+    assert!(repository.list_blocks().unwrap().unwrap().is_empty());
+}
+
+#[test]
+fn resume_sync_test() {
+    use std::io::Read;
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 239) as u8).collect();
+    folder.write("large.bin", &data);
+    folder.write("small.txt", b"small");
+
+    let earlier = ::filetime::FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    ::filetime::set_file_times(folder.source.join("large.bin"), earlier, earlier).unwrap();
+    ::filetime::set_file_times(folder.source.join("small.txt"), earlier, earlier).unwrap();
+
+    let (server, token, folder_id) = folder.serve();
+
+    assert!(::core::interrupted_sync_session(folder_id) == None);
+
+    // every block is stored, but the session itself never makes it
+    server.reject_sessions(true);
+
+    let session = ::util::generate_uuid();
+    assert!(::core::sync(&token, &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).is_err());
+    assert!(server.session_count() == 0);
+    assert!(::core::interrupted_sync_session(folder_id) == Some(session.clone()));
+
+    server.reject_sessions(false);
+
+    let block_writes = server.block_write_count();
+
+    ::core::sync(&token, &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("resumed sync failed");
+
+    // nothing was sent again, and the session was finished under its original name
+    assert!(server.block_write_count() == block_writes);
+    assert!(server.session_count() == 1);
+    assert!(::core::interrupted_sync_session(folder_id) == None);
+
+    let destination = folder.destination();
+
+    ::core::restore(&token, &session, &folder.main, folder_id, destination.clone(), 0, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("restore failed");
+
+    let mut restored = Vec::new();
+    File::open(destination.join("large.bin")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == data);
+
+    let mut small = String::new();
+    File::open(destination.join("small.txt")).unwrap().read_to_string(&mut small).unwrap();
+    assert!(small == "small");
+}
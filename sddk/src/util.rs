@@ -60,6 +60,7 @@ pub fn get_app_directory(config: &Configuration) -> Result<PathBuf, String> {
     match config {
         &Configuration::Staging => storage_path.push("staging"),
         &Configuration::Production => {},
+        &Configuration::Custom { .. } => storage_path.push("custom"),
     }
     match fs::create_dir_all(&storage_path) {
         Ok(()) => {},
@@ -97,6 +98,7 @@ pub fn get_app_directory(config: &Configuration) -> Result<PathBuf, String> {
     match config {
         &Configuration::Staging => storage_path.push("staging"),
         &Configuration::Production => {},
+        &Configuration::Custom { .. } => storage_path.push("custom"),
     }
     match fs::create_dir_all(&storage_path) {
         Ok(()) => {},
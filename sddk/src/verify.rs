@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+// external crate imports
+
+use rustc_serialize::hex::ToHex;
+
+// internal imports
+
+use backend::StorageBackend;
+use block::WrappedBlock;
+use constants::HMAC_SIZE;
+use error::{SDAPIError, SDError};
+use keys::Key;
+use models::{BrokenFile, SessionEntryType, SessionVerification};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum BlockState {
+    Present,
+    Missing,
+    Corrupt,
+}
+
+/// check that every file in a sync session can be restored
+///
+/// The shallow check asks storage whether each block the session uses exists. With `deep` set,
+/// each block is downloaded instead, decrypted with `main_key`, and its hmac recalculated with
+/// `hmac_key` to confirm that it matches the block name.
+///
+/// Blocks used by several files are only checked once. Broken files are reported by path in the
+/// order they appear in the session. A session that can't be read at all is an error rather than
+/// a report, since none of its files can be restored.
+pub fn verify_session(backend: &StorageBackend,
+                      folder_id: u64,
+                      session_name: &str,
+                      main_key: &Key,
+                      hmac_key: &Key,
+                      deep: bool) -> Result<SessionVerification, SDError> {
+    let session = ::browse::read_session(backend, folder_id, session_name, main_key)?;
+    let contents = ::browse::session_contents(&session)?;
+
+    let mut checked: HashMap<Vec<u8>, BlockState> = HashMap::new();
+
+    let mut files: u64 = 0;
+    let mut broken = Vec::new();
+
+    for (entry, hmac_bag) in contents {
+        if entry.entry_type != SessionEntryType::File {
+            continue;
+        }
+
+        files += 1;
+
+        let mut missing_blocks: u64 = 0;
+        let mut corrupt_blocks: u64 = 0;
+
+        for hmac in hmac_bag.chunks(HMAC_SIZE) {
+            let state = match checked.get(hmac) {
+                Some(state) => *state,
+                None => check_block(backend, hmac, main_key, hmac_key, deep)?,
+            };
+
+            checked.insert(hmac.to_vec(), state);
+
+            match state {
+                BlockState::Present => {},
+                BlockState::Missing => missing_blocks += 1,
+                BlockState::Corrupt => corrupt_blocks += 1,
+            }
+        }
+
+        if missing_blocks > 0 || corrupt_blocks > 0 {
+            debug!("{} is broken: {} blocks missing, {} corrupt", entry.path, missing_blocks, corrupt_blocks);
+
+            broken.push(BrokenFile {
+                path: entry.path,
+                missing_blocks: missing_blocks,
+                corrupt_blocks: corrupt_blocks,
+            });
+        }
+    }
+
+    Ok(SessionVerification {
+        folder_id: folder_id,
+        session_name: session_name.to_string(),
+        deep: deep,
+        files: files,
+        blocks: checked.len() as u64,
+        broken: broken,
+    })
+}
+
+fn check_block(backend: &StorageBackend, hmac: &[u8], main_key: &Key, hmac_key: &Key, deep: bool) -> Result<BlockState, SDError> {
+    let name = hmac.to_hex();
+
+    if !deep {
+        return match backend.check_block(&name)? {
+            true => Ok(BlockState::Present),
+            false => Ok(BlockState::Missing),
+        };
+    }
+
+    let raw = match backend.read_block(&name) {
+        Ok(raw) => raw,
+        Err(SDAPIError::BlockMissing) => return Ok(BlockState::Missing),
+        Err(e) => return Err(SDError::from(e)),
+    };
+
+    let block = match WrappedBlock::from(raw, hmac.to_vec()).and_then(|wrapped| wrapped.to_block(main_key)) {
+        Ok(block) => block,
+        Err(e) => {
+            debug!("block {} is unreadable: {}", name, e);
+
+            return Ok(BlockState::Corrupt);
+        },
+    };
+
+    if !block.verify(hmac_key) {
+        debug!("block {} does not match its name", name);
+
+        return Ok(BlockState::Corrupt);
+    }
+
+    Ok(BlockState::Present)
+}
+
+/// sync two small files to a local repository, returning the repository, the folder, the session
+/// and the names of the two blocks holding the file data
+#[cfg(test)]
+fn synced_repository(folder: &::mockapi::TestFolder) -> (::std::sync::Arc<::local_backend::LocalBackend>, u64, String, Vec<String>) {
+    folder.write("first", b"first");
+    folder.write("second", b"second");
+
+    let (repository, folder_id) = folder.local();
+
+    let session = ::util::generate_uuid();
+    ::core::sync_with_backend(repository.clone(), &session, &folder.main, &folder.hmac, &folder.tweak, folder_id, &mut |_, _, _| {}, &mut |_| {}, &mut |_| {}, &mut |_| {}).expect("sync failed");
+
+    // only the blocks holding file data, not the ones holding the session archive
+    let manifest: Vec<String> = ::browse::read_session(&*repository, folder_id, &session, &folder.main).expect("failed to read session")
+        .manifest_hmacs().iter().map(|hmac| hmac.to_hex()).collect();
+
+    let blocks: Vec<String> = repository.list_blocks().unwrap().unwrap().into_iter().map(|block| block.name).filter(|name| !manifest.contains(name)).collect();
+    assert!(blocks.len() == 2);
+
+    (repository, folder_id, session, blocks)
+}
+
+/// where a local repository keeps a block
+#[cfg(test)]
+fn block_path(folder: &::mockapi::TestFolder, name: &str) -> ::std::path::PathBuf {
+    let mut path = folder.storage.join("repository");
+    path.push("blocks");
+    path.push(&name[0..2]);
+    path.push(name);
+
+    path
+}
+
+#[test]
+fn verify_session_test() {
+    use std::io::Write;
+    use binformat::BinaryWriter;
+    use block::Block;
+    use mockapi::TestFolder;
+    use models::SyncVersion;
+
+    let folder = TestFolder::new();
+
+    let (repository, folder_id, session, blocks) = synced_repository(&folder);
+
+    for &deep in &[false, true] {
+        let report = verify_session(&*repository, folder_id, &session, &folder.main, &folder.hmac, deep).expect("verify failed");
+        assert!(report.files == 2);
+        assert!(report.blocks == 2);
+        assert!(report.is_restorable());
+    }
+
+    // one block disappears, the other is replaced with a valid block holding the wrong data
+    repository.remove_blocks(&[blocks[0].clone()]).unwrap();
+
+    let replacement = Block::new(SyncVersion::Version2, &folder.hmac, b"something else".to_vec()).to_wrapped(&folder.main).unwrap();
+
+    ::std::fs::File::create(block_path(&folder, &blocks[1])).unwrap().write_all(&replacement.as_binary()).unwrap();
+
+    let report = verify_session(&*repository, folder_id, &session, &folder.main, &folder.hmac, false).expect("verify failed");
+    assert!(report.broken.len() == 1);
+    assert!(report.broken[0].missing_blocks == 1);
+
+    let report = verify_session(&*repository, folder_id, &session, &folder.main, &folder.hmac, true).expect("verify failed");
+    assert!(report.broken.len() == 2);
+    assert!(report.broken.iter().map(|file| file.missing_blocks).sum::<u64>() == 1);
+    assert!(report.broken.iter().map(|file| file.corrupt_blocks).sum::<u64>() == 1);
+}
+
+#[test]
+fn verify_unknown_version_test() {
+    use std::io::{Read, Write};
+    use mockapi::TestFolder;
+
+    let folder = TestFolder::new();
+
+    let (repository, folder_id, session, blocks) = synced_repository(&folder);
+
+    // the version follows the 2 byte magic and the file type
+    let tampered = block_path(&folder, &blocks[0]);
+
+    let mut raw = Vec::new();
+    ::std::fs::File::open(&tampered).unwrap().read_to_end(&mut raw).unwrap();
+    raw[3] = b'9';
+    raw[4] = b'9';
+    ::std::fs::File::create(&tampered).unwrap().write_all(&raw).unwrap();
+
+    let report = verify_session(&*repository, folder_id, &session, &folder.main, &folder.hmac, false).expect("verify failed");
+    assert!(report.is_restorable());
+
+    let report = verify_session(&*repository, folder_id, &session, &folder.main, &folder.hmac, true).expect("verify failed");
+    assert!(report.broken.len() == 1);
+    assert!(report.broken[0].missing_blocks == 0);
+    assert!(report.broken[0].corrupt_blocks == 1);
+}
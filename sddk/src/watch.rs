@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use std::ffi::{CString, OsStr};
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+
+// external crate imports
+
+#[cfg(target_os = "linux")]
+use walkdir::WalkDir;
+
+// internal imports
+
+use error::SDError;
+use ignore::IgnoreMatcher;
+#[cfg(target_os = "linux")]
+use ignore::IGNORE_FILE;
+
+#[cfg(target_os = "linux")]
+struct WatchedFolder {
+    path: PathBuf,
+    ignore: IgnoreMatcher,
+}
+
+/// Reports which registered folders have changed on disk
+///
+/// On Linux every directory in a folder is watched with inotify, apart from directories the
+/// folder's ignore rules leave out. Directories created later are watched as they appear.
+/// Changes to ignored items are not reported, except for deletions, since there is nothing left
+/// to check the rules against.
+///
+/// Other platforms have no watcher yet, so `wait` never reports a change there and folders are
+/// only synced on their schedule.
+#[cfg(target_os = "linux")]
+pub struct FolderWatcher {
+    fd: ::libc::c_int,
+    folders: HashMap<u64, WatchedFolder>,
+    // watch descriptor -> folder id and the directory it watches, relative to the folder
+    watches: HashMap<::libc::c_int, (u64, PathBuf)>,
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct FolderWatcher {
+    folders: HashMap<u64, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+const WATCH_MASK: u32 = ::libc::IN_CLOSE_WRITE | ::libc::IN_CREATE | ::libc::IN_DELETE | ::libc::IN_MOVED_FROM | ::libc::IN_MOVED_TO | ::libc::IN_ATTRIB | ::libc::IN_DELETE_SELF | ::libc::IN_MOVE_SELF | ::libc::IN_DONT_FOLLOW | ::libc::IN_ONLYDIR;
+
+#[cfg(target_os = "linux")]
+impl FolderWatcher {
+    pub fn new() -> Result<FolderWatcher, SDError> {
+        let fd = unsafe { ::libc::inotify_init1(::libc::IN_NONBLOCK | ::libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(SDError::from(::std::io::Error::last_os_error()));
+        }
+
+        Ok(FolderWatcher {
+            fd: fd,
+            folders: HashMap::new(),
+            watches: HashMap::new(),
+        })
+    }
+
+    /// start watching a folder, replacing any earlier watch of the same folder
+    pub fn watch_folder(&mut self, folder_id: u64, path: &Path, ignore: IgnoreMatcher) -> Result<(), SDError> {
+        self.unwatch_folder(folder_id);
+
+        self.folders.insert(folder_id, WatchedFolder {
+            path: path.to_owned(),
+            ignore: ignore,
+        });
+
+        match self.add_tree(folder_id, Path::new("")) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.unwatch_folder(folder_id);
+
+                Err(e)
+            },
+        }
+    }
+
+    pub fn unwatch_folder(&mut self, folder_id: u64) {
+        let fd = self.fd;
+
+        self.watches.retain(|wd, &mut (id, _)| {
+            if id == folder_id {
+                unsafe { ::libc::inotify_rm_watch(fd, *wd); }
+            }
+
+            id != folder_id
+        });
+
+        self.folders.remove(&folder_id);
+    }
+
+    pub fn watched_folders(&self) -> Vec<u64> {
+        self.folders.keys().cloned().collect()
+    }
+
+    /// wait up to `timeout` for changes, returning the id of every folder that changed
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<u64>, SDError> {
+        let mut pfd = ::libc::pollfd {
+            fd: self.fd,
+            events: ::libc::POLLIN,
+            revents: 0,
+        };
+
+        let timeout_ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos() / 1_000_000);
+
+        let ready = unsafe { ::libc::poll(&mut pfd, 1, timeout_ms as ::libc::c_int) };
+
+        if ready < 0 {
+            let e = ::std::io::Error::last_os_error();
+
+            if e.kind() == ::std::io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+
+            return Err(SDError::from(e));
+        }
+
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut changed: HashSet<u64> = HashSet::new();
+        let mut new_directories: Vec<(u64, PathBuf)> = Vec::new();
+        let mut reload: HashSet<u64> = HashSet::new();
+
+        let header_size = ::std::mem::size_of::<::libc::inotify_event>();
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            let read = unsafe { ::libc::read(self.fd, buffer.as_mut_ptr() as *mut ::libc::c_void, buffer.len()) };
+
+            if read < 0 {
+                let e = ::std::io::Error::last_os_error();
+
+                match e.kind() {
+                    ::std::io::ErrorKind::WouldBlock => break,
+                    ::std::io::ErrorKind::Interrupted => continue,
+                    _ => return Err(SDError::from(e)),
+                }
+            }
+
+            let read = read as usize;
+            let mut offset = 0;
+
+            while offset + header_size <= read {
+                let event: ::libc::inotify_event = unsafe { ::std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const ::libc::inotify_event) };
+
+                let name_start = offset + header_size;
+                let name_end = name_start + event.len as usize;
+
+                offset = name_end;
+
+                // the kernel dropped events, so anything may have changed
+                if event.mask & ::libc::IN_Q_OVERFLOW != 0 {
+                    debug!("inotify queue overflowed, treating every folder as changed");
+
+                    changed.extend(self.folders.keys().cloned());
+
+                    continue;
+                }
+
+                if event.mask & ::libc::IN_IGNORED != 0 {
+                    self.watches.remove(&event.wd);
+
+                    continue;
+                }
+
+                let (folder_id, directory) = match self.watches.get(&event.wd) {
+                    Some(&(folder_id, ref directory)) => (folder_id, directory.clone()),
+                    None => continue,
+                };
+
+                let folder = match self.folders.get(&folder_id) {
+                    Some(folder) => folder,
+                    None => continue,
+                };
+
+                // the name is padded with nul bytes
+                let name = &buffer[name_start..name_end];
+                let name = match name.iter().position(|b| *b == 0) {
+                    Some(end) => &name[..end],
+                    None => name,
+                };
+
+                if name.is_empty() {
+                    // the watched directory itself was removed or moved
+                    changed.insert(folder_id);
+
+                    continue;
+                }
+
+                let relative_path = directory.join(OsStr::from_bytes(name));
+
+                if let Ok(md) = ::std::fs::symlink_metadata(folder.path.join(&relative_path)) {
+                    if folder.ignore.is_ignored(&relative_path, &md) {
+                        trace!("ignoring change to {}", relative_path.display());
+
+                        continue;
+                    }
+
+                    if md.is_dir() && event.mask & (::libc::IN_CREATE | ::libc::IN_MOVED_TO) != 0 {
+                        new_directories.push((folder_id, relative_path.clone()));
+                    }
+                }
+
+                if relative_path == Path::new(IGNORE_FILE) {
+                    reload.insert(folder_id);
+                }
+
+                trace!("{} changed in folder {}", relative_path.display(), folder_id);
+
+                changed.insert(folder_id);
+            }
+        }
+
+        for folder_id in reload {
+            let path = match self.folders.get(&folder_id) {
+                Some(folder) => folder.path.clone(),
+                None => continue,
+            };
+
+            // the old rules stay in place until the file can be read
+            match IgnoreMatcher::for_folder(folder_id, &path) {
+                Ok(ignore) => {
+                    if let Err(e) = self.watch_folder(folder_id, &path, ignore) {
+                        warn!("failed to watch folder {} again after its ignore rules changed: {}", folder_id, e);
+                    }
+                },
+                Err(e) => {
+                    warn!("failed to reload ignore rules for folder {}: {}", folder_id, e);
+                },
+            }
+        }
+
+        for (folder_id, relative_path) in new_directories {
+            if let Err(e) = self.add_tree(folder_id, &relative_path) {
+                warn!("failed to watch {} in folder {}: {}", relative_path.display(), folder_id, e);
+            }
+        }
+
+        Ok(changed.into_iter().collect())
+    }
+
+    // watch a directory in a folder and every directory below it that isn't ignored
+    fn add_tree(&mut self, folder_id: u64, relative_path: &Path) -> Result<(), SDError> {
+        let mut directories: Vec<PathBuf> = Vec::new();
+
+        {
+            let folder = match self.folders.get(&folder_id) {
+                Some(folder) => folder,
+                None => return Ok(()),
+            };
+
+            let folder_path = &folder.path;
+            let ignore = &folder.ignore;
+
+            let walk = WalkDir::new(folder_path.join(relative_path)).into_iter().filter_entry(|entry| {
+                if !entry.file_type().is_dir() {
+                    return false;
+                }
+
+                let relative_path = match entry.path().strip_prefix(folder_path) {
+                    Ok(p) => p,
+                    Err(_) => return true,
+                };
+
+                match ::std::fs::symlink_metadata(entry.path()) {
+                    Ok(md) => !ignore.is_ignored(relative_path, &md),
+                    Err(_) => false,
+                }
+            });
+
+            for entry in walk.filter_map(|e| e.ok()) {
+                directories.push(entry.path().to_owned());
+            }
+        }
+
+        let folder_path = match self.folders.get(&folder_id) {
+            Some(folder) => folder.path.clone(),
+            None => return Ok(()),
+        };
+
+        for directory in directories {
+            let c_path = match CString::new(directory.as_os_str().as_bytes()) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+
+            let wd = unsafe { ::libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+
+            if wd < 0 {
+                let e = ::std::io::Error::last_os_error();
+
+                match e.raw_os_error() {
+                    // removed or replaced since the walk
+                    Some(::libc::ENOENT) | Some(::libc::ENOTDIR) => continue,
+                    Some(::libc::ENOSPC) => {
+                        return Err(SDError::Internal("too many directories to watch, the limit can be raised with the fs.inotify.max_user_watches sysctl".to_string()));
+                    },
+                    _ => return Err(SDError::from(e)),
+                }
+            }
+
+            let relative_path = match directory.strip_prefix(&folder_path) {
+                Ok(p) => p.to_owned(),
+                Err(_) => continue,
+            };
+
+            self.watches.insert(wd, (folder_id, relative_path));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for FolderWatcher {
+    fn drop(&mut self) {
+        unsafe { ::libc::close(self.fd); }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FolderWatcher {
+    pub fn new() -> Result<FolderWatcher, SDError> {
+        Ok(FolderWatcher {
+            folders: HashMap::new(),
+        })
+    }
+
+    pub fn watch_folder(&mut self, folder_id: u64, path: &Path, _: IgnoreMatcher) -> Result<(), SDError> {
+        debug!("changes to {} can't be watched on this platform", path.display());
+
+        self.folders.insert(folder_id, path.to_owned());
+
+        Ok(())
+    }
+
+    pub fn unwatch_folder(&mut self, folder_id: u64) {
+        self.folders.remove(&folder_id);
+    }
+
+    pub fn watched_folders(&self) -> Vec<u64> {
+        self.folders.keys().cloned().collect()
+    }
+
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<u64>, SDError> {
+        ::std::thread::sleep(timeout);
+
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn folder_watcher_test() {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use models::IgnoreRules;
+
+    let dir = ::std::env::temp_dir().join(format!("sddk-watch-{}", ::util::generate_uuid()));
+    fs::create_dir_all(dir.join("existing")).unwrap();
+
+    let rules = IgnoreRules {
+        patterns: vec!["*.tmp".to_string(), "build/".to_string()],
+        max_file_size: None,
+        skip_hidden: false,
+    };
+
+    let mut watcher = FolderWatcher::new().unwrap();
+    watcher.watch_folder(1, &dir, IgnoreMatcher::new(rules).unwrap()).unwrap();
+
+    assert!(watcher.wait(Duration::from_millis(100)).unwrap().is_empty());
+
+    File::create(dir.join("existing").join("a.txt")).unwrap().write_all(b"a").unwrap();
+    assert!(watcher.wait(Duration::from_secs(1)).unwrap() == vec![1]);
+
+    File::create(dir.join("scratch.tmp")).unwrap().write_all(b"tmp").unwrap();
+    assert!(watcher.wait(Duration::from_millis(200)).unwrap().is_empty());
+
+    // new directories are watched as they appear, ignored ones aren't
+    fs::create_dir_all(dir.join("new")).unwrap();
+    fs::create_dir_all(dir.join("build")).unwrap();
+    assert!(watcher.wait(Duration::from_secs(1)).unwrap() == vec![1]);
+
+    File::create(dir.join("build").join("out.o")).unwrap().write_all(b"o").unwrap();
+    assert!(watcher.wait(Duration::from_millis(200)).unwrap().is_empty());
+
+    File::create(dir.join("new").join("b.txt")).unwrap().write_all(b"b").unwrap();
+    assert!(watcher.wait(Duration::from_secs(1)).unwrap() == vec![1]);
+
+    fs::remove_file(dir.join("existing").join("a.txt")).unwrap();
+    assert!(watcher.wait(Duration::from_secs(1)).unwrap() == vec![1]);
+
+    watcher.unwatch_folder(1);
+    assert!(watcher.watched_folders().is_empty());
+
+    File::create(dir.join("c.txt")).unwrap().write_all(b"c").unwrap();
+    assert!(watcher.wait(Duration::from_millis(200)).unwrap().is_empty());
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,2 +1,634 @@
 #![cfg(feature = "webui")]
 
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread;
+
+// external crate imports
+
+use chrono::{Local, TimeZone};
+use parking_lot::Mutex;
+use rocket::{Outcome, Request, State};
+use rocket::config::{Config, Environment};
+use rocket::http::{ContentType, Cookie, Cookies, SameSite, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{Failure, Redirect, Response};
+use rocket::response::content::Html;
+use rocket_contrib::Json;
+use tera::{Context, Tera};
+
+// internal imports
+
+use backend::{StorageBackend, SafeDriveBackend};
+use constants::HMAC_SIZE;
+use error::SDError;
+use keys::{Key, Keyset};
+use models::{SessionEntryType, Token};
+use util::pretty_bytes;
+
+/// the cookie holding the auth token once a browser has logged in
+static AUTH_COOKIE: &'static str = "sdwebui";
+
+/// the header scripts can send the auth token in instead of the cookie
+static AUTH_HEADER: &'static str = "X-SafeDrive-Token";
+
+static BASE_TEMPLATE: &'static str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>SafeDrive</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+td, th { padding: 0.3em 1em; text-align: left; border-bottom: 1px solid #ddd; }
+nav a { margin-right: 1em; }
+.error { color: #a00; }
+</style>
+</head>
+<body>
+<nav><a href="/">Account</a><a href="/folders">Folders</a></nav>
+{% block content %}{% endblock content %}
+</body>
+</html>
+"#;
+
+static ACCOUNT_TEMPLATE: &'static str = r#"{% extends "base.html" %}
+{% block content %}
+<h1>Account</h1>
+<table>
+<tr><th>User</th><td>{{ user }}</td></tr>
+<tr><th>Status</th><td>{{ state }}</td></tr>
+<tr><th>Storage used</th><td>{{ used }} of {{ assigned }}</td></tr>
+<tr><th>Expires</th><td>{{ expires }}</td></tr>
+</table>
+{% endblock content %}
+"#;
+
+static FOLDERS_TEMPLATE: &'static str = r#"{% extends "base.html" %}
+{% block content %}
+<h1>Folders</h1>
+<table>
+<tr><th>Name</th><th>Path</th><th>Encrypted</th></tr>
+{% for folder in folders %}
+<tr><td><a href="/folders/{{ folder.id }}">{{ folder.folderName }}</a></td><td>{{ folder.folderPath }}</td><td>{% if folder.encrypted %}Yes{% else %}No{% endif %}</td></tr>
+{% endfor %}
+</table>
+{% endblock content %}
+"#;
+
+static FOLDER_TEMPLATE: &'static str = r#"{% extends "base.html" %}
+{% block content %}
+<h1>{{ folder.folderName }}</h1>
+<p>{{ folder.folderPath }}</p>
+<form method="post" action="/folders/{{ folder.id }}/sync"><button type="submit">Sync now</button></form>
+<p id="sync-status"></p>
+<progress id="sync-progress" max="100" value="0" hidden></progress>
+<h2>Sessions</h2>
+<table>
+<tr><th>Time</th><th>Size</th><th>Name</th></tr>
+{% for session in sessions %}
+<tr><td><a href="/folders/{{ folder.id }}/sessions/{{ session.name }}">{{ session.time }}</a></td><td>{{ session.size }}</td><td>{{ session.name }}</td></tr>
+{% endfor %}
+</table>
+<script>
+function poll() {
+    fetch("/folders/{{ folder.id }}/progress", { credentials: "same-origin" })
+        .then(function (response) { return response.json(); })
+        .then(function (sync) {
+            var status = document.getElementById("sync-status");
+            var progress = document.getElementById("sync-progress");
+            if (sync === null) {
+                return;
+            }
+            progress.hidden = !sync.running;
+            progress.value = sync.percent;
+            if (sync.running) {
+                status.textContent = "Syncing session " + sync.session_name + ", " + sync.percent.toFixed(1) + "%";
+            } else if (sync.error !== null) {
+                status.textContent = "Sync failed: " + sync.error;
+            } else {
+                status.textContent = "Sync finished";
+            }
+            if (sync.running) {
+                setTimeout(poll, 1000);
+            }
+        });
+}
+poll();
+</script>
+{% endblock content %}
+"#;
+
+static SESSION_TEMPLATE: &'static str = r#"{% extends "base.html" %}
+{% block content %}
+<h1><a href="/folders/{{ folder.id }}">{{ folder.folderName }}</a>, {{ time }}</h1>
+<table>
+<tr><th>Type</th><th>Mode</th><th>Size</th><th>Modified</th><th>Path</th></tr>
+{% for entry in entries %}
+<tr><td>{{ entry.kind }}</td><td>{{ entry.mode }}</td><td>{{ entry.size }}</td><td>{{ entry.modified }}</td>
+<td>{% if entry.download %}<a href="{{ entry.download }}">{{ entry.path }}</a>{% else %}{{ entry.path }}{% endif %}{% if entry.link_target %} &rarr; {{ entry.link_target }}{% endif %}</td></tr>
+{% endfor %}
+</table>
+{% endblock content %}
+"#;
+
+static ERROR_TEMPLATE: &'static str = r#"{% extends "base.html" %}
+{% block content %}
+<h1>Error</h1>
+<p class="error">{{ message }}</p>
+{% endblock content %}
+"#;
+
+static UNAUTHORIZED_PAGE: &'static str = "<!DOCTYPE html><html><body><h1>Not logged in</h1><p>Open the address printed when the web UI was started to log in.</p></body></html>";
+
+struct WebState {
+    token: Token,
+    keyset: Keyset,
+    auth_token: String,
+    port: u16,
+    templates: Tera,
+    /// the last sync started from the web UI for each folder
+    syncs: Arc<Mutex<HashMap<u64, SyncProgress>>>,
+}
+
+#[derive(Serialize, Clone)]
+struct SyncProgress {
+    session_name: String,
+    running: bool,
+    percent: f64,
+    issues: Vec<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionView {
+    name: String,
+    time: String,
+    size: String,
+}
+
+#[derive(Serialize)]
+struct EntryView {
+    kind: &'static str,
+    mode: String,
+    size: String,
+    modified: String,
+    path: String,
+    link_target: Option<String>,
+    /// where to download the file from, only set for files
+    download: Option<String>,
+}
+
+#[derive(FromForm)]
+struct LoginQuery {
+    token: String,
+}
+
+#[derive(FromForm)]
+struct FileQuery {
+    path: String,
+}
+
+/// A request sent to the address the web UI listens on, rather than to some other name that
+/// resolves to this machine, as a page on another site could do
+struct LocalHost;
+
+impl<'a, 'r> FromRequest<'a, 'r> for LocalHost {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<LocalHost, ()> {
+        let web = match request.guard::<State<WebState>>() {
+            Outcome::Success(web) => web,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let allowed = [format!("127.0.0.1:{}", web.port), format!("localhost:{}", web.port)];
+
+        match request.headers().get_one("Host") {
+            Some(host) if allowed.iter().any(|a| a == host) => Outcome::Success(LocalHost),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// A request carrying the auth token, in the cookie set by `/login` or in a header
+struct Authorized;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Authorized {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Authorized, ()> {
+        match request.guard::<LocalHost>() {
+            Outcome::Success(_) => {},
+            _ => return Outcome::Failure((Status::Forbidden, ())),
+        }
+
+        let web = match request.guard::<State<WebState>>() {
+            Outcome::Success(web) => web,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let token = match request.headers().get_one(AUTH_HEADER) {
+            Some(token) => Some(token.to_string()),
+            None => request.cookies().get(AUTH_COOKIE).map(|cookie| cookie.value().to_string()),
+        };
+
+        match token {
+            Some(ref token) if is_auth_token(&web, token) => Outcome::Success(Authorized),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Serve the web UI on `127.0.0.1:<port>` until the process exits
+///
+/// Every page needs a token that is generated here and only ever given to `ready`, along with
+/// the address to open to log in with it, so other users on the same machine can't use the UI.
+/// Logging in stores the token in a cookie that is only sent with requests from the UI's own
+/// pages.
+pub fn serve<R>(token: &Token, keyset: &Keyset, port: u16, ready: &mut R) -> Result<(), SDError> where R: FnMut(&str) {
+    let mut templates = Tera::default();
+
+    if let Err(e) = templates.add_raw_templates(vec![
+        ("base.html", BASE_TEMPLATE),
+        ("account.html", ACCOUNT_TEMPLATE),
+        ("folders.html", FOLDERS_TEMPLATE),
+        ("folder.html", FOLDER_TEMPLATE),
+        ("session.html", SESSION_TEMPLATE),
+        ("error.html", ERROR_TEMPLATE),
+    ]) {
+        return Err(SDError::Internal(format!("failed to load web UI templates: {}", e)));
+    }
+
+    let config = match Config::build(Environment::Production).address("127.0.0.1").port(port).finalize() {
+        Ok(config) => config,
+        Err(e) => return Err(SDError::Internal(format!("failed to configure web UI: {}", e))),
+    };
+
+    let web = WebState {
+        token: token.clone(),
+        keyset: keyset.clone(),
+        auth_token: ::util::generate_uuid(),
+        port: port,
+        templates: templates,
+        syncs: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    ready(&format!("http://127.0.0.1:{}/login?token={}", port, web.auth_token));
+
+    let e = ::rocket::custom(config, false)
+        .manage(web)
+        .mount("/", routes![login, account, folders, folder, start_sync, sync_progress, session, download])
+        .catch(errors![unauthorized])
+        .launch();
+
+    Err(SDError::Internal(format!("failed to start web UI: {}", e)))
+}
+
+#[get("/login?<query>")]
+fn login(_local: LocalHost, web: State<WebState>, mut cookies: Cookies, query: LoginQuery) -> Result<Redirect, Failure> {
+    if !is_auth_token(&web, &query.token) {
+        return Err(Failure(Status::Unauthorized));
+    }
+
+    cookies.add(Cookie::build(AUTH_COOKIE, web.auth_token.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish());
+
+    Ok(Redirect::to("/"))
+}
+
+#[get("/")]
+fn account(_auth: Authorized, web: State<WebState>) -> Html<String> {
+    page(&web, "account.html", || {
+        let status = ::core::get_account_status(&web.token)?;
+        let details = ::core::get_account_details(&web.token)?;
+
+        let state = match status.state {
+            Some(ref state) => format!("{:?}", state),
+            None => "Unknown".to_string(),
+        };
+
+        let mut context = Context::new();
+        context.add("user", &status.userName);
+        context.add("state", &state);
+        context.add("used", &pretty_bytes(details.usedStorage as f64));
+        context.add("assigned", &pretty_bytes(details.assignedStorage as f64));
+        context.add("expires", &format_time_ms(details.expirationDate));
+
+        Ok(context)
+    })
+}
+
+#[get("/folders")]
+fn folders(_auth: Authorized, web: State<WebState>) -> Html<String> {
+    page(&web, "folders.html", || {
+        let folders = ::core::get_sync_folders(&web.token)?;
+
+        let mut context = Context::new();
+        context.add("folders", &folders);
+
+        Ok(context)
+    })
+}
+
+#[get("/folders/<id>")]
+fn folder(_auth: Authorized, web: State<WebState>, id: u64) -> Html<String> {
+    page(&web, "folder.html", || {
+        let folder = ::core::get_sync_folder(&web.token, id)?;
+
+        let mut sessions: Vec<SessionView> = ::core::get_sync_sessions(&web.token)?.into_iter()
+            .filter(|session| session.folder_id == Some(id))
+            .map(|session| {
+                SessionView {
+                    time: session.time.map(format_time_ms).unwrap_or(String::new()),
+                    size: session.size.map(|size| pretty_bytes(size as f64)).unwrap_or(String::new()),
+                    name: session.name,
+                }
+            })
+            .collect();
+
+        // newest first, the times sort the same way as the strings do
+        sessions.sort_by(|a, b| b.time.cmp(&a.time));
+
+        let mut context = Context::new();
+        context.add("folder", &folder);
+        context.add("sessions", &sessions);
+
+        Ok(context)
+    })
+}
+
+#[post("/folders/<id>/sync")]
+fn start_sync(_auth: Authorized, web: State<WebState>, id: u64) -> Redirect {
+    let session_name = {
+        let mut syncs = web.syncs.lock();
+
+        if syncs.get(&id).map(|sync| sync.running).unwrap_or(false) {
+            return Redirect::to(&format!("/folders/{}", id));
+        }
+
+        // finish an interrupted session rather than starting over
+        let session_name = match ::journal::interrupted_session(id) {
+            Some(name) => name,
+            None => ::uuid::Uuid::new_v4().hyphenated().to_string(),
+        };
+
+        syncs.insert(id, SyncProgress {
+            session_name: session_name.clone(),
+            running: true,
+            percent: 0.0,
+            issues: Vec::new(),
+            error: None,
+        });
+
+        session_name
+    };
+
+    let token = web.token.clone();
+    let keyset = web.keyset.clone();
+    let syncs = web.syncs.clone();
+
+    info!("web UI syncing folder {} as session {}", id, session_name);
+
+    thread::spawn(move || {
+        let result = ::core::sync(&token,
+                                  &session_name,
+                                  &keyset.main,
+                                  &keyset.hmac,
+                                  &keyset.tweak,
+                                  id,
+                                  &mut |total, current, _| {
+                                      if let Some(sync) = syncs.lock().get_mut(&id) {
+                                          sync.percent = if total == 0 { 0.0 } else { current as f64 / total as f64 * 100.0 };
+                                      }
+                                  },
+                                  &mut |_| {},
+                                  &mut |message| {
+                                      if let Some(sync) = syncs.lock().get_mut(&id) {
+                                          sync.issues.push(message.to_string());
+                                      }
+                                  },
+                                  &mut |_| {});
+
+        if let Some(sync) = syncs.lock().get_mut(&id) {
+            sync.running = false;
+
+            match result {
+                Ok(()) => sync.percent = 100.0,
+                Err(e) => {
+                    error!("web UI sync of folder {} failed: {}", id, e);
+
+                    sync.error = Some(format!("{}", e));
+                },
+            }
+        }
+    });
+
+    Redirect::to(&format!("/folders/{}", id))
+}
+
+/// the last sync started from the web UI for a folder, or `null` if there hasn't been one
+#[get("/folders/<id>/progress")]
+fn sync_progress(_auth: Authorized, web: State<WebState>, id: u64) -> Json<Option<SyncProgress>> {
+    Json(web.syncs.lock().get(&id).cloned())
+}
+
+#[get("/folders/<id>/sessions/<name>")]
+fn session(_auth: Authorized, web: State<WebState>, id: u64, name: String) -> Html<String> {
+    page(&web, "session.html", || {
+        let folder = ::core::get_sync_folder(&web.token, id)?;
+
+        let time = ::core::get_sync_sessions(&web.token)?.into_iter()
+            .find(|session| session.folder_id == Some(id) && session.name == name)
+            .and_then(|session| session.time)
+            .map(format_time_ms)
+            .unwrap_or(name.clone());
+
+        let entries = ::core::list_session_entries(&web.token, id, &name, &web.keyset.main)?;
+
+        let entries: Vec<EntryView> = entries.into_iter().map(|entry| {
+            let kind = match entry.entry_type {
+                SessionEntryType::File => "file",
+                SessionEntryType::Directory => "dir",
+                SessionEntryType::Symlink => "link",
+                SessionEntryType::HardLink => "hardlink",
+                SessionEntryType::Other => "other",
+            };
+
+            let download = match entry.entry_type {
+                SessionEntryType::File => Some(format!("/folders/{}/sessions/{}/file?path={}", id, name, percent_encode(&entry.path))),
+                _ => None,
+            };
+
+            EntryView {
+                kind: kind,
+                mode: format!("{:o}", entry.mode),
+                size: entry.size.map(|size| pretty_bytes(size as f64)).unwrap_or(String::new()),
+                modified: format_time(entry.mtime as i64),
+                path: entry.path,
+                link_target: entry.link_target,
+                download: download,
+            }
+        }).collect();
+
+        let mut context = Context::new();
+        context.add("folder", &folder);
+        context.add("time", &time);
+        context.add("entries", &entries);
+
+        Ok(context)
+    })
+}
+
+/// download a single file from a session, reading its blocks as the browser reads the response
+#[get("/folders/<id>/sessions/<name>/file?<query>")]
+fn download(_auth: Authorized, web: State<WebState>, id: u64, name: String, query: FileQuery) -> Result<Response<'static>, Failure> {
+    let backend: Arc<StorageBackend> = Arc::new(SafeDriveBackend::new(&web.token));
+
+    let hmac_bag = match find_file(&*backend, id, &name, &query.path, &web.keyset.main) {
+        Ok(Some(hmac_bag)) => hmac_bag,
+        Ok(None) => return Err(Failure(Status::NotFound)),
+        Err(e) => {
+            error!("failed to read session {}: {}", name, e);
+
+            return Err(Failure(Status::InternalServerError));
+        },
+    };
+
+    let file_name = query.path.rsplit('/').next().unwrap_or("file").replace('"', "");
+
+    let reader = FileReader {
+        backend: backend,
+        main_key: web.keyset.main.clone(),
+        session_name: name,
+        hmacs: hmac_bag.chunks(HMAC_SIZE).map(|hmac| hmac.to_vec()).collect(),
+        next_block: 0,
+        buffer: Vec::new(),
+        position: 0,
+    };
+
+    let response = Response::build()
+        .header(ContentType::Binary)
+        .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+        .streamed_body(reader)
+        .finalize();
+
+    Ok(response)
+}
+
+#[error(401)]
+fn unauthorized() -> Html<&'static str> {
+    Html(UNAUTHORIZED_PAGE)
+}
+
+/// Reads the contents of a file in a session one block at a time
+struct FileReader {
+    backend: Arc<StorageBackend>,
+    main_key: Key,
+    session_name: String,
+    hmacs: Vec<Vec<u8>>,
+    next_block: usize,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if self.next_block >= self.hmacs.len() {
+                return Ok(0);
+            }
+
+            let block = match ::fetch::fetch_block(&*self.backend, &self.main_key, &self.session_name, &self.hmacs[self.next_block]) {
+                Ok(block) => block,
+                Err(e) => {
+                    error!("failed to read block for download: {}", e);
+
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+                },
+            };
+
+            self.buffer = block.as_ref().to_vec();
+            self.position = 0;
+            self.next_block += 1;
+        }
+
+        let count = ::std::cmp::min(buf.len(), self.buffer.len() - self.position);
+
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+/// the HMAC list of a regular file in a session
+fn find_file(backend: &StorageBackend, folder_id: u64, session_name: &str, path: &str, main_key: &Key) -> Result<Option<Vec<u8>>, SDError> {
+    let session = ::browse::read_session(backend, folder_id, session_name, main_key)?;
+
+    let contents = ::browse::session_contents(&session)?;
+
+    let file = contents.into_iter().find(|&(ref entry, _)| entry.entry_type == SessionEntryType::File && entry.path == path);
+
+    Ok(file.map(|(_, hmac_bag)| hmac_bag))
+}
+
+/// render a page, or the error page if building its context failed
+fn page<F>(web: &WebState, template: &str, context: F) -> Html<String> where F: FnOnce() -> Result<Context, SDError> {
+    let (template, context) = match context() {
+        Ok(context) => (template, context),
+        Err(e) => {
+            error!("web UI failed to load {}: {}", template, e);
+
+            let mut context = Context::new();
+            context.add("message", &format!("{}", e));
+
+            ("error.html", context)
+        },
+    };
+
+    match web.templates.render(template, &context) {
+        Ok(page) => Html(page),
+        Err(e) => {
+            error!("failed to render {}: {}", template, e);
+
+            Html("failed to render page".to_string())
+        },
+    }
+}
+
+fn is_auth_token(web: &WebState, token: &str) -> bool {
+    ::sodiumoxide::utils::memcmp(token.as_bytes(), web.auth_token.as_bytes())
+}
+
+fn format_time(seconds: i64) -> String {
+    Local.timestamp(seconds, 0).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn format_time_ms(ms: u64) -> String {
+    format_time(ms as i64 / 1000)
+}
+
+/// encode everything but unreserved characters, so a path can be used as a query value
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[test]
+fn percent_encode_test() {
+    assert!(percent_encode("docs/a b&c.txt") == "docs/a%20b%26c.txt");
+    assert!(percent_encode("ü") == "%C3%BC");
+}